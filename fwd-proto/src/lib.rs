@@ -0,0 +1,960 @@
+//! The wire protocol that `fwd` speaks between the client and the server.
+//!
+//! Everything goes over the stdin and stdout of the `fwd --server` process
+//! that the client starts with ssh. Each message is a big-endian `u32`
+//! length followed by the encoded [`Message`]; use [`MessageReader`] and
+//! [`MessageWriter`] to read and write them. If the top bit of the length is
+//! set, the message is followed by its CRC32, which the length includes.
+//!
+//! Anything that implements this protocol can stand in for either side, as
+//! long as it checks the version in [`Message::Hello`].
+
+#![warn(missing_docs)]
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::io::Cursor;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+// ----------------------------------------------------------------------------
+// Errors
+
+/// Everything that can go wrong reading or writing a message, or in a
+/// [`Phase`] of a session around it. The ones that wrap another error hand
+/// it back from `source()`, and leave it out of their own message so that
+/// it isn't said twice in a chain.
+/// There may be more kinds in later versions, so matches need a `_` arm.
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum Error {
+    /// The message type byte isn't one we know; the other side is newer, or
+    /// isn't speaking this protocol.
+    #[error("Unknown message type 0x{0:02x}")]
+    Unknown(u8),
+    /// The message (or the stream) stopped partway through.
+    #[error("Message ended before it was complete")]
+    Incomplete,
+    /// The checksum after a message doesn't match it.
+    #[error("Message checksum does not match; the stream is corrupt")]
+    Corrupt,
+    /// The frame is bigger than [`MAX_FRAME_SIZE`].
+    #[error("Message is {0} bytes, more than the limit of {MAX_FRAME_SIZE}")]
+    TooLarge(usize),
+    /// A string in the message isn't UTF-8.
+    #[error("String contained invalid UTF-8")]
+    InvalidString(#[from] std::str::Utf8Error),
+    /// Reading or writing the stream failed.
+    #[error("I/O error")]
+    IO(#[from] std::io::Error),
+    /// Something went wrong during `phase`, on `port` if it was about one.
+    /// [`Error::during`] makes these out of any other error.
+    #[error("Error {phase}{}", on_port(*.port))]
+    Failed {
+        /// What was going on at the time.
+        phase: Phase,
+        /// The forwarded port it was about, if any.
+        port: Option<u16>,
+        /// What went wrong.
+        #[source]
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+}
+
+fn on_port(port: Option<u16>) -> String {
+    port.map(|p| format!(" on port {p}")).unwrap_or_default()
+}
+
+impl Error {
+    /// `source`, along with what was going on when it happened, so that
+    /// whoever gets it can tell one kind of failure from another.
+    pub fn during<E>(phase: Phase, port: Option<u16>, source: E) -> Error
+    where
+        E: Into<Box<dyn std::error::Error + Send + Sync>>,
+    {
+        Error::Failed { phase, port, source: source.into() }
+    }
+
+    /// What was going on when this happened, if we know.
+    pub fn phase(&self) -> Option<Phase> {
+        match self {
+            Error::Failed { phase, .. } => Some(*phase),
+            _ => None,
+        }
+    }
+
+    /// The forwarded port this is about, if it's about one.
+    pub fn port(&self) -> Option<u16> {
+        match self {
+            Error::Failed { port, .. } => *port,
+            _ => None,
+        }
+    }
+}
+
+/// The parts of a session that can fail, for [`Error::Failed`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Phase {
+    /// Waiting for the server to start talking, before any messages.
+    Sync,
+    /// Reading the server's [`Message::Hello`] and answering it.
+    Hello,
+    /// Listening for connections to a forwarded port.
+    Listen,
+    /// Connecting to a forwarded port on the far side.
+    Dial,
+}
+
+impl std::fmt::Display for Phase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Phase::Sync => "synchronizing",
+            Phase::Hello => "saying hello",
+            Phase::Listen => "listening",
+            Phase::Dial => "connecting",
+        })
+    }
+}
+
+/// What reading or writing a message gives back.
+pub type Result<T> = std::result::Result<T, Error>;
+
+// ----------------------------------------------------------------------------
+// Messages
+
+/// A port that the server has, in a [`Message::Ports`].
+#[derive(Debug, PartialEq, Clone)]
+pub struct PortDesc {
+    /// The port number, on the server's loopback interface.
+    pub port: u16,
+    /// What's listening on it, as the server sees it. Encoding cuts it off
+    /// at 64k bytes.
+    pub desc: String,
+    /// The process that's listening on it, if the server knows. Only sent
+    /// if both sides have the `pids` capability.
+    pub pid: Option<u32>,
+}
+
+/// Everything the client and server say to each other. Each one is a type
+/// byte and then its fields: integers are big-endian, and strings and lists
+/// are a `u16` count followed by that many bytes or items. A `Ports` or
+/// `PortAdded` with any pids in it has its own type byte, with a `u32` pid
+/// (0 for none) after each description.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Message {
+    /// Sent every so often by both sides to test the connection. Servers at
+    /// version 0.3 or later answer with a Pong; clients just ignore it.
+    Ping,
+
+    /// Server info announcement: major version, minor version, headers.
+    /// Headers are of the form `name=value`; see `get_header`.
+    Hello(u8, u8, Vec<String>),
+
+    /// Request to refresh list of ports from client.
+    Refresh,
+
+    /// List of available ports from server to client, in answer to a Refresh
+    /// or, if the client sent the `push=true` setting to a server at version
+    /// 0.4 or later, whenever the list changes.
+    Ports(Vec<PortDesc>),
+
+    /// A URL for the client to open in its browser, from `fwd browse` on
+    /// the server.
+    Browse(String),
+
+    /// Session settings from the client, sent right after the server's
+    /// Hello if the server is at least version 0.3. Settings are of the form
+    /// `name=value`, like headers, and servers ignore the ones they don't
+    /// understand.
+    Configure(Vec<String>),
+
+    /// The paths of the Unix domain sockets that the server has listening.
+    /// Only sent if the client asked with `sockets=true`, after a `Ports`
+    /// whenever the list is different from the last one.
+    Sockets(Vec<String>),
+
+    /// The answer to a Ping.
+    Pong,
+
+    /// Sent by either side just before it goes away on purpose, with the
+    /// reason, so that the other side doesn't have to guess from a broken
+    /// pipe. Only sent if both sides have the `goodbye` capability.
+    Goodbye(String),
+
+    /// A port the server didn't have in the last list it sent, or has a
+    /// new description for. Only sent if both sides have the `delta`
+    /// capability, and never before the first `Ports`.
+    PortAdded(PortDesc),
+
+    /// A port that was in the last list the server sent and isn't anymore,
+    /// under the same terms as `PortAdded`.
+    PortRemoved(u16),
+}
+
+impl Message {
+    /// The message as it goes in a frame, without the length.
+    pub fn encode(self: &Message) -> BytesMut {
+        let mut result = BytesMut::new();
+        self.encode_buf(&mut result);
+        result
+    }
+
+    /// Like `encode`, but onto the end of `result`.
+    pub fn encode_buf<T: BufMut>(self: &Message, result: &mut T) {
+        use Message::*;
+        match self {
+            Ping => {
+                result.put_u8(0x00);
+            }
+            Hello(major, minor, details) => {
+                result.put_u8(0x01);
+                result.put_u8(*major);
+                result.put_u8(*minor);
+                result.put_u16(
+                    details.len().try_into().expect("Too many details"),
+                );
+                for detail in details {
+                    put_string(result, detail);
+                }
+            }
+            Refresh => {
+                result.put_u8(0x05);
+            }
+            Ports(ports) => {
+                let pids = ports.iter().any(|p| p.pid.is_some());
+                result.put_u8(if pids { 0x0E } else { 0x06 });
+
+                result.put_u16(ports.len().try_into().expect("Too many ports"));
+                for port in ports {
+                    result.put_u16(port.port);
+
+                    // Port descriptions can be long, let's make sure they're not.
+                    let sliced = slice_up_to(&port.desc, u16::MAX.into());
+                    put_string(result, sliced);
+                    if pids {
+                        result.put_u32(port.pid.unwrap_or(0));
+                    }
+                }
+            }
+            Browse(url) => {
+                result.put_u8(0x07);
+                put_string(result, url);
+            }
+            Configure(settings) => {
+                result.put_u8(0x08);
+                result.put_u16(
+                    settings.len().try_into().expect("Too many settings"),
+                );
+                for setting in settings {
+                    put_string(result, setting);
+                }
+            }
+            Sockets(paths) => {
+                result.put_u8(0x09);
+                result
+                    .put_u16(paths.len().try_into().expect("Too many sockets"));
+                for path in paths {
+                    put_string(result, path);
+                }
+            }
+            Pong => {
+                result.put_u8(0x0A);
+            }
+            Goodbye(reason) => {
+                result.put_u8(0x0B);
+                put_string(result, reason);
+            }
+            PortAdded(port) => {
+                result.put_u8(if port.pid.is_some() { 0x0F } else { 0x0C });
+                result.put_u16(port.port);
+                put_string(result, slice_up_to(&port.desc, u16::MAX.into()));
+                if let Some(pid) = port.pid {
+                    result.put_u32(pid);
+                }
+            }
+            PortRemoved(port) => {
+                result.put_u8(0x0D);
+                result.put_u16(*port);
+            }
+        };
+    }
+
+    /// Read one message from `cursor`, leaving it just past the end. Bytes
+    /// that run out partway give [`Error::Incomplete`], not a panic.
+    pub fn decode(cursor: &mut Cursor<&[u8]>) -> Result<Message> {
+        use Message::*;
+        match get_u8(cursor)? {
+            0x00 => Ok(Ping),
+            0x01 => {
+                let major = get_u8(cursor)?;
+                let minor = get_u8(cursor)?;
+                let count = get_u16(cursor)?;
+                let mut details = Vec::with_capacity(count.into());
+                for _ in 0..count {
+                    details.push(get_string(cursor)?);
+                }
+                Ok(Hello(major, minor, details))
+            }
+            0x05 => Ok(Refresh),
+            kind @ (0x06 | 0x0E) => {
+                let count = get_u16(cursor)?;
+                let mut ports = Vec::with_capacity(count.into());
+                for _ in 0..count {
+                    let port = get_u16(cursor)?;
+                    let desc = get_string(cursor)?;
+                    let pid = match kind {
+                        0x0E => get_pid(cursor)?,
+                        _ => None,
+                    };
+                    ports.push(PortDesc { port, desc, pid });
+                }
+                Ok(Ports(ports))
+            }
+            0x07 => Ok(Browse(get_string(cursor)?)),
+            0x08 => {
+                let count = get_u16(cursor)?;
+                let mut settings = Vec::with_capacity(count.into());
+                for _ in 0..count {
+                    settings.push(get_string(cursor)?);
+                }
+                Ok(Configure(settings))
+            }
+            0x09 => {
+                let count = get_u16(cursor)?;
+                let mut paths = Vec::with_capacity(count.into());
+                for _ in 0..count {
+                    paths.push(get_string(cursor)?);
+                }
+                Ok(Sockets(paths))
+            }
+            0x0A => Ok(Pong),
+            0x0B => Ok(Goodbye(get_string(cursor)?)),
+            kind @ (0x0C | 0x0F) => {
+                let port = get_u16(cursor)?;
+                let desc = get_string(cursor)?;
+                let pid = match kind {
+                    0x0F => get_pid(cursor)?,
+                    _ => None,
+                };
+                Ok(PortAdded(PortDesc { port, desc, pid }))
+            }
+            0x0D => Ok(PortRemoved(get_u16(cursor)?)),
+            b => Err(Error::Unknown(b)),
+        }
+    }
+}
+
+/// Find the value of the named header in the list of headers from a `Hello`
+/// (or settings from a `Configure`) message, if there is one.
+pub fn get_header<'a>(headers: &'a [String], name: &str) -> Option<&'a str> {
+    headers.iter().find_map(|h| match h.split_once('=') {
+        Some((n, value)) if n == name => Some(value),
+        _ => None,
+    })
+}
+
+/// Optional features of the protocol. The server lists the ones it has in
+/// the `capabilities` header of its `Hello`, and the client lists the ones
+/// it wants with the `capabilities` setting in its `Configure`; a feature
+/// is only used if both lists have it. Names that a side doesn't know are
+/// ignored, so a new feature doesn't need a new version of the protocol.
+pub mod capabilities {
+    /// Sending the list of ports whenever it changes, with `push=true`.
+    /// Servers at version 0.4 have it without saying so.
+    pub const PUSH: &str = "push";
+    /// Sending `Goodbye` before going away.
+    pub const GOODBYE: &str = "goodbye";
+    /// Writing a checksum after every message. Readers always check the
+    /// checksums that are there, so this only says what to write.
+    pub const CHECKSUM: &str = "checksum";
+    /// Sending `PortAdded` and `PortRemoved` for what changed, instead of
+    /// the whole list every time. A `Ports` still replaces the list.
+    pub const DELTA: &str = "delta";
+    /// Sending the pid of the process on each port, in `PortDesc::pid`.
+    pub const PIDS: &str = "pids";
+}
+
+/// The capabilities in the `capabilities` header (or setting), if any.
+pub fn get_capabilities(headers: &[String]) -> impl Iterator<Item = &str> {
+    get_header(headers, "capabilities")
+        .unwrap_or("")
+        .split(',')
+        .filter(|c| !c.is_empty())
+}
+
+/// Whether the `capabilities` header (or setting) has the named one.
+pub fn has_capability(headers: &[String], name: &str) -> bool {
+    get_capabilities(headers).any(|c| c == name)
+}
+
+fn get_u8(cursor: &mut Cursor<&[u8]>) -> Result<u8> {
+    if !cursor.has_remaining() {
+        return Err(Error::Incomplete);
+    }
+    Ok(cursor.get_u8())
+}
+
+fn get_u16(cursor: &mut Cursor<&[u8]>) -> Result<u16> {
+    if cursor.remaining() < 2 {
+        return Err(Error::Incomplete);
+    }
+    Ok(cursor.get_u16())
+}
+
+fn get_pid(cursor: &mut Cursor<&[u8]>) -> Result<Option<u32>> {
+    if cursor.remaining() < 4 {
+        return Err(Error::Incomplete);
+    }
+    Ok(Some(cursor.get_u32()).filter(|&pid| pid != 0))
+}
+
+fn get_bytes(cursor: &mut Cursor<&[u8]>, length: usize) -> Result<Bytes> {
+    if cursor.remaining() < length {
+        return Err(Error::Incomplete);
+    }
+
+    Ok(cursor.copy_to_bytes(length))
+}
+
+fn get_string(cursor: &mut Cursor<&[u8]>) -> Result<String> {
+    let length = get_u16(cursor)?;
+
+    let data = get_bytes(cursor, length.into())?;
+    Ok(std::str::from_utf8(&data[..])?.to_owned())
+}
+
+fn slice_up_to(s: &str, max_len: usize) -> &str {
+    if max_len >= s.len() {
+        return s;
+    }
+    let mut idx = max_len;
+    while !s.is_char_boundary(idx) {
+        idx -= 1;
+    }
+    &s[..idx]
+}
+
+fn put_string<T: BufMut>(target: &mut T, str: &str) {
+    target.put_u16(str.len().try_into().expect("String is too long"));
+    target.put_slice(str.as_bytes());
+}
+
+// ----------------------------------------------------------------------------
+// Message IO
+
+/// The bit in the frame length that says the message has a checksum.
+const CHECKSUM_FLAG: u32 = 0x8000_0000;
+
+/// The most that a frame can hold, checksum and all. Nothing we send comes
+/// close, so a bigger length means the stream is garbage and there's no
+/// point trying to read (or allocate room for) the rest.
+pub const MAX_FRAME_SIZE: usize = 64 * 1024 * 1024;
+
+/// Writes messages to a stream, one frame at a time.
+pub struct MessageWriter<T: AsyncWrite + Unpin> {
+    writer: T,
+    checksums: bool,
+    // Where each frame is put together, so that it goes out in one write;
+    // it's kept around so that it only grows for the first big message.
+    buffer: BytesMut,
+}
+
+impl<T: AsyncWrite + Unpin> MessageWriter<T> {
+    /// A writer that doesn't write checksums until it's told to.
+    pub fn new(writer: T) -> MessageWriter<T> {
+        MessageWriter {
+            writer,
+            checksums: false,
+            buffer: BytesMut::new(),
+        }
+    }
+
+    /// Whether to write a checksum after every message. Only turn this on
+    /// if the other side has the `checksum` capability; older readers take
+    /// the flag for part of the length.
+    pub fn set_checksums(&mut self, checksums: bool) {
+        self.checksums = checksums;
+    }
+
+    /// Write `msg` in a single frame, and flush it.
+    pub async fn write(&mut self, msg: Message) -> Result<()> {
+        let buffer = &mut self.buffer;
+        buffer.clear();
+        buffer.put_u32(0); // The length, once we know it.
+        msg.encode_buf(buffer);
+
+        let length = buffer.len() - 4;
+        let limit = if self.checksums {
+            MAX_FRAME_SIZE - 4
+        } else {
+            MAX_FRAME_SIZE
+        };
+        if length > limit {
+            return Err(Error::TooLarge(length));
+        }
+        let length = length as u32; // Way under the checksum flag, too.
+        let frame_length = if self.checksums {
+            let checksum = crc32fast::hash(&buffer[4..]);
+            buffer.put_u32(checksum);
+            (length + 4) | CHECKSUM_FLAG
+        } else {
+            length
+        };
+        buffer[..4].copy_from_slice(&frame_length.to_be_bytes());
+
+        self.writer.write_all(buffer).await?;
+        self.writer.flush().await?;
+        Ok(())
+    }
+}
+
+/// Reads messages from a stream, with or without checksums.
+pub struct MessageReader<T: AsyncRead + Unpin> {
+    reader: T,
+}
+
+impl<T: AsyncRead + Unpin> MessageReader<T> {
+    /// A reader for the frames on `reader`.
+    pub fn new(reader: T) -> MessageReader<T> {
+        MessageReader { reader }
+    }
+
+    /// Read the next message, waiting for all of it.
+    pub async fn read(&mut self) -> Result<Message> {
+        let frame_length = self.reader.read_u32().await?;
+        let checksummed = frame_length & CHECKSUM_FLAG != 0;
+        let frame_length = (frame_length & !CHECKSUM_FLAG) as usize;
+        if frame_length > MAX_FRAME_SIZE {
+            return Err(Error::TooLarge(frame_length));
+        }
+        let mut data = vec![0; frame_length];
+        self.reader.read_exact(&mut data).await?;
+
+        let data = if checksummed {
+            if data.len() < 4 {
+                return Err(Error::Corrupt);
+            }
+            let (data, checksum) = data.split_at(data.len() - 4);
+            if crc32fast::hash(data).to_be_bytes() != checksum {
+                return Err(Error::Corrupt);
+            }
+            data
+        } else {
+            &data[..]
+        };
+
+        let mut cursor = Cursor::new(data);
+        Message::decode(&mut cursor)
+    }
+}
+
+#[cfg(test)]
+mod message_tests {
+    use crate::Message::*;
+    use crate::PortDesc;
+    use crate::{get_capabilities, has_capability};
+    use crate::{get_header, Message, MessageReader, MessageWriter};
+
+    fn assert_round_trip(message: Message) {
+        let encoded = message.encode();
+        let mut cursor = std::io::Cursor::new(&encoded[..]);
+        let result = Message::decode(&mut cursor).unwrap();
+        assert_eq!(message.clone(), result);
+
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .expect("Unable to start tokio runtime");
+
+        rt.block_on(async move {
+            let (client, server) = tokio::io::duplex(64);
+
+            let expected = message.clone();
+            let write = tokio::spawn(async move {
+                let mut writer = MessageWriter::new(client);
+                writer.write(message).await.expect("Write failed");
+            });
+
+            let read = tokio::spawn(async move {
+                let mut reader = MessageReader::new(server);
+                let actual = reader.read().await.expect("Read failed");
+                assert_eq!(expected, actual);
+            });
+
+            write.await.expect("Write proc failed");
+            read.await.expect("Read proc failed");
+        });
+    }
+
+    // A small, seeded generator for messages, so that the property tests
+    // below check the same thousands of cases on every run.
+    struct Random(u64);
+
+    impl Random {
+        fn next(&mut self) -> u64 {
+            // xorshift64
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: u64) -> u64 {
+            self.next() % n
+        }
+
+        fn string(&mut self) -> String {
+            // Mostly short, with some of every width of UTF-8.
+            let len = match self.below(10) {
+                0 => self.below(2000),
+                _ => self.below(20),
+            };
+            (0..len)
+                .map(|_| match self.below(4) {
+                    0 => char::from_u32(self.below(0x80) as u32).unwrap(),
+                    1 => {
+                        char::from_u32(0x80 + self.below(0x780) as u32).unwrap()
+                    }
+                    2 => char::from_u32(0x4e00 + self.below(0x100) as u32)
+                        .unwrap(),
+                    _ => char::from_u32(0x1f600 + self.below(0x50) as u32)
+                        .unwrap(),
+                })
+                .collect()
+        }
+
+        fn strings(&mut self) -> Vec<String> {
+            (0..self.below(8)).map(|_| self.string()).collect()
+        }
+
+        fn pid(&mut self) -> Option<u32> {
+            match self.below(2) {
+                0 => None,
+                _ => Some(1 + self.below(1 << 22) as u32),
+            }
+        }
+
+        fn message(&mut self) -> Message {
+            match self.below(11) {
+                0 => Ping,
+                1 => Pong,
+                2 => Refresh,
+                3 => Hello(
+                    self.below(256) as u8,
+                    self.below(256) as u8,
+                    self.strings(),
+                ),
+                4 => Ports(
+                    (0..self.below(8))
+                        .map(|_| PortDesc {
+                            port: self.below(65536) as u16,
+                            desc: self.string(),
+                            pid: self.pid(),
+                        })
+                        .collect(),
+                ),
+                5 => Browse(self.string()),
+                6 => Configure(self.strings()),
+                7 => Goodbye(self.string()),
+                8 => PortAdded(PortDesc {
+                    port: self.below(65536) as u16,
+                    desc: self.string(),
+                    pid: self.pid(),
+                }),
+                9 => Sockets(self.strings()),
+                _ => PortRemoved(self.below(65536) as u16),
+            }
+        }
+    }
+
+    #[test]
+    fn random_round_trip() {
+        let mut random = Random(0x5eed_f0d0_1234_5678);
+        for _ in 0..1000 {
+            let message = random.message();
+            let encoded = message.encode();
+            let mut cursor = std::io::Cursor::new(&encoded[..]);
+            assert_eq!(Message::decode(&mut cursor).unwrap(), message);
+            assert_eq!(cursor.position() as usize, encoded.len());
+
+            // Anything short of the whole message is incomplete, and never
+            // mistaken for some other message.
+            for cut in 0..encoded.len() {
+                let mut cursor = std::io::Cursor::new(&encoded[..cut]);
+                assert!(matches!(
+                    Message::decode(&mut cursor),
+                    Err(crate::Error::Incomplete)
+                ));
+            }
+        }
+    }
+
+    #[test]
+    fn random_streams() {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .expect("Unable to start tokio runtime");
+
+        rt.block_on(async move {
+            // Whole streams of messages, some with checksums and some not,
+            // come out the way they went in.
+            let mut random = Random(0x0dd_ba11_cafe_f00d);
+            for _ in 0..200 {
+                let messages: Vec<_> =
+                    (0..random.below(20)).map(|_| random.message()).collect();
+                let mut frames = Vec::new();
+                let mut writer = MessageWriter::new(&mut frames);
+                for message in messages.iter() {
+                    writer.set_checksums(random.below(2) == 0);
+                    writer.write(message.clone()).await.unwrap();
+                }
+
+                let mut reader = MessageReader::new(&frames[..]);
+                for message in messages {
+                    assert_eq!(reader.read().await.unwrap(), message);
+                }
+                assert!(matches!(
+                    reader.read().await,
+                    Err(crate::Error::IO(_))
+                ));
+            }
+        });
+    }
+
+    #[test]
+    fn random_garbage() {
+        // Whatever the bytes are, decoding them gives an answer instead of a
+        // panic, and doesn't read past the end.
+        let mut random = Random(0xbad_c0de_dead_beef);
+        for _ in 0..20000 {
+            let len = random.below(64) as usize;
+            let mut bytes: Vec<u8> =
+                (0..len).map(|_| random.below(256) as u8).collect();
+            if let Some(first) = bytes.first_mut() {
+                // Mostly types we know, so that it gets past the first byte.
+                let known = [
+                    0x00, 0x01, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0A, 0x0B, 0x0C,
+                    0x0D, 0x0E, 0x0F,
+                ];
+                if random.below(4) != 0 {
+                    *first = known[random.below(known.len() as u64) as usize];
+                }
+            }
+            let mut cursor = std::io::Cursor::new(&bytes[..]);
+            _ = Message::decode(&mut cursor);
+            assert!(cursor.position() as usize <= bytes.len());
+        }
+    }
+
+    #[test]
+    fn errors() {
+        use std::error::Error as _;
+
+        let mut cursor = std::io::Cursor::new(&[0xfe_u8][..]);
+        let error = Message::decode(&mut cursor).unwrap_err();
+        assert_eq!(error.to_string(), "Unknown message type 0xfe");
+        assert!(error.source().is_none());
+
+        let io = std::io::Error::new(std::io::ErrorKind::BrokenPipe, "gone");
+        let io = crate::Error::from(io);
+        assert_eq!(io.to_string(), "I/O error");
+        assert_eq!(io.source().unwrap().to_string(), "gone");
+
+        let error = crate::Error::during(crate::Phase::Dial, Some(8080), io);
+        assert_eq!(error.to_string(), "Error connecting on port 8080");
+        assert_eq!(error.source().unwrap().to_string(), "I/O error");
+        assert_eq!(error.phase(), Some(crate::Phase::Dial));
+        assert_eq!(error.port(), Some(8080));
+        let error = crate::Error::during(crate::Phase::Sync, None, "no fwd");
+        assert_eq!(error.to_string(), "Error synchronizing");
+        assert_eq!(crate::Error::Corrupt.phase(), None);
+
+        // Anything that takes a std error, like anyhow, takes ours.
+        let error: Box<dyn std::error::Error + Send + Sync> =
+            Box::new(crate::Error::Incomplete);
+        assert_eq!(error.to_string(), "Message ended before it was complete");
+    }
+
+    #[test]
+    fn round_trip() {
+        assert_round_trip(Ping);
+        assert_round_trip(Pong);
+        assert_round_trip(Hello(
+            0x12,
+            0x00,
+            vec!["One".to_string(), "Two".to_string(), "Three".to_string()],
+        ));
+        assert_round_trip(Hello(0x00, 0x01, vec![]));
+        assert_round_trip(Refresh);
+        assert_round_trip(Ports(vec![]));
+        assert_round_trip(Ports(vec![
+            PortDesc {
+                port: 8080,
+                desc: "query-service".to_string(),
+                pid: None,
+            },
+            PortDesc {
+                port: 9090,
+                desc: "metadata-library".to_string(),
+                pid: None,
+            },
+        ]));
+        assert_round_trip(Ports(vec![
+            PortDesc {
+                port: 8080,
+                desc: "query-service".to_string(),
+                pid: Some(4242),
+            },
+            PortDesc {
+                port: 9090,
+                desc: String::new(),
+                pid: None,
+            },
+        ]));
+        assert_round_trip(Browse("https://google.com/".to_string()));
+        assert_round_trip(Configure(vec![]));
+        assert_round_trip(Configure(vec!["discovery=false".to_string()]));
+        assert_round_trip(Sockets(vec![]));
+        assert_round_trip(Sockets(vec!["/run/docker.sock".to_string()]));
+        assert_round_trip(Goodbye("host rebooting".to_string()));
+        assert_round_trip(PortAdded(PortDesc {
+            port: 5432,
+            desc: "postgres".to_string(),
+            pid: None,
+        }));
+        assert_round_trip(PortAdded(PortDesc {
+            port: 5432,
+            desc: "postgres".to_string(),
+            pid: Some(1),
+        }));
+        assert_round_trip(PortRemoved(5432));
+    }
+
+    #[test]
+    fn hello_headers() {
+        let headers = vec![
+            "version=0.5.0".to_string(),
+            "junk".to_string(),
+            "empty=".to_string(),
+        ];
+        assert_eq!(get_header(&headers, "version"), Some("0.5.0"));
+        assert_eq!(get_header(&headers, "empty"), Some(""));
+        assert_eq!(get_header(&headers, "junk"), None);
+        assert_eq!(get_header(&headers, "nope"), None);
+    }
+
+    #[test]
+    fn checksums() {
+        use crate::Error;
+
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .expect("Unable to start tokio runtime");
+
+        rt.block_on(async move {
+            // Readers take both kinds of frames, mixed up however they come.
+            let mut frames = Vec::new();
+            let mut writer = MessageWriter::new(&mut frames);
+            writer.write(Refresh).await.unwrap();
+            writer.set_checksums(true);
+            writer
+                .write(Browse("https://example.com".into()))
+                .await
+                .unwrap();
+            writer.set_checksums(false);
+            writer.write(Ping).await.unwrap();
+
+            let mut reader = MessageReader::new(&frames[..]);
+            assert_eq!(reader.read().await.unwrap(), Refresh);
+            assert_eq!(
+                reader.read().await.unwrap(),
+                Browse("https://example.com".into())
+            );
+            assert_eq!(reader.read().await.unwrap(), Ping);
+
+            // Flip a bit in the URL, which is still a perfectly good URL.
+            frames[16] ^= 0x01;
+            let mut reader = MessageReader::new(&frames[..]);
+            assert_eq!(reader.read().await.unwrap(), Refresh);
+            assert!(matches!(reader.read().await, Err(Error::Corrupt)));
+        });
+    }
+
+    #[test]
+    fn frames() {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .expect("Unable to start tokio runtime");
+
+        rt.block_on(async move {
+            // Each message is written all at once, and the buffer is reused
+            // without leaving anything behind from the last one.
+            let mut frames = Vec::new();
+            let mut writer = MessageWriter::new(&mut frames);
+            writer
+                .write(Browse("https://example.com".into()))
+                .await
+                .unwrap();
+            writer.write(Ping).await.unwrap();
+            assert_eq!(frames[..4], [0, 0, 0, 22]);
+            assert_eq!(frames[26..], [0, 0, 0, 1, 0x00]);
+        });
+    }
+
+    #[test]
+    fn capabilities() {
+        let headers = vec![
+            "version=0.5.0".to_string(),
+            "capabilities=push,,goodbye,teleport".to_string(),
+        ];
+        let found: Vec<_> = get_capabilities(&headers).collect();
+        assert_eq!(found, ["push", "goodbye", "teleport"]);
+        assert!(has_capability(&headers, crate::capabilities::GOODBYE));
+        assert!(!has_capability(&headers, "good"));
+        assert!(!has_capability(&headers[..1], crate::capabilities::PUSH));
+    }
+
+    #[test]
+    fn big_port_desc() {
+        // Strings are capped at 64k let's make a big one!
+        let char = String::from_utf8(vec![0xe0, 0xa0, 0x83]).unwrap();
+        let mut str = String::with_capacity(128 * 1024);
+        while str.len() < 128 * 1024 {
+            str.push_str(&char);
+        }
+
+        let msg = Ports(vec![PortDesc { port: 8080, desc: str, pid: None }]);
+        msg.encode();
+    }
+
+    #[test]
+    fn frame_size_limit() {
+        use crate::Error;
+
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .expect("Unable to start tokio runtime");
+
+        rt.block_on(async move {
+            // Claiming to be huge is enough; we don't wait for the rest.
+            let frame = [0x7f, 0xff, 0xff, 0xff, 0x00];
+            let mut reader = MessageReader::new(&frame[..]);
+            let result = reader.read().await;
+            assert!(matches!(result, Err(Error::TooLarge(0x7fff_ffff))));
+
+            // And we won't write anything that the other side won't read.
+            let desc = "x".repeat(60_000);
+            let ports = (0..2000)
+                .map(|port| PortDesc { port, desc: desc.clone(), pid: None })
+                .collect();
+            let mut frames = Vec::new();
+            let mut writer = MessageWriter::new(&mut frames);
+            let result = writer.write(Ports(ports)).await;
+            assert!(matches!(result, Err(Error::TooLarge(_))));
+            assert!(frames.is_empty());
+        });
+    }
+}
@@ -1,21 +1,19 @@
-use crate::message::{Message, MessageReader, MessageWriter};
 use anyhow::{bail, Context, Result};
+use fwd_proto::{Message, MessageReader, MessageWriter};
 use log::warn;
 use std::os::unix::fs::DirBuilderExt;
 use std::path::PathBuf;
 use tokio::net::{UnixListener, UnixStream};
 use tokio::sync::mpsc;
-use users;
-use xdg;
 
-pub async fn browse_url_impl(url: &String) -> Result<()> {
+pub async fn browse_url_impl(url: &str) -> Result<()> {
     let path = socket_path().context("Error getting socket path")?;
     let stream = UnixStream::connect(&path).await.context(
         "Error connecting to socket (is fwd actually connected here?)",
     )?;
     let mut writer = MessageWriter::new(stream);
     writer
-        .write(Message::Browse(url.clone()))
+        .write(Message::Browse(url.to_string()))
         .await
         .context("Error sending browse message")?;
     Ok(())
@@ -65,7 +63,9 @@ pub fn socket_path() -> Result<PathBuf> {
     Ok(socket_path)
 }
 
-fn socket_directory() -> Result<std::path::PathBuf> {
+/// The directory where we keep sockets for talking to a running fwd; it
+/// still needs to be created.
+pub fn socket_directory() -> Result<std::path::PathBuf> {
     let base_directories = xdg::BaseDirectories::new()
         .context("Error creating BaseDirectories")?;
     match base_directories.place_runtime_file("fwd") {
@@ -95,7 +95,7 @@ async fn handle_connection(
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::message::MessageWriter;
+    use fwd_proto::MessageWriter;
     use tempdir::TempDir;
 
     #[test]
@@ -1,5 +1,5 @@
-use crate::message::Message;
 use anyhow::Result;
+use fwd_proto::Message;
 use tokio::sync::mpsc;
 
 #[cfg(target_family = "unix")]
@@ -8,8 +8,11 @@ mod browse_unix;
 #[cfg(target_family = "unix")]
 use browse_unix::{browse_url_impl, handle_browser_open_impl};
 
+#[cfg(target_family = "unix")]
+pub(crate) use browse_unix::socket_directory;
+
 #[inline]
-pub async fn browse_url(url: &String) {
+pub async fn browse_url(url: &str) {
     if let Err(e) = browse_url_impl(url).await {
         eprintln!("{}", e);
         std::process::exit(1);
@@ -17,7 +20,7 @@ pub async fn browse_url(url: &String) {
 }
 
 #[cfg(not(target_family = "unix"))]
-pub async fn browse_url_impl(_url: &String) -> Result<()> {
+pub async fn browse_url_impl(_url: &str) -> Result<()> {
     use anyhow::anyhow;
     Err(anyhow!(
         "Opening a browser is not supported on this platform"
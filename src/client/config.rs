@@ -1,23 +1,380 @@
-use anyhow::{bail, Result};
+use anyhow::{bail, Context, Result};
+use regex::Regex;
 use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::PathBuf;
+use std::time::Duration;
 use toml::Value;
 
-#[derive(Debug, Clone)]
+/// Presets for the knobs that trade latency against throughput, so that
+/// nobody has to remember what all the individual ssh options do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Profile {
+    /// Interactive traffic: mark packets for low delay and never wait to
+    /// batch up small writes.
+    Latency,
+    /// Whatever ssh and the OS do by default.
+    Balanced,
+    /// Bulk transfers over slower links: mark packets for throughput and
+    /// compress.
+    Throughput,
+}
+
+impl Profile {
+    /// Whether to disable Nagle's algorithm on forwarded sockets.
+    pub fn nodelay(self) -> bool {
+        self == Profile::Latency
+    }
+
+    /// The `-o` options to pass to ssh.
+    pub fn ssh_options(self) -> &'static [&'static str] {
+        match self {
+            Profile::Latency => &["IPQoS=lowdelay", "Compression=no"],
+            Profile::Balanced => &[],
+            Profile::Throughput => &["IPQoS=throughput", "Compression=yes"],
+        }
+    }
+
+    /// How much to read from a forwarded socket at a time, unless the
+    /// configuration says otherwise.
+    pub fn buffer_size(self) -> usize {
+        match self {
+            Profile::Latency | Profile::Balanced => 8 * 1024,
+            Profile::Throughput => 256 * 1024,
+        }
+    }
+}
+
+/// Everything about how we handle connections to forwarded ports, which is
+/// the same for every port on a server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tuning {
+    pub profile: Profile,
+    /// How much we read from one side of a connection before we wait for
+    /// the other side to take it.
+    pub buffer_size: usize,
+    /// How long we wait for the SOCKS proxy to take a connection. That's
+    /// all it covers: ssh's proxy says the connection is made before the
+    /// server has tried to make it, so how long the server takes isn't up
+    /// to us.
+    pub connect_timeout: Duration,
+    /// How many times we try a remote port that the server couldn't connect
+    /// to before we give up on it.
+    pub connect_attempts: u32,
+}
+
+impl std::str::FromStr for Profile {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Profile> {
+        match s {
+            "latency" => Ok(Profile::Latency),
+            "balanced" => Ok(Profile::Balanced),
+            "throughput" => Ok(Profile::Throughput),
+            _ => bail!(
+                "profile must be one of latency, balanced, or throughput, got {s}"
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct PortConfig {
     pub enabled: bool,
     pub description: Option<String>,
+    /// The port to listen on locally, if it isn't the same as the remote
+    /// port.
+    pub local_port: Option<u16>,
+    /// The port to listen on locally if the one we want is taken; without
+    /// one, we take any port that's free.
+    pub fallback_port: Option<u16>,
+    /// The most bytes a second to move through the port, both ways and all
+    /// connections together.
+    pub limit: Option<u64>,
+    /// The host to reach the port on, as the server sees it, if it isn't
+    /// the server itself.
+    pub host: Option<String>,
+    /// The address to listen on locally, if it isn't the server's `bind`.
+    pub bind: Option<IpAddr>,
+}
+
+/// Where to make a remote Unix domain socket available locally.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SocketTarget {
+    Port(u16),
+    Path(String),
 }
 
 #[derive(Debug, Clone)]
 pub struct ServerConfig {
     auto: bool,
+    auto_update: bool,
+    profile: Profile,
+    x11: bool,
+    multiplex: bool,
+    ssh_connections: usize,
+    bootstrap: bool,
+    approve: bool,
+    notify: bool,
+    include: Vec<Regex>,
+    exclude: Vec<Regex>,
+    discovery: bool,
+    only_mine: bool,
+    ignore: Vec<u16>,
+    static_ports: Vec<u16>,
+    reverse: Vec<u16>,
+    sockets: Vec<(String, SocketTarget)>,
+    socks: Option<u16>,
+    keepalive_timeout: Duration,
+    buffer_size: Option<usize>,
+    connect_timeout: Duration,
+    connect_attempts: u32,
+    ssh_args: Vec<String>,
+    bind: IpAddr,
     ports: HashMap<u16, PortConfig>,
+    /// Limits from the command line, which win over the ones in `ports`.
+    limits: HashMap<u16, u64>,
+    /// Hosts from the command line, which win over the ones in `ports`.
+    hosts: HashMap<u16, String>,
+    /// How long we keep going with nothing using the ports, if we stop.
+    idle_exit: Option<Duration>,
 }
 
+/// How long we wait to hear anything from the server before we give up on
+/// the connection and make a new one. The server uses the same default.
+pub const DEFAULT_KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long we wait for a forwarded connection to get through to the remote
+/// port before we give up and close the local side.
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How many times we try a remote port that refuses a connection. A server
+/// that's just restarting is usually back within half a second.
+pub const DEFAULT_CONNECT_ATTEMPTS: u32 = 3;
+
+/// The most attempts we take; the waits between them double each time.
+const MAX_CONNECT_ATTEMPTS: i64 = 10;
+
 impl ServerConfig {
-    #[cfg(test)]
     pub fn default() -> ServerConfig {
-        ServerConfig { auto: true, ports: HashMap::new() }
+        ServerConfig {
+            auto: true,
+            auto_update: true,
+            profile: Profile::Balanced,
+            x11: false,
+            multiplex: false,
+            ssh_connections: 1,
+            bootstrap: false,
+            approve: false,
+            notify: false,
+            include: Vec::new(),
+            exclude: Vec::new(),
+            discovery: true,
+            only_mine: false,
+            ignore: Vec::new(),
+            static_ports: Vec::new(),
+            reverse: Vec::new(),
+            sockets: Vec::new(),
+            socks: None,
+            keepalive_timeout: DEFAULT_KEEPALIVE_TIMEOUT,
+            buffer_size: None,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            connect_attempts: DEFAULT_CONNECT_ATTEMPTS,
+            ssh_args: Vec::new(),
+            bind: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            ports: HashMap::new(),
+            limits: HashMap::new(),
+            hosts: HashMap::new(),
+            idle_exit: None,
+        }
+    }
+
+    /// Whether to replace the copy of fwd we keep on the server when it
+    /// turns out to be older than this fwd.
+    pub fn auto_update(&self) -> bool {
+        self.auto_update
+    }
+
+    pub fn profile(&self) -> Profile {
+        self.profile
+    }
+
+    pub fn set_profile(&mut self, profile: Profile) {
+        self.profile = profile;
+    }
+
+    pub fn tuning(&self) -> Tuning {
+        Tuning {
+            profile: self.profile,
+            buffer_size: self
+                .buffer_size
+                .unwrap_or_else(|| self.profile.buffer_size()),
+            connect_timeout: self.connect_timeout,
+            connect_attempts: self.connect_attempts,
+        }
+    }
+
+    /// Whether ssh should forward X11 from the remote back to our display.
+    pub fn x11(&self) -> bool {
+        self.x11
+    }
+
+    pub fn set_x11(&mut self, x11: bool) {
+        self.x11 = x11;
+    }
+
+    /// Whether to share one ssh connection between sessions, so that
+    /// reconnecting doesn't mean logging in again.
+    pub fn multiplex(&self) -> bool {
+        self.multiplex
+    }
+
+    pub fn set_multiplex(&mut self, multiplex: bool) {
+        self.multiplex = multiplex;
+    }
+
+    /// How many ssh connections to spread forwarded connections across; more
+    /// than one gets more through a long, thin pipe, where one connection's
+    /// window is the limit.
+    pub fn ssh_connections(&self) -> usize {
+        self.ssh_connections
+    }
+
+    pub fn set_ssh_connections(&mut self, connections: usize) {
+        self.ssh_connections = connections;
+    }
+
+    /// Whether to copy this fwd to the server if it doesn't have one.
+    pub fn bootstrap(&self) -> bool {
+        self.bootstrap
+    }
+
+    pub fn set_bootstrap(&mut self, bootstrap: bool) {
+        self.bootstrap = bootstrap;
+    }
+
+    /// The ports to forward without asking the server what it has, if we
+    /// shouldn't ask at all.
+    pub fn static_ports(&self) -> &[u16] {
+        &self.static_ports
+    }
+
+    pub fn add_static_port(&mut self, port: u16) {
+        if !self.static_ports.contains(&port) {
+            self.static_ports.push(port);
+        }
+    }
+
+    /// Make ports the server finds wait for us to say so before we forward
+    /// them, unless the config already says what to do with them.
+    pub fn set_approve(&mut self, approve: bool) {
+        self.approve = approve;
+    }
+
+    /// Whether to show a desktop notification when ports come and go.
+    pub fn notify(&self) -> bool {
+        self.notify
+    }
+
+    pub fn set_notify(&mut self, notify: bool) {
+        self.notify = notify;
+    }
+
+    /// How long nothing can use the forwarded ports before we shut down, if
+    /// we ever do.
+    pub fn idle_exit(&self) -> Option<Duration> {
+        self.idle_exit
+    }
+
+    pub fn set_idle_exit(&mut self, idle_exit: Duration) {
+        self.idle_exit = Some(idle_exit);
+    }
+
+    /// Whether the port has to wait for approval before we forward it.
+    pub fn needs_approval(&self, port: u16) -> bool {
+        self.approve
+            && !self.ports.contains_key(&port)
+            && !self.static_ports.contains(&port)
+    }
+
+    /// Local ports to make available on the remote, on the same port number.
+    pub fn reverse(&self) -> &[u16] {
+        &self.reverse
+    }
+
+    pub fn add_reverse(&mut self, port: u16) {
+        if !self.reverse.contains(&port) {
+            self.reverse.push(port);
+        }
+    }
+
+    /// Extra arguments for ssh, like `-p 2222` or `-J bastion`.
+    pub fn ssh_args(&self) -> &[String] {
+        &self.ssh_args
+    }
+
+    pub fn add_ssh_args(&mut self, args: &[String]) {
+        self.ssh_args.extend_from_slice(args);
+    }
+
+    /// Unix domain sockets on the remote to forward, and where to.
+    pub fn sockets(&self) -> &[(String, SocketTarget)] {
+        &self.sockets
+    }
+
+    #[cfg(test)]
+    pub fn add_socket(&mut self, path: &str, target: SocketTarget) {
+        self.sockets.push((path.to_string(), target));
+    }
+
+    /// The port for ssh's SOCKS proxy, if it should be a fixed one that
+    /// other programs can use too.
+    pub fn socks(&self) -> Option<u16> {
+        self.socks
+    }
+
+    pub fn set_socks(&mut self, port: u16) {
+        self.socks = Some(port);
+    }
+
+    /// The address to listen on locally for forwarded ports, unless the
+    /// port says otherwise.
+    pub fn bind(&self) -> IpAddr {
+        self.bind
+    }
+
+    pub fn set_bind(&mut self, address: IpAddr) {
+        self.bind = address;
+    }
+
+    /// How long either side can go without hearing from the other before it
+    /// decides the connection is dead.
+    pub fn keepalive_timeout(&self) -> Duration {
+        self.keepalive_timeout
+    }
+
+    /// The settings to send to the server when we connect, so that it can
+    /// tailor what it reports to us.
+    pub fn settings(&self) -> Vec<String> {
+        let mut settings = Vec::new();
+        if !self.discovery {
+            settings.push("discovery=false".to_string());
+        }
+        if self.only_mine {
+            settings.push("only_mine=true".to_string());
+        }
+        if !self.ignore.is_empty() {
+            let ports: Vec<String> =
+                self.ignore.iter().map(|p| p.to_string()).collect();
+            settings.push(format!("ignore={}", ports.join(",")));
+        }
+        if self.keepalive_timeout != DEFAULT_KEEPALIVE_TIMEOUT {
+            settings.push(format!(
+                "keepalive_timeout={}",
+                self.keepalive_timeout.as_secs()
+            ));
+        }
+        settings
     }
 
     pub fn contains_key(&self, port: u16) -> bool {
@@ -25,16 +382,111 @@ impl ServerConfig {
     }
 
     pub fn get(&self, port: u16) -> PortConfig {
-        match self.ports.get(&port) {
-            None => PortConfig { enabled: self.auto, description: None },
+        let mut config = match self.ports.get(&port) {
+            None => PortConfig {
+                enabled: self.auto,
+                description: None,
+                local_port: None,
+                fallback_port: None,
+                limit: None,
+                host: None,
+                bind: None,
+            },
             Some(c) => c.clone(),
+        };
+        config.limit = self.limits.get(&port).copied().or(config.limit);
+        if let Some(host) = self.hosts.get(&port) {
+            config.host = Some(host.clone());
+        }
+        // Whatever the server says is listening on the port on its end isn't
+        // what we're forwarding to.
+        if config.description.is_none() {
+            config.description =
+                config.host.as_ref().map(|host| host_port(host, port));
         }
+        config
+    }
+
+    /// Get the configuration for a port that the server just told us about.
+    /// Ports in the config file are taken as they are. Otherwise, a port is
+    /// disabled until it's approved if we're asking for approval, disabled
+    /// if its description matches one of the `exclude` patterns, and
+    /// enabled if it matches one of the `include` patterns; if it matches
+    /// neither, `auto` decides.
+    pub fn get_for_desc(&self, port: u16, desc: &str) -> PortConfig {
+        if self.ports.contains_key(&port) {
+            return self.get(port);
+        }
+
+        let enabled = if self.static_ports.contains(&port) {
+            true // You asked for it by name.
+        } else if self.approve || self.exclude.iter().any(|r| r.is_match(desc))
+        {
+            false
+        } else if self.include.iter().any(|r| r.is_match(desc)) {
+            true
+        } else {
+            self.auto
+        };
+        let host = self.hosts.get(&port);
+        PortConfig {
+            enabled,
+            description: host.map(|host| host_port(host, port)),
+            local_port: None,
+            fallback_port: None,
+            limit: self.limits.get(&port).copied(),
+            host: host.cloned(),
+            bind: None,
+        }
+    }
+
+    /// The ports that go to other hosts, which we forward whatever the
+    /// server has listening on them.
+    pub fn host_ports(&self) -> Vec<u16> {
+        let mut ports: Vec<u16> = self
+            .ports
+            .iter()
+            .filter(|(_, config)| config.host.is_some())
+            .map(|(port, _)| *port)
+            .chain(self.hosts.keys().copied())
+            .collect();
+        ports.sort();
+        ports.dedup();
+        ports
+    }
+
+    /// Move no more than `limit` bytes a second through the remote `port`,
+    /// whatever the config file says.
+    pub fn set_limit(&mut self, port: u16, limit: u64) {
+        self.limits.insert(port, limit);
+    }
+
+    /// Send connections to the remote `port` to that port on `host`, as the
+    /// server sees it, whatever the config file says.
+    pub fn set_host(&mut self, port: u16, host: String) {
+        self.hosts.insert(port, host);
+    }
+
+    /// Listen for the remote `port` on `local_port` instead. Mapping a port
+    /// means that you want it, so this also enables it.
+    pub fn map_port(&mut self, port: u16, local_port: u16) {
+        let config = self.ports.entry(port).or_insert(PortConfig {
+            enabled: true,
+            description: None,
+            local_port: None,
+            fallback_port: None,
+            limit: None,
+            host: None,
+            bind: None,
+        });
+        config.enabled = true;
+        config.local_port = Some(local_port);
     }
 }
 
 #[derive(Debug)]
 pub struct Config {
-    auto: bool,
+    defaults: ServerConfig,
     servers: HashMap<String, ServerConfig>,
 }
 
@@ -42,21 +494,25 @@ impl Config {
     pub fn get(&self, remote: &str) -> ServerConfig {
         match self.servers.get(remote) {
             Some(cfg) => cfg.clone(),
-            None => ServerConfig { auto: self.auto, ports: HashMap::new() },
+            None => self.defaults.clone(),
         }
     }
 }
 
+/// Where the config file is, if we have a home to keep it in.
+pub fn config_path() -> Option<PathBuf> {
+    home::home_dir().map(|home| home.join(".fwd"))
+}
+
 pub fn load_config() -> Result<Config> {
     use std::io::ErrorKind;
 
-    let mut home = match home::home_dir() {
-        Some(h) => h,
+    let path = match config_path() {
+        Some(path) => path,
         None => return Ok(default()),
     };
-    home.push(".fwd");
 
-    let contents = match std::fs::read_to_string(home) {
+    let contents = match std::fs::read_to_string(path) {
         Ok(contents) => contents,
         Err(e) => match e.kind() {
             ErrorKind::NotFound => return Ok(default()),
@@ -64,40 +520,221 @@ pub fn load_config() -> Result<Config> {
         },
     };
 
-    Ok(parse_config(&contents.parse::<Value>()?)?)
+    parse_config(&contents.parse::<Value>()?)
 }
 
 fn default() -> Config {
-    Config { auto: true, servers: HashMap::new() }
+    Config {
+        defaults: ServerConfig::default(),
+        servers: HashMap::new(),
+    }
 }
 
 fn parse_config(value: &Value) -> Result<Config> {
     match value {
         Value::Table(table) => Ok({
-            let auto = match table.get("auto") {
-                None => true,
-                Some(Value::Boolean(v)) => *v,
-                Some(v) => bail!("expected a true or false, got {:?}", v),
+            // Only some settings at the top level are defaults for all of
+            // the servers; things like ports only make sense per server.
+            let base = ServerConfig::default();
+            let defaults = ServerConfig {
+                auto: get_bool(table, "auto", base.auto)?,
+                auto_update: get_bool(table, "auto_update", base.auto_update)?,
+                profile: get_profile(table, base.profile)?,
+                x11: get_bool(table, "x11", base.x11)?,
+                multiplex: get_bool(table, "multiplex", base.multiplex)?,
+                ssh_connections: get_ssh_connections(
+                    table,
+                    base.ssh_connections,
+                )?,
+                bootstrap: get_bool(table, "bootstrap", base.bootstrap)?,
+                approve: get_bool(table, "approve", base.approve)?,
+                notify: get_bool(table, "notify", base.notify)?,
+                include: get_patterns(table, "include", &base.include)?,
+                exclude: get_patterns(table, "exclude", &base.exclude)?,
+                only_mine: get_bool(table, "only_mine", base.only_mine)?,
+                keepalive_timeout: get_seconds(
+                    table,
+                    "keepalive_timeout",
+                    base.keepalive_timeout,
+                )?,
+                buffer_size: get_buffer_size(table, base.buffer_size)?,
+                connect_timeout: get_seconds(
+                    table,
+                    "connect_timeout",
+                    base.connect_timeout,
+                )?,
+                connect_attempts: get_connect_attempts(
+                    table,
+                    base.connect_attempts,
+                )?,
+                ssh_args: get_strings(table, "ssh_args", &base.ssh_args)?,
+                bind: match table.get("bind") {
+                    Some(v) => get_address(v)?,
+                    None => base.bind,
+                },
+                ..base
             };
             Config {
-                auto,
-                servers: get_servers(&table, auto)?,
+                servers: get_servers(table, &defaults)?,
+                defaults,
             }
         }),
         _ => bail!("top level must be a table"),
     }
 }
 
+fn get_bool(
+    table: &toml::value::Table,
+    key: &str,
+    default: bool,
+) -> Result<bool> {
+    match table.get(key) {
+        None => Ok(default),
+        Some(Value::Boolean(v)) => Ok(*v),
+        Some(v) => bail!("expected true or false for {key}, got {:?}", v),
+    }
+}
+
+fn get_address(value: &Value) -> Result<IpAddr> {
+    match value {
+        Value::String(address) => address
+            .parse()
+            .with_context(|| format!("invalid address {address:?}")),
+        v => bail!("expected an address like \"0.0.0.0\", got {:?}", v),
+    }
+}
+
+fn get_seconds(
+    table: &toml::value::Table,
+    key: &str,
+    default: Duration,
+) -> Result<Duration> {
+    match table.get(key) {
+        None => Ok(default),
+        Some(Value::Integer(v)) if *v > 0 => Ok(Duration::from_secs(*v as u64)),
+        Some(v) => bail!("expected a number of seconds for {key}, got {:?}", v),
+    }
+}
+
+/// The most ssh connections we make to one server; past a handful they only
+/// compete with each other.
+pub const MAX_SSH_CONNECTIONS: usize = 16;
+
+/// Check a number of ssh connections from the command line.
+pub fn parse_ssh_connections(s: &str) -> Result<usize> {
+    match s.parse() {
+        Ok(n) if (1..=MAX_SSH_CONNECTIONS).contains(&n) => Ok(n),
+        _ => {
+            bail!("expected a number from 1 to {MAX_SSH_CONNECTIONS}, got {s}")
+        }
+    }
+}
+
+fn get_ssh_connections(
+    table: &toml::value::Table,
+    default: usize,
+) -> Result<usize> {
+    match table.get("ssh_connections") {
+        None => Ok(default),
+        Some(Value::Integer(v))
+            if *v >= 1 && *v <= MAX_SSH_CONNECTIONS as i64 =>
+        {
+            Ok(*v as usize)
+        }
+        Some(v) => bail!(
+            "expected a number from 1 to {MAX_SSH_CONNECTIONS} for \
+             ssh_connections, got {:?}",
+            v
+        ),
+    }
+}
+
+fn get_connect_attempts(
+    table: &toml::value::Table,
+    default: u32,
+) -> Result<u32> {
+    match table.get("connect_attempts") {
+        None => Ok(default),
+        Some(Value::Integer(v)) if (1..=MAX_CONNECT_ATTEMPTS).contains(v) => {
+            Ok(*v as u32)
+        }
+        Some(v) => bail!(
+            "expected a number from 1 to {MAX_CONNECT_ATTEMPTS} for \
+             connect_attempts, got {:?}",
+            v
+        ),
+    }
+}
+
+/// The largest `buffer_size` we take; there are two buffers per connection,
+/// and much more than this just sits in memory.
+const MAX_BUFFER_SIZE: i64 = 16 * 1024 * 1024;
+
+fn get_buffer_size(
+    table: &toml::value::Table,
+    default: Option<usize>,
+) -> Result<Option<usize>> {
+    match table.get("buffer_size") {
+        None => Ok(default),
+        Some(Value::Integer(v)) if *v > 0 && *v <= MAX_BUFFER_SIZE => {
+            Ok(Some(*v as usize))
+        }
+        Some(v) => bail!(
+            "expected a number of bytes up to {MAX_BUFFER_SIZE} for \
+             buffer_size, got {:?}",
+            v
+        ),
+    }
+}
+
+fn get_profile(
+    table: &toml::value::Table,
+    default: Profile,
+) -> Result<Profile> {
+    match table.get("profile") {
+        None => Ok(default),
+        Some(Value::String(v)) => v.parse(),
+        Some(v) => bail!("expected a profile name, got {:?}", v),
+    }
+}
+
+fn get_patterns(
+    table: &toml::value::Table,
+    key: &str,
+    default: &[Regex],
+) -> Result<Vec<Regex>> {
+    match table.get(key) {
+        None => Ok(default.to_vec()),
+        Some(Value::Array(array)) => {
+            let mut patterns = Vec::new();
+            for v in array {
+                match v {
+                    Value::String(p) => {
+                        patterns.push(Regex::new(p).with_context(|| {
+                            format!("invalid {key} pattern")
+                        })?)
+                    }
+                    v => bail!("expected a pattern in {key}, got {:?}", v),
+                }
+            }
+            Ok(patterns)
+        }
+        Some(v) => {
+            bail!("expected an array of patterns for {key}, got {:?}", v)
+        }
+    }
+}
+
 fn get_servers(
     table: &toml::value::Table,
-    auto: bool,
+    defaults: &ServerConfig,
 ) -> Result<HashMap<String, ServerConfig>> {
     match table.get("servers") {
         None => Ok(HashMap::new()),
         Some(Value::Table(table)) => Ok({
             let mut servers = HashMap::new();
             for (k, v) in table {
-                servers.insert(k.clone(), get_server(v, auto)?);
+                servers.insert(k.clone(), get_server(v, defaults)?);
             }
             servers
         }),
@@ -105,20 +742,89 @@ fn get_servers(
     }
 }
 
-fn get_server(value: &Value, auto: bool) -> Result<ServerConfig> {
+fn get_server(value: &Value, defaults: &ServerConfig) -> Result<ServerConfig> {
     match value {
         Value::Table(table) => Ok(ServerConfig {
-            auto: match table.get("auto") {
-                None => auto, // Default to global default
-                Some(Value::Boolean(v)) => *v,
-                Some(v) => bail!("expected true or false, got {:?}", v),
+            auto: get_bool(table, "auto", defaults.auto)?,
+            auto_update: get_bool(table, "auto_update", defaults.auto_update)?,
+            profile: get_profile(table, defaults.profile)?,
+            x11: get_bool(table, "x11", defaults.x11)?,
+            multiplex: get_bool(table, "multiplex", defaults.multiplex)?,
+            ssh_connections: get_ssh_connections(
+                table,
+                defaults.ssh_connections,
+            )?,
+            bootstrap: get_bool(table, "bootstrap", defaults.bootstrap)?,
+            approve: get_bool(table, "approve", defaults.approve)?,
+            notify: get_bool(table, "notify", defaults.notify)?,
+            include: get_patterns(table, "include", &defaults.include)?,
+            exclude: get_patterns(table, "exclude", &defaults.exclude)?,
+            discovery: get_bool(table, "discovery", defaults.discovery)?,
+            only_mine: get_bool(table, "only_mine", defaults.only_mine)?,
+            ignore: get_port_list(table, "ignore")?,
+            static_ports: get_port_list(table, "static_ports")?,
+            reverse: get_port_list(table, "reverse")?,
+            sockets: get_sockets(table)?,
+            socks: match table.get("socks") {
+                None => None,
+                Some(v) => Some(get_port_number(v)?),
+            },
+            keepalive_timeout: get_seconds(
+                table,
+                "keepalive_timeout",
+                defaults.keepalive_timeout,
+            )?,
+            buffer_size: get_buffer_size(table, defaults.buffer_size)?,
+            connect_timeout: get_seconds(
+                table,
+                "connect_timeout",
+                defaults.connect_timeout,
+            )?,
+            connect_attempts: get_connect_attempts(
+                table,
+                defaults.connect_attempts,
+            )?,
+            ssh_args: get_strings(table, "ssh_args", &defaults.ssh_args)?,
+            bind: match table.get("bind") {
+                Some(v) => get_address(v)?,
+                None => defaults.bind,
             },
             ports: get_ports(table)?,
+            limits: HashMap::new(),
+            hosts: HashMap::new(),
+            idle_exit: None,
         }),
         value => bail!("expected a table, got {:?}", value),
     }
 }
 
+/// `host:port`, with brackets around IPv6 addresses so that it's clear
+/// where the port starts.
+pub fn host_port(host: &str, port: u16) -> String {
+    if host.contains(':') {
+        format!("[{host}]:{port}")
+    } else {
+        format!("{host}:{port}")
+    }
+}
+
+/// Check a host to reach a port on, as it goes in a SOCKS request: an
+/// address, or a name that fits. Brackets around IPv6 addresses, as in
+/// `[fd00::1]:5432`, are taken off.
+pub fn parse_host(host: &str) -> Result<String> {
+    let host = host
+        .strip_prefix('[')
+        .and_then(|h| h.strip_suffix(']'))
+        .unwrap_or(host);
+    if host.is_empty() || host.len() > 255 {
+        bail!("{host:?} is not a host name or address");
+    }
+    if host.contains(|c: char| c.is_whitespace() || c == '/') {
+        bail!("{host:?} is not a host name or address");
+    }
+    Ok(host.to_string())
+}
+
 fn get_ports(table: &toml::value::Table) -> Result<HashMap<u16, PortConfig>> {
     match table.get("ports") {
         None => Ok(HashMap::new()),
@@ -127,7 +833,7 @@ fn get_ports(table: &toml::value::Table) -> Result<HashMap<u16, PortConfig>> {
             for (k,v) in table {
                 let port:u16 = k.parse()?;
                 let config = match v {
-                    Value::Boolean(enabled) => PortConfig{enabled:*enabled, description:None},
+                    Value::Boolean(enabled) => PortConfig{enabled:*enabled, description:None, local_port:None, fallback_port:None, limit:None, host:None, bind:None},
                     Value::Table(table) => PortConfig{
                         enabled: match table.get("enabled") {
                             Some(Value::Boolean(enabled)) => *enabled,
@@ -138,6 +844,28 @@ fn get_ports(table: &toml::value::Table) -> Result<HashMap<u16, PortConfig>> {
                             Some(v) => bail!("expect a string description, got {:?}", v),
                             None => None,
                         },
+                        local_port: match table.get("local_port") {
+                            Some(v) => Some(get_port_number(v)?),
+                            None => None,
+                        },
+                        fallback_port: match table.get("fallback_port") {
+                            Some(v) => Some(get_port_number(v)?),
+                            None => None,
+                        },
+                        limit: match table.get("limit") {
+                            Some(Value::String(rate)) => Some(parse_rate(rate)?),
+                            Some(v) => bail!("expected a rate like \"1MBps\" for limit, got {:?}", v),
+                            None => None,
+                        },
+                        host: match table.get("host") {
+                            Some(Value::String(host)) => Some(parse_host(host)?),
+                            Some(v) => bail!("expected a host name or address for host, got {:?}", v),
+                            None => None,
+                        },
+                        bind: match table.get("bind") {
+                            Some(v) => Some(get_address(v)?),
+                            None => None,
+                        },
                     },
                     _ => bail!("expected either a boolean (enabled) or a table for a port config, got {:?}", v),
                 };
@@ -148,7 +876,7 @@ fn get_ports(table: &toml::value::Table) -> Result<HashMap<u16, PortConfig>> {
         Some(Value::Array(array)) => Ok({
             let mut ports = HashMap::new();
             for v in array {
-                ports.insert(get_port_number(v)?, PortConfig{enabled:true, description:None});
+                ports.insert(get_port_number(v)?, PortConfig{enabled:true, description:None, local_port:None, fallback_port:None, limit:None, host:None, bind:None});
             }
             ports
         }),
@@ -156,6 +884,111 @@ fn get_ports(table: &toml::value::Table) -> Result<HashMap<u16, PortConfig>> {
     }
 }
 
+fn get_sockets(
+    table: &toml::value::Table,
+) -> Result<Vec<(String, SocketTarget)>> {
+    match table.get("sockets") {
+        None => Ok(Vec::new()),
+        Some(Value::Table(table)) => {
+            let mut sockets = Vec::new();
+            for (path, v) in table {
+                let target = match v {
+                    Value::Integer(_) => {
+                        SocketTarget::Port(get_port_number(v)?)
+                    }
+                    Value::String(s) => SocketTarget::Path(s.clone()),
+                    v => bail!(
+                        "expected a port or a path for socket {path}, got {:?}",
+                        v
+                    ),
+                };
+                sockets.push((path.clone(), target));
+            }
+            Ok(sockets)
+        }
+        Some(v) => bail!("expected a table of sockets, got {:?}", v),
+    }
+}
+
+fn get_strings(
+    table: &toml::value::Table,
+    key: &str,
+    default: &[String],
+) -> Result<Vec<String>> {
+    match table.get(key) {
+        None => Ok(default.to_vec()),
+        Some(Value::Array(array)) => array
+            .iter()
+            .map(|v| match v {
+                Value::String(s) => Ok(s.clone()),
+                v => bail!("expected a string in {key}, got {:?}", v),
+            })
+            .collect(),
+        Some(v) => bail!("expected an array of strings for {key}, got {:?}", v),
+    }
+}
+
+fn get_port_list(table: &toml::value::Table, key: &str) -> Result<Vec<u16>> {
+    match table.get(key) {
+        None => Ok(Vec::new()),
+        Some(Value::Array(array)) => {
+            array.iter().map(get_port_number).collect()
+        }
+        Some(v) => bail!("expected an array of ports for {key}, got {:?}", v),
+    }
+}
+
+/// Parse a rate like `1MBps`, `500KiB/s` or `2000`, in bytes a second. KB,
+/// MB and GB count in thousands; KiB, MiB and GiB in 1024s.
+pub fn parse_rate(rate: &str) -> Result<u64> {
+    let s = rate.trim();
+    let s = s
+        .strip_suffix("ps")
+        .or_else(|| s.strip_suffix("/s"))
+        .unwrap_or(s);
+    let split = s
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let scale = match unit.trim() {
+        "" | "B" => 1.0,
+        "K" | "KB" => 1e3,
+        "M" | "MB" => 1e6,
+        "G" | "GB" => 1e9,
+        "KiB" => 1024.0,
+        "MiB" => 1024.0 * 1024.0,
+        "GiB" => 1024.0 * 1024.0 * 1024.0,
+        _ => bail!("expected a rate like 1MBps, got {rate}"),
+    };
+    match number.parse::<f64>() {
+        Ok(number) if number * scale >= 1.0 => Ok((number * scale) as u64),
+        _ => bail!("expected a rate like 1MBps, got {rate}"),
+    }
+}
+
+/// Parse a length of time like `90s`, `30m`, `2h` or `1d`; a plain number
+/// is in seconds.
+pub fn parse_duration(duration: &str) -> Result<Duration> {
+    let s = duration.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let scale = match unit.trim() {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => bail!("expected a time like 2h, got {duration}"),
+    };
+    match number
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(scale))
+    {
+        Some(secs) if secs > 0 => Ok(Duration::from_secs(secs)),
+        _ => bail!("expected a time like 2h, got {duration}"),
+    }
+}
+
 fn get_port_number(v: &Value) -> Result<u16> {
     let port: u16 = match v {
         Value::Integer(i) => (*i).try_into()?,
@@ -163,3 +996,534 @@ fn get_port_number(v: &Value) -> Result<u16> {
     };
     Ok(port)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Config {
+        parse_config(&text.parse::<Value>().expect("Invalid TOML"))
+            .expect("Invalid config")
+    }
+
+    #[test]
+    fn profiles() {
+        let config = parse(
+            r#"
+            profile = "latency"
+
+            [servers.a]
+            [servers.b]
+            profile = "throughput"
+            "#,
+        );
+        assert_eq!(config.get("a").profile(), Profile::Latency);
+        assert_eq!(config.get("b").profile(), Profile::Throughput);
+        assert_eq!(config.get("c").profile(), Profile::Latency);
+
+        assert_eq!(parse("").get("a").profile(), Profile::Balanced);
+        assert!(parse_config(&"profile = 'fast'".parse::<Value>().unwrap())
+            .is_err());
+    }
+
+    #[test]
+    fn buffer_size() {
+        let config = parse(
+            r#"
+            buffer_size = 65536
+            [servers.a]
+            profile = "throughput"
+            [servers.b]
+            buffer_size = 1024
+            "#,
+        );
+        assert_eq!(config.get("a").tuning().buffer_size, 65536);
+        assert_eq!(config.get("b").tuning().buffer_size, 1024);
+        assert_eq!(config.get("c").tuning().buffer_size, 65536);
+
+        let config = parse("[servers.a]\nprofile = 'throughput'");
+        assert_eq!(config.get("a").tuning().buffer_size, 256 * 1024);
+        assert_eq!(config.get("b").tuning().buffer_size, 8 * 1024);
+        assert!(
+            parse_config(&"buffer_size = 0".parse::<Value>().unwrap()).is_err()
+        );
+        assert!(parse_config(
+            &"buffer_size = 1073741824".parse::<Value>().unwrap()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn connect_timeout() {
+        let config = parse(
+            r#"
+            connect_timeout = 5
+            [servers.a]
+            connect_timeout = 60
+            "#,
+        );
+        let timeout = |server| config.get(server).tuning().connect_timeout;
+        assert_eq!(timeout("a"), Duration::from_secs(60));
+        assert_eq!(timeout("b"), Duration::from_secs(5));
+        assert_eq!(
+            parse("").get("a").tuning().connect_timeout,
+            DEFAULT_CONNECT_TIMEOUT
+        );
+        assert!(
+            parse_config(&"connect_timeout = 0".parse::<Value>().unwrap())
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn connect_attempts() {
+        let config = parse(
+            r#"
+            connect_attempts = 5
+            [servers.a]
+            connect_attempts = 1
+            "#,
+        );
+        let attempts = |server| config.get(server).tuning().connect_attempts;
+        assert_eq!(attempts("a"), 1);
+        assert_eq!(attempts("b"), 5);
+        assert_eq!(
+            parse("").get("a").tuning().connect_attempts,
+            DEFAULT_CONNECT_ATTEMPTS
+        );
+        for bad in ["connect_attempts = 0", "connect_attempts = 100"] {
+            assert!(parse_config(&bad.parse::<Value>().unwrap()).is_err());
+        }
+    }
+
+    #[test]
+    fn notify() {
+        let config = parse(
+            r#"
+            notify = true
+            [servers.a]
+            notify = false
+            "#,
+        );
+        assert!(!config.get("a").notify());
+        assert!(config.get("b").notify());
+        assert!(!parse("").get("b").notify());
+    }
+
+    #[test]
+    fn approve() {
+        let config = parse(
+            r#"
+            approve = true
+            include = ["node"]
+            [servers.a]
+            static_ports = [5432]
+            [servers.a.ports]
+            8080 = true
+            [servers.b]
+            approve = false
+            "#,
+        );
+        let a = config.get("a");
+        assert!(a.needs_approval(3000));
+        assert!(!a.get_for_desc(3000, "node").enabled);
+        assert!(!a.needs_approval(8080));
+        assert!(a.get_for_desc(8080, "").enabled);
+        assert!(!a.needs_approval(5432));
+        assert!(a.get_for_desc(5432, "").enabled);
+
+        assert!(!config.get("b").needs_approval(3000));
+        assert!(config.get("b").get_for_desc(3000, "node").enabled);
+        assert!(!parse("").get("a").needs_approval(3000));
+    }
+
+    #[test]
+    fn static_ports() {
+        let config = parse(
+            r#"
+            auto = false
+            [servers.a]
+            static_ports = [5432, 8080]
+            [servers.a.ports]
+            8080 = false
+            "#,
+        );
+        let mut a = config.get("a");
+        assert_eq!(a.static_ports(), &[5432, 8080]);
+        assert!(a.get_for_desc(5432, "").enabled);
+        assert!(!a.get_for_desc(8080, "").enabled);
+        assert!(!a.get_for_desc(3000, "").enabled);
+
+        a.add_static_port(3000);
+        a.add_static_port(5432);
+        assert_eq!(a.static_ports(), &[5432, 8080, 3000]);
+        assert!(a.get_for_desc(3000, "").enabled);
+        assert!(config.get("b").static_ports().is_empty());
+    }
+
+    #[test]
+    fn ssh_connections() {
+        let config = parse(
+            r#"
+            ssh_connections = 4
+            [servers.a]
+            ssh_connections = 1
+            "#,
+        );
+        assert_eq!(config.get("a").ssh_connections(), 1);
+        assert_eq!(config.get("b").ssh_connections(), 4);
+        assert_eq!(parse("").get("a").ssh_connections(), 1);
+
+        for bad in ["ssh_connections = 0", "ssh_connections = 17"] {
+            let value = bad.parse::<Value>().unwrap();
+            assert!(parse_config(&value).is_err());
+        }
+    }
+
+    #[test]
+    fn multiplex() {
+        let config = parse(
+            r#"
+            multiplex = true
+            [servers.a]
+            multiplex = false
+            "#,
+        );
+        assert!(!config.get("a").multiplex());
+        assert!(config.get("b").multiplex());
+        assert!(!parse("").get("a").multiplex());
+    }
+
+    #[test]
+    fn bootstrap() {
+        let config = parse("[servers.a]\nbootstrap = true");
+        assert!(config.get("a").bootstrap());
+        assert!(!config.get("b").bootstrap());
+
+        let config =
+            parse("auto_update = false\n[servers.a]\nauto_update = true");
+        assert!(config.get("a").auto_update());
+        assert!(!config.get("b").auto_update());
+        assert!(parse("").get("a").auto_update());
+    }
+
+    #[test]
+    fn x11() {
+        let config = parse(
+            r#"
+            [servers.a]
+            x11 = true
+            [servers.b]
+            "#,
+        );
+        assert!(config.get("a").x11());
+        assert!(!config.get("b").x11());
+        assert!(!config.get("c").x11());
+
+        assert!(parse("x11 = true").get("c").x11());
+        assert!(parse_config(&"x11 = 'yes'".parse::<Value>().unwrap()).is_err());
+    }
+
+    #[test]
+    fn settings() {
+        let config = parse(
+            r#"
+            [servers.a]
+            discovery = false
+            only_mine = true
+            ignore = [22, 5432]
+            [servers.b]
+            "#,
+        );
+        assert_eq!(
+            config.get("a").settings(),
+            vec!["discovery=false", "only_mine=true", "ignore=22,5432"]
+        );
+        assert!(config.get("b").settings().is_empty());
+        assert!(config.get("c").settings().is_empty());
+        assert!(parse_config(
+            &"[servers.a]\nignore = 22".parse::<Value>().unwrap()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn bind() {
+        let config = parse(
+            r#"
+            bind = "0.0.0.0"
+            [servers.a]
+            bind = "::1"
+            [servers.a.ports]
+            8080 = { enabled = true, bind = "192.168.1.5" }
+            "#,
+        );
+        let a = config.get("a");
+        assert_eq!(a.bind(), "::1".parse::<IpAddr>().unwrap());
+        assert_eq!(a.get(8080).bind, Some("192.168.1.5".parse().unwrap()));
+        assert_eq!(a.get(3000).bind, None);
+        assert!(config.get("b").bind().is_unspecified());
+        assert!(parse("").get("b").bind().is_loopback());
+        assert!(
+            parse_config(&"bind = 'localhost'".parse::<Value>().unwrap())
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn keepalive_timeout() {
+        let config = parse(
+            r#"
+            keepalive_timeout = 60
+            [servers.a]
+            keepalive_timeout = 10
+            [servers.b]
+            "#,
+        );
+        assert_eq!(
+            config.get("a").keepalive_timeout(),
+            Duration::from_secs(10)
+        );
+        assert_eq!(config.get("a").settings(), vec!["keepalive_timeout=10"]);
+        assert_eq!(
+            config.get("b").keepalive_timeout(),
+            Duration::from_secs(60)
+        );
+        assert_eq!(
+            config.get("c").keepalive_timeout(),
+            Duration::from_secs(60)
+        );
+        assert!(parse("").get("c").settings().is_empty());
+        assert!(parse_config(
+            &"keepalive_timeout = 0".parse::<Value>().unwrap()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn reverse() {
+        let mut config = parse(
+            r#"
+            [servers.a]
+            reverse = [3000]
+            "#,
+        )
+        .get("a");
+        assert_eq!(config.reverse(), &[3000]);
+        config.add_reverse(3000);
+        config.add_reverse(4000);
+        assert_eq!(config.reverse(), &[3000, 4000]);
+    }
+
+    #[test]
+    fn ssh_args() {
+        let mut config = parse(
+            r#"
+            ssh_args = ["-J", "bastion"]
+            [servers.a]
+            ssh_args = ["-p", "2222"]
+            "#,
+        );
+        assert_eq!(config.get("a").ssh_args(), ["-p", "2222"]);
+        assert_eq!(config.get("b").ssh_args(), ["-J", "bastion"]);
+
+        let mut b = config.get("b");
+        b.add_ssh_args(&["-i".to_string(), "key".to_string()]);
+        assert_eq!(b.ssh_args(), ["-J", "bastion", "-i", "key"]);
+
+        config = parse("");
+        assert!(config.get("a").ssh_args().is_empty());
+        assert!(
+            parse_config(&"ssh_args = '-p 22'".parse::<Value>().unwrap())
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn sockets() {
+        let config = parse(
+            r#"
+            [servers.a.sockets]
+            "/var/run/docker.sock" = 2375
+            "/tmp/.s.PGSQL.5432" = "/tmp/remote-pg.sock"
+            "#,
+        );
+        let mut sockets = config.get("a").sockets().to_vec();
+        sockets.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            sockets,
+            vec![
+                (
+                    "/tmp/.s.PGSQL.5432".to_string(),
+                    SocketTarget::Path("/tmp/remote-pg.sock".to_string())
+                ),
+                ("/var/run/docker.sock".to_string(), SocketTarget::Port(2375)),
+            ]
+        );
+        assert!(parse_config(
+            &"[servers.a.sockets]\n'/x' = true".parse::<Value>().unwrap()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn socks() {
+        let config = parse(
+            r#"
+            [servers.a]
+            socks = 1080
+            [servers.b]
+            "#,
+        );
+        assert_eq!(config.get("a").socks(), Some(1080));
+        assert_eq!(config.get("b").socks(), None);
+        assert!(parse_config(
+            &"[servers.a]\nsocks = 'yes'".parse::<Value>().unwrap()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn patterns() {
+        let config = parse(
+            r#"
+            auto = false
+            include = ["^node "]
+
+            [servers.a]
+            exclude = ["postgres"]
+
+            [servers.b]
+            include = ["api"]
+
+            [servers.b.ports]
+            5432 = true
+            "#,
+        );
+
+        let a = config.get("a");
+        assert!(a.get_for_desc(3000, "node server.js").enabled);
+        assert!(!a.get_for_desc(5432, "postgres -D /data").enabled);
+        assert!(!a.get_for_desc(8080, "python -m http.server").enabled);
+
+        let b = config.get("b");
+        assert!(!b.get_for_desc(3000, "node server.js").enabled);
+        assert!(b.get_for_desc(8080, "billing-api").enabled);
+        assert!(b.get_for_desc(5432, "postgres -D /data").enabled);
+
+        assert!(
+            parse_config(&"include = ['(']".parse::<Value>().unwrap()).is_err()
+        );
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("2h").unwrap(), Duration::from_secs(7200));
+        assert_eq!(parse_duration("30m").unwrap(), Duration::from_secs(1800));
+        assert_eq!(parse_duration("1d").unwrap(), Duration::from_secs(86400));
+        assert_eq!(parse_duration("90").unwrap(), Duration::from_secs(90));
+        assert!(parse_duration("0s").is_err());
+        assert!(parse_duration("soon").is_err());
+        assert!(parse_duration("1.5h").is_err());
+        assert!(parse_duration("h").is_err());
+        assert!(parse_duration("300000000000000d").is_err());
+    }
+
+    #[test]
+    fn limits() {
+        assert_eq!(parse_rate("1MBps").unwrap(), 1_000_000);
+        assert_eq!(parse_rate("1.5 KiB/s").unwrap(), 1536);
+        assert_eq!(parse_rate("2000").unwrap(), 2000);
+        assert_eq!(parse_rate("1GB").unwrap(), 1_000_000_000);
+        assert!(parse_rate("fast").is_err());
+        assert!(parse_rate("1TBps").is_err());
+        assert!(parse_rate("0").is_err());
+        assert!(parse_rate("MB").is_err());
+
+        let mut config = parse(
+            r#"
+            [servers.a.ports]
+            8080 = { enabled = true, limit = "1MBps" }
+            "#,
+        )
+        .get("a");
+        assert_eq!(config.get(8080).limit, Some(1_000_000));
+        assert_eq!(config.get_for_desc(8080, "").limit, Some(1_000_000));
+        assert_eq!(config.get(3000).limit, None);
+
+        config.set_limit(8080, 1000);
+        config.set_limit(3000, 2000);
+        assert_eq!(config.get(8080).limit, Some(1000));
+        assert_eq!(config.get_for_desc(3000, "node").limit, Some(2000));
+        assert!(parse_config(
+            &"[servers.a.ports]\n80 = { enabled = true, limit = 1 }"
+                .parse::<Value>()
+                .unwrap()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn hosts() {
+        let mut config = parse(
+            r#"
+            [servers.a.ports]
+            5432 = { enabled = true, host = "db.internal" }
+            6379 = { enabled = true, host = "[fd00::1]" }
+            "#,
+        )
+        .get("a");
+        assert_eq!(config.get(5432).host.as_deref(), Some("db.internal"));
+        assert_eq!(config.get(6379).host.as_deref(), Some("fd00::1"));
+        assert_eq!(config.get(8080).host, None);
+        assert_eq!(
+            config.get(5432).description.as_deref(),
+            Some("db.internal:5432")
+        );
+        assert_eq!(
+            config.get(6379).description.as_deref(),
+            Some("[fd00::1]:6379")
+        );
+
+        config.set_host(8080, "web.internal".to_string());
+        assert_eq!(config.host_ports(), [5432, 6379, 8080]);
+        assert_eq!(
+            config.get_for_desc(8080, "").host.as_deref(),
+            Some("web.internal")
+        );
+        assert!(parse_host("").is_err());
+        assert!(parse_host("two words").is_err());
+        assert!(parse_host(&"a".repeat(256)).is_err());
+        assert!(parse_config(
+            &"[servers.a.ports]\n80 = { enabled = true, host = 10 }"
+                .parse::<Value>()
+                .unwrap()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn local_ports() {
+        let mut config = parse(
+            r#"
+            [servers.a.ports]
+            8080 = { enabled = true, local_port = 18080 }
+            9090 = false
+            5000 = { enabled = true, fallback_port = 15000 }
+            "#,
+        )
+        .get("a");
+        assert_eq!(config.get(8080).local_port, Some(18080));
+        assert_eq!(config.get(3000).local_port, None);
+        assert_eq!(config.get(5000).local_port, None);
+        assert_eq!(config.get(5000).fallback_port, Some(15000));
+        assert_eq!(config.get(8080).fallback_port, None);
+
+        config.map_port(9090, 19090);
+        config.map_port(3000, 13000);
+        assert!(config.get(9090).enabled);
+        assert_eq!(config.get(9090).local_port, Some(19090));
+        assert!(config.get_for_desc(3000, "node").enabled);
+        assert_eq!(config.get(3000).local_port, Some(13000));
+    }
+}
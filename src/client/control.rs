@@ -0,0 +1,352 @@
+use super::ui::UIEvent;
+use anyhow::{bail, Result};
+use std::fmt;
+use tokio::sync::mpsc;
+
+/// Something that `fwd ctl` (or `fwd status`) asks a running client to do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    /// List the ports and what's going on with them.
+    Status,
+    /// List the open connections, busiest first.
+    Connections,
+    /// Start forwarding the port.
+    Forward(u16),
+    /// Stop forwarding the port.
+    Stop(u16),
+    /// Start forwarding a port that's waiting for approval.
+    Allow(u16),
+    /// Ask the server for the list of ports right away.
+    Refresh,
+    /// Disconnect and exit, like quitting the terminal UI.
+    Quit,
+}
+
+// Commands go over the socket as a single line, in the same form as they
+// are typed after `fwd ctl`.
+impl std::str::FromStr for Command {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Command> {
+        let words: Vec<_> = s.split_whitespace().collect();
+        match words[..] {
+            ["status"] => Ok(Command::Status),
+            ["connections"] => Ok(Command::Connections),
+            ["refresh"] => Ok(Command::Refresh),
+            ["quit"] => Ok(Command::Quit),
+            ["forward", port] => Ok(Command::Forward(port.parse()?)),
+            ["stop", port] => Ok(Command::Stop(port.parse()?)),
+            ["allow", port] => Ok(Command::Allow(port.parse()?)),
+            _ => bail!("unknown command {s}"),
+        }
+    }
+}
+
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Command::Status => write!(f, "status"),
+            Command::Connections => write!(f, "connections"),
+            Command::Forward(port) => write!(f, "forward {port}"),
+            Command::Stop(port) => write!(f, "stop {port}"),
+            Command::Allow(port) => write!(f, "allow {port}"),
+            Command::Refresh => write!(f, "refresh"),
+            Command::Quit => write!(f, "quit"),
+        }
+    }
+}
+
+/// Accept commands for the session with `remote` until the client exits.
+/// Not being able to is no reason to stop forwarding, so this never returns.
+pub async fn serve(remote: &str, events: mpsc::Sender<UIEvent>) {
+    if let Err(e) = serve_impl(remote, events).await {
+        log::warn!("Not accepting commands from fwd ctl: {e:#}");
+    }
+    std::future::pending().await
+}
+
+#[cfg(target_family = "unix")]
+use unix::{request, serve_impl, socket_paths};
+
+#[cfg(target_family = "unix")]
+mod unix {
+    use super::Command;
+    use crate::client::{history, ui::UIEvent};
+    use anyhow::{bail, Context, Result};
+    use log::warn;
+    use std::os::unix::fs::DirBuilderExt;
+    use std::path::{Path, PathBuf};
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::{UnixListener, UnixStream};
+    use tokio::sync::{mpsc, oneshot};
+
+    /// Hand a command to the UI, which owns the ports, and wait for the answer.
+    async fn execute(
+        command: Command,
+        events: &mpsc::Sender<UIEvent>,
+    ) -> Result<String, String> {
+        let (sender, receiver) = oneshot::channel();
+        if events
+            .send(UIEvent::Control(command, sender))
+            .await
+            .is_err()
+        {
+            return Err("fwd is shutting down".to_string());
+        }
+        receiver
+            .await
+            .unwrap_or_else(|_| Err("fwd is shutting down".to_string()))
+    }
+
+    fn socket_directory() -> Result<PathBuf> {
+        let mut path = crate::browse::socket_directory()?;
+        path.push("control");
+        std::fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(&path)
+            .context("Error creating socket directory")?;
+        Ok(path)
+    }
+
+    /// The sockets to try: the one for `remote`, or every one there is.
+    pub fn socket_paths(remote: Option<&str>) -> Result<Vec<PathBuf>> {
+        let directory = socket_directory()?;
+        match remote {
+            Some(remote) => {
+                Ok(vec![directory.join(history::file_name(remote))])
+            }
+            None => {
+                let mut paths = Vec::new();
+                for entry in std::fs::read_dir(&directory)? {
+                    paths.push(entry?.path());
+                }
+                paths.sort();
+                Ok(paths)
+            }
+        }
+    }
+
+    pub async fn serve_impl(
+        remote: &str,
+        events: mpsc::Sender<UIEvent>,
+    ) -> Result<()> {
+        let path = socket_paths(Some(remote))?.remove(0);
+        serve_with_path(&path, events).await
+    }
+
+    pub(super) async fn serve_with_path(
+        path: &Path,
+        events: mpsc::Sender<UIEvent>,
+    ) -> Result<()> {
+        let _ = std::fs::remove_file(path);
+        let listener = UnixListener::bind(path)
+            .with_context(|| format!("Failed to bind to {}", path.display()))?;
+        loop {
+            let (socket, _) = listener
+                .accept()
+                .await
+                .context("Error accepting connection")?;
+
+            let events = events.clone();
+            tokio::spawn(async move {
+                if let Err(e) = handle_connection(socket, events).await {
+                    warn!("Error handling control connection: {e:?}");
+                }
+            });
+        }
+    }
+
+    // The answer is `ok` or `error` on a line by itself, and then whatever
+    // there is to say about it.
+    async fn handle_connection(
+        socket: UnixStream,
+        events: mpsc::Sender<UIEvent>,
+    ) -> Result<()> {
+        let mut socket = BufReader::new(socket);
+        let mut line = String::new();
+        socket.read_line(&mut line).await?;
+        let response = match line.parse::<Command>() {
+            Ok(command) => execute(command, &events).await,
+            Err(e) => Err(e.to_string()),
+        };
+        let response = match response {
+            Ok(text) => format!("ok\n{text}"),
+            Err(text) => format!("error\n{text}"),
+        };
+        socket.write_all(response.as_bytes()).await?;
+        Ok(())
+    }
+
+    /// Send a command to the client listening on `path`.
+    pub async fn request(
+        path: &Path,
+        command: Command,
+    ) -> Result<Result<String, String>> {
+        let mut socket = UnixStream::connect(path).await?;
+        socket.write_all(format!("{command}\n").as_bytes()).await?;
+        let mut response = String::new();
+        socket.read_to_string(&mut response).await?;
+        match response.split_once('\n') {
+            Some(("ok", text)) => Ok(Ok(text.to_string())),
+            Some(("error", text)) => Ok(Err(text.to_string())),
+            _ => bail!("fwd sent a response we don't understand"),
+        }
+    }
+}
+
+#[cfg(not(target_family = "unix"))]
+async fn serve_impl(
+    _remote: &str,
+    _events: mpsc::Sender<UIEvent>,
+) -> Result<()> {
+    bail!("fwd ctl is not supported on this platform");
+}
+
+#[cfg(not(target_family = "unix"))]
+fn socket_paths(_remote: Option<&str>) -> Result<Vec<std::path::PathBuf>> {
+    bail!("fwd ctl is not supported on this platform");
+}
+
+#[cfg(not(target_family = "unix"))]
+async fn request(
+    _path: &std::path::Path,
+    _command: Command,
+) -> Result<Result<String, String>> {
+    unreachable!("There are never any sockets to send requests to")
+}
+
+async fn run_control_impl(
+    remote: Option<&str>,
+    command: Command,
+) -> Result<Result<String, String>> {
+    // Sockets for sessions that have gone away are left behind, so the ones
+    // that count are the ones we can talk to.
+    let mut found = Vec::new();
+    for path in socket_paths(remote)? {
+        if let Ok(response) = request(&path, Command::Status).await {
+            found.push((path, response));
+        }
+    }
+
+    match (remote, found.len()) {
+        (Some(remote), 0) => bail!("fwd isn't connected to {remote}"),
+        (None, 0) => bail!("fwd isn't connected to anything"),
+        (None, n) if n > 1 => {
+            let names: Vec<_> = found
+                .iter()
+                .filter_map(|(p, _)| p.file_name())
+                .map(|n| n.to_string_lossy())
+                .collect();
+            bail!(
+                "fwd is connected to more than one server, say which: {}",
+                names.join(", ")
+            )
+        }
+        _ => (),
+    }
+
+    let (path, status) = found.remove(0);
+    match command {
+        Command::Status => Ok(status),
+        _ => request(&path, command).await,
+    }
+}
+
+/// Whether there's a client for `remote` that answers on its socket.
+pub async fn is_running(remote: &str) -> bool {
+    let Ok(paths) = socket_paths(Some(remote)) else {
+        return false;
+    };
+    for path in paths {
+        if request(&path, Command::Status).await.is_ok() {
+            return true;
+        }
+    }
+    false
+}
+
+/// Send a command to the running client connected to `remote`, or to the
+/// only one there is.
+pub async fn run_control(remote: Option<&str>, command: Command) {
+    match run_control_impl(remote, command).await {
+        Ok(Ok(text)) => print!("{text}"),
+        Ok(Err(text)) => {
+            eprint!("{text}");
+            std::process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("{e:#}");
+            std::process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands() {
+        for command in [
+            Command::Status,
+            Command::Connections,
+            Command::Forward(9000),
+            Command::Stop(9000),
+            Command::Allow(9000),
+            Command::Refresh,
+            Command::Quit,
+        ] {
+            assert_eq!(
+                command.to_string().parse::<Command>().unwrap(),
+                command
+            );
+        }
+        assert_eq!(
+            "forward  8080\n".parse::<Command>().unwrap(),
+            Command::Forward(8080)
+        );
+        assert!("forward".parse::<Command>().is_err());
+        assert!("stop http".parse::<Command>().is_err());
+        assert!("reboot".parse::<Command>().is_err());
+    }
+
+    #[cfg(target_family = "unix")]
+    #[tokio::test]
+    async fn request_and_response() {
+        let (sender, mut receiver) = mpsc::channel(64);
+        let dir = tempdir::TempDir::new("fwd-control").unwrap();
+        let path = dir.path().join("some.server");
+
+        let listen_path = path.clone();
+        tokio::spawn(async move {
+            unix::serve_with_path(&listen_path, sender).await
+        });
+
+        // Pretend to be the UI.
+        tokio::spawn(async move {
+            while let Some(event) = receiver.recv().await {
+                if let UIEvent::Control(command, reply) = event {
+                    _ = reply.send(match command {
+                        Command::Refresh => Ok("Refreshing\n".to_string()),
+                        _ => Err("Nope\n".to_string()),
+                    });
+                }
+            }
+        });
+
+        let mut attempt = 0;
+        let response = loop {
+            match request(&path, Command::Refresh).await {
+                Ok(response) => break response,
+                Err(e) if attempt == 5 => panic!("Error connecting: {e:?}"),
+                Err(_) => attempt += 1,
+            }
+            tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+        };
+        assert_eq!(response, Ok("Refreshing\n".to_string()));
+
+        let response = request(&path, Command::Stop(8080)).await.unwrap();
+        assert_eq!(response, Err("Nope\n".to_string()));
+    }
+}
@@ -0,0 +1,136 @@
+use super::{control, history};
+use anyhow::{bail, Context, Result};
+use std::path::{Path, PathBuf};
+
+/// Set in the environment of the copy of fwd that `start` runs in the
+/// background, to where it should keep its pidfile.
+const DAEMON_VAR: &str = "FWD_DAEMON";
+
+/// Where the client running in the background for `remote` writes what
+/// happens.
+fn log_path(remote: &str) -> Result<PathBuf> {
+    let base_directories = xdg::BaseDirectories::with_prefix("fwd")
+        .context("Error creating BaseDirectories")?;
+    let mut path = base_directories
+        .create_state_directory("logs")
+        .context("Error creating log directory")?;
+    path.push(format!("{}.log", history::file_name(remote)));
+    Ok(path)
+}
+
+#[cfg(target_family = "unix")]
+fn pid_path(remote: &str) -> Result<PathBuf> {
+    use std::os::unix::fs::DirBuilderExt;
+
+    let mut path = crate::browse::socket_directory()?;
+    path.push("daemon");
+    std::fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(&path)
+        .context("Error creating pidfile directory")?;
+    path.push(format!("{}.pid", history::file_name(remote)));
+    Ok(path)
+}
+
+#[cfg(not(target_family = "unix"))]
+fn pid_path(_remote: &str) -> Result<PathBuf> {
+    bail!("--daemon is not supported on this platform");
+}
+
+/// Run fwd again in the background, with the same arguments but with JSON
+/// output to a log file instead of the terminal UI, and leave it to it.
+pub async fn start(remote: &str) -> Result<()> {
+    if control::is_running(remote).await {
+        bail!("fwd is already running for {remote}; run `fwd stop {remote}` to stop it");
+    }
+
+    let pid_path = pid_path(remote)?;
+    let log_path = log_path(remote)?;
+    let log = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&log_path)
+        .with_context(|| format!("Error opening {}", log_path.display()))?;
+
+    let exe = std::env::current_exe().context("Error finding fwd")?;
+    let mut command = std::process::Command::new(exe);
+    command
+        .args(std::env::args_os().skip(1).filter(|a| a != "--daemon"))
+        .args(["--output", "json"])
+        .env(DAEMON_VAR, pid_path)
+        .stdin(std::process::Stdio::null())
+        .stdout(log.try_clone()?)
+        .stderr(log);
+
+    // Keep Ctrl-C in this terminal from reaching it once we're gone.
+    #[cfg(target_family = "unix")]
+    {
+        use std::os::unix::process::CommandExt;
+        command.process_group(0);
+    }
+
+    let child = command
+        .spawn()
+        .context("Error starting fwd in the background")?;
+    println!(
+        "fwd is connecting to {remote} in the background (pid {}), and \
+         logging to {}",
+        child.id(),
+        log_path.display()
+    );
+    println!("Run `fwd status {remote}` to see the ports and `fwd stop {remote}` to stop it.");
+    Ok(())
+}
+
+/// The pidfile of a client running in the background, which goes away when
+/// the client does.
+#[derive(Debug)]
+pub struct PidFile(PathBuf);
+
+impl PidFile {
+    /// Write the pidfile, if this is the copy of fwd that `start` ran in the
+    /// background.
+    pub fn create() -> Option<PidFile> {
+        let path = std::env::var_os(DAEMON_VAR)?;
+        match PidFile::write(Path::new(&path)) {
+            Ok(pid_file) => Some(pid_file),
+            Err(e) => {
+                // The logger isn't up yet, but stderr goes to the log.
+                eprintln!("Not writing a pidfile: {e:#}");
+                None
+            }
+        }
+    }
+
+    fn write(path: &Path) -> Result<PidFile> {
+        std::fs::write(path, format!("{}\n", std::process::id()))
+            .with_context(|| format!("Error writing {}", path.display()))?;
+        Ok(PidFile(path.to_path_buf()))
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        _ = std::fs::remove_file(&self.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pid_file() {
+        let dir = tempdir::TempDir::new("fwd-daemon").unwrap();
+        let path = dir.path().join("some.server.pid");
+
+        let pid_file = PidFile::write(&path).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            format!("{}\n", std::process::id())
+        );
+        drop(pid_file);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,151 @@
+use super::config::ServerConfig;
+use anyhow::{bail, Context, Result};
+use tokio::io::AsyncWriteExt;
+use tokio::process;
+
+/// Where we keep our own copy of fwd on the server, for servers that don't
+/// have one installed. The remote shell expands the `~`.
+pub const DEPLOY_PATH: &str = "~/.cache/fwd/fwd";
+
+/// Copies the binary from stdin to `DEPLOY_PATH`, so that a copy that's cut
+/// off halfway never gets run.
+const DEPLOY_COMMAND: &str = "mkdir -p ~/.cache/fwd && \
+    cat > ~/.cache/fwd/fwd.part && \
+    chmod 755 ~/.cache/fwd/fwd.part && \
+    mv ~/.cache/fwd/fwd.part ~/.cache/fwd/fwd";
+
+/// Which fwd we run on the server: the one it has, or ours.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Server {
+    Installed,
+    /// The copy in `DEPLOY_PATH`, which might be from an earlier run.
+    Cached,
+    /// The copy in `DEPLOY_PATH`, which we just put there.
+    Uploaded,
+}
+
+impl Server {
+    /// The command that runs this fwd on the server.
+    pub fn program(self) -> &'static str {
+        match self {
+            Server::Installed => "fwd",
+            Server::Cached | Server::Uploaded => DEPLOY_PATH,
+        }
+    }
+}
+
+/// Whether `version` comes before `than`, comparing them a number at a time,
+/// so that 0.9.2 comes before 0.10.0. Anything after a `-` or `+` is
+/// ignored. A version we can't make sense of is older than any other: it's
+/// better to replace it than to keep it.
+pub fn is_older(version: &str, than: &str) -> bool {
+    fn parse(version: &str) -> Option<Vec<u64>> {
+        let release = version.split(['-', '+']).next()?;
+        release.split('.').map(|n| n.parse().ok()).collect()
+    }
+
+    match (parse(version), parse(than)) {
+        (Some(version), Some(than)) => version < than,
+        (None, Some(_)) => true,
+        (_, None) => false,
+    }
+}
+
+/// Run `command` on the server with ssh, on the side.
+fn ssh_run(
+    server: &str,
+    config: &ServerConfig,
+    command: &str,
+) -> process::Command {
+    let mut cmd = process::Command::new("ssh");
+    cmd.arg("-T")
+        .args(config.ssh_args())
+        .arg(server)
+        .arg(command);
+    cmd.stdin(std::process::Stdio::null());
+    cmd.stdout(std::process::Stdio::piped());
+    cmd.stderr(std::process::Stdio::piped());
+    cmd
+}
+
+/// How `uname -sm` describes the machine we're running on, if we know.
+fn local_platform() -> Option<String> {
+    use std::env::consts::{ARCH, OS};
+    let os = match OS {
+        "linux" => "Linux",
+        "macos" => "Darwin",
+        "freebsd" => "FreeBSD",
+        "netbsd" => "NetBSD",
+        "dragonfly" => "DragonFly",
+        _ => return None,
+    };
+    let arch = match (OS, ARCH) {
+        ("macos" | "freebsd", "aarch64") => "arm64",
+        ("freebsd" | "netbsd" | "dragonfly", "x86_64") => "amd64",
+        (_, arch) => arch,
+    };
+    Some(format!("{os} {arch}"))
+}
+
+/// Copy the fwd that's running to `DEPLOY_PATH` on the server, as long as
+/// it's the same kind of machine.
+pub async fn upload(server: &str, config: &ServerConfig) -> Result<()> {
+    let Some(local) = local_platform() else {
+        bail!("Don't know how to tell if the server can run this fwd");
+    };
+    let output = ssh_run(server, config, "uname -sm").output().await?;
+    if !output.status.success() {
+        bail!(
+            "Error running uname: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    let remote = String::from_utf8_lossy(&output.stdout);
+    let remote = remote.trim();
+    if remote != local {
+        bail!("The server is {remote} but this fwd is for {local}");
+    }
+
+    let binary = std::env::current_exe().context("Error finding fwd")?;
+    let binary = tokio::fs::read(&binary)
+        .await
+        .context("Error reading fwd")?;
+    let mut cmd = ssh_run(server, config, DEPLOY_COMMAND);
+    cmd.stdin(std::process::Stdio::piped());
+    let mut child = cmd.spawn()?;
+    let mut stdin = child.stdin.take().expect("child has no stdin");
+    stdin.write_all(&binary).await?;
+    drop(stdin); // That's the end of the file.
+    let output = child.wait_with_output().await?;
+    if !output.status.success() {
+        bail!("{}", String::from_utf8_lossy(&output.stderr).trim());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn older_versions() {
+        assert!(is_older("0.9.2", "0.10.0"));
+        assert!(is_older("0.9", "0.9.1"));
+        assert!(is_older("1.2.3", "1.2.4-beta.1"));
+        assert!(is_older("garbage", "0.1.0"));
+        assert!(!is_older("0.10.0", "0.9.2"));
+        assert!(!is_older("0.9.2", "0.9.2"));
+        assert!(!is_older("0.9.2-beta.1", "0.9.2"));
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn platform_matches_uname() {
+        let output = std::process::Command::new("uname")
+            .arg("-sm")
+            .output()
+            .unwrap();
+        let uname = String::from_utf8(output.stdout).unwrap();
+        assert_eq!(local_platform().as_deref(), Some(uname.trim()));
+    }
+}
@@ -0,0 +1,88 @@
+use serde_json::{json, Value};
+use std::net::SocketAddr;
+
+/// Something that happened, for programs that follow along: these are what
+/// `--output json` writes, one to a line, and what `ClientHandle::events`
+/// yields.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// We're connected to the server, for the first time or again.
+    Connected,
+    /// The connection is gone, and we're making a new one.
+    Disconnected,
+    /// The server has a port we didn't know about.
+    PortAdded {
+        port: u16,
+        desc: Option<String>,
+        enabled: bool,
+        /// Where we listen for it, or would if it were enabled.
+        local: SocketAddr,
+        /// Whether it's waiting to be allowed.
+        pending: bool,
+    },
+    PortRemoved {
+        port: u16,
+    },
+    ConnectionOpened {
+        port: u16,
+    },
+    ConnectionClosed {
+        port: u16,
+    },
+    /// How much went through the port since the last time we said.
+    Bytes {
+        port: u16,
+        sent: u64,
+        received: u64,
+    },
+    /// The server wants us to open `url`.
+    Browse {
+        url: String,
+    },
+    /// Something went wrong, and fwd's log said so.
+    Error {
+        message: String,
+    },
+}
+
+impl Event {
+    /// The event as `--output json` writes it.
+    pub fn to_json(&self) -> Value {
+        match self {
+            Event::Connected => json!({"event": "connected"}),
+            Event::Disconnected => json!({"event": "disconnected"}),
+            Event::PortAdded { port, desc, enabled, local, pending } => {
+                let mut event = json!({
+                    "event": "port_added",
+                    "port": port,
+                    "desc": desc,
+                    "enabled": enabled,
+                    "local": local.to_string(),
+                });
+                if *pending {
+                    event["pending"] = json!(true);
+                }
+                event
+            }
+            Event::PortRemoved { port } => {
+                json!({"event": "port_removed", "port": port})
+            }
+            Event::ConnectionOpened { port } => {
+                json!({"event": "connection_opened", "port": port})
+            }
+            Event::ConnectionClosed { port } => {
+                json!({"event": "connection_closed", "port": port})
+            }
+            Event::Bytes { port, sent, received } => json!({
+                "event": "bytes",
+                "port": port,
+                "sent": sent,
+                "received": received,
+            }),
+            Event::Browse { url } => json!({"event": "browse", "url": url}),
+            Event::Error { message } => {
+                json!({"event": "error", "message": message})
+            }
+        }
+    }
+}
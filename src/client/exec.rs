@@ -0,0 +1,126 @@
+use super::{ClientBuilder, ClientHandle, ClientOptions};
+use anyhow::{bail, Context, Result};
+use std::net::SocketAddr;
+use tokio::time::{sleep, Duration};
+
+/// How often we look for the port while we wait for it.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Wait until the server has `port` and we're listening for it, and return
+/// where. If it's there but not forwarded, forward it: that's what we're
+/// waiting for.
+async fn wait_for_port(handle: &ClientHandle, port: u16) -> Result<SocketAddr> {
+    loop {
+        let ports = handle.ports().await?;
+        if let Some(info) = ports.iter().find(|p| p.port == port) {
+            match info.local {
+                Some(local) => return Ok(local),
+                None if info.pending => handle.allow(port).await?,
+                None if !info.enabled => handle.forward(port).await?,
+                None => (),
+            }
+        }
+        sleep(POLL_INTERVAL).await;
+    }
+}
+
+/// Run `command` to the end, and return its exit code. Ctrl-C goes to it,
+/// and it's up to it whether to stop; we're not stopping before it does.
+async fn run_command(command: &[String], local: SocketAddr) -> Result<i32> {
+    let Some((program, args)) = command.split_first() else {
+        bail!("No command to run");
+    };
+    let mut child = tokio::process::Command::new(program)
+        .args(args)
+        .env("FWD_LOCAL_PORT", local.port().to_string())
+        .spawn()
+        .with_context(|| format!("Error running {program}"))?;
+    loop {
+        tokio::select! {
+            status = child.wait() => {
+                // Killed by a signal, so there's no code to pass on.
+                return Ok(status?.code().unwrap_or(1));
+            }
+            _ = tokio::signal::ctrl_c() => (),
+        }
+    }
+}
+
+async fn run_with_port_impl(
+    remote: &str,
+    options: ClientOptions,
+    port: u16,
+    command: &[String],
+) -> Result<i32> {
+    let handle = ClientBuilder::new(remote).options(options).spawn()?;
+    let result = async {
+        let local = tokio::select! {
+            local = wait_for_port(&handle, port) => local?,
+            _ = tokio::signal::ctrl_c() => bail!("Interrupted"),
+        };
+        if local.port() != port {
+            let local = local.port();
+            eprintln!("fwd: port {port} is forwarded to local port {local}");
+        }
+        run_command(command, local).await
+    }
+    .await;
+    handle.shutdown().await?;
+    result
+}
+
+/// Forward ports from `remote`, wait for `port` to be forwarded, and run
+/// `command`; stop forwarding when it's done, and exit the way it did.
+pub async fn run_with_port(
+    remote: &str,
+    options: ClientOptions,
+    port: u16,
+    command: Vec<String>,
+) {
+    match run_with_port_impl(remote, options, port, &command).await {
+        Ok(code) => std::process::exit(code),
+        Err(e) => {
+            eprintln!("Error: {e:#}");
+            std::process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{
+        config::ServerConfig, history::History, LocalTransport,
+    };
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn wait_and_run() {
+        let mut config = ServerConfig::default();
+        config.add_static_port(5432);
+        config.map_port(5432, 0);
+        let handle = ClientBuilder::new("fwd-exec-test").start(
+            config,
+            LocalTransport::new(),
+            Arc::new(History::disabled()),
+        );
+        let local = wait_for_port(&handle, 5432).await.unwrap();
+        assert!(local.ip().is_loopback());
+        assert_ne!(local.port(), 0);
+
+        let command = ["sh", "-c", "exit $((FWD_LOCAL_PORT % 100))"];
+        let command: Vec<String> =
+            command.iter().map(|s| s.to_string()).collect();
+        let code = run_command(&command, local).await.unwrap();
+        assert_eq!(code, i32::from(local.port() % 100));
+        handle.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn missing_command() {
+        let local = "127.0.0.1:5432".parse().unwrap();
+        assert!(run_command(&[], local).await.is_err());
+        let command = vec!["fwd-no-such-command".to_string()];
+        assert!(run_command(&command, local).await.is_err());
+    }
+}
@@ -0,0 +1,306 @@
+use super::{
+    config::ServerConfig, history::History, load_config, run,
+    transport::SshTransport, ui::UIEvent, ClientOptions, Command, Event,
+    Output, Profile, Transport,
+};
+use anyhow::{bail, Result};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tokio_stream::{wrappers::ReceiverStream, Stream};
+
+/// How many events a listener can fall behind by before it misses some.
+const EVENT_BUFFER: usize = 1024;
+
+/// A port the server has, and what we're doing with it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PortInfo {
+    pub port: u16,
+    /// What the config calls it, or what the server says is listening on
+    /// it.
+    pub desc: String,
+    pub enabled: bool,
+    /// Whether it's waiting to be allowed before we forward it.
+    pub pending: bool,
+    /// Where we're listening for it, while we are.
+    pub local: Option<SocketAddr>,
+}
+
+/// Sets up a client to run inside another program: like running `fwd
+/// <remote>`, but without the terminal, and with a `ClientHandle` to keep
+/// track of it. Anything not set here comes from `~/.fwd`, as usual.
+#[derive(Debug, Clone)]
+pub struct ClientBuilder {
+    remote: String,
+    options: ClientOptions,
+    /// Whether the config came from `~/.fwd`, so that changes to it apply.
+    reload: bool,
+}
+
+impl ClientBuilder {
+    pub fn new(remote: &str) -> ClientBuilder {
+        ClientBuilder {
+            remote: remote.to_string(),
+            options: ClientOptions::default(),
+            reload: false,
+        }
+    }
+
+    /// Start from `options`, as if they'd been given on the command line.
+    /// Their `output` and `daemon` are ignored.
+    pub fn options(mut self, options: ClientOptions) -> ClientBuilder {
+        self.options = options;
+        self
+    }
+
+    /// Listen for forwarded ports on `address` instead of 127.0.0.1.
+    pub fn bind_addr(mut self, address: IpAddr) -> ClientBuilder {
+        self.options.bind = Some(address);
+        self
+    }
+
+    /// Forward `port` without asking the server which ports it has, and
+    /// forward no others.
+    pub fn port(mut self, port: u16) -> ClientBuilder {
+        self.options.ports.push(port);
+        self
+    }
+
+    /// Forward the remote `port` to the local `local_port`.
+    pub fn map(mut self, port: u16, local_port: u16) -> ClientBuilder {
+        self.options.map.push((port, local_port));
+        self
+    }
+
+    /// Move no more than `rate` bytes a second through the remote `port`.
+    pub fn limit(mut self, port: u16, rate: u64) -> ClientBuilder {
+        self.options.limits.push((port, rate));
+        self
+    }
+
+    /// Wait for `ClientHandle::allow` before forwarding the ports that the
+    /// server finds.
+    pub fn approve(mut self, approve: bool) -> ClientBuilder {
+        self.options.approve = approve;
+        self
+    }
+
+    pub fn profile(mut self, profile: Profile) -> ClientBuilder {
+        self.options.profile = Some(profile);
+        self
+    }
+
+    /// Connect over ssh, and keep the ports forwarded in the background
+    /// until `ClientHandle::shutdown`. This has to be called from inside a
+    /// tokio runtime.
+    pub fn spawn(self) -> Result<ClientHandle> {
+        let config = load_config(&self.remote, &self.options)?;
+        let transport = SshTransport::new(&self.remote, config.clone());
+        let history = Arc::new(History::open(&self.remote));
+        let builder = ClientBuilder { reload: true, ..self };
+        Ok(builder.start(config, transport, history))
+    }
+
+    /// Like `spawn`, but reach the server with `transport` instead of ssh.
+    pub fn spawn_with_transport<T: Transport + Send + 'static>(
+        self,
+        transport: T,
+    ) -> Result<ClientHandle> {
+        let config = load_config(&self.remote, &self.options)?;
+        let history = Arc::new(History::open(&self.remote));
+        let builder = ClientBuilder { reload: true, ..self };
+        Ok(builder.start(config, transport, history))
+    }
+
+    pub(super) fn start<T: Transport + Send + 'static>(
+        mut self,
+        config: ServerConfig,
+        transport: T,
+        history: Arc<History>,
+    ) -> ClientHandle {
+        self.options.output = Output::Quiet;
+        let (events, receiver) = mpsc::channel(1024);
+        let sender = events.clone();
+        let task = tokio::spawn(async move {
+            let (remote, options) = (self.remote, self.options);
+            let events = (sender, receiver);
+            let reload = self.reload;
+            run(
+                &remote, config, &options, transport, history, events, reload,
+            )
+            .await
+        });
+        ClientHandle { events, task }
+    }
+}
+
+/// A client started by `ClientBuilder`. Dropping the handle leaves the
+/// client running; `shutdown` is how to stop it.
+#[derive(Debug)]
+pub struct ClientHandle {
+    events: mpsc::Sender<UIEvent>,
+    task: JoinHandle<()>,
+}
+
+impl ClientHandle {
+    /// The ports the server has, and what we're doing with them.
+    pub async fn ports(&self) -> Result<Vec<PortInfo>> {
+        let (reply, receiver) = oneshot::channel();
+        if self.events.send(UIEvent::List(reply)).await.is_err() {
+            bail!("The client has stopped");
+        }
+        match receiver.await {
+            Ok(ports) => Ok(ports),
+            Err(_) => bail!("The client has stopped"),
+        }
+    }
+
+    /// Everything that happens from now on, until the client stops. Ask
+    /// right after `spawn` to hear about everything. A stream that isn't
+    /// read from for long enough misses some events instead of holding the
+    /// client up.
+    pub async fn events(&self) -> Result<impl Stream<Item = Event>> {
+        let (sender, receiver) = mpsc::channel(EVENT_BUFFER);
+        if self.events.send(UIEvent::Subscribe(sender)).await.is_err() {
+            bail!("The client has stopped");
+        }
+        Ok(ReceiverStream::new(receiver))
+    }
+
+    /// Start forwarding `port`.
+    pub async fn forward(&self, port: u16) -> Result<()> {
+        self.command(Command::Forward(port)).await
+    }
+
+    /// Stop forwarding `port`.
+    pub async fn stop(&self, port: u16) -> Result<()> {
+        self.command(Command::Stop(port)).await
+    }
+
+    /// Start forwarding a port that's waiting to be allowed.
+    pub async fn allow(&self, port: u16) -> Result<()> {
+        self.command(Command::Allow(port)).await
+    }
+
+    /// Ask the server for the list of ports right away.
+    pub async fn refresh(&self) -> Result<()> {
+        self.command(Command::Refresh).await
+    }
+
+    /// Say goodbye to the server, stop listening, and wait until it's all
+    /// cleaned up.
+    pub async fn shutdown(self) -> Result<()> {
+        // If it's already stopped, there's nothing left to do.
+        _ = self.command(Command::Quit).await;
+        self.task.await?;
+        Ok(())
+    }
+
+    async fn command(&self, command: Command) -> Result<()> {
+        let (reply, receiver) = oneshot::channel();
+        let event = UIEvent::Control(command, reply);
+        if self.events.send(event).await.is_err() {
+            bail!("The client has stopped");
+        }
+        match receiver.await {
+            Ok(Ok(_)) => Ok(()),
+            Ok(Err(e)) => bail!("{}", e.trim_end()),
+            Err(_) => bail!("The client has stopped"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::Session;
+    use fwd_proto::{Message, MessageReader, MessageWriter, PortDesc};
+    use tokio::io::AsyncWriteExt;
+    use tokio::time::{sleep, Duration};
+    use tokio_stream::StreamExt;
+
+    // A transport with a server that has one port, and goes away when it's
+    // told to.
+    struct OnePortTransport;
+
+    impl Transport for OnePortTransport {
+        async fn connect(&mut self) -> Result<Session> {
+            let (reader, mut server_write) = tokio::io::duplex(4096);
+            let (server_read, writer) = tokio::io::duplex(4096);
+            tokio::spawn(async move {
+                server_write.write_u64(0).await?;
+                let mut server_write = MessageWriter::new(server_write);
+                let hello = Message::Hello(0, 5, vec![]);
+                server_write.write(hello).await?;
+                let mut server_read = MessageReader::new(server_read);
+                loop {
+                    let reply = match server_read.read().await? {
+                        Message::Ping => Message::Pong,
+                        Message::Refresh => Message::Ports(vec![PortDesc {
+                            port: 8080,
+                            desc: "web".to_string(),
+                            pid: None,
+                        }]),
+                        Message::Goodbye(_) => return anyhow::Ok(()),
+                        _ => continue,
+                    };
+                    server_write.write(reply).await?;
+                }
+            });
+
+            Ok(Session {
+                reader: Box::new(reader),
+                writer: Box::new(writer),
+                stderr: Box::new(tokio::io::empty()),
+                socks_ports: vec![1080],
+            })
+        }
+
+        async fn disconnected(&mut self) -> bool {
+            false
+        }
+    }
+
+    #[tokio::test]
+    async fn handle() {
+        // Leave ~/.fwd out of it, and take any free port.
+        let mut config = ServerConfig::default();
+        config.map_port(8080, 0);
+        let handle = ClientBuilder::new("fwd-handle-test").start(
+            config,
+            OnePortTransport,
+            Arc::new(History::disabled()),
+        );
+
+        let mut events = handle.events().await.unwrap();
+        let ports = loop {
+            let ports = handle.ports().await.unwrap();
+            if !ports.is_empty() {
+                break ports;
+            }
+            sleep(Duration::from_millis(10)).await;
+        };
+        assert_eq!(ports.len(), 1);
+        assert_eq!(ports[0].port, 8080);
+        assert_eq!(ports[0].desc, "web");
+        assert!(ports[0].enabled);
+        let local = ports[0].local.unwrap();
+        assert!(local.ip().is_loopback());
+        assert!(tokio::net::TcpStream::connect(local).await.is_ok());
+
+        handle.stop(8080).await.unwrap();
+        let ports = handle.ports().await.unwrap();
+        assert!(!ports[0].enabled);
+        assert_eq!(ports[0].local, None);
+        assert!(handle.forward(9000).await.is_err());
+
+        handle.shutdown().await.unwrap();
+        assert_eq!(events.next().await, Some(Event::Connected));
+        assert_matches::assert_matches!(
+            events.next().await,
+            Some(Event::PortAdded { port: 8080, enabled: true, .. })
+        );
+        assert_eq!(events.next().await, None);
+    }
+}
@@ -0,0 +1,306 @@
+use anyhow::{Context, Result};
+use log::warn;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+use tokio::time::Instant;
+
+/// Once the history for a remote gets bigger than this we start a new file,
+/// keeping just the one before it.
+const MAX_HISTORY_BYTES: u64 = 1024 * 1024;
+
+/// We only note that a port was used once in this long, otherwise a busy
+/// web app would drown out everything else.
+const USED_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Something that happened that we want to remember.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Event<'a> {
+    Connected,
+    Disconnected,
+    Up(u16, &'a str),
+    Down(u16),
+    Used(u16),
+}
+
+/// A timeline of what happened to the ports on a remote, kept on disk so
+/// that it outlives the session.
+#[derive(Debug)]
+pub struct History {
+    file: Mutex<Option<File>>,
+    last_used: Mutex<HashMap<u16, Instant>>,
+}
+
+impl History {
+    /// Open the history for the given remote, or don't keep any history if
+    /// we can't.
+    pub fn open(remote: &str) -> History {
+        let file = match open_file(remote) {
+            Ok(file) => Some(file),
+            Err(e) => {
+                warn!("Not keeping history: {e:#}");
+                None
+            }
+        };
+        History::new(file)
+    }
+
+    /// A history that doesn't remember anything.
+    #[cfg(test)]
+    pub fn disabled() -> History {
+        History::new(None)
+    }
+
+    fn new(file: Option<File>) -> History {
+        History {
+            file: Mutex::new(file),
+            last_used: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn record(&self, event: Event) {
+        if let Event::Used(port) = event {
+            let mut last_used = self.last_used.lock().unwrap();
+            let now = Instant::now();
+            match last_used.get(&port) {
+                Some(last) if now.duration_since(*last) < USED_INTERVAL => {
+                    return;
+                }
+                _ => _ = last_used.insert(port, now),
+            }
+        }
+
+        let mut file = self.file.lock().unwrap();
+        if let Some(f) = file.as_mut() {
+            let line = format_line(now(), event);
+            if let Err(e) = f.write_all(line.as_bytes()) {
+                warn!("Error writing history, not keeping any more: {e}");
+                *file = None;
+            }
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Remotes can be things like `user@host:port`, so keep file names that we
+/// make out of them tame.
+pub fn file_name(remote: &str) -> String {
+    remote
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '-' | '@' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+fn history_path(remote: &str) -> Result<PathBuf> {
+    let name = file_name(remote);
+    let base_directories = xdg::BaseDirectories::with_prefix("fwd")
+        .context("Error creating BaseDirectories")?;
+    let mut path = base_directories
+        .create_data_directory("history")
+        .context("Error creating history directory")?;
+    path.push(name);
+    Ok(path)
+}
+
+fn open_file(remote: &str) -> Result<File> {
+    let path = history_path(remote)?;
+    if let Ok(metadata) = std::fs::metadata(&path) {
+        if metadata.len() > MAX_HISTORY_BYTES {
+            std::fs::rename(&path, path.with_extension("old"))?;
+        }
+    }
+
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .with_context(|| format!("Error opening {}", path.display()))
+}
+
+// Lines are tab-separated: the time in seconds since the epoch, the kind of
+// event, the port (if there is one) and the description (if there is one).
+fn format_line(time: u64, event: Event) -> String {
+    match event {
+        Event::Connected => format!("{time}\tconnected\t\t\n"),
+        Event::Disconnected => format!("{time}\tdisconnected\t\t\n"),
+        Event::Up(port, desc) => {
+            let desc = desc.replace(['\t', '\n'], " ");
+            format!("{time}\tup\t{port}\t{desc}\n")
+        }
+        Event::Down(port) => format!("{time}\tdown\t{port}\t\n"),
+        Event::Used(port) => format!("{time}\tused\t{port}\t\n"),
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct Entry<'a> {
+    time: u64,
+    event: &'a str,
+    port: Option<u16>,
+    desc: &'a str,
+}
+
+fn parse_line(line: &str) -> Option<Entry<'_>> {
+    let mut parts = line.splitn(4, '\t');
+    let time = parts.next()?.parse().ok()?;
+    let event = parts.next()?;
+    let port = match parts.next()? {
+        "" => None,
+        port => Some(port.parse().ok()?),
+    };
+    let desc = parts.next().unwrap_or("");
+    Some(Entry { time, event, port, desc })
+}
+
+/// Format seconds since the epoch as a UTC date and time.
+fn format_time(time: u64) -> String {
+    let days = (time / 86400) as i64;
+    let secs = time % 86400;
+
+    // From Howard Hinnant's `civil_from_days`.
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02}:{:02}",
+        secs / 3600,
+        (secs / 60) % 60,
+        secs % 60
+    )
+}
+
+fn format_entry(entry: &Entry<'_>) -> String {
+    let time = format_time(entry.time);
+    match entry.port {
+        None => format!("{time}        {}", entry.event),
+        Some(port) => {
+            format!("{time}  {port:>5} {:<5} {}", entry.event, entry.desc)
+                .trim_end()
+                .to_string()
+        }
+    }
+}
+
+fn show_history_impl(remote: &str, port: Option<u16>) -> Result<()> {
+    let path = history_path(remote)?;
+    let mut contents = String::new();
+    for path in [path.with_extension("old"), path] {
+        match std::fs::read_to_string(&path) {
+            Ok(c) => contents.push_str(&c),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+            Err(e) => {
+                return Err(e)
+                    .context(format!("Error reading {}", path.display()))
+            }
+        }
+    }
+
+    let entries: Vec<_> = contents
+        .lines()
+        .filter_map(parse_line)
+        .filter(|e| port.is_none() || e.port.is_none() || e.port == port)
+        .collect();
+    if entries.is_empty() {
+        println!("No history for {remote}");
+        return Ok(());
+    }
+    println!("All times are UTC");
+    for entry in entries {
+        println!("{}", format_entry(&entry));
+    }
+    Ok(())
+}
+
+/// Print the timeline for the given remote, optionally just for one port.
+pub async fn show_history(remote: &str, port: Option<u16>) {
+    if let Err(e) = show_history_impl(remote, port) {
+        eprintln!("Error showing history: {e:#}");
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lines_round_trip() {
+        let line = format_line(10, Event::Up(8080, "query\tservice"));
+        assert_eq!(line, "10\tup\t8080\tquery service\n");
+        assert_eq!(
+            parse_line(line.lines().next().unwrap()),
+            Some(Entry {
+                time: 10,
+                event: "up",
+                port: Some(8080),
+                desc: "query service"
+            })
+        );
+
+        let line = format_line(20, Event::Disconnected);
+        assert_eq!(
+            parse_line(line.lines().next().unwrap()),
+            Some(Entry {
+                time: 20,
+                event: "disconnected",
+                port: None,
+                desc: ""
+            })
+        );
+
+        assert_eq!(parse_line("garbage"), None);
+        assert_eq!(parse_line("10\tup\tnope\t"), None);
+    }
+
+    #[test]
+    fn times() {
+        assert_eq!(format_time(0), "1970-01-01 00:00:00");
+        assert_eq!(format_time(951782400), "2000-02-29 00:00:00");
+        assert_eq!(format_time(1791979200), "2026-10-14 12:00:00");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn used_is_coalesced() {
+        let dir = tempdir::TempDir::new("fwd-history").unwrap();
+        let path = dir.path().join("history");
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .unwrap();
+        let history = History::new(Some(file));
+
+        history.record(Event::Used(8080));
+        history.record(Event::Used(8080));
+        history.record(Event::Used(9090));
+        tokio::time::advance(USED_INTERVAL).await;
+        history.record(Event::Used(8080));
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let events: Vec<_> = contents
+            .lines()
+            .filter_map(parse_line)
+            .map(|e| e.port.unwrap())
+            .collect();
+        assert_eq!(events, vec![8080, 9090, 8080]);
+    }
+}
@@ -0,0 +1,177 @@
+use super::{
+    client_hello, client_sync, during, load_config, transport::SshTransport,
+    ClientOptions, Output, Session, Transport,
+};
+use anyhow::{bail, Result};
+use fwd_proto::{Message, MessageReader, MessageWriter, Phase, PortDesc};
+use serde_json::{json, Value};
+use tokio::io::{AsyncRead, AsyncWrite, BufReader, BufWriter};
+use tokio::time::{timeout, Duration};
+
+/// Ask the server for its ports once, and say goodbye if it wants us to.
+async fn list_session<Reader: AsyncRead + Unpin, Writer: AsyncWrite + Unpin>(
+    mut reader: MessageReader<Reader>,
+    mut writer: MessageWriter<Writer>,
+    settings: &[String],
+    wait: Duration,
+) -> Result<Vec<PortDesc>> {
+    let (_, goodbye) =
+        client_hello(&mut reader, &mut writer, settings, false, false).await?;
+    writer.write(Message::Refresh).await?;
+    let ports = loop {
+        let message = match timeout(wait, reader.read()).await {
+            Ok(message) => message?,
+            Err(_) => {
+                bail!("Heard nothing from the server in {}s", wait.as_secs())
+            }
+        };
+        match message {
+            Message::Ports(ports) => break ports,
+            Message::Goodbye(reason) => bail!("The server exited: {reason}"),
+            _ => (),
+        }
+    };
+    if goodbye {
+        let reason = "the client is done".to_string();
+        writer.write(Message::Goodbye(reason)).await?;
+    }
+    Ok(ports)
+}
+
+async fn list_ports_with_transport<T: Transport>(
+    transport: &mut T,
+    settings: &[String],
+    wait: Duration,
+) -> Result<Vec<PortDesc>> {
+    let Session { reader, writer, mut stderr, .. } =
+        transport.connect().await?;
+    let mut reader = BufReader::new(reader);
+    client_sync(&mut reader, &mut stderr)
+        .await
+        .map_err(during(Phase::Sync, None))?;
+    let reader = MessageReader::new(reader);
+    let writer = MessageWriter::new(BufWriter::new(writer));
+    list_session(reader, writer, settings, wait).await
+}
+
+fn format_ports(remote: &str, ports: &[PortDesc]) -> String {
+    if ports.is_empty() {
+        return format!("No ports on {remote}\n");
+    }
+    let mut result = String::new();
+    for port in ports {
+        let line = format!("{:<6}{}", port.port, port.desc);
+        result.push_str(line.trim_end());
+        result.push('\n');
+    }
+    result
+}
+
+fn ports_json(ports: &[PortDesc]) -> Value {
+    let ports: Vec<_> = ports
+        .iter()
+        .map(|p| {
+            let desc = (!p.desc.is_empty()).then_some(&p.desc);
+            json!({"port": p.port, "desc": desc})
+        })
+        .collect();
+    Value::Array(ports)
+}
+
+async fn list_ports_impl(remote: &str, options: &ClientOptions) -> Result<()> {
+    let mut config = load_config(remote, options)?;
+    // Extra ssh connections would only be there for forwarding.
+    config.set_ssh_connections(1);
+    let settings = config.settings();
+    let wait = config.keepalive_timeout();
+    let mut transport = SshTransport::new(remote, config);
+    let result =
+        list_ports_with_transport(&mut transport, &settings, wait).await;
+    transport.disconnected().await;
+    transport.shutdown().await;
+
+    let mut ports = result?;
+    ports.sort_by_key(|p| p.port);
+    match options.output {
+        Output::Json => println!("{}", ports_json(&ports)),
+        _ => print!("{}", format_ports(remote, &ports)),
+    }
+    Ok(())
+}
+
+/// Print the ports the server has, the way it sees them, without forwarding
+/// any of them.
+pub async fn list_ports(remote: &str, options: ClientOptions) {
+    if let Err(e) = list_ports_impl(remote, &options).await {
+        eprintln!("Error listing ports: {e:#}");
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::LocalTransport;
+    use crate::server::ServerOptions;
+    use assert_matches::assert_matches;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn list() {
+        // The server leaves out its own sockets, which are ours too, so the
+        // best we can do is a port that nothing listens on once we're done
+        // with it.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        drop(listener);
+        let mut transport = LocalTransport::with_options(ServerOptions {
+            allow: vec![port..=port],
+            ..ServerOptions::default()
+        });
+        let wait = Duration::from_secs(5);
+        let ports = list_ports_with_transport(&mut transport, &[], wait)
+            .await
+            .unwrap();
+        assert_eq!(ports, []);
+        transport.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn silent_server() {
+        let (reader, mut server_write) = tokio::io::duplex(4096);
+        let (_server_read, writer) = tokio::io::duplex(4096);
+        let mut server = MessageWriter::new(&mut server_write);
+        server.write(Message::Hello(0, 5, vec![])).await.unwrap();
+        server.write(Message::Ping).await.unwrap();
+        let result = list_session(
+            MessageReader::new(reader),
+            MessageWriter::new(writer),
+            &[],
+            Duration::from_millis(10),
+        )
+        .await;
+        assert_matches!(result, Err(e) if e.to_string().contains("nothing"));
+    }
+
+    #[test]
+    fn output() {
+        let ports = vec![
+            PortDesc {
+                port: 80,
+                desc: "nginx".to_string(),
+                pid: None,
+            },
+            PortDesc {
+                port: 5432,
+                desc: String::new(),
+                pid: None,
+            },
+        ];
+        assert_eq!(format_ports("a", &ports), "80    nginx\n5432\n");
+        assert_eq!(format_ports("a", &[]), "No ports on a\n");
+        assert_eq!(
+            ports_json(&ports).to_string(),
+            r#"[{"desc":"nginx","port":80},{"desc":null,"port":5432}]"#
+        );
+    }
+}
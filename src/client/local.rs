@@ -0,0 +1,249 @@
+use super::{Session, Transport};
+use crate::server::{run_server_with_io, ServerOptions};
+use anyhow::{bail, Context, Result};
+use log::{debug, warn};
+use std::net::{Ipv4Addr, Ipv6Addr};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+
+/// A transport to a server running in this process, over in-memory pipes
+/// instead of ssh, so the "remote" is this machine. It's for tests, of fwd
+/// or of programs that embed it: the whole protocol runs, but nothing has
+/// to be installed or logged in to.
+///
+/// The forwarded connections still need a SOCKS proxy, the way ssh's `-D`
+/// is one, so this runs a small one on the loopback interface that connects
+/// straight to wherever it's asked.
+#[derive(Debug, Default)]
+pub struct LocalTransport {
+    options: ServerOptions,
+    socks: Option<(u16, JoinHandle<()>)>,
+    /// Tells the server from the current session to go.
+    server: Option<oneshot::Sender<()>>,
+}
+
+impl LocalTransport {
+    pub fn new() -> LocalTransport {
+        LocalTransport::default()
+    }
+
+    /// Run the server with `options`, as if they'd been given after
+    /// `--server`.
+    pub fn with_options(options: ServerOptions) -> LocalTransport {
+        LocalTransport { options, ..LocalTransport::default() }
+    }
+
+    async fn socks_port(&mut self) -> Result<u16> {
+        if let Some((port, _)) = self.socks {
+            return Ok(port);
+        }
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let port = listener.local_addr()?.port();
+        self.socks = Some((port, tokio::spawn(socks_server(listener))));
+        Ok(port)
+    }
+}
+
+impl Transport for LocalTransport {
+    async fn connect(&mut self) -> Result<Session> {
+        let socks_port = self.socks_port().await?;
+        let (reader, server_write) = tokio::io::duplex(64 * 1024);
+        let (server_read, writer) = tokio::io::duplex(64 * 1024);
+        let (stop, stopped) = oneshot::channel();
+        let options = self.options.clone();
+        tokio::spawn(async move {
+            let shutdown = async {
+                _ = stopped.await;
+            };
+            if let Err(e) =
+                run_server_with_io(server_read, server_write, options, shutdown)
+                    .await
+            {
+                warn!("The local server stopped: {e:#}");
+            }
+        });
+        self.server = Some(stop);
+
+        Ok(Session {
+            reader: Box::new(reader),
+            writer: Box::new(writer),
+            stderr: Box::new(tokio::io::empty()),
+            socks_ports: vec![socks_port],
+        })
+    }
+
+    async fn disconnected(&mut self) -> bool {
+        self.server.take();
+        true
+    }
+
+    async fn shutdown(&mut self) {
+        if let Some(stop) = self.server.take() {
+            _ = stop.send(());
+        }
+        if let Some((_, task)) = self.socks.take() {
+            task.abort();
+        }
+    }
+}
+
+async fn socks_server(listener: TcpListener) {
+    loop {
+        let socket = match listener.accept().await {
+            Ok((socket, _)) => socket,
+            Err(e) => {
+                warn!("Error accepting SOCKS connection: {e}");
+                return;
+            }
+        };
+        tokio::spawn(async move {
+            if let Err(e) = socks_proxy(socket).await {
+                debug!("Error proxying SOCKS connection: {e:#}");
+            }
+        });
+    }
+}
+
+/// Just enough of a SOCKS5 server for `socks_connect`: no authentication,
+/// and only CONNECT.
+async fn socks_proxy(mut socket: TcpStream) -> Result<()> {
+    let version = socket.read_u8().await?;
+    if version != 0x05 {
+        bail!("SOCKS client wants version {version}");
+    }
+    let mut methods = vec![0; socket.read_u8().await? as usize];
+    socket.read_exact(&mut methods).await?;
+    if !methods.contains(&0x00) {
+        socket.write_all(&[0x05, 0xFF]).await?;
+        bail!("SOCKS client can't do without authentication");
+    }
+    socket.write_all(&[0x05, 0x00]).await?;
+
+    let mut request = [0; 4];
+    socket.read_exact(&mut request).await?;
+    let host = match request[3] {
+        0x01 => {
+            let mut ip = [0; 4];
+            socket.read_exact(&mut ip).await?;
+            Ipv4Addr::from(ip).to_string()
+        }
+        0x04 => {
+            let mut ip = [0; 16];
+            socket.read_exact(&mut ip).await?;
+            Ipv6Addr::from(ip).to_string()
+        }
+        0x03 => {
+            let mut name = vec![0; socket.read_u8().await? as usize];
+            socket.read_exact(&mut name).await?;
+            String::from_utf8(name)?
+        }
+        atyp => {
+            reply(&mut socket, 0x08).await?;
+            bail!("SOCKS client sent address type {atyp}");
+        }
+    };
+    let port = socket.read_u16().await?;
+    if request[1] != 0x01 {
+        reply(&mut socket, 0x07).await?;
+        bail!("SOCKS client sent command {}", request[1]);
+    }
+
+    // Like ssh, say we're connected before we are, and just hang up if it
+    // turns out we can't.
+    reply(&mut socket, 0x00).await?;
+    let mut target = TcpStream::connect((host.as_str(), port))
+        .await
+        .with_context(|| format!("Error connecting to {host}:{port}"))?;
+    tokio::io::copy_bidirectional(&mut socket, &mut target).await?;
+    Ok(())
+}
+
+// The bound address is always 0.0.0.0:0; nobody looks at it.
+async fn reply(socket: &mut TcpStream, code: u8) -> Result<()> {
+    socket
+        .write_all(&[0x05, code, 0x00, 0x01, 0, 0, 0, 0, 0, 0])
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{
+        config::ServerConfig, history::History, socks_connect, ClientBuilder,
+        Profile, SocksAddr,
+    };
+    use std::net::IpAddr;
+    use std::sync::Arc;
+    use tokio::time::{sleep, Duration};
+
+    #[tokio::test]
+    async fn socks() {
+        let mut transport = LocalTransport::new();
+        let socks_port = transport.socks_port().await.unwrap();
+
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = target.local_addr().unwrap().port();
+        let addr = SocksAddr::Ip(IpAddr::V4(Ipv4Addr::LOCALHOST));
+        let mut socket =
+            socks_connect(socks_port, addr, port, Profile::Balanced)
+                .await
+                .unwrap();
+        let (mut accepted, _) = target.accept().await.unwrap();
+        socket.write_all(b"hello").await.unwrap();
+        let mut buffer = [0; 5];
+        accepted.read_exact(&mut buffer).await.unwrap();
+        assert_eq!(&buffer, b"hello");
+
+        // With nothing there, it hangs up, the way ssh does.
+        drop(target);
+        let mut socket =
+            socks_connect(socks_port, addr, port, Profile::Balanced)
+                .await
+                .unwrap();
+        assert_eq!(socket.read(&mut buffer).await.unwrap(), 0);
+        transport.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn forward() {
+        // Something to forward to, which says back what it's told.
+        let echo = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = echo.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = echo.accept().await {
+                tokio::spawn(async move {
+                    let (mut read, mut write) = socket.split();
+                    _ = tokio::io::copy(&mut read, &mut write).await;
+                });
+            }
+        });
+
+        // Leave ~/.fwd out of it, and take any free port locally.
+        let mut config = ServerConfig::default();
+        config.add_static_port(port);
+        config.map_port(port, 0);
+        let handle = ClientBuilder::new("fwd-local-test").start(
+            config,
+            LocalTransport::new(),
+            Arc::new(History::disabled()),
+        );
+
+        let local = loop {
+            let ports = handle.ports().await.unwrap();
+            if let Some(local) = ports.first().and_then(|p| p.local) {
+                break local;
+            }
+            sleep(Duration::from_millis(10)).await;
+        };
+        let mut socket = TcpStream::connect(local).await.unwrap();
+        socket.write_all(b"hello").await.unwrap();
+        let mut buffer = [0; 5];
+        socket.read_exact(&mut buffer).await.unwrap();
+        assert_eq!(&buffer, b"hello");
+
+        handle.shutdown().await.unwrap();
+    }
+}
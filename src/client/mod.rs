@@ -1,31 +1,191 @@
-use crate::message::{Message, MessageReader, MessageWriter};
-use anyhow::{bail, Result};
+use crate::logging::Filter;
+use anyhow::{anyhow, bail, Context, Result};
 use bytes::BytesMut;
-use log::LevelFilter;
-use log::{debug, error, info, warn};
-use std::net::{Ipv4Addr, SocketAddrV4};
+use fwd_proto::{
+    capabilities, get_header, has_capability, Message, MessageReader,
+    MessageWriter, Phase, PortDesc,
+};
+use log::{debug, error, info, trace, warn};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::SystemTime;
 use tokio::io::{
     AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite,
     AsyncWriteExt, BufReader, BufWriter,
 };
-use tokio::net::{TcpListener, TcpStream};
-use tokio::process;
-use tokio::sync::mpsc;
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+use tokio::sync::{mpsc, watch, Notify};
+use tokio::time::{sleep, timeout, Duration, Instant};
 
 mod config;
+mod control;
+mod daemon;
+mod deploy;
+mod events;
+mod exec;
+mod handle;
+mod history;
+mod list;
+mod local;
+mod multi;
+mod notify;
+mod sniff;
+mod stats;
+mod stdio;
+mod transport;
 mod ui;
 
+use config::Tuning;
+pub use config::{
+    parse_duration, parse_host, parse_rate, parse_ssh_connections, Profile,
+};
+pub use control::{run_control, Command};
+pub use events::Event;
+pub use exec::run_with_port;
+pub use handle::{ClientBuilder, ClientHandle, PortInfo};
+pub use history::show_history;
+pub use list::list_ports;
+pub use local::LocalTransport;
+pub use multi::run_clients;
+pub use stdio::run_stdio;
+pub use transport::{Session, Transport};
+pub use ui::Output;
+
+/// How often we ask the server for the list of ports.
+const REFRESH_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How often we ping the server, so that we hear back from it even if it's
+/// too busy scanning to answer a refresh.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long we wait before trying again when we fail to connect; this
+/// doubles every time we fail, up to `MAX_RECONNECT_DELAY`.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
+/// The optional parts of the protocol that we want, for servers that list
+/// theirs in the `Hello`.
+const CAPABILITIES: &[&str] = &[
+    capabilities::GOODBYE,
+    capabilities::CHECKSUM,
+    capabilities::DELTA,
+    capabilities::PIDS,
+];
+
+/// How long we give the server to hear our goodbye before we exit anyway.
+const GOODBYE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How often we look at the config file to see if it changed.
+const CONFIG_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The ways the rest of the client can poke the connection to the server:
+/// asking for the list of ports right now, and hanging up for good.
+#[derive(Default)]
+struct Requests {
+    refresh: Arc<Notify>,
+    quit: Notify,
+    quitting: AtomicBool,
+}
+
+impl Requests {
+    /// Say goodbye to the server and stop reconnecting.
+    fn quit(&self) {
+        self.quitting.store(true, Ordering::SeqCst);
+        self.quit.notify_one();
+    }
+
+    fn quitting(&self) -> bool {
+        self.quitting.load(Ordering::SeqCst)
+    }
+}
+
+/// Keeps track of how long to wait before the next connection attempt, so
+/// that we don't hammer a server that's down.
+struct Backoff {
+    delay: Duration,
+}
+
+impl Backoff {
+    fn new() -> Backoff {
+        Backoff { delay: RECONNECT_DELAY }
+    }
+
+    /// We got all the way through connecting, so the next failure starts
+    /// over from the shortest delay.
+    fn reset(&mut self) {
+        self.delay = RECONNECT_DELAY;
+    }
+
+    async fn wait(&mut self) {
+        info!("Reconnecting in {}s...", self.delay.as_secs());
+        sleep(self.delay).await;
+        self.delay = (self.delay * 2).min(MAX_RECONNECT_DELAY);
+    }
+}
+
+/// Options from the command line, which take precedence over the config
+/// file.
+#[derive(Debug, Default, Clone)]
+pub struct ClientOptions {
+    pub profile: Option<Profile>,
+    pub x11: bool,
+    pub multiplex: bool,
+    /// How many ssh connections to spread forwarded connections across.
+    pub ssh_connections: Option<usize>,
+    pub bootstrap: bool,
+    /// Run in the background instead of in this terminal.
+    pub daemon: bool,
+    /// Wait for approval before forwarding the ports that the server finds.
+    pub approve: bool,
+    /// Show a desktop notification when ports come and go.
+    pub notify: bool,
+    /// Pairs of (remote port, bytes a second).
+    pub limits: Vec<(u16, u64)>,
+    pub reverse: Vec<u16>,
+    /// Ports to forward without asking the server what it has.
+    pub ports: Vec<u16>,
+    /// Pairs of (remote port, host to reach it on from the server).
+    pub hosts: Vec<(u16, String)>,
+    pub socks: Option<u16>,
+    /// Pairs of (remote port, local port).
+    pub map: Vec<(u16, u16)>,
+    pub bind: Option<IpAddr>,
+    pub output: Output,
+    /// How many times `-v` was given.
+    pub verbosity: u8,
+    /// Extra arguments for ssh, added to the ones in the config file.
+    pub ssh_args: Vec<String>,
+    /// Shut down once nothing has used the forwarded ports for this long.
+    pub idle_exit: Option<Duration>,
+}
+
 /// Wait for the server to be ready; we know the server is there and
 /// listening when we see the special sync marker, which is 8 NUL bytes in a
-/// row.
+/// row. Anything that comes before it goes to our stdout.
 async fn client_sync<S: AsyncRead + Unpin, T: AsyncRead + Unpin>(
     reader: &mut S,
     client_stderr: &mut T,
 ) -> Result<(), tokio::io::Error> {
+    client_sync_to(reader, client_stderr, &mut tokio::io::stdout()).await
+}
+
+/// Like `client_sync`, but what comes before the marker goes to `stdout`,
+/// for when our own stdout is spoken for.
+async fn client_sync_to<S, T, U>(
+    reader: &mut S,
+    client_stderr: &mut T,
+    stdout: &mut U,
+) -> Result<(), tokio::io::Error>
+where
+    S: AsyncRead + Unpin,
+    T: AsyncRead + Unpin,
+    U: AsyncWrite + Unpin,
+{
     info!("Waiting for synchronization marker...");
 
     let mut stderr = tokio::io::stderr();
-    let mut stdout = tokio::io::stdout();
     let mut buf = BytesMut::with_capacity(1024);
 
     let mut seen = 0;
@@ -52,7 +212,7 @@ async fn client_sync<S: AsyncRead + Unpin, T: AsyncRead + Unpin>(
         } => result,
     };
 
-    if let Err(_) = result {
+    if result.is_err() {
         // Something went wrong, let's just make sure we flush the client's
         // stderr before we return.
         _ = stderr.write_all(&buf[..]).await;
@@ -63,21 +223,79 @@ async fn client_sync<S: AsyncRead + Unpin, T: AsyncRead + Unpin>(
     result
 }
 
-/// Handle an incoming client connection, by forwarding it to the SOCKS5
-/// server at the specified port. This is the core of the entire thing.
+/// Say that an error happened during `phase`, on `port` if it was about one,
+/// for `map_err`.
+fn during<E>(
+    phase: Phase,
+    port: Option<u16>,
+) -> impl FnOnce(E) -> fwd_proto::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    move |e| fwd_proto::Error::during(phase, port, e)
+}
+
+/// What went wrong, given the reply code from a SOCKS5 server (RFC 1928).
+fn socks_error(reply: u8) -> String {
+    match reply {
+        0x01 => "general SOCKS server failure".to_string(),
+        0x02 => "connection not allowed by the SOCKS server".to_string(),
+        0x03 => "network unreachable".to_string(),
+        0x04 => "host unreachable".to_string(),
+        0x05 => "connection refused".to_string(),
+        0x06 => "connection timed out".to_string(),
+        0x07 => "SOCKS command not supported".to_string(),
+        0x08 => "address type not supported".to_string(),
+        reply => format!("unknown SOCKS error {reply}"),
+    }
+}
+
+/// A SOCKS5 server's answer when it couldn't connect to the port we asked
+/// for.
+#[derive(Debug)]
+struct SocksError {
+    reply: u8,
+    port: u16,
+}
+
+impl std::fmt::Display for SocksError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} on remote port {}",
+            socks_error(self.reply),
+            self.port
+        )
+    }
+}
+
+impl std::error::Error for SocksError {}
+
+/// Where to ask the SOCKS5 proxy to connect to.
+#[derive(Debug, Clone, Copy)]
+enum SocksAddr<'a> {
+    Ip(IpAddr),
+    /// A name, which the server looks up.
+    Domain(&'a str),
+}
+
+/// Connect to `dest`:`port` on the remote through the SOCKS5 server at the
+/// specified port.
 ///
 /// This contains a very simplified implementation of a SOCKS5 connector,
 /// enough to work with the SSH I have. I would have liked it to be SOCKS4,
 /// which is a much simpler protocol, but somehow it didn't work.
-async fn client_handle_connection(
+async fn socks_connect(
     socks_port: u16,
+    dest: SocksAddr<'_>,
     port: u16,
-    socket: TcpStream,
-) -> Result<()> {
-    debug!("Handling connection!");
-
+    profile: Profile,
+) -> Result<TcpStream> {
     let dest_addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, socks_port);
     let mut dest_socket = TcpStream::connect(dest_addr).await?;
+    if profile.nodelay() {
+        dest_socket.set_nodelay(true)?;
+    }
 
     debug!("Connected, sending handshake request");
     let packet: [u8; 3] = [
@@ -101,18 +319,30 @@ async fn client_handle_connection(
     }
 
     debug!("Handshake response received, sending connect request");
-    let packet: [u8; 10] = [
-        0x05,                                       // version again :P
-        0x01,                                       // connect
-        0x00,                                       // reserved!
-        0x01,                                       // ipv4
-        127,                                        // lo..
-        0,                                          // ..cal..
-        0,                                          // ..ho..
-        1,                                          // ..st
-        ((port & 0xFF00) >> 8).try_into().unwrap(), // port (high)
-        ((port & 0x00FF) >> 0).try_into().unwrap(), // port (low)
+    let mut packet = vec![
+        0x05, // version again :P
+        0x01, // connect
+        0x00, // reserved!
     ];
+    match dest {
+        SocksAddr::Ip(IpAddr::V4(ip)) => {
+            packet.push(0x01);
+            packet.extend_from_slice(&ip.octets());
+        }
+        SocksAddr::Ip(IpAddr::V6(ip)) => {
+            packet.push(0x04);
+            packet.extend_from_slice(&ip.octets());
+        }
+        SocksAddr::Domain(name) => {
+            let Ok(len) = u8::try_from(name.len()) else {
+                bail!("The host name {name} is too long");
+            };
+            packet.push(0x03);
+            packet.push(len);
+            packet.extend_from_slice(name.as_bytes());
+        }
+    }
+    packet.extend_from_slice(&port.to_be_bytes());
     dest_socket.write_all(&packet[..]).await?;
 
     debug!("Connect request sent, awaiting response");
@@ -122,7 +352,7 @@ async fn client_handle_connection(
         bail!("SOCKS5 incorrect response version again? {}", response[0]);
     }
     if response[1] != 0x00 {
-        bail!("SOCKS5 reports a connect error {}", response[1]);
+        bail!(SocksError { reply: response[1], port });
     }
     // Now we 100% do not care about the following information but we must
     // discard it so we can get to the good stuff. response[3] is the type of
@@ -138,8 +368,8 @@ async fn client_handle_connection(
             dest_socket.read_u8().await?; // So slow!
         }
     } else if response[3] == 0x04 {
-        // IPv6 - 8 bytes
-        let mut response: [u8; 8] = [0; 8];
+        // IPv6 - 16 bytes
+        let mut response: [u8; 16] = [0; 16];
         dest_socket.read_exact(&mut response).await?;
     } else {
         bail!(
@@ -151,56 +381,524 @@ async fn client_handle_connection(
     // need to skip.
     let mut response: [u8; 2] = [0; 2];
     dest_socket.read_exact(&mut response).await?;
+    Ok(dest_socket)
+}
 
-    info!("Connection established on port {}", port);
+/// Where connections to a forwarded port go: the port on the server itself,
+/// or on a `host` that the server can reach, like `ssh -L host:port`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Destination {
+    host: Option<String>,
+    port: u16,
+}
 
-    let mut socket = socket;
-    tokio::io::copy_bidirectional(&mut socket, &mut dest_socket).await?;
-    Ok(())
+impl std::fmt::Display for Destination {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.host {
+            None => write!(f, "remote port {}", self.port),
+            Some(host) => write!(f, "{}", config::host_port(host, self.port)),
+        }
+    }
+}
+
+/// Handle an incoming client connection, by forwarding it to the SOCKS5
+/// server at the specified port. This is the core of the entire thing.
+///
+/// Most things listen on 127.0.0.1, but some only listen on ::1, so if the
+/// first doesn't work we try the second. Other hosts are asked for just as
+/// they are, and names are left to the server to look up. If the proxy
+/// doesn't get through the handshake in time, we give up and drop the
+/// socket, so that the local side isn't left hanging.
+///
+/// Returns the number of bytes sent to and received from the remote port.
+async fn client_handle_connection<S: AsyncRead + AsyncWrite + Unpin>(
+    socks_port: u16,
+    dest: &Destination,
+    mut socket: S,
+    tuning: Tuning,
+    limit: Option<Arc<RateLimit>>,
+) -> Result<(u64, u64)> {
+    debug!("Handling connection!");
+    let (dest_socket, early) =
+        remote_connect(socks_port, dest, tuning, &mut socket, limit.as_deref())
+            .await
+            .map_err(during(Phase::Dial, Some(dest.port)))?;
+    info!("Connection established on port {}", dest.port);
+
+    let (sent, received) = copy_bidirectional(
+        socket,
+        dest_socket,
+        tuning.buffer_size,
+        limit.as_deref(),
+    )
+    .await?;
+    Ok((early + sent, received))
 }
 
-/// Listen on a port that we are currently forwarding, and use the SOCKS5
-/// proxy on the specified port to handle the connections.
-async fn client_listen(port: u16, socks_port: u16) -> Result<()> {
+/// How long we wait before we try a port that we couldn't get to again; the
+/// wait doubles after each try.
+const CONNECT_RETRY_WAIT: Duration = Duration::from_millis(100);
+
+/// The most we keep of what the local side sends before the remote port
+/// says anything, so that we can send it again if we have to connect
+/// again. Past that we stop waiting to find out whether we got there.
+const MAX_REPLAY: usize = 64 * 1024;
+
+/// Whether a connection through the proxy got to the remote port.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Dial {
+    /// The remote port said something.
+    Answered,
+    /// The proxy hung up before the remote port said anything.
+    Closed,
+    /// We stopped waiting to find out, because the local side was done or
+    /// had sent more than we keep.
+    Unknown,
+}
+
+/// Wait to hear from the remote port on `socket`, which has just been
+/// through the SOCKS handshake, passing on what `local` sends meanwhile and
+/// keeping it in `sent`.
+///
+/// ssh's proxy says the connection is made before the server has even
+/// tried to make it, and if the server can't, ssh just closes the socket.
+/// So a connection that ends before the remote port says anything never
+/// got there. One that's quiet might just be waiting for us to go first,
+/// which is why we keep passing on what we're sent.
+async fn dial_answer<L: AsyncRead + Unpin>(
+    socket: &mut TcpStream,
+    local: &mut L,
+    sent: &mut Vec<u8>,
+    limit: Option<&RateLimit>,
+) -> std::io::Result<Dial> {
+    let mut buffer = vec![0; 8 * 1024];
     loop {
-        let listener =
-            TcpListener::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, port))
-                .await?;
-        loop {
-            // The second item contains the IP and port of the new
-            // connection, but we don't care.
-            let (socket, _) = listener.accept().await?;
+        let mut byte = [0];
+        let read = tokio::select! {
+            peeked = socket.peek(&mut byte) => {
+                return Ok(match peeked {
+                    Ok(0) | Err(_) => Dial::Closed,
+                    Ok(_) => Dial::Answered,
+                });
+            }
+            read = local.read(&mut buffer) => read?,
+        };
+        if read == 0 {
+            return Ok(Dial::Unknown);
+        }
+        if let Some(limit) = limit {
+            limit.take(read).await;
+        }
+        sent.extend_from_slice(&buffer[..read]);
+        if socket.write_all(&buffer[..read]).await.is_err() {
+            return Ok(Dial::Closed);
+        }
+        if sent.len() > MAX_REPLAY {
+            return Ok(Dial::Unknown);
+        }
+    }
+}
 
-            tokio::spawn(async move {
-                if let Err(e) =
-                    client_handle_connection(socks_port, port, socket).await
-                {
-                    error!("Error handling connection: {:?}", e);
-                } else {
-                    debug!("Done???");
+/// Try each of `addrs` in turn for `dest`, through the SOCKS5 server at
+/// `socks_port`, until one gets there. Each gets what `local` has `sent` so
+/// far, and then waits for an answer the way `dial_answer` does.
+async fn remote_dial<L: AsyncRead + Unpin>(
+    socks_port: u16,
+    dest: &Destination,
+    addrs: &[SocksAddr<'_>],
+    tuning: Tuning,
+    local: &mut L,
+    sent: &mut Vec<u8>,
+    limit: Option<&RateLimit>,
+) -> Result<TcpStream> {
+    let mut failure = anyhow!("Nowhere to look for {dest}");
+    for &addr in addrs {
+        let handshake =
+            socks_connect(socks_port, addr, dest.port, tuning.profile);
+        let mut socket = match timeout(tuning.connect_timeout, handshake).await
+        {
+            Ok(Ok(socket)) => socket,
+            // The proxy told us it couldn't (not that ssh's ever does).
+            Ok(Err(e)) if e.is::<SocksError>() => {
+                debug!("Error connecting to {dest} at {addr:?}: {e:#}");
+                failure = e;
+                continue;
+            }
+            Ok(Err(e)) => {
+                return Err(e.context(format!("Error connecting to {dest}")))
+            }
+            Err(_) => bail!(
+                "Timed out after {}s connecting to {dest}",
+                tuning.connect_timeout.as_secs()
+            ),
+        };
+        if socket.write_all(sent).await.is_ok()
+            && dial_answer(&mut socket, local, sent, limit).await?
+                != Dial::Closed
+        {
+            return Ok(socket);
+        }
+        debug!("The proxy hung up on {dest} at {addr:?}");
+        failure = NotConnected(dest.clone()).into();
+    }
+    Err(failure)
+}
+
+/// The proxy hung up on a connection before the remote port said anything,
+/// which is how ssh says that the server couldn't connect to it.
+#[derive(Debug)]
+struct NotConnected(Destination);
+
+impl std::fmt::Display for NotConnected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "the server couldn't connect to {}; is anything listening there?",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for NotConnected {}
+
+/// Connect to `dest` through the SOCKS5 server at `socks_port`, the way
+/// `client_handle_connection` says, passing on what `local` sends until the
+/// remote port answers. Returns the socket, and how many bytes of `local`
+/// we've passed on.
+///
+/// A port that the server couldn't connect to gets a few more tries, a
+/// little further apart each time, since whatever was there might be
+/// restarting and just not listening again yet. What the local side sent on
+/// a try that didn't get there is sent again on the next.
+///
+/// `connect_timeout` covers each SOCKS handshake, and nothing after it. ssh
+/// answers the handshake itself, before the server tries to connect, so a
+/// server that's slow to connect (or never does, to a host that drops
+/// packets) only shows up when ssh hangs up on us, whenever that is.
+async fn remote_connect<L: AsyncRead + Unpin>(
+    socks_port: u16,
+    dest: &Destination,
+    tuning: Tuning,
+    local: &mut L,
+    limit: Option<&RateLimit>,
+) -> Result<(TcpStream, u64)> {
+    let addrs = match &dest.host {
+        Some(host) => vec![match host.parse() {
+            Ok(ip) => SocksAddr::Ip(ip),
+            Err(_) => SocksAddr::Domain(host),
+        }],
+        None => vec![
+            SocksAddr::Ip(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+            SocksAddr::Ip(IpAddr::V6(Ipv6Addr::LOCALHOST)),
+        ],
+    };
+    let mut sent = Vec::new();
+    let mut wait = CONNECT_RETRY_WAIT;
+    let mut attempts = 1;
+    loop {
+        let dial = remote_dial(
+            socks_port, dest, &addrs, tuning, local, &mut sent, limit,
+        );
+        match dial.await {
+            Err(e)
+                if attempts < tuning.connect_attempts
+                    && (e.is::<NotConnected>() || e.is::<SocksError>()) =>
+            {
+                debug!("{e:#}, trying again in {}ms", wait.as_millis());
+                sleep(wait).await;
+                wait *= 2;
+                attempts += 1;
+            }
+            result => return result.map(|socket| (socket, sent.len() as u64)),
+        }
+    }
+}
+
+/// A token bucket, shared by every connection to a port, that keeps them
+/// all together under so many bytes a second. It holds a second's worth,
+/// so short bursts go through at full speed.
+#[derive(Debug)]
+struct RateLimit {
+    rate: f64,
+    bucket: std::sync::Mutex<(f64, Instant)>,
+}
+
+impl RateLimit {
+    fn new(rate: u64) -> RateLimit {
+        let rate = rate as f64;
+        RateLimit {
+            rate,
+            bucket: std::sync::Mutex::new((rate, Instant::now())),
+        }
+    }
+
+    /// Wait until we're allowed to send `bytes` more. Whoever asks takes
+    /// the bytes right away, even if that leaves the bucket in debt, and
+    /// then waits the debt off; that way everybody waiting is served in
+    /// turn.
+    async fn take(&self, bytes: usize) {
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let (tokens, last) = *bucket;
+            let now = Instant::now();
+            let refill = (now - last).as_secs_f64() * self.rate;
+            let tokens = (tokens + refill).min(self.rate) - bytes as f64;
+            *bucket = (tokens, now);
+            Duration::from_secs_f64((-tokens).max(0.0) / self.rate)
+        };
+        if !wait.is_zero() {
+            sleep(wait).await;
+        }
+    }
+}
+
+/// Like `tokio::io::copy_buf`, but getting each buffer's worth past `limit`
+/// before we write it.
+async fn copy_limited<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    limit: &RateLimit,
+) -> std::io::Result<u64>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut count = 0;
+    loop {
+        let buffer = reader.fill_buf().await?;
+        if buffer.is_empty() {
+            writer.flush().await?;
+            return Ok(count);
+        }
+        let n = buffer.len();
+        limit.take(n).await;
+        writer.write_all(buffer).await?;
+        reader.consume(n);
+        count += n as u64;
+    }
+}
+
+/// Copy both ways between `a` and `b` until both directions are done, like
+/// `tokio::io::copy_bidirectional` but reading up to `buffer_size` bytes at a
+/// time. Nothing more is read from a side until what we read from it last
+/// has been written to the other, so a slow reader slows down the writer
+/// instead of piling data up in memory. If there's a `limit`, both
+/// directions count against it.
+async fn copy_bidirectional<A, B>(
+    a: A,
+    b: B,
+    buffer_size: usize,
+    limit: Option<&RateLimit>,
+) -> std::io::Result<(u64, u64)>
+where
+    A: AsyncRead + AsyncWrite,
+    B: AsyncRead + AsyncWrite,
+{
+    let (a_read, mut a_write) = tokio::io::split(a);
+    let (b_read, mut b_write) = tokio::io::split(b);
+    let mut a_read = BufReader::with_capacity(buffer_size, a_read);
+    let mut b_read = BufReader::with_capacity(buffer_size, b_read);
+    let a_to_b = async {
+        let count = match limit {
+            Some(limit) => {
+                copy_limited(&mut a_read, &mut b_write, limit).await?
+            }
+            None => tokio::io::copy_buf(&mut a_read, &mut b_write).await?,
+        };
+        b_write.shutdown().await?;
+        Ok(count)
+    };
+    let b_to_a = async {
+        let count = match limit {
+            Some(limit) => {
+                copy_limited(&mut b_read, &mut a_write, limit).await?
+            }
+            None => tokio::io::copy_buf(&mut b_read, &mut a_write).await?,
+        };
+        a_write.shutdown().await?;
+        Ok(count)
+    };
+    tokio::try_join!(a_to_b, b_to_a)
+}
+
+fn bind(address: SocketAddr) -> std::io::Result<TcpListener> {
+    let socket = match address {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    // Like `TcpListener::bind`, so that we can listen again right away.
+    #[cfg(target_family = "unix")]
+    socket.set_reuseaddr(true)?;
+    socket.bind(address)?;
+    socket.listen(1024)
+}
+
+/// Bind the local end of the forward for the remote `port`, which is
+/// usually 127.0.0.1 and the same port. If something else here already has
+/// it, the port is no use to anybody unless we put it somewhere else, so we
+/// try `fallback` and then any port that's free. The listener knows which
+/// one it got.
+fn client_bind(
+    port: u16,
+    local: SocketAddr,
+    fallback: Option<u16>,
+) -> std::io::Result<TcpListener> {
+    let mut result = bind(local);
+    for fallback in fallback.into_iter().chain([0]) {
+        match result {
+            Err(e) if e.kind() == std::io::ErrorKind::AddrInUse => {
+                result = bind(SocketAddr::new(local.ip(), fallback));
+            }
+            _ => break,
+        }
+    }
+    let listener = result?;
+    let address = listener.local_addr()?;
+    if address != local {
+        warn!("{local} is already in use, so port {port} is on {address}");
+    }
+    Ok(listener)
+}
+
+/// One of the SOCKS ports of the session we're in; the `n`th connection gets
+/// the `n`th, round and round. Between sessions, wait up to `wait` for the
+/// next one, instead of turning the connection away over a blip.
+async fn session_socks_port(
+    socks: &mut watch::Receiver<Vec<u16>>,
+    n: usize,
+    wait: Duration,
+) -> Result<u16> {
+    let next = async {
+        loop {
+            {
+                let ports = socks.borrow_and_update();
+                if !ports.is_empty() {
+                    return Ok(ports[n % ports.len()]);
                 }
-            });
+            }
+            if socks.changed().await.is_err() {
+                bail!("fwd is shutting down");
+            }
+        }
+    };
+    match timeout(wait, next).await {
+        Ok(result) => result,
+        Err(_) => bail!(
+            "Not connected to the server, and still not after {}s",
+            wait.as_secs()
+        ),
+    }
+}
+
+/// Accept connections for a port that we are currently forwarding, and use
+/// the session's SOCKS5 proxies to handle them. Each connection sticks to one
+/// proxy, and so to one ssh connection, for as long as it lasts.
+async fn client_listen(
+    dest: Destination,
+    listener: TcpListener,
+    socks: watch::Receiver<Vec<u16>>,
+    stats: Arc<stats::PortStats>,
+    history: Arc<history::History>,
+    tuning: Tuning,
+    limit: Option<Arc<RateLimit>>,
+) -> Result<()> {
+    let port = dest.port;
+    let local = listener
+        .local_addr()
+        .map_err(during(Phase::Listen, Some(port)))?;
+    if !local.ip().is_loopback() {
+        warn!("Port {port} is reachable from other machines on {local}");
+    }
+    let mut n: usize = 0;
+    loop {
+        let (socket, peer) = listener
+            .accept()
+            .await
+            .map_err(during(Phase::Listen, Some(port)))?;
+        debug!("Connection to port {port} from {peer}");
+        if tuning.profile.nodelay() {
+            // Only this connection is slower for it, so don't lose the port.
+            if let Err(e) = socket.set_nodelay(true) {
+                warn!("Error setting TCP_NODELAY for {peer}: {e}");
+            }
         }
+        // The connection stops counting as open when the socket is dropped,
+        // which is when we're done handling it.
+        let connection = stats.connection_opened(peer);
+        let socket = sniff::Sniff::new(socket, connection);
+
+        history.record(history::Event::Used(port));
+        let (dest, limit) = (dest.clone(), limit.clone());
+        let mut socks = socks.clone();
+        n = n.wrapping_add(1);
+        tokio::spawn(async move {
+            let result = async {
+                let wait = tuning.connect_timeout;
+                let socks_port =
+                    session_socks_port(&mut socks, n, wait).await?;
+                client_handle_connection(
+                    socks_port, &dest, socket, tuning, limit,
+                )
+                .await
+            };
+            match result.await {
+                Ok(_) => debug!("Done???"),
+                Err(e) => error!("Error handling connection: {e:#}"),
+            }
+        });
     }
 }
 
 async fn client_handle_messages<T: AsyncRead + Unpin>(
     mut reader: MessageReader<T>,
+    keepalive_timeout: Duration,
     events: mpsc::Sender<ui::UIEvent>,
 ) -> Result<()> {
+    // The whole list, as the server's changes to it leave it; the UI always
+    // gets all of it.
+    let mut ports: Vec<PortDesc> = Vec::new();
     loop {
         use Message::*;
-        match reader.read().await? {
-            Ping => (),
-            Ports(ports) => {
-                if let Err(_) = events.send(ui::UIEvent::Ports(ports)).await {
-                    // TODO: Log
-                }
+        // If ssh hangs without closing the pipe we'd wait here forever, so
+        // anything at all from the server counts as proof of life.
+        let message = match timeout(keepalive_timeout, reader.read()).await {
+            // A newer server might say things we don't know yet.
+            Ok(Err(fwd_proto::Error::Unknown(kind))) => {
+                warn!("Ignoring a message of unknown type 0x{kind:02x}");
+                continue;
+            }
+            Ok(message) => message?,
+            Err(_) => bail!(
+                "Heard nothing from the server in {}s",
+                keepalive_timeout.as_secs()
+            ),
+        };
+        trace!("< {message:?}");
+        match message {
+            Ping | Pong => (),
+            Ports(new_ports) => {
+                ports = new_ports;
+                _ = events.send(ui::UIEvent::Ports(ports.clone())).await;
+            }
+            PortAdded(desc) => {
+                ports.retain(|p| p.port != desc.port);
+                ports.push(desc);
+                _ = events.send(ui::UIEvent::Ports(ports.clone())).await;
+            }
+            PortRemoved(port) => {
+                ports.retain(|p| p.port != port);
+                _ = events.send(ui::UIEvent::Ports(ports.clone())).await;
+            }
+            Sockets(paths) => {
+                _ = events.send(ui::UIEvent::Sockets(paths)).await;
             }
             Browse(url) => {
                 // TODO: Uh, security?
-                info!("Browsing to {url}...");
-                _ = open::that(url);
+                _ = events.send(ui::UIEvent::Browse(url)).await;
+            }
+            Goodbye(reason) => {
+                warn!("Remote server exited: {reason}");
+                return Ok(());
             }
             message => error!("Unsupported: {:?}", message),
         };
@@ -229,134 +927,258 @@ async fn client_pipe_stderr<Debug: AsyncBufRead + Unpin>(
     }
 }
 
+/// What we want from every session with the server, however many times we
+/// have to reconnect.
+#[derive(Debug, Clone)]
+struct SessionOptions {
+    /// The settings to send with `Configure`.
+    settings: Vec<String>,
+    keepalive_timeout: Duration,
+    /// The ports to forward, if we're not to ask the server what it has.
+    static_ports: Vec<u16>,
+}
+
+impl Default for SessionOptions {
+    fn default() -> SessionOptions {
+        SessionOptions {
+            settings: Vec::new(),
+            keepalive_timeout: config::DEFAULT_KEEPALIVE_TIMEOUT,
+            static_ports: Vec::new(),
+        }
+    }
+}
+
+/// Ask the server for the list of ports, and keep asking unless it will tell
+/// us when it changes; either way, ping it every so often. We also ask
+/// whenever `refresh` is notified, which is how the user forces an update.
+/// If we're not to `discover` ports at all, we only ping.
+async fn client_refresh_loop<Writer: AsyncWrite + Unpin>(
+    writer: &mut MessageWriter<Writer>,
+    push: bool,
+    discover: bool,
+    refresh: &Notify,
+) -> fwd_proto::Result<()> {
+    if discover {
+        writer.write(Message::Refresh).await?;
+    }
+    let interval = if push || !discover {
+        KEEPALIVE_INTERVAL
+    } else {
+        REFRESH_INTERVAL
+    };
+    let mut last_ping = Instant::now();
+    loop {
+        let asked = tokio::select! {
+            _ = sleep(interval) => false,
+            _ = refresh.notified() => true,
+        };
+        if discover && (asked || !push) {
+            writer.write(Message::Refresh).await?;
+        }
+        if last_ping.elapsed() >= KEEPALIVE_INTERVAL {
+            writer.write(Message::Ping).await?;
+            last_ping = Instant::now();
+        }
+    }
+}
+
+/// The server runs an older fwd than us, which we can `replace`; it has the
+/// version it says it is, if any.
+#[derive(Debug)]
+struct Outdated(Option<String>);
+
+impl std::fmt::Display for Outdated {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let local = env!("CARGO_PKG_VERSION");
+        match &self.0 {
+            Some(version) => write!(
+                f,
+                "fwd on the server is version {version}, older than {local}"
+            ),
+            None => write!(f, "fwd on the server doesn't say its version"),
+        }
+    }
+}
+
+impl std::error::Error for Outdated {}
+
+/// Check the version of fwd that the server says it is in its hello
+/// `headers`, and warn if it isn't ours. If we can `replace` the server, one
+/// that's older fails with `Outdated` instead.
+fn check_version(headers: &[String], replace: bool) -> Result<()> {
+    let local_version = env!("CARGO_PKG_VERSION");
+    let version = get_header(headers, "version");
+    if replace && version.is_none_or(|v| deploy::is_older(v, local_version)) {
+        bail!(Outdated(version.map(str::to_string)));
+    }
+    match version {
+        Some(version) if version != local_version => warn!(
+            "Remote fwd is version {version} but local fwd is version \
+             {local_version}; consider updating"
+        ),
+        Some(_) => (),
+        None => {
+            warn!("Remote fwd does not report its version; consider updating")
+        }
+    }
+    Ok(())
+}
+
+/// Wait for the server's announcement, and tell it what we want from the
+/// session. Return whether it will push changes to the list of ports (which
+/// it only does if we `discover` them), and whether it wants a goodbye.
+async fn client_hello<Reader: AsyncRead + Unpin, Writer: AsyncWrite + Unpin>(
+    reader: &mut MessageReader<Reader>,
+    writer: &mut MessageWriter<Writer>,
+    settings: &[String],
+    discover: bool,
+    replace: bool,
+) -> Result<(bool, bool)> {
+    let hello = reader.read().await.map_err(during(Phase::Hello, None))?;
+    let Message::Hello(major, minor, headers) = hello else {
+        bail!("Expected a hello message from the remote server");
+    };
+
+    info!("Server Version: {major} {minor}");
+    if major != 0 || minor > 5 {
+        bail!("Unsupported remote protocol version {}.{}", major, minor);
+    }
+
+    check_version(&headers, replace)?;
+
+    if let Some(display) = get_header(&headers, "display") {
+        info!("To show windows here, run `export DISPLAY={display}` on the server");
+    }
+
+    // Servers that can push changes to us save us from asking for the
+    // list of ports over and over.
+    let has = |name| has_capability(&headers, name);
+    let push = discover && (minor >= 4 || has(capabilities::PUSH));
+    let goodbye = has(capabilities::GOODBYE);
+    writer.set_checksums(has(capabilities::CHECKSUM));
+    let mut settings = settings.to_vec();
+    if push {
+        settings.push("push=true".to_string());
+    }
+
+    // Servers that don't list what they can do don't want to know what
+    // we can do either.
+    if get_header(&headers, "capabilities").is_some() {
+        settings.push(format!("capabilities={}", CAPABILITIES.join(",")));
+    }
+
+    // Older servers don't know about settings, and would choke on them.
+    if minor >= 3 {
+        // Servers that can't find sockets ignore this, like any other
+        // setting they don't know.
+        settings.push("sockets=true".to_string());
+        writer
+            .write(Message::Configure(settings))
+            .await
+            .map_err(during(Phase::Hello, None))?;
+    } else if !settings.is_empty() {
+        warn!("Remote fwd is too old for per-session settings; ignoring");
+    }
+    Ok((push, goodbye))
+}
+
 async fn client_main<Reader: AsyncRead + Unpin, Writer: AsyncWrite + Unpin>(
-    socks_port: u16,
+    socks_ports: Vec<u16>,
     mut reader: MessageReader<Reader>,
     mut writer: MessageWriter<Writer>,
+    options: &SessionOptions,
+    requests: &Requests,
     events: mpsc::Sender<ui::UIEvent>,
+    replace: bool,
 ) -> Result<()> {
-    // Wait for the server's announcement.
-    if let Message::Hello(major, minor, _) = reader.read().await? {
-        info!("Server Version: {major} {minor}");
-        if major != 0 || minor > 2 {
-            bail!("Unsupported remote protocol version {}.{}", major, minor);
-        }
-    } else {
-        bail!("Expected a hello message from the remote server");
-    }
+    // With a fixed list of ports there's nothing to discover, and the
+    // server doesn't need to go looking.
+    let discover = options.static_ports.is_empty();
+    let (push, goodbye) = client_hello(
+        &mut reader,
+        &mut writer,
+        &options.settings,
+        discover,
+        replace,
+    )
+    .await?;
 
     // And now really get into it...
-    _ = events.send(ui::UIEvent::Connected(socks_port)).await;
+    _ = events.send(ui::UIEvent::Connected(socks_ports)).await;
+    if !discover {
+        let ports = options
+            .static_ports
+            .iter()
+            .map(|&port| PortDesc { port, desc: String::new(), pid: None })
+            .collect();
+        _ = events.send(ui::UIEvent::Ports(ports)).await;
+    }
 
     tokio::select! {
-        result = async {
-            loop {
-                use tokio::time::{sleep, Duration};
-                if let Err(e) = writer.write(Message::Refresh).await {
-                    break Err::<(), _>(e);
-                }
-                sleep(Duration::from_millis(500)).await;
-            }
-        } => {
+        result = client_refresh_loop(
+            &mut writer, push, discover, &requests.refresh
+        ) => {
             if let Err(e) = result {
-                print!("Error sending refreshes\n");
+                error!("Error sending refreshes");
                 return Err(e.into());
             }
         },
-        result = client_handle_messages(reader, events) => {
+        result = client_handle_messages(
+            reader, options.keepalive_timeout, events
+        ) => {
             if let Err(e) = result {
-                print!("Error handling messages\n");
-                return Err(e.into());
+                error!("Error handling messages");
+                return Err(e);
+            }
+        },
+        _ = requests.quit.notified() => {
+            if goodbye {
+                let reason = "the client is exiting".to_string();
+                writer.write(Message::Goodbye(reason)).await?;
             }
         },
     }
     Ok(())
 }
 
-async fn spawn_ssh(
-    server: &str,
-) -> Result<(tokio::process::Child, u16), std::io::Error> {
-    let socks_port = {
-        let listener = TcpListener::bind("127.0.0.1:0").await?;
-        listener.local_addr()?.port()
-    };
-
-    let mut cmd = process::Command::new("ssh");
-    cmd.arg("-T")
-        .arg("-D")
-        .arg(socks_port.to_string())
-        .arg(server)
-        .arg("fwd")
-        .arg("--server");
-
-    cmd.stdout(std::process::Stdio::piped());
-    cmd.stdin(std::process::Stdio::piped());
-    cmd.stderr(std::process::Stdio::piped());
-    let child = cmd.spawn()?;
-    Ok((child, socks_port))
-}
-
-#[cfg(target_family = "windows")]
-fn is_sigint(status: std::process::ExitStatus) -> bool {
-    match status.code() {
-        Some(255) => true,
-        _ => false,
-    }
-}
-
-#[cfg(target_family = "unix")]
-fn is_sigint(status: std::process::ExitStatus) -> bool {
-    use std::os::unix::process::ExitStatusExt;
-    match status.signal() {
-        Some(2) => true,
-        Some(_) => false,
-        None => false,
-    }
-}
-
-async fn client_connect_loop(remote: &str, events: mpsc::Sender<ui::UIEvent>) {
+async fn client_connect_loop<T: Transport>(
+    transport: &mut T,
+    options: &SessionOptions,
+    requests: &Requests,
+    events: mpsc::Sender<ui::UIEvent>,
+) {
+    let mut backoff = Backoff::new();
     loop {
         _ = events.send(ui::UIEvent::Disconnected).await;
 
-        let (mut child, socks_port) =
-            spawn_ssh(remote).await.expect("failed to spawn");
-
-        let mut stderr = child
-            .stderr
-            .take()
-            .expect("child did not have a handle to stderr");
-
-        let writer = child
-            .stdin
-            .take()
-            .expect("child did not have a handle to stdin");
-
-        let mut reader = BufReader::new(
-            child
-                .stdout
-                .take()
-                .expect("child did not have a handle to stdout"),
-        );
-
-        if let Err(e) = client_sync(&mut reader, &mut stderr).await {
-            error!("Error synchronizing: {:?}", e);
-            match child.wait().await {
-                Ok(status) => {
-                    if is_sigint(status) {
-                        return;
-                    } else {
-                        match status.code() {
-                            Some(127) => eprintln!("Cannot find `fwd` remotely, make sure it is installed"),
-                            _ => (),
-                        };
-                    }
+        let session = match transport.connect().await {
+            Ok(session) => session,
+            Err(e) => {
+                error!("Error connecting: {e:#}");
+                if !transport.disconnected().await {
+                    return;
                 }
-                Err(_) => (),
-            };
+                backoff.wait().await;
+                continue;
+            }
+        };
 
-            tokio::time::sleep(tokio::time::Duration::from_secs(3)).await;
+        let Session { reader, writer, mut stderr, socks_ports } = session;
+        if socks_ports.is_empty() {
+            error!("The transport has no SOCKS proxy for us to use");
+            return;
+        }
+        let mut reader = BufReader::new(reader);
+        let sync = client_sync(&mut reader, &mut stderr).await;
+        if let Err(e) = sync.map_err(during(Phase::Sync, None)) {
+            error!("{:?}", anyhow::Error::from(e));
+            if !transport.disconnected().await {
+                return;
+            }
+            backoff.wait().await;
             continue;
         }
+        backoff.reset();
 
         let mut stderr = BufReader::new(stderr);
         let writer = MessageWriter::new(BufWriter::new(writer));
@@ -367,35 +1189,286 @@ async fn client_connect_loop(remote: &str, events: mpsc::Sender<ui::UIEvent>) {
             client_pipe_stderr(&mut stderr, sec).await;
         });
 
-        if let Err(e) =
-            client_main(socks_port, reader, writer, events.clone()).await
+        let replace = transport.replaceable();
+        if let Err(e) = client_main(
+            socks_ports,
+            reader,
+            writer,
+            options,
+            requests,
+            events.clone(),
+            replace,
+        )
+        .await
         {
-            error!("Server disconnected with error: {:?}", e);
-        } else {
+            if let Some(outdated) = e.downcast_ref::<Outdated>() {
+                info!("{outdated}; replacing it with this one");
+                if let Err(e) = transport.replace().await {
+                    error!("Error copying fwd to the server: {e:#}");
+                }
+            } else {
+                error!("Server disconnected with error: {:?}", e);
+            }
+        } else if requests.quitting() {
+            return;
+        } else {
             warn!("Disconnected from server, reconnecting...");
         }
+
+        if !transport.disconnected().await {
+            return;
+        }
+        backoff.wait().await;
+    }
+}
+
+fn load_config(
+    remote: &str,
+    options: &ClientOptions,
+) -> Result<config::ServerConfig> {
+    let mut config = config::load_config()
+        .context("Error loading configuration")?
+        .get(remote);
+    if let Some(profile) = options.profile {
+        config.set_profile(profile);
+    }
+    if options.x11 {
+        config.set_x11(true);
+    }
+    if options.multiplex {
+        config.set_multiplex(true);
+    }
+    if let Some(connections) = options.ssh_connections {
+        config.set_ssh_connections(connections);
+    }
+    if options.bootstrap {
+        config.set_bootstrap(true);
+    }
+    if options.approve {
+        config.set_approve(true);
+    }
+    if options.notify {
+        config.set_notify(true);
+    }
+    if let Some(idle_exit) = options.idle_exit {
+        config.set_idle_exit(idle_exit);
+    }
+    config.add_ssh_args(&options.ssh_args);
+    for port in options.reverse.iter() {
+        config.add_reverse(*port);
+    }
+    for (port, host) in options.hosts.iter() {
+        config.set_host(*port, host.clone());
+    }
+    for port in options.ports.iter() {
+        config.add_static_port(*port);
     }
+    if let Some(port) = options.socks {
+        config.set_socks(port);
+    }
+    if let Some(address) = options.bind {
+        config.set_bind(address);
+    }
+    for (port, local_port) in options.map.iter() {
+        config.map_port(*port, *local_port);
+    }
+    for (port, limit) in options.limits.iter() {
+        config.set_limit(*port, *limit);
+    }
+    Ok(config)
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
 }
 
-pub async fn run_client(remote: &str) {
-    let (event_sender, event_receiver) = mpsc::channel(1024);
-    _ = log::set_boxed_logger(ui::Logger::new(event_sender.clone()));
-    log::set_max_level(LevelFilter::Info);
+/// Whenever the file at `path` changes, `load` the config again and hand it
+/// to the UI. A config that doesn't load is left for the next change, and
+/// the UI keeps the one it has.
+async fn watch_config<F: Fn() -> Result<config::ServerConfig>>(
+    path: Option<PathBuf>,
+    load: F,
+    events: mpsc::Sender<ui::UIEvent>,
+) {
+    let Some(path) = path else {
+        return std::future::pending().await;
+    };
+    let mut last = modified(&path);
+    loop {
+        sleep(CONFIG_CHECK_INTERVAL).await;
+        let now = modified(&path);
+        if now == last {
+            continue;
+        }
+        last = now;
+        match load() {
+            Ok(config) => {
+                _ = events.send(ui::UIEvent::Config(Box::new(config))).await
+            }
+            Err(e) => error!("Error reloading {}: {e:#}", path.display()),
+        }
+    }
+}
 
-    let config = match config::load_config() {
-        Ok(config) => config.get(remote),
+/// Connect to `fwd --server` on the remote over ssh, and forward ports until
+/// the user quits.
+pub async fn run_client(remote: &str, options: ClientOptions) {
+    let config = match load_config(remote, &options) {
+        Ok(config) => config,
         Err(e) => {
-            eprintln!("Error loading configuration: {:?}", e);
+            eprintln!("{e:?}");
+            return;
+        }
+    };
+    if options.daemon {
+        if let Err(e) = daemon::start(remote).await {
+            eprintln!("{e:#}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let _pid_file = daemon::PidFile::create();
+    let transport = transport::SshTransport::new(remote, config.clone());
+    let history = Arc::new(history::History::open(remote));
+    let (events, receiver) = mpsc::channel(1024);
+    let events = (events, receiver);
+    run(remote, config, &options, transport, history, events, true).await;
+}
+
+/// Like `run_client`, but reach the server with the given transport instead
+/// of ssh. `remote` is still used to look up the configuration, although the
+/// ssh-specific parts of it are up to the transport.
+pub async fn run_client_with_transport<T: Transport>(
+    remote: &str,
+    options: ClientOptions,
+    transport: T,
+) {
+    match load_config(remote, &options) {
+        Ok(config) => {
+            let history = Arc::new(history::History::open(remote));
+            let events = mpsc::channel(1024);
+            run(remote, config, &options, transport, history, events, true)
+                .await;
+        }
+        Err(e) => eprintln!("{e:?}"),
+    }
+}
+
+/// Wait for a signal asking us to stop. In the terminal UI, Ctrl-C is a key
+/// like any other, but with JSON output (or from `kill`) it's a signal.
+async fn shutdown_signal() {
+    #[cfg(target_family = "unix")]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        if let Ok(mut terminate) = signal(SignalKind::terminate()) {
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => (),
+                _ = terminate.recv() => (),
+            }
             return;
         }
+    }
+    if tokio::signal::ctrl_c().await.is_err() {
+        std::future::pending().await // No signals for us, then.
+    }
+}
+
+/// Run the client until it's asked to stop, with the UI taking its events
+/// from the receiving end of `events`. If we `reload`, changes to the config
+/// file apply as soon as it's saved.
+async fn run<T: Transport>(
+    remote: &str,
+    config: config::ServerConfig,
+    options: &ClientOptions,
+    mut transport: T,
+    history: Arc<history::History>,
+    events: (mpsc::Sender<ui::UIEvent>, mpsc::Receiver<ui::UIEvent>),
+    reload: bool,
+) {
+    let (event_sender, event_receiver) = events;
+    let output = options.output;
+    let filter = Filter::verbosity(options.verbosity);
+    let (filter, filter_error) = match filter.clone().with_env() {
+        Ok(filter) => (filter, None),
+        Err(e) => (filter, Some(e)),
     };
+    // Inside some other program, the log is that program's business.
+    if output != Output::Quiet {
+        log::set_max_level(filter.max_level());
+        _ = log::set_boxed_logger(ui::Logger::new(
+            event_sender.clone(),
+            filter.clone(),
+        ));
+    }
+    if let Some(e) = filter_error {
+        warn!("{e:#}");
+    }
 
-    let mut ui = ui::UI::new(event_receiver, config);
+    // The server logs to its stderr, which ends up in our log, so it should
+    // be just as chatty.
+    let mut settings = config.settings();
+    if filter != Filter::default() {
+        settings.push(format!("log={filter}"));
+    }
+    let session_options = SessionOptions {
+        settings,
+        keepalive_timeout: config.keepalive_timeout(),
+        static_ports: config.static_ports().to_vec(),
+    };
+    let requests = Requests::default();
+    let mut ui = ui::UI::new(
+        event_receiver,
+        config,
+        history,
+        requests.refresh.clone(),
+        output,
+    );
 
     // Start the reconnect loop.
-    tokio::select! {
-        _ = ui.run() => (),
-        _ = client_connect_loop(remote, event_sender) => ()
+    {
+        let connect = client_connect_loop(
+            &mut transport,
+            &session_options,
+            &requests,
+            event_sender.clone(),
+        );
+        tokio::pin!(connect);
+        let watch = watch_config(
+            config::config_path(),
+            || load_config(remote, options),
+            event_sender.clone(),
+        );
+        let disconnected = tokio::select! {
+            _ = ui.run() => false,
+            _ = watch, if reload => false,
+            _ = control::serve(remote, event_sender) => false,
+            _ = &mut connect => true,
+            _ = shutdown_signal(), if output != Output::Quiet => {
+                info!("Shutting down...");
+                false
+            }
+        };
+
+        // Tell the server we're going on purpose, so that it doesn't take
+        // us for a broken connection, but don't hang around if it's stuck.
+        if !disconnected && ui.connected() {
+            requests.quit();
+            _ = timeout(GOODBYE_TIMEOUT, connect).await;
+        }
+    }
+
+    // Stop taking new connections before the session goes away, so that
+    // nobody connects just to be dropped, and then make sure it's gone.
+    ui.stop_listening();
+    transport.shutdown().await;
+
+    // Make sure the terminal is back in shape before we print the summary.
+    ui.close();
+    match output {
+        Output::Tui => print!("{}", ui.stats()),
+        Output::Json => eprint!("{}", ui.stats()), // Keep stdout parseable.
+        Output::Quiet => (),
     }
 }
 
@@ -407,14 +1480,26 @@ mod tests {
     use tokio::sync::mpsc::Receiver;
 
     struct Fixture {
-        _server_read: MessageReader<DuplexStream>,
+        server_read: MessageReader<DuplexStream>,
         server_write: MessageWriter<DuplexStream>,
-        _event_receiver: Receiver<ui::UIEvent>,
+        events: Receiver<ui::UIEvent>,
+        requests: Arc<Requests>,
         client_result: Option<tokio::task::JoinHandle<anyhow::Result<()>>>,
     }
 
     impl Fixture {
         pub fn new() -> Self {
+            Fixture::with_settings(vec![])
+        }
+
+        pub fn with_settings(settings: Vec<String>) -> Self {
+            Fixture::with_static_ports(settings, vec![])
+        }
+
+        pub fn with_static_ports(
+            settings: Vec<String>,
+            static_ports: Vec<u16>,
+        ) -> Self {
             let (server_read, client_write) = tokio::io::duplex(4096);
             let server_read = MessageReader::new(server_read);
             let client_write = MessageWriter::new(client_write);
@@ -424,15 +1509,31 @@ mod tests {
             let server_write = MessageWriter::new(server_write);
 
             let (event_sender, event_receiver) = mpsc::channel(1024);
+            let requests = Arc::new(Requests::default());
+            let client_requests = requests.clone();
 
             let client_result = tokio::spawn(async move {
-                client_main(0, client_read, client_write, event_sender).await
+                client_main(
+                    vec![0],
+                    client_read,
+                    client_write,
+                    &SessionOptions {
+                        settings,
+                        keepalive_timeout: config::DEFAULT_KEEPALIVE_TIMEOUT,
+                        static_ports,
+                    },
+                    &client_requests,
+                    event_sender,
+                    false,
+                )
+                .await
             });
 
             Fixture {
-                _server_read: server_read,
+                server_read,
                 server_write,
-                _event_receiver: event_receiver,
+                events: event_receiver,
+                requests,
                 client_result: Some(client_result),
             }
         }
@@ -466,6 +1567,25 @@ mod tests {
         assert_matches!(t.shutdown().await, Err(_));
     }
 
+    #[test]
+    fn replace_older_servers() {
+        let outdated = |version: Option<&str>, replace| {
+            let headers: Vec<_> =
+                version.iter().map(|v| format!("version={v}")).collect();
+            check_version(&headers, replace).is_err_and(|e| e.is::<Outdated>())
+        };
+        assert!(outdated(Some("0.0.1"), true));
+        assert!(outdated(None, true));
+        assert!(!outdated(Some(env!("CARGO_PKG_VERSION")), true));
+
+        // A newer one stays, so that two clients don't keep swapping it out
+        // for their own.
+        assert!(!outdated(Some("999.0.0"), true));
+
+        // Any version will do for a server we can't replace.
+        assert!(!outdated(Some("0.0.1"), false));
+    }
+
     #[tokio::test]
     async fn basic_hello_wrong_major() {
         let mut t = Fixture::new();
@@ -477,4 +1597,776 @@ mod tests {
 
         assert_matches!(t.shutdown().await, Err(_));
     }
+
+    #[tokio::test(start_paused = true)]
+    async fn refresh_interval() {
+        let mut t = Fixture::new();
+
+        t.server_write
+            .write(Message::Hello(0, 2, vec![]))
+            .await
+            .expect("Error sending hello");
+
+        // The first refresh goes out right away, and then one every interval.
+        let start = tokio::time::Instant::now();
+        for _ in 0..3 {
+            let message = t.server_read.read().await;
+            assert_matches!(message, Ok(Message::Refresh));
+        }
+        assert_eq!(start.elapsed(), REFRESH_INTERVAL * 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn keepalive() {
+        let mut t = Fixture::new();
+
+        t.server_write
+            .write(Message::Hello(0, 3, vec![]))
+            .await
+            .expect("Error sending hello");
+
+        // We ping every so often, in between the refreshes...
+        let start = tokio::time::Instant::now();
+        loop {
+            match t.server_read.read().await {
+                Ok(Message::Ping) => break,
+                Ok(_) => (),
+                Err(e) => panic!("Error reading: {e:?}"),
+            }
+        }
+        assert_eq!(start.elapsed(), KEEPALIVE_INTERVAL);
+
+        // ...and give up if the server never answers anything.
+        let result = t.client_result.take().unwrap();
+        assert_matches!(result.await.unwrap(), Err(_));
+        assert_eq!(start.elapsed(), config::DEFAULT_KEEPALIVE_TIMEOUT);
+    }
+
+    #[tokio::test]
+    async fn settings_sent_to_new_servers() {
+        let settings = vec!["discovery=false".to_string()];
+        let mut t = Fixture::with_settings(settings.clone());
+
+        t.server_write
+            .write(Message::Hello(0, 3, vec![]))
+            .await
+            .expect("Error sending hello");
+
+        let message = t.server_read.read().await;
+        assert_matches!(
+            message,
+            Ok(Message::Configure(s)) if s == ["discovery=false", "sockets=true"]
+        );
+        let message = t.server_read.read().await;
+        assert_matches!(message, Ok(Message::Refresh));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn push_servers_are_not_polled() {
+        let mut t = Fixture::new();
+
+        t.server_write
+            .write(Message::Hello(0, 4, vec![]))
+            .await
+            .expect("Error sending hello");
+
+        let message = t.server_read.read().await;
+        assert_matches!(message, Ok(Message::Configure(s)) if s == ["push=true", "sockets=true"]);
+        let message = t.server_read.read().await;
+        assert_matches!(message, Ok(Message::Refresh));
+
+        // After that we only ping.
+        let start = tokio::time::Instant::now();
+        let message = t.server_read.read().await;
+        assert_matches!(message, Ok(Message::Ping));
+        assert_eq!(start.elapsed(), KEEPALIVE_INTERVAL);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn refresh_on_request() {
+        let mut t = Fixture::new();
+
+        t.server_write
+            .write(Message::Hello(0, 4, vec![]))
+            .await
+            .expect("Error sending hello");
+
+        let message = t.server_read.read().await;
+        assert_matches!(message, Ok(Message::Configure(_)));
+        let message = t.server_read.read().await;
+        assert_matches!(message, Ok(Message::Refresh));
+
+        // Even a server that pushes gets asked when the user wants it.
+        let start = tokio::time::Instant::now();
+        t.requests.refresh.notify_one();
+        let message = t.server_read.read().await;
+        assert_matches!(message, Ok(Message::Refresh));
+        assert!(start.elapsed() < KEEPALIVE_INTERVAL);
+    }
+
+    #[tokio::test]
+    async fn goodbye_on_quit() {
+        let mut t = Fixture::new();
+
+        let headers = vec!["capabilities=goodbye,teleport".to_string()];
+        t.server_write
+            .write(Message::Hello(0, 5, headers))
+            .await
+            .expect("Error sending hello");
+
+        let message = t.server_read.read().await;
+        assert_matches!(
+            message,
+            Ok(Message::Configure(s))
+                if s == [
+                    "push=true",
+                    "capabilities=goodbye,checksum,delta,pids",
+                    "sockets=true",
+                ]
+        );
+        let message = t.server_read.read().await;
+        assert_matches!(message, Ok(Message::Refresh));
+
+        t.requests.quit();
+        let message = t.server_read.read().await;
+        assert_matches!(message, Ok(Message::Goodbye(_)));
+        assert_matches!(t.shutdown().await, Ok(()));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn static_ports_skip_discovery() {
+        let mut t = Fixture::with_static_ports(vec![], vec![5432, 8080]);
+
+        let headers = vec!["capabilities=push".to_string()];
+        t.server_write
+            .write(Message::Hello(0, 5, headers))
+            .await
+            .expect("Error sending hello");
+
+        // We don't want the server looking for ports, so no push and no
+        // refreshes, only pings.
+        let message = t.server_read.read().await;
+        assert_matches!(
+            message,
+            Ok(Message::Configure(s))
+                if s == ["capabilities=goodbye,checksum,delta,pids", "sockets=true"]
+        );
+        let message = t.server_read.read().await;
+        assert_matches!(message, Ok(Message::Ping));
+
+        let mut ports = None;
+        while let Ok(event) = t.events.try_recv() {
+            if let ui::UIEvent::Ports(p) = event {
+                ports = Some(p.iter().map(|d| d.port).collect::<Vec<_>>());
+            }
+        }
+        assert_eq!(ports, Some(vec![5432, 8080]));
+    }
+
+    #[tokio::test]
+    async fn no_goodbye_without_capability() {
+        let mut t = Fixture::new();
+
+        t.server_write
+            .write(Message::Hello(0, 5, vec![]))
+            .await
+            .expect("Error sending hello");
+
+        let message = t.server_read.read().await;
+        assert_matches!(message, Ok(Message::Configure(_)));
+        let message = t.server_read.read().await;
+        assert_matches!(message, Ok(Message::Refresh));
+
+        t.requests.quit();
+        let result = t.client_result.take().unwrap();
+        assert_matches!(result.await.unwrap(), Ok(()));
+        assert_matches!(t.server_read.read().await, Err(_));
+    }
+
+    #[tokio::test]
+    async fn goodbye_from_server() {
+        let mut t = Fixture::new();
+
+        t.server_write
+            .write(Message::Hello(0, 5, vec![]))
+            .await
+            .expect("Error sending hello");
+        t.server_write
+            .write(Message::Goodbye("rebooting".to_string()))
+            .await
+            .expect("Error sending goodbye");
+
+        let result = t.client_result.take().unwrap();
+        assert_matches!(result.await.unwrap(), Ok(()));
+    }
+
+    #[tokio::test]
+    async fn port_changes() {
+        let mut t = Fixture::new();
+        t.server_write
+            .write(Message::Hello(0, 5, vec![]))
+            .await
+            .expect("Error sending hello");
+        let desc = |port, desc: &str| PortDesc {
+            port,
+            desc: desc.to_string(),
+            pid: None,
+        };
+
+        // The UI gets the whole list, whichever way the server sends it.
+        for message in [
+            Message::Ports(vec![desc(8080, "web"), desc(5432, "db")]),
+            Message::PortAdded(desc(3000, "node")),
+            Message::PortRemoved(5432),
+            Message::PortAdded(desc(8080, "nginx")),
+        ] {
+            t.server_write.write(message).await.unwrap();
+        }
+        let mut lists = Vec::new();
+        while lists.len() < 4 {
+            if let Some(ui::UIEvent::Ports(ports)) = t.events.recv().await {
+                lists.push(ports);
+            }
+        }
+        assert_eq!(
+            lists[1],
+            [desc(8080, "web"), desc(5432, "db"), desc(3000, "node")]
+        );
+        assert_eq!(lists[2], [desc(8080, "web"), desc(3000, "node")]);
+        assert_eq!(lists[3], [desc(3000, "node"), desc(8080, "nginx")]);
+    }
+
+    #[tokio::test]
+    async fn settings_not_sent_to_old_servers() {
+        let mut t = Fixture::with_settings(vec!["discovery=false".to_string()]);
+
+        t.server_write
+            .write(Message::Hello(0, 2, vec![]))
+            .await
+            .expect("Error sending hello");
+
+        let message = t.server_read.read().await;
+        assert_matches!(message, Ok(Message::Refresh));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn backoff() {
+        let mut backoff = Backoff::new();
+        let mut delays = Vec::new();
+        for _ in 0..8 {
+            let start = tokio::time::Instant::now();
+            backoff.wait().await;
+            delays.push(start.elapsed().as_secs());
+        }
+        assert_eq!(delays, vec![1, 2, 4, 8, 16, 32, 60, 60]);
+
+        backoff.reset();
+        let start = tokio::time::Instant::now();
+        backoff.wait().await;
+        assert_eq!(start.elapsed(), RECONNECT_DELAY);
+    }
+
+    // A SOCKS server that acts like ssh's: it says it's connected as soon as
+    // it's asked, and then hangs up if it can't really connect. It can only
+    // reach something on ::1, which waits to be greeted before it answers.
+    async fn ipv6_only_ssh_proxy(listener: TcpListener) {
+        loop {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut hello = [0; 3];
+            socket.read_exact(&mut hello).await.unwrap();
+            socket.write_all(&[0x05, 0x00]).await.unwrap();
+
+            let mut request = [0; 4];
+            socket.read_exact(&mut request).await.unwrap();
+            let mut address = vec![0; if request[3] == 0x04 { 16 } else { 4 }];
+            socket.read_exact(&mut address).await.unwrap();
+            socket.read_u16().await.unwrap();
+            socket.write_all(&[0x05, 0x00, 0x00, 0x01]).await.unwrap();
+            socket.write_all(&[0; 6]).await.unwrap();
+            if request[3] != 0x04 || address != Ipv6Addr::LOCALHOST.octets() {
+                continue;
+            }
+
+            let mut greeting = [0; 2];
+            socket.read_exact(&mut greeting).await.unwrap();
+            assert_eq!(&greeting, b"hi");
+            socket.write_all(b"hello from ::1").await.unwrap();
+        }
+    }
+
+    // A SOCKS server that can only reach things on ::1, and answers with an
+    // IPv6 address when it does.
+    async fn ipv6_only_socks_server(listener: TcpListener) {
+        loop {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut hello = [0; 3];
+            socket.read_exact(&mut hello).await.unwrap();
+            socket.write_all(&[0x05, 0x00]).await.unwrap();
+
+            let mut request = [0; 4];
+            socket.read_exact(&mut request).await.unwrap();
+            let mut address = vec![0; if request[3] == 0x04 { 16 } else { 4 }];
+            socket.read_exact(&mut address).await.unwrap();
+            socket.read_u16().await.unwrap();
+            if request[3] != 0x04 || address != Ipv6Addr::LOCALHOST.octets() {
+                // Connection refused.
+                socket.write_all(&[0x05, 0x05, 0x00, 0x01]).await.unwrap();
+                socket.write_all(&[0; 6]).await.unwrap();
+                continue;
+            }
+
+            socket.write_all(&[0x05, 0x00, 0x00, 0x04]).await.unwrap();
+            socket.write_all(&[0; 18]).await.unwrap();
+            socket.write_all(b"hello from ::1").await.unwrap();
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rate_limit() {
+        let limit = RateLimit::new(1000);
+
+        // A second's worth goes right through, and then we wait.
+        let start = Instant::now();
+        limit.take(1000).await;
+        assert_eq!(start.elapsed(), Duration::ZERO);
+        limit.take(500).await;
+        assert!(start.elapsed() >= Duration::from_millis(500));
+
+        // Both directions of a connection count.
+        let (a, mut a_peer) = tokio::io::duplex(64 * 1024);
+        let (b, mut b_peer) = tokio::io::duplex(64 * 1024);
+        let copy = tokio::spawn(async move {
+            let limit = RateLimit::new(1000);
+            copy_bidirectional(a, b, 1024, Some(&limit)).await
+        });
+        let start = Instant::now();
+        a_peer.write_all(&[0; 1500]).await.unwrap();
+        b_peer.write_all(&[0; 1500]).await.unwrap();
+        drop((a_peer.shutdown().await, b_peer.shutdown().await));
+        let mut received = Vec::new();
+        b_peer.read_to_end(&mut received).await.unwrap();
+        a_peer.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received.len(), 3000);
+        assert_eq!(copy.await.unwrap().unwrap(), (1500, 1500));
+        assert!(start.elapsed() >= Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn bind_elsewhere_when_taken() {
+        let taken = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local = taken.local_addr().unwrap();
+
+        let listener = client_bind(8080, local, None).unwrap();
+        let address = listener.local_addr().unwrap();
+        assert_eq!(address.ip(), local.ip());
+        assert_ne!(address.port(), local.port());
+
+        // That one's free again now, so it makes a good fallback.
+        drop(listener);
+        let listener = client_bind(8080, local, Some(address.port())).unwrap();
+        assert_eq!(listener.local_addr().unwrap(), address);
+
+        // And if the fallback is taken too, we still get something.
+        let listener = client_bind(8080, local, Some(address.port())).unwrap();
+        let elsewhere = listener.local_addr().unwrap().port();
+        assert_ne!(elsewhere, local.port());
+        assert_ne!(elsewhere, address.port());
+    }
+
+    #[tokio::test]
+    async fn socks_errors() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let socks_port = listener.local_addr().unwrap().port();
+        tokio::spawn(ipv6_only_socks_server(listener));
+
+        let v4 = SocksAddr::Ip(IpAddr::V4(Ipv4Addr::LOCALHOST));
+        let result =
+            socks_connect(socks_port, v4, 5432, Profile::Balanced).await;
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "connection refused on remote port 5432"
+        );
+
+        // ssh never says why. It just hangs up, which is what people see.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let socks_port = listener.local_addr().unwrap().port();
+        tokio::spawn(ipv6_only_ssh_proxy(listener));
+        let dest = Destination {
+            host: Some("10.0.0.1".to_string()),
+            port: 5432,
+        };
+        let tuning = config::ServerConfig::default().tuning();
+        let tuning = Tuning { connect_attempts: 1, ..tuning };
+        let (mut quiet, _user) = tokio::io::duplex(64);
+        let result =
+            remote_connect(socks_port, &dest, tuning, &mut quiet, None).await;
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "the server couldn't connect to 10.0.0.1:5432; is anything \
+             listening there?"
+        );
+    }
+
+    #[tokio::test]
+    async fn config_reload() {
+        let dir = tempdir::TempDir::new("fwd-config").unwrap();
+        let path = dir.path().join("fwd.toml");
+        std::fs::write(&path, "good").unwrap();
+        let load = {
+            let path = path.clone();
+            move || match std::fs::read_to_string(&path)?.as_str() {
+                "good" => Ok(config::ServerConfig::default()),
+                _ => bail!("That's no good"),
+            }
+        };
+        let (sender, mut receiver) = mpsc::channel(4);
+        tokio::spawn(watch_config(Some(path.clone()), load, sender));
+
+        // Nothing changed, so there's nothing new...
+        sleep(CONFIG_CHECK_INTERVAL * 2).await;
+        assert!(receiver.try_recv().is_err());
+
+        // ...and a config that doesn't load isn't passed on, but the next
+        // one that does is.
+        std::fs::write(&path, "bad").unwrap();
+        sleep(CONFIG_CHECK_INTERVAL * 2).await;
+        assert!(receiver.try_recv().is_err());
+        std::fs::write(&path, "good").unwrap();
+        let event = timeout(CONFIG_CHECK_INTERVAL * 3, receiver.recv()).await;
+        assert!(matches!(event, Ok(Some(ui::UIEvent::Config(_)))));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn wait_for_session() {
+        let (sender, mut socks) = watch::channel(Vec::new());
+        let wait = Duration::from_secs(10);
+
+        // Connections that come in between sessions wait for the next, and
+        // take turns with its proxies...
+        tokio::spawn(async move {
+            sleep(Duration::from_secs(3)).await;
+            sender.send_replace(vec![1080, 1081]);
+            sleep(Duration::from_secs(3)).await;
+            sender.send_replace(Vec::new());
+            sleep(Duration::from_secs(60)).await;
+        });
+        for (n, port) in [(0, 1080), (1, 1081), (2, 1080)] {
+            let result = session_socks_port(&mut socks, n, wait).await;
+            assert_eq!(result.unwrap(), port);
+        }
+
+        // ...but not forever.
+        sleep(Duration::from_secs(5)).await;
+        let result = session_socks_port(&mut socks, 0, wait).await;
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "Not connected to the server, and still not after 10s"
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn connect_timeout() {
+        // A SOCKS server that never says anything.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let socks_port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (_socket, _) = listener.accept().await.unwrap();
+            std::future::pending::<()>().await
+        });
+
+        let (client, _user) = tokio::io::duplex(4096);
+        let tuning = config::ServerConfig::default().tuning();
+        let dest = Destination { host: None, port: 8080 };
+        let start = Instant::now();
+        let result =
+            client_handle_connection(socks_port, &dest, client, tuning, None)
+                .await;
+        // It says what it was doing, and on which port, and what happened.
+        let e = result.unwrap_err();
+        let failed = e.downcast_ref::<fwd_proto::Error>().unwrap();
+        assert_eq!(failed.phase(), Some(Phase::Dial));
+        assert_eq!(failed.port(), Some(8080));
+        assert!(e.root_cause().to_string().starts_with("Timed out"));
+        assert_eq!(start.elapsed(), config::DEFAULT_CONNECT_TIMEOUT);
+    }
+
+    // A remote end that says hello and then resets the connection.
+    struct Resets(bool);
+
+    impl AsyncRead for Resets {
+        fn poll_read(
+            mut self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+            buf: &mut tokio::io::ReadBuf<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            if std::mem::replace(&mut self.0, true) {
+                let reset = std::io::ErrorKind::ConnectionReset;
+                return std::task::Poll::Ready(Err(reset.into()));
+            }
+            buf.put_slice(b"hello");
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    impl AsyncWrite for Resets {
+        fn poll_write(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+            buf: &[u8],
+        ) -> std::task::Poll<std::io::Result<usize>> {
+            std::task::Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_connections_count() {
+        // What a connection moved counts even if it ends in an error, since
+        // those are the ones people want to know about.
+        let stats = Arc::new(stats::PortStats::default());
+        let peer = "127.0.0.1:50000".parse().unwrap();
+        let (local, mut user) = tokio::io::duplex(4096);
+        user.write_all(b"hi").await.unwrap();
+        let local = sniff::Sniff::new(local, stats.connection_opened(peer));
+        let result = copy_bidirectional(local, Resets(false), 1024, None).await;
+        assert!(result.is_err());
+        assert_eq!(stats.bytes_sent(), 2);
+        assert_eq!(stats.bytes_received(), 5);
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_ipv6() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let socks_port = listener.local_addr().unwrap().port();
+        tokio::spawn(ipv6_only_ssh_proxy(listener));
+
+        // What the user says before we get through isn't lost on the way.
+        let (client, mut user) = tokio::io::duplex(4096);
+        user.write_all(b"hi").await.unwrap();
+        let connection = tokio::spawn(async move {
+            client_handle_connection(
+                socks_port,
+                &Destination { host: None, port: 8080 },
+                client,
+                config::ServerConfig::default().tuning(),
+                None,
+            )
+            .await
+        });
+
+        let mut greeting = [0; 14];
+        user.read_exact(&mut greeting).await.unwrap();
+        assert_eq!(&greeting, b"hello from ::1");
+        drop(user);
+        connection.abort();
+    }
+
+    #[tokio::test]
+    async fn retries_closed() {
+        // A SOCKS server like ssh's, which hangs up on the first two tries
+        // at both addresses, since there's nothing there yet.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let socks_port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            for n in 0.. {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut hello = [0; 3];
+                socket.read_exact(&mut hello).await.unwrap();
+                socket.write_all(&[0x05, 0x00]).await.unwrap();
+                let mut request = [0; 10];
+                socket.read_exact(&mut request).await.unwrap();
+                if request[3] == 0x04 {
+                    socket.read_exact(&mut [0; 12]).await.unwrap();
+                }
+                socket.write_all(&[0x05, 0x00, 0x00, 0x01]).await.unwrap();
+                socket.write_all(&[0; 6]).await.unwrap();
+                if n >= 4 {
+                    let mut greeting = [0; 4];
+                    socket.read_exact(&mut greeting).await.unwrap();
+                    assert_eq!(&greeting, b"ping");
+                    socket.write_all(b"back").await.unwrap();
+                }
+            }
+        });
+
+        // The last try still gets what was sent before the first.
+        let dest = Destination { host: None, port: 8080 };
+        let tuning = config::ServerConfig::default().tuning();
+        let (mut local, mut user) = tokio::io::duplex(4096);
+        user.write_all(b"ping").await.unwrap();
+        let (mut socket, sent) =
+            remote_connect(socks_port, &dest, tuning, &mut local, None)
+                .await
+                .unwrap();
+        assert_eq!(sent, 4);
+        let mut greeting = [0; 4];
+        socket.read_exact(&mut greeting).await.unwrap();
+        assert_eq!(&greeting, b"back");
+
+        // Without retrying, it's given up on. (The server's up for good now,
+        // so this is a new one.)
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let socks_port = listener.local_addr().unwrap().port();
+        tokio::spawn(ipv6_only_ssh_proxy(listener));
+        let dest = Destination {
+            host: Some("10.0.0.1".into()),
+            port: 8080,
+        };
+        let tuning = Tuning { connect_attempts: 1, ..tuning };
+        let start = Instant::now();
+        let (mut quiet, _user) = tokio::io::duplex(64);
+        let result =
+            remote_connect(socks_port, &dest, tuning, &mut quiet, None).await;
+        let e = result.unwrap_err();
+        assert!(e.is::<NotConnected>());
+        assert!(start.elapsed() < CONNECT_RETRY_WAIT);
+    }
+
+    #[tokio::test]
+    async fn other_hosts() {
+        // A SOCKS server that says where it was asked to go.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let socks_port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut hello = [0; 3];
+            socket.read_exact(&mut hello).await.unwrap();
+            socket.write_all(&[0x05, 0x00]).await.unwrap();
+
+            let mut request = [0; 4];
+            socket.read_exact(&mut request).await.unwrap();
+            assert_eq!(request[3], 0x03);
+            let mut name = vec![0; socket.read_u8().await.unwrap().into()];
+            socket.read_exact(&mut name).await.unwrap();
+            let port = socket.read_u16().await.unwrap();
+
+            socket.write_all(&[0x05, 0x00, 0x00, 0x01]).await.unwrap();
+            socket.write_all(&[0; 6]).await.unwrap();
+            let name = String::from_utf8(name).unwrap();
+            socket.write_all(format!("{name}:{port}").as_bytes()).await
+        });
+
+        let (client, mut user) = tokio::io::duplex(4096);
+        let dest = Destination {
+            host: Some("db.internal".to_string()),
+            port: 5432,
+        };
+        assert_eq!(dest.to_string(), "db.internal:5432");
+        let connection = tokio::spawn(async move {
+            client_handle_connection(
+                socks_port,
+                &dest,
+                client,
+                config::ServerConfig::default().tuning(),
+                None,
+            )
+            .await
+        });
+
+        let mut greeting = [0; 16];
+        user.read_exact(&mut greeting).await.unwrap();
+        assert_eq!(&greeting, b"db.internal:5432");
+        drop(user);
+        connection.abort();
+    }
+
+    #[tokio::test]
+    async fn copy_waits_for_slow_readers() {
+        let (a, a_user) = tokio::io::duplex(16);
+        let (b, mut b_user) = tokio::io::duplex(16);
+        let copy = tokio::spawn(copy_bidirectional(a, b, 4, None));
+
+        // Nobody reads from b, so once the pipes and the buffer are full
+        // the copy stops reading from a.
+        let (mut a_read, mut a_write) = tokio::io::split(a_user);
+        let mut write = tokio::spawn(async move {
+            a_write.write_all(&[7; 1024]).await.unwrap();
+            a_write.shutdown().await.unwrap();
+        });
+        assert!(timeout(Duration::from_millis(50), &mut write)
+            .await
+            .is_err());
+
+        // When a reader shows up, it gets everything, in order.
+        b_user.write_all(b"pong").await.unwrap();
+        b_user.shutdown().await.unwrap();
+        let mut received = Vec::new();
+        b_user.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, [7; 1024]);
+        write.await.unwrap();
+
+        let mut pong = Vec::new();
+        a_read.read_to_end(&mut pong).await.unwrap();
+        assert_eq!(pong, b"pong");
+        assert_eq!(copy.await.unwrap().unwrap(), (1024, 4));
+    }
+
+    // A transport that runs a server which says hello and hangs up.
+    struct FakeTransport {
+        connects: usize,
+    }
+
+    impl Transport for FakeTransport {
+        async fn connect(&mut self) -> Result<Session> {
+            self.connects += 1;
+            let (reader, mut server_write) = tokio::io::duplex(4096);
+            let (server_read, writer) = tokio::io::duplex(4096);
+            tokio::spawn(async move {
+                server_write.write_u64(0).await.unwrap();
+                let mut server_write = MessageWriter::new(server_write);
+                server_write
+                    .write(Message::Hello(0, 3, vec![]))
+                    .await
+                    .unwrap();
+                let mut server_read = MessageReader::new(server_read);
+                _ = server_read.read().await; // Configure
+            });
+
+            Ok(Session {
+                reader: Box::new(reader),
+                writer: Box::new(writer),
+                stderr: Box::new(tokio::io::empty()),
+                socks_ports: vec![1080],
+            })
+        }
+
+        async fn disconnected(&mut self) -> bool {
+            self.connects < 2
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn connect_loop_uses_transport() {
+        let (sender, mut receiver) = mpsc::channel(64);
+        let mut transport = FakeTransport { connects: 0 };
+        client_connect_loop(
+            &mut transport,
+            &SessionOptions::default(),
+            &Requests::default(),
+            sender,
+        )
+        .await;
+
+        let mut connected = 0;
+        while let Ok(event) = receiver.try_recv() {
+            if let ui::UIEvent::Connected(socks_ports) = event {
+                assert_eq!(socks_ports, [1080]);
+                connected += 1;
+            }
+        }
+        assert_eq!(connected, 2);
+    }
 }
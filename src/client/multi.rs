@@ -0,0 +1,635 @@
+use super::{
+    ui::{Logger, UIEvent},
+    ClientBuilder, ClientHandle, ClientOptions, Event, Output, PortInfo,
+};
+use crate::logging::Filter;
+use anyhow::{bail, Result};
+use crossterm::{
+    event::{
+        Event as ConsoleEvent, EventStream, KeyCode, KeyEvent, KeyModifiers,
+    },
+    execute,
+    terminal::{
+        disable_raw_mode, enable_raw_mode, DisableLineWrap, EnableLineWrap,
+        EnterAlternateScreen, LeaveAlternateScreen,
+    },
+};
+use log::{error, info, Level};
+use serde_json::{json, Value};
+use std::collections::{HashMap, VecDeque};
+use std::io::{stdout, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::sync::mpsc;
+use tokio::time::{interval, Duration, MissedTickBehavior};
+use tokio_stream::{Stream, StreamExt, StreamMap};
+use tui::{
+    backend::{Backend, CrosstermBackend},
+    layout::{Constraint, Direction, Layout, Rect},
+    style::{Color, Style},
+    widgets::{
+        Block, Borders, List, ListItem, ListState, Row, Table, TableState,
+    },
+    Frame, Terminal,
+};
+
+/// How often we ask the clients for their ports, to keep the table current.
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// One of the servers we forward ports from, and what we've heard from it.
+struct Remote {
+    name: String,
+    /// The client, until it stops, by itself or because we're quitting.
+    handle: Option<ClientHandle>,
+    ports: Vec<PortInfo>,
+    connected: bool,
+    /// How many connections are open on each of its ports.
+    open: HashMap<u16, usize>,
+}
+
+/// The clients for all the servers, and the one table of all their ports.
+struct Clients {
+    remotes: Vec<Remote>,
+    output: Output,
+    /// Events waiting to be written out, for JSON output.
+    json: Vec<Value>,
+    lines: VecDeque<String>,
+    selection: TableState,
+    show_logs: bool,
+    running: bool,
+}
+
+impl Clients {
+    fn new(remotes: Vec<(String, ClientHandle)>, output: Output) -> Clients {
+        let remotes = remotes
+            .into_iter()
+            .map(|(name, handle)| Remote {
+                name,
+                handle: Some(handle),
+                ports: Vec::new(),
+                connected: false,
+                open: HashMap::new(),
+            })
+            .collect();
+        Clients {
+            remotes,
+            output,
+            json: Vec::new(),
+            lines: VecDeque::with_capacity(1024),
+            selection: TableState::default(),
+            show_logs: false,
+            running: true,
+        }
+    }
+
+    /// Keep track of something that happened on the `index`th remote.
+    fn event(&mut self, index: usize, event: Event) {
+        let remote = &mut self.remotes[index];
+        match &event {
+            Event::Connected => remote.connected = true,
+            Event::Disconnected => remote.connected = false,
+            Event::PortRemoved { port } => _ = remote.open.remove(port),
+            Event::ConnectionOpened { port } => {
+                *remote.open.entry(*port).or_default() += 1;
+            }
+            Event::ConnectionClosed { port } => {
+                if let Some(open) = remote.open.get_mut(port) {
+                    *open = open.saturating_sub(1);
+                }
+            }
+            _ => (),
+        }
+        if self.output == Output::Json {
+            let mut json = event.to_json();
+            json["remote"] = json!(remote.name);
+            self.json.push(json);
+        }
+    }
+
+    /// Keep a line of the log. The clients all log to the same place, so
+    /// errors can't say which remote they're about, except in what they say.
+    fn log(&mut self, level: Level, line: String) {
+        if level == Level::Error && self.output == Output::Json {
+            let event = Event::Error { message: line.clone() };
+            self.json.push(event.to_json());
+        }
+        while self.lines.len() >= 1024 {
+            self.lines.pop_front();
+        }
+        self.lines.push_back(line);
+    }
+
+    /// Ask every client what ports it has now. Clients that have stopped
+    /// are done with, and once they all have, so are we.
+    async fn poll(&mut self) {
+        for remote in self.remotes.iter_mut() {
+            let Some(handle) = &remote.handle else {
+                continue;
+            };
+            match handle.ports().await {
+                Ok(ports) => remote.ports = ports,
+                Err(_) => {
+                    info!("The client for {} has stopped", remote.name);
+                    remote.handle = None;
+                    remote.ports.clear();
+                    remote.connected = false;
+                }
+            }
+        }
+        if self.remotes.iter().all(|r| r.handle.is_none()) {
+            self.running = false;
+        }
+    }
+
+    /// Every port of every remote, in the order we show them: by remote, in
+    /// the order they were given, and then as each client has them.
+    fn rows(&self) -> Vec<(usize, &PortInfo)> {
+        self.remotes
+            .iter()
+            .enumerate()
+            .flat_map(|(i, r)| r.ports.iter().map(move |p| (i, p)))
+            .collect()
+    }
+
+    fn get_selected(&self) -> Option<(usize, PortInfo)> {
+        let index = self.selection.selected()?;
+        let (remote, info) = self.rows().get(index).copied()?;
+        Some((remote, info.clone()))
+    }
+
+    async fn handle_console_event(
+        &mut self,
+        ev: Option<Result<ConsoleEvent, std::io::Error>>,
+    ) {
+        let Some(Ok(ConsoleEvent::Key(ev))) = ev else {
+            return;
+        };
+        match ev {
+            KeyEvent { code: KeyCode::Char('c'), .. }
+                if ev.modifiers.intersects(KeyModifiers::CONTROL) =>
+            {
+                self.running = false;
+            }
+            KeyEvent { code: KeyCode::Esc, .. }
+            | KeyEvent { code: KeyCode::Char('q'), .. } => {
+                self.running = false;
+            }
+            KeyEvent { code: KeyCode::Char('r'), .. } => {
+                info!("Refreshing...");
+                for handle in self.remotes.iter().flat_map(|r| &r.handle) {
+                    _ = handle.refresh().await;
+                }
+            }
+            KeyEvent { code: KeyCode::Char('l'), .. } => {
+                self.show_logs = !self.show_logs;
+            }
+            KeyEvent { code: KeyCode::Char('e'), .. } => {
+                if let Some((remote, info)) = self.get_selected() {
+                    if let Some(handle) = &self.remotes[remote].handle {
+                        let result = if info.enabled {
+                            handle.stop(info.port).await
+                        } else {
+                            handle.forward(info.port).await
+                        };
+                        if let Err(e) = result {
+                            error!("{e:#}");
+                        }
+                    }
+                }
+            }
+            KeyEvent { code: KeyCode::Char('a'), .. } => {
+                if let Some((remote, info)) = self.get_selected() {
+                    if let Some(handle) = &self.remotes[remote].handle {
+                        if let Err(e) = handle.allow(info.port).await {
+                            error!("{e:#}");
+                        }
+                    }
+                }
+            }
+            KeyEvent { code: KeyCode::Up, .. }
+            | KeyEvent { code: KeyCode::Char('j'), .. } => {
+                let index = match self.selection.selected() {
+                    Some(i) => i.saturating_sub(1),
+                    None => 0,
+                };
+                self.selection.select(Some(index));
+            }
+            KeyEvent { code: KeyCode::Down, .. }
+            | KeyEvent { code: KeyCode::Char('k'), .. } => {
+                let rows = self.rows().len();
+                let index = match self.selection.selected() {
+                    Some(i) => (i + 1).min(rows.saturating_sub(1)),
+                    None => 0,
+                };
+                self.selection.select(Some(index));
+            }
+            KeyEvent { code: KeyCode::Enter, .. } => {
+                if let Some(local) = self.get_selected().and_then(|s| s.1.local)
+                {
+                    _ = open::that(format!(
+                        "http://{}/",
+                        browse_address(local)
+                    ));
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn render<B: Backend>(&mut self, frame: &mut Frame<B>) {
+        let constraints = if self.show_logs {
+            vec![Constraint::Percentage(50), Constraint::Percentage(50)]
+        } else {
+            vec![Constraint::Percentage(100)]
+        };
+
+        let chunks = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints(constraints)
+            .split(frame.size());
+
+        self.render_ports(frame, chunks[0]);
+        if self.show_logs {
+            self.render_logs(frame, chunks[1]);
+        }
+    }
+
+    fn render_ports<B: Backend>(&mut self, frame: &mut Frame<B>, size: Rect) {
+        let enabled_port_style = Style::default();
+        let disabled_port_style = Style::default().fg(Color::DarkGray);
+        let pending_port_style = Style::default().fg(Color::Yellow);
+
+        let mut rows = Vec::new();
+        for (index, info) in self.rows() {
+            let remote = &self.remotes[index];
+            let port = info.port;
+            let port_text = match info.local.map(|l| l.port()) {
+                Some(local_port) if local_port != port => {
+                    format!("{port}→{local_port}")
+                }
+                _ => format!("{port}"),
+            };
+            let open = match remote.open.get(&port).copied().unwrap_or(0) {
+                0 => "".to_string(),
+                n => n.to_string(),
+            };
+            let desc = if info.pending {
+                format!("{} (press a to allow)", info.desc)
+                    .trim_start()
+                    .to_string()
+            } else {
+                info.desc.clone()
+            };
+
+            let style = if !remote.connected {
+                disabled_port_style
+            } else if info.enabled {
+                enabled_port_style
+            } else if info.pending {
+                pending_port_style
+            } else {
+                disabled_port_style
+            };
+            let name = remote.name.clone();
+            rows.push(Row::new(vec![name, port_text, open, desc]).style(style));
+        }
+
+        let remote_width = self
+            .remotes
+            .iter()
+            .map(|r| r.name.chars().count())
+            .max()
+            .unwrap_or(0)
+            .max("Remote".len());
+        let port_width =
+            if self.rows().iter().any(|(_, info)| {
+                info.local.is_some_and(|l| l.port() != info.port)
+            }) {
+                11 // Room for `65535→65535`
+            } else {
+                5
+            };
+        let widths = vec![
+            Constraint::Length(remote_width as u16),
+            Constraint::Length(port_width),
+            Constraint::Length(5),
+            Constraint::Length(size.width),
+        ];
+
+        let waiting: Vec<&str> = self
+            .remotes
+            .iter()
+            .filter(|r| r.handle.is_some() && !r.connected)
+            .map(|r| &r.name[..])
+            .collect();
+        let title = if waiting.is_empty() {
+            "Ports".to_string()
+        } else {
+            format!("Ports (connecting to {})", waiting.join(", "))
+        };
+
+        let port_list = Table::new(rows)
+            .header(Row::new(vec!["Remote", "Port", "Conns", "Description"]))
+            .block(Block::default().title(title).borders(Borders::ALL))
+            .column_spacing(1)
+            .widths(&widths)
+            .highlight_symbol(">> ");
+
+        frame.render_stateful_widget(port_list, size, &mut self.selection);
+    }
+
+    fn render_logs<B: Backend>(&mut self, frame: &mut Frame<B>, size: Rect) {
+        let items: Vec<_> =
+            self.lines.iter().map(|l| ListItem::new(&l[..])).collect();
+
+        let list = List::new(items)
+            .block(Block::default().title("Log").borders(Borders::ALL));
+
+        let mut list_state = ListState::default();
+        list_state.select(if !self.lines.is_empty() {
+            Some(self.lines.len() - 1)
+        } else {
+            None
+        });
+
+        frame.render_stateful_widget(list, size, &mut list_state);
+    }
+
+    /// Write out the events we've kept, for JSON output.
+    fn flush(&mut self) -> Result<()> {
+        if self.json.is_empty() {
+            return Ok(());
+        }
+        let mut stdout = stdout().lock();
+        for event in self.json.drain(..) {
+            writeln!(stdout, "{event}")?;
+        }
+        stdout.flush()?;
+        Ok(())
+    }
+
+    /// Follow along with the clients until we're told to stop or they all
+    /// have, drawing the table or writing JSON as we go.
+    async fn run(
+        &mut self,
+        logs: &mut mpsc::Receiver<UIEvent>,
+        events: &mut StreamMap<usize, impl Stream<Item = Event> + Unpin>,
+    ) -> Result<()> {
+        let mut poll = interval(POLL_INTERVAL);
+        poll.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        let mut screen = None;
+        let mut console_events = EventStream::new();
+        let mut terminal = None;
+        if self.output == Output::Tui {
+            screen = Some(Screen::enter()?);
+            terminal = Some(Terminal::new(CrosstermBackend::new(stdout()))?);
+        }
+
+        while self.running {
+            tokio::select! {
+                ev = console_events.next(), if screen.is_some() => {
+                    self.handle_console_event(ev).await;
+                }
+                Some((index, event)) = events.next() => {
+                    self.event(index, event);
+                }
+                Some(UIEvent::LogLine(level, line)) = logs.recv() => {
+                    self.log(level, line);
+                }
+                _ = poll.tick() => self.poll().await,
+                _ = super::shutdown_signal(), if screen.is_none() => {
+                    info!("Shutting down...");
+                    self.running = false;
+                }
+            }
+            match &mut terminal {
+                Some(terminal) => _ = terminal.draw(|f| self.render(f))?,
+                None => self.flush()?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Stop all the clients that haven't stopped yet, at the same time so
+    /// that slow goodbyes don't add up.
+    async fn shutdown(&mut self) {
+        let handles: Vec<_> = self
+            .remotes
+            .iter_mut()
+            .flat_map(|r| r.handle.take())
+            .map(|h| tokio::spawn(h.shutdown()))
+            .collect();
+        for handle in handles {
+            if let Ok(Err(e)) = handle.await {
+                error!("{e:#}");
+            }
+        }
+    }
+}
+
+/// The terminal, for as long as we're drawing on it; dropping this puts it
+/// back the way it was.
+struct Screen;
+
+impl Screen {
+    fn enter() -> Result<Screen> {
+        enable_raw_mode()?;
+        let screen = Screen;
+        execute!(stdout(), EnterAlternateScreen, DisableLineWrap)?;
+        Ok(screen)
+    }
+}
+
+impl Drop for Screen {
+    fn drop(&mut self) {
+        _ = execute!(stdout(), LeaveAlternateScreen, EnableLineWrap);
+        _ = disable_raw_mode();
+    }
+}
+
+/// Where to point a browser to reach a port we listen for on `local`, which
+/// might be every address rather than one we can connect to.
+fn browse_address(local: SocketAddr) -> SocketAddr {
+    let ip = match local.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => {
+            IpAddr::V4(Ipv4Addr::LOCALHOST)
+        }
+        IpAddr::V6(ip) if ip.is_unspecified() => {
+            IpAddr::V6(Ipv6Addr::LOCALHOST)
+        }
+        ip => ip,
+    };
+    SocketAddr::new(ip, local.port())
+}
+
+async fn run_clients_impl(
+    remotes: &[String],
+    options: ClientOptions,
+) -> Result<()> {
+    if options.daemon {
+        bail!("--daemon runs one server at a time; run it once for each");
+    }
+
+    // The clients are quiet, like any inside another program, so the log is
+    // ours to show.
+    let (log_sender, mut logs) = mpsc::channel(1024);
+    let filter = Filter::verbosity(options.verbosity);
+    let filter = filter.clone().with_env().unwrap_or(filter);
+    log::set_max_level(filter.max_level());
+    _ = log::set_boxed_logger(Logger::new(log_sender, filter));
+
+    let mut handles = Vec::new();
+    let mut events = StreamMap::new();
+    for (index, remote) in remotes.iter().enumerate() {
+        let handle = ClientBuilder::new(remote)
+            .options(options.clone())
+            .spawn()?;
+        events.insert(index, handle.events().await?);
+        handles.push((remote.clone(), handle));
+    }
+
+    let mut clients = Clients::new(handles, options.output);
+    let result = clients.run(&mut logs, &mut events).await;
+    clients.shutdown().await;
+    result
+}
+
+/// Like `run_client`, but for more than one server at once: each gets its
+/// own ssh connection and client, and their ports all go in one table, with
+/// a column for which server each is on. Two servers can't both have the
+/// same local port, so whichever asks second gets another one.
+pub async fn run_clients(remotes: &[String], options: ClientOptions) {
+    if let Err(e) = run_clients_impl(remotes, options).await {
+        eprintln!("Error: {e:#}");
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{
+        config::ServerConfig, history::History, LocalTransport,
+    };
+    use std::sync::Arc;
+    use tokio::time::sleep;
+
+    fn spawn(name: &str, port: u16) -> ClientHandle {
+        let mut config = ServerConfig::default();
+        config.add_static_port(port);
+        config.map_port(port, 0);
+        ClientBuilder::new(name).start(
+            config,
+            LocalTransport::new(),
+            Arc::new(History::disabled()),
+        )
+    }
+
+    #[tokio::test]
+    async fn one_table() {
+        let remotes = vec![
+            (
+                "fwd-multi-a-test".to_string(),
+                spawn("fwd-multi-a-test", 5432),
+            ),
+            (
+                "fwd-multi-b-test".to_string(),
+                spawn("fwd-multi-b-test", 6379),
+            ),
+        ];
+        let mut clients = Clients::new(remotes, Output::Quiet);
+        while clients.rows().iter().any(|(_, p)| p.local.is_none())
+            || clients.rows().len() < 2
+        {
+            clients.poll().await;
+            sleep(Duration::from_millis(10)).await;
+        }
+
+        let rows: Vec<(usize, u16)> =
+            clients.rows().iter().map(|(r, p)| (*r, p.port)).collect();
+        assert_eq!(rows, vec![(0, 5432), (1, 6379)]);
+        assert!(clients.running);
+
+        clients.selection.select(Some(1));
+        let (remote, info) = clients.get_selected().unwrap();
+        assert_eq!((remote, info.port), (1, 6379));
+
+        clients.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn stopped_clients() {
+        let mut config = ServerConfig::default();
+        config.set_idle_exit(Duration::from_millis(1));
+        let idle = ClientBuilder::new("fwd-multi-e-test").start(
+            config,
+            LocalTransport::new(),
+            Arc::new(History::disabled()),
+        );
+        let remotes = vec![
+            ("fwd-multi-e-test".to_string(), idle),
+            (
+                "fwd-multi-f-test".to_string(),
+                spawn("fwd-multi-f-test", 80),
+            ),
+        ];
+        let mut clients = Clients::new(remotes, Output::Quiet);
+        while clients.remotes[0].handle.is_some() {
+            clients.poll().await;
+            sleep(Duration::from_millis(10)).await;
+        }
+        assert!(clients.running);
+
+        clients.remotes[1]
+            .handle
+            .take()
+            .unwrap()
+            .shutdown()
+            .await
+            .unwrap();
+        clients.poll().await;
+        assert!(!clients.running);
+    }
+
+    #[tokio::test]
+    async fn events_say_which_remote() {
+        let remotes = vec![
+            (
+                "fwd-multi-c-test".to_string(),
+                spawn("fwd-multi-c-test", 80),
+            ),
+            (
+                "fwd-multi-d-test".to_string(),
+                spawn("fwd-multi-d-test", 81),
+            ),
+        ];
+        let mut clients = Clients::new(remotes, Output::Json);
+        clients.event(1, Event::Connected);
+        clients.event(1, Event::ConnectionOpened { port: 81 });
+        clients.event(1, Event::ConnectionOpened { port: 81 });
+        clients.event(1, Event::ConnectionClosed { port: 81 });
+        assert!(!clients.remotes[0].connected);
+        assert!(clients.remotes[1].connected);
+        assert_eq!(clients.remotes[1].open[&81], 1);
+        assert!(clients.remotes[0].open.is_empty());
+
+        assert_eq!(
+            clients.json[0],
+            json!({"event": "connected", "remote": "fwd-multi-d-test"})
+        );
+        clients.log(Level::Error, "Oh no".to_string());
+        assert_eq!(
+            clients.json.last().unwrap(),
+            &json!({"event": "error", "message": "Oh no"})
+        );
+        clients.shutdown().await;
+    }
+
+    #[test]
+    fn browse_everywhere() {
+        let local = "0.0.0.0:8080".parse().unwrap();
+        assert_eq!(browse_address(local), "127.0.0.1:8080".parse().unwrap());
+        let local = "10.0.0.2:8080".parse().unwrap();
+        assert_eq!(browse_address(local), local);
+    }
+}
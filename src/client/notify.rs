@@ -0,0 +1,88 @@
+use log::debug;
+use std::process::Stdio;
+
+/// Show a desktop notification. It's only a nicety, so we don't wait for it
+/// or complain much if it doesn't work.
+pub fn notify(title: &str, body: &str) {
+    let Some((program, args)) = notify_command(title, body) else {
+        return;
+    };
+    let child = tokio::process::Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn();
+    match child {
+        Ok(mut child) => {
+            tokio::spawn(async move { _ = child.wait().await });
+        }
+        Err(e) => debug!("Error running {program} for a notification: {e}"),
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn notify_command(
+    title: &str,
+    body: &str,
+) -> Option<(&'static str, Vec<String>)> {
+    // AppleScript strings are double quoted, with backslash escapes.
+    let quote = |s: &str| {
+        format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+    };
+    let script = format!(
+        "display notification {} with title {}",
+        quote(body),
+        quote(title)
+    );
+    Some(("osascript", vec!["-e".to_string(), script]))
+}
+
+#[cfg(all(target_family = "unix", not(target_os = "macos")))]
+fn notify_command(
+    title: &str,
+    body: &str,
+) -> Option<(&'static str, Vec<String>)> {
+    Some((
+        "notify-send",
+        vec![
+            "--app-name=fwd".to_string(),
+            title.to_string(),
+            body.to_string(),
+        ],
+    ))
+}
+
+#[cfg(not(target_family = "unix"))]
+fn notify_command(
+    _title: &str,
+    _body: &str,
+) -> Option<(&'static str, Vec<String>)> {
+    debug!("Notifications are not supported on this platform");
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(target_os = "macos")]
+    #[test]
+    fn command() {
+        let (program, args) =
+            notify_command("fwd", r#"Port 80: "a\b""#).unwrap();
+        assert_eq!(program, "osascript");
+        assert_eq!(
+            args[1],
+            r#"display notification "Port 80: \"a\\b\"" with title "fwd""#
+        );
+    }
+
+    #[cfg(all(target_family = "unix", not(target_os = "macos")))]
+    #[test]
+    fn command() {
+        let (program, args) = notify_command("fwd", "Port 80 is up").unwrap();
+        assert_eq!(program, "notify-send");
+        assert_eq!(args, ["--app-name=fwd", "fwd", "Port 80 is up"]);
+    }
+}
@@ -0,0 +1,269 @@
+use super::stats::Connection;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+const HTTP_METHODS: [&[u8]; 9] = [
+    b"GET ",
+    b"HEAD ",
+    b"POST ",
+    b"PUT ",
+    b"DELETE ",
+    b"OPTIONS ",
+    b"PATCH ",
+    b"CONNECT ",
+    b"TRACE ",
+];
+
+/// Guess the protocol being spoken from the first bytes a client sends.
+pub fn classify(data: &[u8]) -> Option<String> {
+    if data.starts_with(b"PRI * HTTP/2.0\r\n") {
+        Some("HTTP/2".to_string())
+    } else if HTTP_METHODS.iter().any(|m| data.starts_with(m)) {
+        Some("HTTP/1".to_string())
+    } else if data.len() >= 3 && data[0] == 0x16 && data[1] == 0x03 {
+        // A TLS handshake record; let's see if we can find out who it's
+        // for.
+        match server_name(data) {
+            Some(name) => Some(format!("TLS {name}")),
+            None => Some("TLS".to_string()),
+        }
+    } else if is_postgres(data) {
+        Some("Postgres".to_string())
+    } else if is_redis(data) {
+        Some("Redis".to_string())
+    } else {
+        None
+    }
+}
+
+fn is_postgres(data: &[u8]) -> bool {
+    // The startup message (or SSL request) is a 4-byte length followed by a
+    // 4-byte protocol code.
+    if data.len() < 8 {
+        return false;
+    }
+    let code = u32::from_be_bytes([data[4], data[5], data[6], data[7]]);
+    code == 0x0003_0000 // Protocol 3.0
+        || code == 80877103 // SSLRequest
+        || code == 80877104 // GSSENCRequest
+}
+
+fn is_redis(data: &[u8]) -> bool {
+    // Clients send commands as RESP arrays of bulk strings: `*2\r\n$3\r\n...`
+    if data.first() != Some(&b'*') {
+        return false;
+    }
+    let digits = data[1..].iter().take_while(|b| b.is_ascii_digit()).count();
+    digits > 0 && data[1 + digits..].starts_with(b"\r\n$")
+}
+
+/// Find the server name indication in a TLS ClientHello, if it's all in
+/// `data`.
+fn server_name(data: &[u8]) -> Option<String> {
+    struct Reader<'a>(&'a [u8]);
+
+    impl<'a> Reader<'a> {
+        fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+            if self.0.len() < n {
+                return None;
+            }
+            let (head, tail) = self.0.split_at(n);
+            self.0 = tail;
+            Some(head)
+        }
+        fn u8(&mut self) -> Option<usize> {
+            self.take(1).map(|b| b[0].into())
+        }
+        fn u16(&mut self) -> Option<usize> {
+            self.take(2)
+                .map(|b| u16::from_be_bytes([b[0], b[1]]).into())
+        }
+    }
+
+    let mut r = Reader(data);
+    r.take(5)?; // Record header.
+    if r.u8()? != 0x01 {
+        return None; // Not a ClientHello.
+    }
+    r.take(3)?; // Handshake length.
+    r.take(2 + 32)?; // Version and random.
+    let session_id = r.u8()?;
+    r.take(session_id)?;
+    let ciphers = r.u16()?;
+    r.take(ciphers)?;
+    let compression = r.u8()?;
+    r.take(compression)?;
+
+    let extensions = r.u16()?;
+    let mut r = Reader(r.take(extensions)?);
+    while !r.0.is_empty() {
+        let kind = r.u16()?;
+        let length = r.u16()?;
+        let mut ext = Reader(r.take(length)?);
+        if kind == 0x0000 {
+            // server_name: a list of (type, name), we want a host_name (0).
+            let list = ext.u16()?;
+            let mut list = Reader(ext.take(list)?);
+            while !list.0.is_empty() {
+                let name_type = list.u8()?;
+                let name_length = list.u16()?;
+                let name = list.take(name_length)?;
+                if name_type == 0 {
+                    return std::str::from_utf8(name).ok().map(String::from);
+                }
+            }
+        }
+    }
+    None
+}
+
+/// Wraps the local side of a forwarded connection, counts the bytes that go
+/// through it, and labels the port with what the first bytes that come
+/// through look like. We only look; nothing is buffered or delayed.
+pub struct Sniff<S> {
+    inner: S,
+    stats: Connection,
+    sniffed: bool,
+}
+
+impl<S> Sniff<S> {
+    pub fn new(inner: S, stats: Connection) -> Sniff<S> {
+        Sniff { inner, stats, sniffed: false }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Sniff<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = result {
+            let data = &buf.filled()[before..];
+            self.stats.add_sent(data.len() as u64);
+            if !data.is_empty() && !self.sniffed {
+                if let Some(protocol) = classify(data) {
+                    self.stats.port().set_protocol(protocol);
+                }
+                self.sniffed = true;
+            }
+        }
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Sniff<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = result {
+            self.stats.add_received(n as u64);
+        }
+        result
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::stats::PortStats;
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    // A minimal ClientHello with a server name extension for `name`.
+    fn client_hello(name: &str) -> Vec<u8> {
+        let mut sni = vec![];
+        sni.extend_from_slice(&((name.len() + 3) as u16).to_be_bytes());
+        sni.push(0);
+        sni.extend_from_slice(&(name.len() as u16).to_be_bytes());
+        sni.extend_from_slice(name.as_bytes());
+
+        let mut extensions = vec![0x00, 0x0b, 0x00, 0x02, 0x01, 0x00];
+        extensions.extend_from_slice(&[0x00, 0x00]);
+        extensions.extend_from_slice(&(sni.len() as u16).to_be_bytes());
+        extensions.extend_from_slice(&sni);
+
+        let mut hello = vec![0x03, 0x03];
+        hello.extend_from_slice(&[0; 32]);
+        hello.push(0); // No session.
+        hello.extend_from_slice(&[0x00, 0x02, 0x13, 0x01]);
+        hello.extend_from_slice(&[0x01, 0x00]);
+        hello.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        hello.extend_from_slice(&extensions);
+
+        let mut record = vec![0x16, 0x03, 0x01];
+        record.extend_from_slice(&((hello.len() + 4) as u16).to_be_bytes());
+        record.push(0x01);
+        record.extend_from_slice(&(hello.len() as u32).to_be_bytes()[1..]);
+        record.extend_from_slice(&hello);
+        record
+    }
+
+    #[test]
+    fn protocols() {
+        assert_eq!(classify(b"GET / HTTP/1.1\r\n").as_deref(), Some("HTTP/1"));
+        assert_eq!(classify(b"POST /x HTTP/1.0").as_deref(), Some("HTTP/1"));
+        assert_eq!(
+            classify(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n").as_deref(),
+            Some("HTTP/2")
+        );
+        assert_eq!(
+            classify(&client_hello("example.com")).as_deref(),
+            Some("TLS example.com")
+        );
+        assert_eq!(classify(&[0x16, 0x03, 0x01, 0x00]).as_deref(), Some("TLS"));
+        assert_eq!(
+            classify(&[0, 0, 0, 8, 0x04, 0xd2, 0x16, 0x2f]).as_deref(),
+            Some("Postgres")
+        );
+        assert_eq!(classify(b"*1\r\n$4\r\nPING\r\n").as_deref(), Some("Redis"));
+        assert_eq!(classify(b"SSH-2.0-OpenSSH").as_deref(), None);
+        assert_eq!(classify(b"*\r\n").as_deref(), None);
+        assert_eq!(classify(b"").as_deref(), None);
+    }
+
+    #[tokio::test]
+    async fn sniff_passes_data_through() {
+        let (client, server) = tokio::io::duplex(64);
+        let stats = Arc::new(PortStats::default());
+        let peer = "127.0.0.1:50000".parse().unwrap();
+        let mut sniffed = Sniff::new(server, stats.connection_opened(peer));
+
+        let mut client = client;
+        client.write_all(b"GET / HTTP/1.1\r\n").await.unwrap();
+        sniffed.write_all(b"HTTP/1.1 200 OK\r\n").await.unwrap();
+        drop(client);
+
+        let mut data = Vec::new();
+        sniffed.read_to_end(&mut data).await.unwrap();
+        assert_eq!(&data[..], b"GET / HTTP/1.1\r\n");
+        assert_eq!(stats.protocol().as_deref(), Some("HTTP/1"));
+        assert_eq!(stats.bytes_sent(), 16);
+        assert_eq!(stats.bytes_received(), 17);
+        assert_eq!(stats.open()[0].bytes_sent(), 16);
+
+        drop(sniffed);
+        assert_eq!(stats.open_connections(), 0);
+    }
+}
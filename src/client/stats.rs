@@ -0,0 +1,304 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Counters for the connections made to a single forwarded port. These are
+/// shared with the tasks handling the connections, hence the atomics.
+#[derive(Debug, Default)]
+pub struct PortStats {
+    connections: AtomicU64,
+    open: Mutex<Vec<Arc<ConnectionStats>>>,
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    protocol: Mutex<Option<String>>,
+}
+
+impl PortStats {
+    /// Start counting a connection from `peer`, until the `Connection` is
+    /// dropped.
+    pub fn connection_opened(self: &Arc<Self>, peer: SocketAddr) -> Connection {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        let stats = Arc::new(ConnectionStats {
+            peer,
+            started: Instant::now(),
+            bytes_sent: AtomicU64::new(0),
+            bytes_received: AtomicU64::new(0),
+        });
+        self.open.lock().unwrap().push(stats.clone());
+        Connection { port: self.clone(), stats }
+    }
+
+    /// Count bytes as they go by, so that the totals are up to date even
+    /// while connections are still open.
+    pub fn add_sent(&self, bytes: u64) {
+        self.bytes_sent.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn add_received(&self, bytes: u64) {
+        self.bytes_received.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// How many connections there have been, ever.
+    pub fn connections(&self) -> u64 {
+        self.connections.load(Ordering::Relaxed)
+    }
+
+    /// How many connections are open right now.
+    pub fn open_connections(&self) -> u64 {
+        self.open.lock().unwrap().len() as u64
+    }
+
+    /// The connections that are open right now, oldest first.
+    pub fn open(&self) -> Vec<Arc<ConnectionStats>> {
+        self.open.lock().unwrap().clone()
+    }
+
+    pub fn bytes_sent(&self) -> u64 {
+        self.bytes_sent.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes_received(&self) -> u64 {
+        self.bytes_received.load(Ordering::Relaxed)
+    }
+
+    /// The protocol we saw the last time somebody connected, if we could
+    /// tell what it was.
+    pub fn protocol(&self) -> Option<String> {
+        self.protocol.lock().unwrap().clone()
+    }
+
+    pub fn set_protocol(&self, protocol: String) {
+        *self.protocol.lock().unwrap() = Some(protocol);
+    }
+}
+
+/// Counters for one open connection to a forwarded port.
+#[derive(Debug)]
+pub struct ConnectionStats {
+    pub peer: SocketAddr,
+    pub started: Instant,
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+}
+
+impl ConnectionStats {
+    pub fn bytes_sent(&self) -> u64 {
+        self.bytes_sent.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes_received(&self) -> u64 {
+        self.bytes_received.load(Ordering::Relaxed)
+    }
+}
+
+/// Counts the traffic on a connection, for the connection and its port, and
+/// takes it off the port's list of open connections when it's dropped.
+#[derive(Debug)]
+pub struct Connection {
+    port: Arc<PortStats>,
+    stats: Arc<ConnectionStats>,
+}
+
+impl Connection {
+    pub fn port(&self) -> &PortStats {
+        &self.port
+    }
+
+    pub fn add_sent(&self, bytes: u64) {
+        self.stats.bytes_sent.fetch_add(bytes, Ordering::Relaxed);
+        self.port.add_sent(bytes);
+    }
+
+    pub fn add_received(&self, bytes: u64) {
+        self.stats
+            .bytes_received
+            .fetch_add(bytes, Ordering::Relaxed);
+        self.port.add_received(bytes);
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let mut open = self.port.open.lock().unwrap();
+        open.retain(|c| !Arc::ptr_eq(c, &self.stats));
+    }
+}
+
+/// Everything we want to tell the user about a session when it's over.
+#[derive(Debug)]
+pub struct SessionStats {
+    started: Instant,
+    connects: u64,
+    ports: BTreeMap<u16, Arc<PortStats>>,
+    error_count: usize,
+    errors: Vec<String>,
+}
+
+// We only keep the first few errors for the summary, nobody wants to read
+// a thousand of them.
+const MAX_ERRORS: usize = 10;
+
+impl SessionStats {
+    pub fn new() -> SessionStats {
+        SessionStats {
+            started: Instant::now(),
+            connects: 0,
+            ports: BTreeMap::new(),
+            error_count: 0,
+            errors: Vec::new(),
+        }
+    }
+
+    pub fn connected(&mut self) {
+        self.connects += 1;
+    }
+
+    pub fn reconnects(&self) -> u64 {
+        self.connects.saturating_sub(1)
+    }
+
+    pub fn error(&mut self, error: String) {
+        self.error_count += 1;
+        if self.errors.len() < MAX_ERRORS {
+            self.errors.push(error);
+        }
+    }
+
+    /// Get the counters for the given port, creating them if necessary.
+    pub fn port(&mut self, port: u16) -> Arc<PortStats> {
+        self.ports.entry(port).or_default().clone()
+    }
+}
+
+impl fmt::Display for SessionStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Session lasted {}, reconnected {} time(s)",
+            format_duration(self.started.elapsed()),
+            self.reconnects()
+        )?;
+
+        let used: Vec<_> = self
+            .ports
+            .iter()
+            .filter(|(_, s)| s.connections() > 0)
+            .collect();
+        if used.is_empty() {
+            writeln!(f, "No connections were forwarded")?;
+        } else {
+            writeln!(
+                f,
+                "{:>5} {:>11} {:>10} {:>10}",
+                "Port", "Connections", "Sent", "Received"
+            )?;
+            for (port, stats) in used {
+                writeln!(
+                    f,
+                    "{:>5} {:>11} {:>10} {:>10}",
+                    port,
+                    stats.connections(),
+                    format_bytes(stats.bytes_sent()),
+                    format_bytes(stats.bytes_received())
+                )?;
+            }
+        }
+
+        if self.error_count > 0 {
+            writeln!(f, "{} error(s):", self.error_count)?;
+            for error in self.errors.iter() {
+                writeln!(f, "  {error}")?;
+            }
+            if self.error_count > self.errors.len() {
+                writeln!(f, "  ...")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs < 60 {
+        format!("{secs}s")
+    } else if secs < 60 * 60 {
+        format!("{}m {:02}s", secs / 60, secs % 60)
+    } else {
+        format!("{}h {:02}m", secs / 3600, (secs / 60) % 60)
+    }
+}
+
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{value:.1} {}", UNITS[unit])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations() {
+        assert_eq!(format_duration(Duration::from_secs(5)), "5s");
+        assert_eq!(format_duration(Duration::from_secs(65)), "1m 05s");
+        assert_eq!(format_duration(Duration::from_secs(7260)), "2h 01m");
+    }
+
+    #[test]
+    fn bytes() {
+        assert_eq!(format_bytes(10), "10 B");
+        assert_eq!(format_bytes(1536), "1.5 KiB");
+        assert_eq!(format_bytes(3 * 1024 * 1024), "3.0 MiB");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn summary() {
+        let mut stats = SessionStats::new();
+        stats.connected();
+        stats.connected();
+        let peer = "127.0.0.1:50000".parse().unwrap();
+        let first = stats.port(8080).connection_opened(peer);
+        let second = stats.port(8080).connection_opened(peer);
+        first.add_sent(100);
+        second.add_received(2048);
+        drop(first);
+        assert_eq!(stats.port(8080).open_connections(), 1);
+        assert_eq!(stats.port(8080).open()[0].bytes_received(), 2048);
+        assert_eq!(stats.port(8080).open()[0].bytes_sent(), 0);
+        stats.port(9090); // Never used, not reported.
+        stats.error("Oh no".to_string());
+
+        tokio::time::advance(Duration::from_secs(90)).await;
+        assert_eq!(
+            format!("{stats}"),
+            "Session lasted 1m 30s, reconnected 1 time(s)\n\
+             \x20Port Connections       Sent   Received\n\
+             \x208080           2      100 B    2.0 KiB\n\
+             1 error(s):\n\
+             \x20 Oh no\n"
+        );
+    }
+
+    #[test]
+    fn errors_are_capped() {
+        let mut stats = SessionStats::new();
+        for i in 0..20 {
+            stats.error(format!("Error {i}"));
+        }
+        assert_eq!(stats.errors.len(), MAX_ERRORS);
+        assert!(format!("{stats}").contains("20 error(s):"));
+    }
+}
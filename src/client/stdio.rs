@@ -0,0 +1,201 @@
+use super::{
+    client_hello, client_refresh_loop, client_sync_to, config::Tuning, during,
+    load_config, remote_connect, transport::SshTransport, ClientOptions,
+    Destination, Session, Transport,
+};
+use anyhow::{bail, Context, Result};
+use fwd_proto::{Message, MessageReader, MessageWriter, Phase};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, BufWriter};
+use tokio::net::TcpStream;
+use tokio::sync::Notify;
+
+/// Copy `input` to `socket` and `socket` to `output` until the far end is
+/// done. When `input` runs out we tell the far end, but keep going until it
+/// has said all it's going to.
+async fn bridge<R, W>(
+    socket: TcpStream,
+    mut input: R,
+    mut output: W,
+) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let (mut read, mut write) = socket.into_split();
+    let up = async {
+        tokio::io::copy(&mut input, &mut write).await?;
+        write.shutdown().await?;
+        std::future::pending::<std::io::Result<()>>().await
+    };
+    let down = async {
+        tokio::io::copy(&mut read, &mut output).await?;
+        output.flush().await
+    };
+    tokio::select! {
+        result = up => result?,
+        result = down => result?,
+    }
+    Ok(())
+}
+
+/// Read what the server sends until it goes away, which is only ever pings
+/// and lists of ports we don't need, so that it never waits on us.
+async fn drain<T: AsyncRead + Unpin>(
+    mut reader: MessageReader<T>,
+) -> Result<()> {
+    loop {
+        if let Message::Goodbye(reason) = reader.read().await? {
+            bail!("The server exited: {reason}");
+        }
+    }
+}
+
+async fn stdio_with_transport<T, R, W>(
+    transport: &mut T,
+    dest: &Destination,
+    settings: &[String],
+    tuning: Tuning,
+    mut input: R,
+    output: W,
+) -> Result<()>
+where
+    T: Transport,
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let Session { reader, writer, mut stderr, socks_ports } =
+        transport.connect().await?;
+    let Some(&socks_port) = socks_ports.first() else {
+        bail!("The transport has no SOCKS proxy for us to use");
+    };
+    // Our stdout is the connection, so nothing else can go there.
+    let mut reader = BufReader::new(reader);
+    client_sync_to(&mut reader, &mut stderr, &mut tokio::io::stderr())
+        .await
+        .map_err(during(Phase::Sync, None))?;
+    tokio::spawn(async move {
+        _ = tokio::io::copy(&mut stderr, &mut tokio::io::sink()).await;
+    });
+    let mut reader = MessageReader::new(reader);
+    let mut writer = MessageWriter::new(BufWriter::new(writer));
+
+    // The server only needs to know we're still here.
+    let (_, goodbye) =
+        client_hello(&mut reader, &mut writer, settings, false, false).await?;
+    let (socket, _) =
+        remote_connect(socks_port, dest, tuning, &mut input, None)
+            .await
+            .map_err(during(Phase::Dial, Some(dest.port)))?;
+    let refresh = Notify::new();
+    tokio::select! {
+        result = bridge(socket, input, output) => result?,
+        result = client_refresh_loop(&mut writer, false, false, &refresh) => {
+            result.context("Error sending pings")?
+        }
+        result = drain(reader) => result?,
+    }
+    if goodbye {
+        let reason = "the client is done".to_string();
+        writer.write(Message::Goodbye(reason)).await?;
+    }
+    Ok(())
+}
+
+async fn run_stdio_impl(
+    remote: &str,
+    options: &ClientOptions,
+    dest: &Destination,
+) -> Result<()> {
+    let mut config = load_config(remote, options)?;
+    config.set_ssh_connections(1);
+    let (settings, tuning) = (config.settings(), config.tuning());
+    let mut transport = SshTransport::new(remote, config);
+    let (input, output) = (tokio::io::stdin(), tokio::io::stdout());
+    let result = stdio_with_transport(
+        &mut transport,
+        dest,
+        &settings,
+        tuning,
+        input,
+        output,
+    )
+    .await;
+    transport.disconnected().await;
+    transport.shutdown().await;
+    result
+}
+
+/// Connect stdin and stdout to `port` on the server, or on `host` as the
+/// server sees it, like netcat, so that fwd can be an ssh `ProxyCommand`.
+pub async fn run_stdio(
+    remote: &str,
+    options: ClientOptions,
+    host: Option<String>,
+    port: u16,
+) {
+    let dest = Destination { host, port };
+    if let Err(e) = run_stdio_impl(remote, &options, &dest).await {
+        eprintln!("Error connecting to {dest}: {e:#}");
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{config::ServerConfig, LocalTransport};
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn stdio() {
+        // Something to connect to, which answers and then hangs up.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buffer = [0; 5];
+            socket.read_exact(&mut buffer).await.unwrap();
+            socket.write_all(b"you said ").await.unwrap();
+            socket.write_all(&buffer).await.unwrap();
+        });
+
+        let mut transport = LocalTransport::new();
+        let dest = Destination { host: None, port };
+        let tuning = ServerConfig::default().tuning();
+        let (input, mut input_write) = tokio::io::duplex(4096);
+        let (mut output_read, output) = tokio::io::duplex(4096);
+        input_write.write_all(b"hello").await.unwrap();
+        stdio_with_transport(&mut transport, &dest, &[], tuning, input, output)
+            .await
+            .unwrap();
+        let mut said = String::new();
+        output_read.read_to_string(&mut said).await.unwrap();
+        assert_eq!(said, "you said hello");
+        transport.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn nothing_there() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        drop(listener);
+
+        let mut transport = LocalTransport::new();
+        let dest = Destination { host: None, port };
+        let tuning = ServerConfig::default().tuning();
+        let (input, _input_write) = tokio::io::duplex(4096);
+        let (_output_read, output) = tokio::io::duplex(4096);
+        let result = stdio_with_transport(
+            &mut transport,
+            &dest,
+            &[],
+            tuning,
+            input,
+            output,
+        )
+        .await;
+        assert!(result.is_err());
+        transport.shutdown().await;
+    }
+}
@@ -0,0 +1,632 @@
+use super::config::{ServerConfig, SocketTarget};
+use super::deploy::{self, Server, DEPLOY_PATH};
+use anyhow::{bail, Result};
+use log::{error, info, warn};
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::process;
+use tokio::time::{sleep, timeout, Duration};
+
+/// Everything we need from a connection to `fwd --server`.
+pub struct Session {
+    /// The server's stdout, where the protocol comes from.
+    pub reader: Box<dyn AsyncRead + Send + Unpin>,
+    /// The server's stdin, where the protocol goes.
+    pub writer: Box<dyn AsyncWrite + Send + Unpin>,
+    /// Diagnostics from the server (or the transport), which go in the log.
+    pub stderr: Box<dyn AsyncRead + Send + Unpin>,
+    /// Local SOCKS5 proxies that can reach the server's loopback interface,
+    /// which is how the forwarded connections get there. There has to be at
+    /// least one; connections take turns if there are more.
+    pub socks_ports: Vec<u16>,
+}
+
+/// A way of reaching `fwd --server` on the remote. The client calls
+/// `connect` to start a session, and `disconnected` once it's over, then
+/// starts again unless `disconnected` says not to.
+pub trait Transport {
+    fn connect(&mut self) -> impl Future<Output = Result<Session>> + Send;
+
+    /// The last session is over, one way or another; clean it up. Return
+    /// false if we should stop now instead of reconnecting.
+    fn disconnected(&mut self) -> impl Future<Output = bool> + Send;
+
+    /// We're quitting, maybe in the middle of a session; make sure it's
+    /// gone before we do.
+    fn shutdown(&mut self) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+
+    /// Whether the server we run is a copy of fwd that we put there, and
+    /// can put this one in place of if it's older.
+    fn replaceable(&self) -> bool {
+        false
+    }
+
+    /// Put this fwd in place of the server's, for the next session.
+    fn replace(&mut self) -> impl Future<Output = Result<()>> + Send {
+        async { bail!("Can't replace the server with this transport") }
+    }
+}
+
+/// The default transport: run `fwd --server` with ssh, and use ssh's dynamic
+/// forwarding as the SOCKS proxy.
+pub struct SshTransport {
+    remote: String,
+    config: ServerConfig,
+    server: Server,
+    child: Option<process::Child>,
+    /// The ssh connections that are only there for their SOCKS proxies, with
+    /// `ssh_connections`.
+    extra: Vec<process::Child>,
+}
+
+impl SshTransport {
+    pub fn new(remote: &str, config: ServerConfig) -> SshTransport {
+        SshTransport {
+            remote: remote.to_string(),
+            config,
+            server: Server::Installed,
+            child: None,
+            extra: Vec::new(),
+        }
+    }
+
+    /// The server doesn't have the fwd we tried to run; find it another
+    /// one. Return false if there's nothing more to try.
+    async fn bootstrap(&mut self) -> bool {
+        match self.server {
+            Server::Installed => {
+                info!("`fwd` isn't installed remotely; trying {DEPLOY_PATH}");
+                self.server = Server::Cached;
+            }
+            Server::Cached if self.config.bootstrap() => {
+                info!("Copying fwd to {DEPLOY_PATH} on the server...");
+                if let Err(e) = deploy::upload(&self.remote, &self.config).await
+                {
+                    error!("Error copying fwd to the server: {e:#}");
+                    return false;
+                }
+                self.server = Server::Uploaded;
+            }
+            Server::Cached => error!(
+                "Cannot find `fwd` remotely, make sure it is installed \
+                 (or use --bootstrap)"
+            ),
+            Server::Uploaded => {
+                error!("Copied fwd to the server, but it doesn't run there");
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Start the extra ssh connections, and return the SOCKS ports of the
+    /// ones that get logged in. The session doesn't need them, so the ones
+    /// that don't are only worth a warning.
+    async fn connect_extra(&mut self) -> Vec<u16> {
+        let mut started = Vec::new();
+        for _ in 1..self.config.ssh_connections() {
+            match spawn_socks_ssh(&self.remote, &self.config).await {
+                Ok(started_ssh) => started.push(started_ssh),
+                Err(e) => warn!("Error starting another ssh connection: {e}"),
+            }
+        }
+
+        // They log in side by side, so this takes as long as the slowest.
+        let mut ports = Vec::new();
+        for (mut child, port) in started {
+            if socks_ready(&mut child, port).await {
+                ports.push(port);
+                self.extra.push(child);
+            } else {
+                warn!("Another ssh connection didn't get going; doing without");
+                _ = child.kill().await;
+            }
+        }
+        if !ports.is_empty() {
+            info!("Using {} ssh connections", ports.len() + 1);
+        }
+        ports
+    }
+
+    async fn disconnect_extra(&mut self) {
+        for mut child in self.extra.drain(..) {
+            _ = child.kill().await;
+        }
+    }
+}
+
+impl Transport for SshTransport {
+    async fn connect(&mut self) -> Result<Session> {
+        let (mut child, socks_port) =
+            spawn_ssh(&self.remote, &self.config, self.server.program())
+                .await?;
+
+        let stderr = child
+            .stderr
+            .take()
+            .expect("child did not have a handle to stderr");
+        let writer = child
+            .stdin
+            .take()
+            .expect("child did not have a handle to stdin");
+        let reader = child
+            .stdout
+            .take()
+            .expect("child did not have a handle to stdout");
+        self.child = Some(child);
+
+        let mut socks_ports = vec![socks_port];
+        socks_ports.extend(self.connect_extra().await);
+        Ok(Session {
+            reader: Box::new(reader),
+            writer: Box::new(writer),
+            stderr: Box::new(stderr),
+            socks_ports,
+        })
+    }
+
+    async fn disconnected(&mut self) -> bool {
+        self.disconnect_extra().await;
+        let mut child = match self.child.take() {
+            Some(child) => child,
+            None => return true,
+        };
+
+        // If ssh went away on its own it should be done by now, and how it
+        // went away tells us what to do. If not, make sure it's gone, and
+        // with it anything it was listening on, before we start a new one.
+        match timeout(Duration::from_secs(1), child.wait()).await {
+            Ok(Ok(status)) => {
+                if is_sigint(status) {
+                    return false;
+                } else if let Some(127) = status.code() {
+                    return self.bootstrap().await;
+                }
+            }
+            _ => {
+                // A session that's over with ssh still running is a hung one,
+                // like after the laptop sleeps. If it shares a connection,
+                // that connection is what hung, and the next session would
+                // only hang on it too.
+                _ = child.kill().await;
+                if let Some(path) = control_path(&self.config) {
+                    info!("Closing the shared ssh connection, which hung");
+                    let mut cmd = ssh_exit(&self.remote, &self.config, &path);
+                    _ = timeout(Duration::from_secs(5), cmd.status()).await;
+                }
+            }
+        }
+        true
+    }
+
+    async fn shutdown(&mut self) {
+        self.disconnect_extra().await;
+        // Once we let go of its stdin the server exits, and ssh with it, so
+        // give them a moment to do that before we insist.
+        if let Some(mut child) = self.child.take() {
+            if timeout(Duration::from_secs(1), child.wait()).await.is_err() {
+                _ = child.kill().await;
+            }
+        }
+    }
+
+    fn replaceable(&self) -> bool {
+        self.server == Server::Cached && self.config.auto_update()
+    }
+
+    async fn replace(&mut self) -> Result<()> {
+        // Whether or not this works, the copy there is the one we run from
+        // now on, and we don't try again.
+        self.server = Server::Uploaded;
+        info!("Copying fwd to {DEPLOY_PATH} on the server...");
+        deploy::upload(&self.remote, &self.config).await
+    }
+}
+
+/// How long a shared ssh connection stays up after the last session using
+/// it is gone, waiting for the next one.
+const CONTROL_PERSIST: &str = "10m";
+
+/// Where ssh keeps the sockets for shared connections.
+#[cfg(target_family = "unix")]
+fn control_directory() -> Result<PathBuf> {
+    use anyhow::Context;
+    use std::os::unix::fs::DirBuilderExt;
+
+    let mut path = crate::browse::socket_directory()?;
+    path.push("ssh");
+    std::fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(&path)
+        .context("Error creating ssh control directory")?;
+    Ok(path)
+}
+
+#[cfg(not(target_family = "unix"))]
+fn control_directory() -> Result<PathBuf> {
+    anyhow::bail!("Sharing ssh connections is not supported on this platform")
+}
+
+fn ssh_command(
+    server: &str,
+    config: &ServerConfig,
+    socks_port: u16,
+    control_path: Option<&Path>,
+    program: &str,
+) -> process::Command {
+    let mut cmd = process::Command::new("ssh");
+    cmd.arg("-T").arg("-D").arg(socks_port.to_string());
+    if config.x11() {
+        // ssh takes care of DISPLAY and xauth on the remote side.
+        cmd.arg("-X");
+    }
+    for port in config.reverse() {
+        // The remote end only listens on loopback, same as we do.
+        cmd.arg("-R").arg(format!("{port}:localhost:{port}"));
+    }
+    for (path, target) in config.sockets() {
+        match target {
+            SocketTarget::Port(port) => {
+                cmd.arg("-L").arg(format!("127.0.0.1:{port}:{path}"))
+            }
+            SocketTarget::Path(local) => {
+                cmd.arg("-L").arg(format!("{local}:{path}"))
+            }
+        };
+    }
+    // ssh goes with the first value it sees for an option, so these come
+    // before the profile's options to be able to override them.
+    cmd.args(config.ssh_args());
+    if let Some(path) = control_path {
+        // If there's already a connection we just ask it for another
+        // session, with its own SOCKS port; otherwise this one is it.
+        cmd.arg("-o").arg("ControlMaster=auto");
+        cmd.arg("-o").arg(format!("ControlPath={}", path.display()));
+        cmd.arg("-o")
+            .arg(format!("ControlPersist={CONTROL_PERSIST}"));
+    }
+    for option in config.profile().ssh_options() {
+        cmd.arg("-o").arg(option);
+    }
+    cmd.arg(server).arg(program).arg("--server");
+    cmd
+}
+
+/// Ask the ssh that holds the shared connection at `control_path` to close
+/// it, and go away.
+fn ssh_exit(
+    server: &str,
+    config: &ServerConfig,
+    control_path: &Path,
+) -> process::Command {
+    let mut cmd = process::Command::new("ssh");
+    cmd.arg("-O").arg("exit");
+    cmd.arg("-o")
+        .arg(format!("ControlPath={}", control_path.display()));
+    // These can change the user and port, which are part of the path.
+    cmd.args(config.ssh_args()).arg(server);
+    cmd.stdin(std::process::Stdio::null());
+    cmd.stdout(std::process::Stdio::null());
+    cmd.stderr(std::process::Stdio::null());
+    cmd
+}
+
+/// An ssh connection that's only a SOCKS proxy on `socks_port`, with a
+/// connection of its own even if the ssh config says to share them. It
+/// can't ask for a password in the middle of the terminal UI, so it needs a
+/// key or an agent.
+fn ssh_socks_command(
+    server: &str,
+    config: &ServerConfig,
+    socks_port: u16,
+) -> process::Command {
+    let mut cmd = process::Command::new("ssh");
+    cmd.arg("-N")
+        .arg("-T")
+        .arg("-D")
+        .arg(socks_port.to_string());
+    cmd.args(config.ssh_args());
+    for option in ["BatchMode=yes", "ControlMaster=no", "ControlPath=none"] {
+        cmd.arg("-o").arg(option);
+    }
+    for option in config.profile().ssh_options() {
+        cmd.arg("-o").arg(option);
+    }
+    cmd.arg(server);
+    cmd.stdin(std::process::Stdio::null());
+    cmd.stdout(std::process::Stdio::null());
+    cmd.stderr(std::process::Stdio::null());
+    cmd
+}
+
+/// How long an extra ssh connection gets to log in and start listening.
+const EXTRA_LOGIN_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Wait for an ssh from `ssh_socks_command` to be listening, which it is once
+/// it has logged in. False if it gave up, or took too long.
+async fn socks_ready(child: &mut process::Child, port: u16) -> bool {
+    let ready = async {
+        loop {
+            if TcpStream::connect(("127.0.0.1", port)).await.is_ok() {
+                return true;
+            }
+            if let Ok(Some(_)) = child.try_wait() {
+                return false;
+            }
+            sleep(Duration::from_millis(100)).await;
+        }
+    };
+    timeout(EXTRA_LOGIN_TIMEOUT, ready).await.unwrap_or(false)
+}
+
+async fn spawn_socks_ssh(
+    server: &str,
+    config: &ServerConfig,
+) -> Result<(process::Child, u16), std::io::Error> {
+    let socks_port = free_port(0).await?;
+    let child = ssh_socks_command(server, config, socks_port).spawn()?;
+    Ok((child, socks_port))
+}
+
+/// Where the shared ssh connection's socket is, if we share one; ssh fills
+/// in the `%C` for each server.
+fn control_path(config: &ServerConfig) -> Option<PathBuf> {
+    if !config.multiplex() {
+        return None;
+    }
+    match control_directory() {
+        Ok(directory) => Some(directory.join("%C")),
+        Err(e) => {
+            warn!("Not sharing the ssh connection: {e:#}");
+            None
+        }
+    }
+}
+
+/// Find a port for ssh's SOCKS proxy: `port`, or any if that's 0. We make
+/// sure that we can have it, since ssh only warns when it can't listen for
+/// SOCKS and we would be talking to somebody else.
+async fn free_port(port: u16) -> Result<u16, std::io::Error> {
+    let listener = TcpListener::bind(("127.0.0.1", port)).await?;
+    Ok(listener.local_addr()?.port())
+}
+
+async fn spawn_ssh(
+    server: &str,
+    config: &ServerConfig,
+    program: &str,
+) -> Result<(tokio::process::Child, u16), std::io::Error> {
+    let socks_port = free_port(config.socks().unwrap_or(0)).await?;
+
+    let control_path = control_path(config);
+    let mut cmd = ssh_command(
+        server,
+        config,
+        socks_port,
+        control_path.as_deref(),
+        program,
+    );
+
+    cmd.stdout(std::process::Stdio::piped());
+    cmd.stdin(std::process::Stdio::piped());
+    cmd.stderr(std::process::Stdio::piped());
+    let child = cmd.spawn()?;
+    Ok((child, socks_port))
+}
+
+#[cfg(target_family = "windows")]
+fn is_sigint(status: std::process::ExitStatus) -> bool {
+    match status.code() {
+        Some(255) => true,
+        _ => false,
+    }
+}
+
+#[cfg(target_family = "unix")]
+fn is_sigint(status: std::process::ExitStatus) -> bool {
+    use std::os::unix::process::ExitStatusExt;
+    match status.signal() {
+        Some(2) => true,
+        Some(_) => false,
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn shutdown_kills_ssh() {
+        let mut transport =
+            SshTransport::new("foo.com", ServerConfig::default());
+        let child = process::Command::new("sleep").arg("60").spawn().unwrap();
+        let pid = child.id().unwrap();
+        transport.child = Some(child);
+
+        transport.shutdown().await;
+        assert!(transport.child.is_none());
+        assert!(!std::path::Path::new(&format!("/proc/{pid}")).exists());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn disconnected_kills_hung_ssh() {
+        let mut transport =
+            SshTransport::new("foo.com", ServerConfig::default());
+        let child = process::Command::new("sleep").arg("60").spawn().unwrap();
+        let pid = child.id().unwrap();
+        transport.child = Some(child);
+
+        // It's hung, not gone for a reason, so we try again.
+        assert!(transport.disconnected().await);
+        assert!(transport.child.is_none());
+        assert!(!std::path::Path::new(&format!("/proc/{pid}")).exists());
+    }
+
+    #[test]
+    fn exit_shared_connection() {
+        let mut config = ServerConfig::default();
+        config.add_ssh_args(&["-p".to_string(), "2222".to_string()]);
+        let path = Path::new("/tmp/fwd/ssh/%C");
+        let cmd = ssh_exit("foo.com", &config, path);
+        let args: Vec<_> = cmd
+            .as_std()
+            .get_args()
+            .map(|a| a.to_str().unwrap())
+            .collect();
+        assert_eq!(
+            args,
+            [
+                "-O",
+                "exit",
+                "-o",
+                "ControlPath=/tmp/fwd/ssh/%C",
+                "-p",
+                "2222",
+                "foo.com"
+            ]
+        );
+    }
+
+    #[test]
+    fn extra_ssh_arguments() {
+        let mut config = ServerConfig::default();
+        config.set_profile(crate::client::Profile::Latency);
+        config.add_ssh_args(&["-o".to_string(), "Compression=yes".to_string()]);
+        let cmd = ssh_command("foo.com", &config, 1080, None, "fwd");
+        let args: Vec<_> = cmd
+            .as_std()
+            .get_args()
+            .map(|a| a.to_str().unwrap())
+            .collect();
+        assert_eq!(
+            args[3..7],
+            ["-o", "Compression=yes", "-o", "IPQoS=lowdelay"]
+        );
+    }
+
+    #[tokio::test]
+    async fn bootstrap_tries_the_cached_copy_first() {
+        let mut transport =
+            SshTransport::new("foo.com", ServerConfig::default());
+        assert!(transport.bootstrap().await);
+        assert_eq!(transport.server, Server::Cached);
+
+        // Without --bootstrap, that's as far as it goes.
+        assert!(transport.bootstrap().await);
+        assert_eq!(transport.server, Server::Cached);
+
+        // If even the one we uploaded won't run, there's no point going on.
+        transport.server = Server::Uploaded;
+        assert!(!transport.bootstrap().await);
+    }
+
+    #[test]
+    fn socks_only_ssh() {
+        let mut config = ServerConfig::default();
+        config.set_profile(crate::client::Profile::Throughput);
+        config.add_reverse(3000);
+        config.set_multiplex(true);
+        config.add_ssh_args(&["-p".to_string(), "2222".to_string()]);
+        let cmd = ssh_socks_command("foo.com", &config, 1081);
+        let args: Vec<_> = cmd
+            .as_std()
+            .get_args()
+            .map(|a| a.to_str().unwrap())
+            .collect();
+        // Nothing but the proxy, on a connection of its own.
+        assert_eq!(
+            args,
+            [
+                "-N",
+                "-T",
+                "-D",
+                "1081",
+                "-p",
+                "2222",
+                "-o",
+                "BatchMode=yes",
+                "-o",
+                "ControlMaster=no",
+                "-o",
+                "ControlPath=none",
+                "-o",
+                "IPQoS=throughput",
+                "-o",
+                "Compression=yes",
+                "foo.com"
+            ]
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn extra_connections_that_fail() {
+        // An "ssh" that gives up right away never gets listening.
+        let mut child = process::Command::new("true").spawn().unwrap();
+        let port = free_port(0).await.unwrap();
+        assert!(!socks_ready(&mut child, port).await);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut child =
+            process::Command::new("sleep").arg("60").spawn().unwrap();
+        assert!(socks_ready(&mut child, port).await);
+        _ = child.kill().await;
+    }
+
+    #[test]
+    fn multiplexing() {
+        let config = ServerConfig::default();
+        let path = Path::new("/run/fwd/ssh/%C");
+        let cmd = ssh_command("foo.com", &config, 1080, Some(path), "fwd");
+        let args: Vec<_> = cmd
+            .as_std()
+            .get_args()
+            .map(|a| a.to_str().unwrap())
+            .collect();
+        assert_eq!(
+            args[3..9],
+            [
+                "-o",
+                "ControlMaster=auto",
+                "-o",
+                "ControlPath=/run/fwd/ssh/%C",
+                "-o",
+                "ControlPersist=10m"
+            ]
+        );
+    }
+
+    #[test]
+    fn ssh_arguments() {
+        let mut config = ServerConfig::default();
+        config.add_reverse(3000);
+        let cmd = ssh_command("foo.com", &config, 1080, None, "fwd");
+        let args: Vec<_> = cmd
+            .as_std()
+            .get_args()
+            .map(|a| a.to_str().unwrap())
+            .collect();
+        assert_eq!(
+            args,
+            vec![
+                "-T",
+                "-D",
+                "1080",
+                "-R",
+                "3000:localhost:3000",
+                "foo.com",
+                "fwd",
+                "--server"
+            ]
+        );
+    }
+}
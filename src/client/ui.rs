@@ -1,6 +1,16 @@
-use super::{client_listen, config::ServerConfig};
-use crate::message::PortDesc;
-use anyhow::Result;
+use super::{
+    client_bind, client_listen,
+    config::{PortConfig, ServerConfig, Tuning},
+    control::Command,
+    events::Event as ClientEvent,
+    handle::PortInfo,
+    history::{Event as HistoryEvent, History},
+    notify,
+    stats::{format_bytes, format_duration, PortStats, SessionStats},
+    Destination, RateLimit,
+};
+use crate::logging::Filter;
+use anyhow::{bail, Result};
 use crossterm::{
     event::{Event, EventStream, KeyCode, KeyEvent, KeyModifiers},
     execute,
@@ -9,18 +19,23 @@ use crossterm::{
         EnterAlternateScreen, LeaveAlternateScreen,
     },
 };
-use log::{error, info, Level, Metadata, Record};
-use open;
+use fwd_proto::PortDesc;
+use log::{error, info, warn, Level, Metadata, Record};
+use serde_json::Value;
 use std::collections::vec_deque::VecDeque;
 use std::collections::{HashMap, HashSet};
-use std::io::stdout;
-use tokio::sync::mpsc;
+use std::io::{stdout, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
 use tokio::sync::oneshot;
+use tokio::sync::Notify;
+use tokio::sync::{mpsc, watch};
+use tokio::time::{interval, Duration, Instant, Interval, MissedTickBehavior};
 use tokio_stream::StreamExt;
 use tui::{
     backend::{Backend, CrosstermBackend},
     layout::{Constraint, Direction, Layout, Rect},
-    style::{Color, Style},
+    style::{Color, Modifier, Style},
     widgets::{
         Block, Borders, List, ListItem, ListState, Row, Table, TableState,
     },
@@ -28,32 +43,73 @@ use tui::{
 };
 
 pub enum UIEvent {
-    Connected(u16),
+    Connected(Vec<u16>),
     Disconnected,
     ServerLine(String),
     LogLine(log::Level, String),
     Ports(Vec<PortDesc>),
+    /// The Unix domain sockets that the server has listening, by path.
+    Sockets(Vec<String>),
+    /// Something on the server wants us to open this URL.
+    Browse(String),
+    /// The config file changed, and this is what it says now.
+    Config(Box<ServerConfig>),
+    /// `fwd ctl` wants something done, and is waiting to hear how it went.
+    Control(Command, oneshot::Sender<Result<String, String>>),
+    /// A `ClientHandle` wants to know about the ports.
+    List(oneshot::Sender<Vec<PortInfo>>),
+    /// A `ClientHandle` wants to hear about everything from now on.
+    Subscribe(mpsc::Sender<ClientEvent>),
 }
 
+#[derive(Debug)]
 pub enum UIReturn {
     Quit,
     Disconnected,
 }
 
+/// How we show what's going on: a table in the terminal for people, or one
+/// JSON object per line on stdout for programs.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Output {
+    #[default]
+    Tui,
+    Json,
+    /// Nothing at all, for when we're running inside some other program,
+    /// which asks for what it wants to know.
+    Quiet,
+}
+
+impl std::str::FromStr for Output {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Output> {
+        match s {
+            "tui" => Ok(Output::Tui),
+            "json" => Ok(Output::Json),
+            _ => bail!("output must be one of tui or json, got {s}"),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Logger {
     line_sender: mpsc::Sender<UIEvent>,
+    filter: Filter,
 }
 
 impl Logger {
-    pub fn new(line_sender: mpsc::Sender<UIEvent>) -> Box<Logger> {
-        Box::new(Logger { line_sender })
+    pub fn new(
+        line_sender: mpsc::Sender<UIEvent>,
+        filter: Filter,
+    ) -> Box<Logger> {
+        Box::new(Logger { line_sender, filter })
     }
 }
 
 impl log::Log for Logger {
     fn enabled(&self, metadata: &Metadata) -> bool {
-        metadata.level() <= Level::Info
+        self.filter.enabled(metadata)
     }
 
     fn log(&self, record: &Record) {
@@ -71,19 +127,61 @@ impl log::Log for Logger {
 #[derive(Debug)]
 struct Listener {
     enabled: bool,
+    /// Whether the port is waiting for approval before we forward it.
+    pending: bool,
     stop: Option<oneshot::Sender<()>>,
     desc: Option<PortDesc>,
+    local_port: Option<u16>,
+    fallback_port: Option<u16>,
+    /// The port we got when we last listened, which isn't the one we
+    /// wanted if that was taken.
+    bound_port: Option<u16>,
+    bind: IpAddr,
+    stats: Arc<PortStats>,
+    /// The counters as they were at the last tick, and how many bytes went
+    /// through in the tick before that.
+    last: Activity,
+    rate: u64,
+    history: Arc<History>,
+    tuning: Tuning,
+    limit: Option<Arc<RateLimit>>,
+    /// Where the connections go, if it isn't the server itself.
+    host: Option<String>,
+    /// The SOCKS ports of the session we're in, if we're in one.
+    socks: watch::Receiver<Vec<u16>>,
 }
 
 impl Listener {
     pub fn from_desc(
-        socks_port: Option<u16>,
+        socks: watch::Receiver<Vec<u16>>,
         desc: PortDesc,
-        enabled: bool,
+        config: PortConfig,
+        bind: IpAddr,
+        stats: Arc<PortStats>,
+        history: Arc<History>,
+        tuning: Tuning,
     ) -> Listener {
-        let mut listener = Listener { enabled, stop: None, desc: Some(desc) };
+        let enabled = config.enabled;
+        let mut listener = Listener {
+            enabled,
+            pending: false,
+            stop: None,
+            desc: Some(desc),
+            local_port: config.local_port,
+            fallback_port: config.fallback_port,
+            bound_port: None,
+            bind: config.bind.unwrap_or(bind),
+            last: Activity::now(&stats),
+            rate: 0,
+            stats,
+            history,
+            tuning,
+            limit: config.limit.map(|rate| Arc::new(RateLimit::new(rate))),
+            host: config.host,
+            socks,
+        };
         if enabled {
-            listener.start(socks_port);
+            listener.start();
         }
         listener
     }
@@ -92,41 +190,130 @@ impl Listener {
         self.enabled
     }
 
-    pub fn set_enabled(&mut self, socks_port: Option<u16>, enabled: bool) {
+    /// Called once every `TICK_INTERVAL` to work out how busy the port is;
+    /// returns what happened since the last tick.
+    fn tick(&mut self) -> Activity {
+        let now = Activity::now(&self.stats);
+        let activity = Activity {
+            opened: now.opened - self.last.opened,
+            closed: now.closed - self.last.closed,
+            sent: now.sent - self.last.sent,
+            received: now.received - self.last.received,
+        };
+        self.rate = activity.sent + activity.received;
+        self.last = now;
+        activity
+    }
+
+    /// The local port that connections to the remote `port` go in on.
+    fn local_port(&self, port: u16) -> u16 {
+        self.bound_port.or(self.local_port).unwrap_or(port)
+    }
+
+    /// Where connections to this port go in, as a string for JSON.
+    fn local(&self, port: u16) -> String {
+        self.local_addr(port).to_string()
+    }
+
+    fn local_addr(&self, port: u16) -> SocketAddr {
+        SocketAddr::new(self.bind, self.local_port(port))
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
         if enabled {
             self.enabled = true;
-            self.start(socks_port);
+            self.pending = false;
+            self.start();
         } else {
             self.enabled = false;
             self.stop = None;
+            self.bound_port = None;
         }
     }
 
-    pub fn connect(&mut self, socks_port: Option<u16>, desc: PortDesc) {
+    /// Take up `config`, which used to be `was`, moving the listener if it
+    /// has to. Whether the port is enabled only changes if the config
+    /// changed its mind about it, so that toggling it by hand sticks.
+    fn reconfigure(
+        &mut self,
+        was: &PortConfig,
+        config: PortConfig,
+        bind: IpAddr,
+        tuning: Tuning,
+    ) {
+        let bind = config.bind.unwrap_or(bind);
+        let mut moved = self.local_port != config.local_port
+            || self.fallback_port != config.fallback_port
+            || self.bind != bind
+            || self.host != config.host
+            || self.tuning != tuning;
+        if was.limit != config.limit {
+            self.limit =
+                config.limit.map(|rate| Arc::new(RateLimit::new(rate)));
+            moved = true;
+        }
+        self.local_port = config.local_port;
+        self.fallback_port = config.fallback_port;
+        self.bind = bind;
+        self.host = config.host;
+        self.tuning = tuning;
+
+        if was.enabled != config.enabled {
+            self.set_enabled(config.enabled);
+        } else if moved && self.stop.is_some() {
+            self.stop = None;
+            self.bound_port = None;
+            self.start();
+        }
+    }
+
+    pub fn connect(&mut self, desc: PortDesc) {
         self.desc = Some(desc);
-        self.start(socks_port);
+        self.start();
     }
 
     pub fn disconnect(&mut self) {
         self.desc = None;
         self.stop = None;
+        self.bound_port = None;
     }
 
-    pub fn start(&mut self, socks_port: Option<u16>) {
-        if self.enabled {
-            if let (Some(desc), Some(socks_port), None) =
-                (&self.desc, socks_port, &self.stop)
-            {
-                info!("Starting port {port} to {socks_port}", port = desc.port);
-                let (l, stop) = oneshot::channel();
+    pub fn start(&mut self) {
+        let connected = !self.socks.borrow().is_empty();
+        if self.enabled && connected {
+            if let (Some(desc), None) = (&self.desc, &self.stop) {
+                info!("Starting port {port}", port = desc.port);
                 let port = desc.port;
+                let local =
+                    SocketAddr::new(self.bind, self.local_port.unwrap_or(port));
+                let listener =
+                    match client_bind(port, local, self.fallback_port) {
+                        Ok(listener) => listener,
+                        Err(e) => {
+                            error!("Error listening on port {port}: {e:?}");
+                            return;
+                        }
+                    };
+                self.bound_port =
+                    listener.local_addr().ok().map(|address| address.port());
+
+                let (l, stop) = oneshot::channel();
+                let stats = self.stats.clone();
+                let history = self.history.clone();
+                let tuning = self.tuning;
+                let limit = self.limit.clone();
+                let dest = Destination { host: self.host.clone(), port };
+                let socks = self.socks.clone();
                 tokio::spawn(async move {
                     let result = tokio::select! {
-                        r = client_listen(port, socks_port) => r,
+                        r = client_listen(
+                            dest, listener, socks, stats, history, tuning, limit,
+                        ) => r,
                         _ = stop => Ok(()),
                     };
+                    // It says which port, and that it was listening.
                     if let Err(e) = result {
-                        error!("Error listening on port {port}: {e:?}");
+                        error!("{e:#}");
                     } else {
                         info!("Stopped listening on port {port}");
                     }
@@ -138,41 +325,134 @@ impl Listener {
     }
 }
 
+/// Connections and bytes on a port, either in total or between two ticks.
+#[derive(Debug, Default, Clone, PartialEq)]
+struct Activity {
+    opened: u64,
+    closed: u64,
+    sent: u64,
+    received: u64,
+}
+
+impl Activity {
+    fn now(stats: &PortStats) -> Activity {
+        let opened = stats.connections();
+        Activity {
+            opened,
+            closed: opened - stats.open_connections(),
+            sent: stats.bytes_sent(),
+            received: stats.bytes_received(),
+        }
+    }
+
+    /// The events for this activity on `port`, if there was any.
+    fn events(&self, port: u16) -> Vec<ClientEvent> {
+        let mut events = Vec::new();
+        for _ in 0..self.opened {
+            events.push(ClientEvent::ConnectionOpened { port });
+        }
+        for _ in 0..self.closed {
+            events.push(ClientEvent::ConnectionClosed { port });
+        }
+        if self.sent > 0 || self.received > 0 {
+            events.push(ClientEvent::Bytes {
+                port,
+                sent: self.sent,
+                received: self.received,
+            });
+        }
+        events
+    }
+}
+
+/// How often we redraw even if nothing happened, so that the connection
+/// counts and rates stay current. With JSON output, it's how often we report
+/// connections and bytes.
+const TICK_INTERVAL: Duration = Duration::from_secs(1);
+
 #[derive(Debug)]
 pub struct UI {
     events: mpsc::Receiver<UIEvent>,
+    refresh: Arc<Notify>,
+    output: Output,
+    /// JSON events waiting to be written, with JSON output.
+    json: Vec<Value>,
+    /// Where `ClientHandle`s hear about events.
+    subscribers: Vec<mpsc::Sender<ClientEvent>>,
     ports: HashMap<u16, Listener>,
-    socks_port: Option<u16>,
+    /// How many of the ports each process has, by description, counted
+    /// whenever the ports change rather than on every frame.
+    group_sizes: HashMap<String, usize>,
+    /// The SOCKS ports of the session we're in, if we're in one. Listeners
+    /// keep an eye on them, so that they can carry on across sessions.
+    socks: watch::Sender<Vec<u16>>,
+    /// The Unix domain sockets the server last said it has.
+    sockets: Vec<String>,
     lines: VecDeque<String>,
     config: ServerConfig,
+    stats: SessionStats,
+    history: Arc<History>,
     selection: TableState,
     running: bool,
+    /// Whether we've had the list of ports since we connected, so that
+    /// changes to it are news.
+    seen_ports: bool,
+    /// The last time there was a connection to any of the ports, or when we
+    /// started if there hasn't been one.
+    active: Instant,
     show_logs: bool,
     alternate_screen: bool,
     raw_mode: bool,
 }
 
 impl UI {
-    pub fn new(events: mpsc::Receiver<UIEvent>, config: ServerConfig) -> UI {
+    pub fn new(
+        events: mpsc::Receiver<UIEvent>,
+        config: ServerConfig,
+        history: Arc<History>,
+        refresh: Arc<Notify>,
+        output: Output,
+    ) -> UI {
         UI {
             events,
+            refresh,
+            output,
+            json: Vec::new(),
+            subscribers: Vec::new(),
             ports: HashMap::new(),
-            socks_port: None,
+            group_sizes: HashMap::new(),
+            socks: watch::channel(Vec::new()).0,
+            sockets: Vec::new(),
             running: true,
+            seen_ports: false,
+            active: Instant::now(),
             show_logs: false,
             selection: TableState::default(),
             lines: VecDeque::with_capacity(1024),
             config,
+            stats: SessionStats::new(),
+            history,
             alternate_screen: false,
             raw_mode: false,
         }
     }
 
     pub async fn run(&mut self) -> Result<UIReturn> {
+        if self.output != Output::Tui {
+            return self.run_json().await;
+        }
+        self.running = true;
+        let mut tick = interval(TICK_INTERVAL);
+        tick.set_missed_tick_behavior(MissedTickBehavior::Delay);
         loop {
-            while !self.connected() {
-                let ev = self.events.recv().await;
-                self.handle_internal_event(ev);
+            while self.running && !self.connected() {
+                tokio::select! {
+                    ev = self.events.recv() => self.handle_internal_event(ev),
+                    _ = tick.tick() => self.tick(),
+                }
+            }
+            if !self.running {
+                return Ok(UIReturn::Quit);
             }
 
             let result = self.run_connected().await;
@@ -193,6 +473,23 @@ impl UI {
         }
     }
 
+    pub fn stats(&self) -> &SessionStats {
+        &self.stats
+    }
+
+    /// Stop listening on all the forwarded ports, because we're quitting.
+    pub fn stop_listening(&mut self) {
+        for listener in self.ports.values_mut() {
+            listener.stop = None;
+        }
+    }
+
+    /// Put the terminal back the way we found it.
+    pub fn close(&mut self) {
+        _ = self.disable_raw_mode();
+        _ = self.leave_alternate_screen();
+    }
+
     async fn run_connected(&mut self) -> Result<UIReturn> {
         let mut console_events = EventStream::new();
         self.enter_alternate_screen()?;
@@ -202,9 +499,11 @@ impl UI {
         let backend = CrosstermBackend::new(stdout);
         let mut terminal = Terminal::new(backend)?;
 
-        self.running = true;
+        let mut tick = interval(TICK_INTERVAL);
+        tick.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
         while self.running && self.connected() {
-            self.handle_events(&mut console_events).await;
+            self.handle_events(&mut console_events, &mut tick).await;
             terminal.draw(|f| {
                 self.render_connected(f);
             })?;
@@ -218,6 +517,96 @@ impl UI {
         Ok(code)
     }
 
+    /// Like `run`, but never touch the terminal; just write what happens to
+    /// stdout, if anything, until the client goes away.
+    async fn run_json(&mut self) -> Result<UIReturn> {
+        let mut tick = interval(TICK_INTERVAL);
+        tick.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        self.running = true;
+        while self.running {
+            tokio::select! {
+                ev = self.events.recv() => self.handle_internal_event(ev),
+                _ = tick.tick() => self.tick(),
+            }
+            if self.json.is_empty() {
+                continue;
+            }
+
+            let mut stdout = stdout().lock();
+            for event in self.json.drain(..) {
+                writeln!(stdout, "{event}")?;
+            }
+            stdout.flush()?;
+        }
+        Ok(UIReturn::Quit)
+    }
+
+    /// The ports, in the order we show them, for a `ClientHandle`.
+    fn port_infos(&self) -> Vec<PortInfo> {
+        self.get_ui_ports()
+            .into_iter()
+            .map(|port| {
+                let listener = &self.ports[&port];
+                PortInfo {
+                    port,
+                    desc: self.description(port),
+                    enabled: listener.enabled,
+                    pending: listener.pending,
+                    local: listener
+                        .bound_port
+                        .map(|p| SocketAddr::new(listener.bind, p)),
+                }
+            })
+            .collect()
+    }
+
+    /// What we say is on the port: whatever the config calls it, or else
+    /// what the server told us.
+    fn description(&self, port: u16) -> String {
+        match (self.config.get(port).description, &self.ports[&port].desc) {
+            (Some(desc), _) => desc,
+            (None, Some(desc)) => desc.desc.clone(),
+            (None, None) => String::new(),
+        }
+    }
+
+    /// Queue up an event for JSON output, if that's what we're doing, and
+    /// pass it on to anybody listening. Like log lines, events are dropped
+    /// for listeners that don't keep up, so that they can't hold us up.
+    fn emit(&mut self, event: ClientEvent) {
+        if self.output == Output::Json {
+            self.json.push(event.to_json());
+        }
+        self.subscribers.retain(|s| !s.is_closed());
+        for subscriber in self.subscribers.iter() {
+            _ = subscriber.try_send(event.clone());
+        }
+    }
+
+    /// What to say about a port that just came up, for a notification.
+    fn port_up(&self, port: u16) -> String {
+        let desc = self.description(port);
+        if desc.is_empty() {
+            format!("Port {port} is up")
+        } else {
+            format!("Port {port} is up: {desc}")
+        }
+    }
+
+    fn port_added(&mut self, port: u16) {
+        if let Some(listener) = self.ports.get(&port) {
+            let event = ClientEvent::PortAdded {
+                port,
+                desc: listener.desc.as_ref().map(|d| d.desc.clone()),
+                enabled: listener.enabled,
+                local: listener.local_addr(port),
+                pending: listener.pending,
+            };
+            self.emit(event);
+        }
+    }
+
     fn render_connected<T: Backend>(&mut self, frame: &mut Frame<T>) {
         let constraints = if self.show_logs {
             vec![Constraint::Percentage(50), Constraint::Percentage(50)]
@@ -239,43 +628,121 @@ impl UI {
     fn render_ports<B: Backend>(&mut self, frame: &mut Frame<B>, size: Rect) {
         let enabled_port_style = Style::default();
         let disabled_port_style = Style::default().fg(Color::DarkGray);
+        let pending_port_style = Style::default().fg(Color::Yellow);
+        let group_style = Style::default().add_modifier(Modifier::BOLD);
 
+        // Ports that belong to the same process get a heading row with the
+        // process description, so the table has more rows than there are
+        // ports. The selection is tracked by port index, so we need a
+        // separate state for the rendered table.
         let mut rows = Vec::new();
+        let mut table_selection = TableState::default();
+        let selected = self.get_selected_port();
         let ports = self.get_ui_ports();
-        let port_strings: Vec<_> =
-            ports.iter().map(|p| format!("{p}")).collect();
-        for (index, port) in ports.into_iter().enumerate() {
-            let listener = self.ports.get(&port).unwrap();
-            rows.push(
-                Row::new(vec![
-                    &port_strings[index][..],
-                    match &listener.desc {
-                        Some(port_desc) => &port_desc.desc,
-                        None => "",
-                    },
-                ])
-                .style(if listener.enabled {
+        let mut current_group = None;
+        for port in ports.iter() {
+            let listener = self.ports.get(port).unwrap();
+            let group = self.get_group(*port);
+            let config_desc = self.config.get(*port).description;
+
+            let desc = match group {
+                Some(name) if self.group_size(name) > 1 => {
+                    if current_group != group {
+                        rows.push(
+                            Row::new(vec![
+                                "".to_string(),
+                                "".to_string(),
+                                "".to_string(),
+                                name.to_string(),
+                            ])
+                            .style(group_style),
+                        );
+                    }
+                    config_desc.unwrap_or_default()
+                }
+                _ => match (config_desc, group) {
+                    (Some(desc), _) => desc,
+                    (None, Some(desc)) => desc.to_string(),
+                    (None, None) => "".to_string(),
+                },
+            };
+            current_group = group;
+
+            // The pid is only for showing; it isn't part of the description
+            // that ports are grouped and picked by.
+            let desc = match listener.desc.as_ref().and_then(|d| d.pid) {
+                Some(pid) => format!("{desc} (pid {pid})").trim_start().into(),
+                None => desc,
+            };
+
+            // If we've seen what protocol the port speaks, say so.
+            let desc = match (listener.stats.protocol(), desc.is_empty()) {
+                (Some(protocol), true) => format!("[{protocol}]"),
+                (Some(protocol), false) => format!("{desc} [{protocol}]"),
+                (None, _) => desc,
+            };
+            let desc = if listener.pending {
+                format!("{desc} (press a to allow)")
+                    .trim_start()
+                    .to_string()
+            } else {
+                desc
+            };
+
+            let port_text = match listener.local_port(*port) {
+                local_port if local_port != *port => {
+                    format!("{port}→{local_port}")
+                }
+                _ => format!("{port}"),
+            };
+
+            let open = match listener.stats.open_connections() {
+                0 => "".to_string(),
+                n => n.to_string(),
+            };
+            let rate = match listener.rate {
+                0 => "".to_string(),
+                n => format!("{}/s", format_bytes(n)),
+            };
+
+            if selected == Some(*port) {
+                table_selection.select(Some(rows.len()));
+            }
+            rows.push(Row::new(vec![port_text, open, rate, desc]).style(
+                if listener.enabled {
                     enabled_port_style
+                } else if listener.pending {
+                    pending_port_style
                 } else {
                     disabled_port_style
-                }),
-            );
+                },
+            ));
         }
 
         // TODO: I don't know how to express the lengths I want here.
         //       That last length is extremely wrong but guaranteed to work I
         //       guess.
-        let widths =
-            vec![Constraint::Length(5), Constraint::Length(size.width)];
+        let port_width =
+            if self.ports.iter().any(|(p, l)| l.local_port(*p) != *p) {
+                11 // Room for `65535→65535`
+            } else {
+                5
+            };
+        let widths = vec![
+            Constraint::Length(port_width),
+            Constraint::Length(5),
+            Constraint::Length(12), // Room for `1023.9 KiB/s`
+            Constraint::Length(size.width),
+        ];
 
         let port_list = Table::new(rows)
-            .header(Row::new(vec!["Port", "Description"]))
+            .header(Row::new(vec!["Port", "Conns", "Traffic", "Description"]))
             .block(Block::default().title("Ports").borders(Borders::ALL))
             .column_spacing(1)
             .widths(&widths)
             .highlight_symbol(">> ");
 
-        frame.render_stateful_widget(port_list, size, &mut self.selection);
+        frame.render_stateful_widget(port_list, size, &mut table_selection);
     }
 
     fn render_logs<B: Backend>(&mut self, frame: &mut Frame<B>, size: Rect) {
@@ -286,7 +753,7 @@ impl UI {
             .block(Block::default().title("Log").borders(Borders::ALL));
 
         let mut list_state = ListState::default();
-        list_state.select(if self.lines.len() > 0 {
+        list_state.select(if !self.lines.is_empty() {
             Some(self.lines.len() - 1)
         } else {
             None
@@ -295,16 +762,87 @@ impl UI {
         frame.render_stateful_widget(list, size, &mut list_state);
     }
 
-    fn connected(&self) -> bool {
-        match self.socks_port {
-            Some(_) => true,
-            None => false,
+    pub fn connected(&self) -> bool {
+        !self.socks.borrow().is_empty()
+    }
+
+    /// Where to connect locally to reach the remote `port`, if we're
+    /// forwarding it.
+    fn local_address(&self, port: u16) -> Option<SocketAddr> {
+        let listener = self.ports.get(&port)?;
+        if !listener.enabled {
+            return None;
+        }
+        let ip = match listener.bind {
+            IpAddr::V4(ip) if ip.is_unspecified() => {
+                IpAddr::V4(Ipv4Addr::LOCALHOST)
+            }
+            IpAddr::V6(ip) if ip.is_unspecified() => {
+                IpAddr::V6(Ipv6Addr::LOCALHOST)
+            }
+            ip => ip,
+        };
+        Some(SocketAddr::new(ip, listener.local_port(port)))
+    }
+
+    /// Open a URL that the server sent us, pointing it at our end of the
+    /// forward if it's for a port on the server's loopback.
+    fn browse(&self, url: &str) {
+        let url = match local_url(url, |port| self.local_address(port)) {
+            Ok(url) => url,
+            Err(Some(port)) => {
+                warn!(
+                    "Port {port} isn't being forwarded; opening {url} anyway"
+                );
+                url.to_string()
+            }
+            Err(None) => url.to_string(),
+        };
+        info!("Browsing to {url}...");
+        _ = open::that(url);
+    }
+
+    /// Get the description of the process that owns the port, which is what
+    /// we use to group ports together.
+    fn get_group(&self, port: u16) -> Option<&str> {
+        match self.ports.get(&port).and_then(|l| l.desc.as_ref()) {
+            Some(desc) if !desc.desc.is_empty() => Some(&desc.desc),
+            _ => None,
         }
     }
 
+    fn group_size(&self, group: &str) -> usize {
+        self.group_sizes.get(group).copied().unwrap_or(0)
+    }
+
+    fn count_groups(&mut self) {
+        let mut sizes = HashMap::new();
+        for port in self.ports.keys() {
+            if let Some(group) = self.get_group(*port) {
+                *sizes.entry(group.to_string()).or_default() += 1;
+            }
+        }
+        self.group_sizes = sizes;
+    }
+
+    /// Get the ports in the order they are displayed: ports owned by the same
+    /// process are kept together, and the groups are ordered by their lowest
+    /// port.
     fn get_ui_ports(&self) -> Vec<u16> {
         let mut ports: Vec<u16> = self.ports.keys().copied().collect();
         ports.sort();
+
+        let mut first_ports: HashMap<&str, u16> = HashMap::new();
+        for port in ports.iter() {
+            if let Some(group) = self.get_group(*port) {
+                first_ports.entry(group).or_insert(*port);
+            }
+        }
+
+        ports.sort_by_key(|p| match self.get_group(*p) {
+            Some(group) => (first_ports[group], *p),
+            None => (*p, *p),
+        });
         ports
     }
 
@@ -312,9 +850,137 @@ impl UI {
         self.selection.selected().map(|i| self.get_ui_ports()[i])
     }
 
+    /// Do what `fwd ctl` asked, and say what happened.
+    fn control(&mut self, command: Command) -> Result<String, String> {
+        match command {
+            Command::Status => Ok(self.status()),
+            Command::Connections => Ok(self.connections()),
+            Command::Forward(port)
+            | Command::Stop(port)
+            | Command::Allow(port) => {
+                let enabled = command != Command::Stop(port);
+                let Some(listener) = self.ports.get_mut(&port) else {
+                    return Err(format!("Port {port} isn't listening\n"));
+                };
+                listener.set_enabled(enabled);
+                if enabled {
+                    Ok(format!("Forwarding port {port}\n"))
+                } else {
+                    Ok(format!("Stopped forwarding port {port}\n"))
+                }
+            }
+            Command::Refresh => {
+                self.refresh.notify_one();
+                Ok("Refreshing\n".to_string())
+            }
+            Command::Quit => {
+                self.running = false;
+                Ok("Stopping\n".to_string())
+            }
+        }
+    }
+
+    /// The table of ports for `fwd status`.
+    fn status(&self) -> String {
+        let mut status = format!(
+            "{:<5} {:<21} {:>5} {}\n",
+            "Port", "Forwarded to", "Conns", "Description"
+        );
+        for port in self.get_ui_ports() {
+            let listener = &self.ports[&port];
+            let local = if listener.enabled {
+                listener.local(port)
+            } else if listener.pending {
+                "waiting to be allowed".to_string()
+            } else {
+                "-".to_string()
+            };
+            status.push_str(&format!(
+                "{:<5} {:<21} {:>5} {}\n",
+                port,
+                local,
+                listener.stats.open_connections(),
+                self.description(port)
+            ));
+        }
+        let sockets = self.unforwarded_sockets();
+        if !sockets.is_empty() {
+            status.push_str("\nUnix domain sockets that aren't forwarded:\n");
+            for path in sockets {
+                status.push_str(&format!("  {path}\n"));
+            }
+        }
+        status
+    }
+
+    /// The table of open connections for `fwd ctl connections`, with the
+    /// ones that moved the most data first.
+    fn connections(&self) -> String {
+        let mut connections: Vec<_> = self
+            .ports
+            .iter()
+            .flat_map(|(port, l)| {
+                l.stats.open().into_iter().map(|c| (*port, c))
+            })
+            .collect();
+        connections.sort_by_key(|(port, c)| {
+            (
+                std::cmp::Reverse(c.bytes_sent() + c.bytes_received()),
+                *port,
+            )
+        });
+
+        let mut table = format!(
+            "{:<5} {:<21} {:>7} {:>10} {:>10}\n",
+            "Port", "From", "Age", "Sent", "Received"
+        );
+        for (port, connection) in connections {
+            table.push_str(&format!(
+                "{:<5} {:<21} {:>7} {:>10} {:>10}\n",
+                port,
+                connection.peer,
+                format_duration(connection.started.elapsed()),
+                format_bytes(connection.bytes_sent()),
+                format_bytes(connection.bytes_received()),
+            ));
+        }
+        table
+    }
+
+    /// Take up a new config without reconnecting: ports whose rules changed
+    /// are started, stopped or moved to match, and the next list of ports
+    /// takes care of the rest.
+    fn reconfigure(&mut self, config: ServerConfig) {
+        let old = std::mem::replace(&mut self.config, config);
+        let (bind, tuning) = (self.config.bind(), self.config.tuning());
+        for (&port, listener) in self.ports.iter_mut() {
+            let desc = listener.desc.as_ref().map_or("", |d| d.desc.as_str());
+            let was = old.get_for_desc(port, desc);
+            let config = self.config.get_for_desc(port, desc);
+            if was != config || old.bind() != bind || old.tuning() != tuning {
+                info!("Port config {port} -> {config:?}");
+                listener.reconfigure(&was, config, bind, tuning);
+            }
+        }
+        // Ports that were only here because the config named them.
+        let config = &self.config;
+        self.ports
+            .retain(|&port, l| l.desc.is_some() || config.contains_key(port));
+        self.count_groups();
+        self.refresh.notify_one();
+    }
+
     fn enable_disable_port(&mut self, port: u16) {
         if let Some(listener) = self.ports.get_mut(&port) {
-            listener.set_enabled(self.socks_port, !listener.enabled());
+            listener.set_enabled(!listener.enabled());
+        }
+    }
+
+    fn allow_port(&mut self, port: u16) {
+        if let Some(listener) = self.ports.get_mut(&port) {
+            if listener.pending {
+                listener.set_enabled(true);
+            }
         }
     }
 
@@ -352,10 +1018,40 @@ impl UI {
         Ok(())
     }
 
-    async fn handle_events(&mut self, console_events: &mut EventStream) {
+    async fn handle_events(
+        &mut self,
+        console_events: &mut EventStream,
+        tick: &mut Interval,
+    ) {
         tokio::select! {
             ev = console_events.next() => self.handle_console_event(ev),
             ev = self.events.recv() => self.handle_internal_event(ev),
+            _ = tick.tick() => self.tick(),
+        }
+    }
+
+    fn tick(&mut self) {
+        let mut events = Vec::new();
+        for (port, listener) in self.ports.iter_mut() {
+            let activity = listener.tick();
+            if activity.opened > 0 || listener.stats.open_connections() > 0 {
+                self.active = Instant::now();
+            }
+            events.extend(activity.events(*port));
+        }
+        for event in events {
+            self.emit(event);
+        }
+
+        // Connected or not, a tunnel nobody uses is one somebody forgot.
+        if let Some(idle_exit) = self.config.idle_exit() {
+            if self.running && self.active.elapsed() >= idle_exit {
+                info!(
+                    "Nothing has used the ports in {}, shutting down",
+                    format_duration(idle_exit)
+                );
+                self.running = false;
+            }
         }
     }
 
@@ -365,15 +1061,19 @@ impl UI {
     ) {
         match ev {
             Some(Ok(Event::Key(ev))) => match ev {
-                KeyEvent { code: KeyCode::Char('c'), .. } => {
-                    if ev.modifiers.intersects(KeyModifiers::CONTROL) {
-                        self.running = false;
-                    }
+                KeyEvent { code: KeyCode::Char('c'), .. }
+                    if ev.modifiers.intersects(KeyModifiers::CONTROL) =>
+                {
+                    self.running = false;
                 }
                 KeyEvent { code: KeyCode::Esc, .. }
                 | KeyEvent { code: KeyCode::Char('q'), .. } => {
                     self.running = false;
                 }
+                KeyEvent { code: KeyCode::Char('r'), .. } => {
+                    info!("Refreshing...");
+                    self.refresh.notify_one();
+                }
                 KeyEvent { code: KeyCode::Char('l'), .. } => {
                     self.show_logs = !self.show_logs;
                 }
@@ -382,6 +1082,11 @@ impl UI {
                         self.enable_disable_port(p);
                     }
                 }
+                KeyEvent { code: KeyCode::Char('a'), .. } => {
+                    if let Some(p) = self.get_selected_port() {
+                        self.allow_port(p);
+                    }
+                }
                 KeyEvent { code: KeyCode::Up, .. }
                 | KeyEvent { code: KeyCode::Char('j'), .. } => {
                     let index = match self.selection.selected() {
@@ -400,7 +1105,7 @@ impl UI {
                 | KeyEvent { code: KeyCode::Char('k'), .. } => {
                     let index = match self.selection.selected() {
                         Some(i) => {
-                            assert!(self.ports.len() > 0, "We must have ports because we have a selection.");
+                            assert!(!self.ports.is_empty(), "We must have ports because we have a selection.");
                             (i + 1).min(self.ports.len() - 1)
                         }
                         None => 0,
@@ -409,7 +1114,10 @@ impl UI {
                 }
                 KeyEvent { code: KeyCode::Enter, .. } => {
                     if let Some(p) = self.get_selected_port() {
-                        _ = open::that(format!("http://127.0.0.1:{}/", p));
+                        let address = self.local_address(p).unwrap_or(
+                            SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), p),
+                        );
+                        _ = open::that(format!("http://{address}/"));
                     }
                 }
                 _ => (),
@@ -420,56 +1128,123 @@ impl UI {
         }
     }
 
+    /// The server's Unix domain sockets that the config doesn't forward.
+    /// ssh only forwards the ones it's started with, so we can't just start
+    /// forwarding one, but we can say it's there.
+    fn unforwarded_sockets(&self) -> Vec<&str> {
+        let forwarded = self.config.sockets();
+        self.sockets
+            .iter()
+            .filter(|s| !forwarded.iter().any(|(path, _)| path == *s))
+            .map(|s| &s[..])
+            .collect()
+    }
+
     fn handle_internal_event(&mut self, event: Option<UIEvent>) {
         match event {
             Some(UIEvent::Disconnected) => {
-                self.socks_port = None;
-                for port in self.ports.values_mut() {
-                    port.disconnect();
+                if self.connected() {
+                    self.history.record(HistoryEvent::Disconnected);
+                    self.emit(ClientEvent::Disconnected);
                 }
+                // The ports keep listening, so that they're still where they
+                // were once we're back; new connections wait for that. The
+                // first list of ports from the new session stops the ones
+                // that are gone.
+                self.socks.send_replace(Vec::new());
             }
-            Some(UIEvent::Connected(sp)) => {
-                info!("Socks port {sp}");
-                self.socks_port = Some(sp);
+            Some(UIEvent::Connected(ports)) => {
+                info!("SOCKS ports {ports:?}");
+                self.socks.send_replace(ports);
+                self.seen_ports = false;
+                self.stats.connected();
+                self.history.record(HistoryEvent::Connected);
+                self.emit(ClientEvent::Connected);
                 for port in self.ports.values_mut() {
-                    port.start(self.socks_port);
+                    port.start();
                 }
             }
-            Some(UIEvent::Ports(p)) => {
+            Some(UIEvent::Ports(mut p)) => {
+                // Ports that go to other hosts are there whatever the server
+                // has listening on them.
+                let host_ports = self.config.host_ports();
+                p.retain(|desc| !host_ports.contains(&desc.port));
+                p.extend(host_ports.into_iter().map(|port| PortDesc {
+                    port,
+                    desc: String::new(),
+                    pid: None,
+                }));
+
                 let mut leftover_ports: HashSet<u16> =
                     HashSet::from_iter(self.ports.keys().copied());
+                let mut changes = Vec::new();
 
                 for port_desc in p.into_iter() {
                     leftover_ports.remove(&port_desc.port);
                     if let Some(listener) = self.ports.get_mut(&port_desc.port)
                     {
-                        listener.connect(self.socks_port, port_desc);
+                        let added = listener.desc.is_none();
+                        if added {
+                            self.history.record(HistoryEvent::Up(
+                                port_desc.port,
+                                &port_desc.desc,
+                            ));
+                        }
+                        let port = port_desc.port;
+                        listener.connect(port_desc);
+                        if added {
+                            self.port_added(port);
+                            changes.push(self.port_up(port));
+                        }
                     } else {
-                        let config = self.config.get(port_desc.port);
+                        self.history.record(HistoryEvent::Up(
+                            port_desc.port,
+                            &port_desc.desc,
+                        ));
+                        let config = self
+                            .config
+                            .get_for_desc(port_desc.port, &port_desc.desc);
                         info!("Port config {port_desc:?} -> {config:?}");
 
-                        self.ports.insert(
-                            port_desc.port,
-                            Listener::from_desc(
-                                self.socks_port,
-                                port_desc,
-                                config.enabled,
-                            ),
+                        let port = port_desc.port;
+                        let stats = self.stats.port(port);
+                        let mut listener = Listener::from_desc(
+                            self.socks.subscribe(),
+                            port_desc,
+                            config,
+                            self.config.bind(),
+                            stats,
+                            self.history.clone(),
+                            self.config.tuning(),
                         );
+                        listener.pending = self.config.needs_approval(port);
+                        if listener.pending {
+                            info!("Port {port} is waiting to be allowed");
+                        }
+                        self.ports.insert(port, listener);
+                        self.port_added(port);
+                        changes.push(self.port_up(port));
                     }
                 }
 
                 for port in leftover_ports {
                     if let Some(listener) = self.ports.get_mut(&port) {
+                        let removed = listener.desc.is_some();
                         listener.disconnect();
+                        if removed {
+                            self.history.record(HistoryEvent::Down(port));
+                            self.emit(ClientEvent::PortRemoved { port });
+                            changes.push(format!("Port {port} went away"));
+                        }
                     }
 
                     if !self.config.contains_key(port) {
                         self.ports.remove(&port);
                     }
                 }
+                self.count_groups();
 
-                let selected = if self.ports.len() == 0 {
+                let selected = if self.ports.is_empty() {
                     None // No ports, no selection.
                 } else {
                     match self.selection.selected() {
@@ -478,6 +1253,44 @@ impl UI {
                     }
                 };
                 self.selection.select(selected);
+
+                // Everything is new on the first list after we connect, so
+                // that's no news.
+                if self.config.notify()
+                    && self.seen_ports
+                    && !changes.is_empty()
+                {
+                    notify::notify("fwd", &changes.join("\n"));
+                }
+                self.seen_ports = true;
+            }
+            Some(UIEvent::Config(config)) => {
+                info!("Reloaded the configuration");
+                self.reconfigure(*config);
+            }
+            Some(UIEvent::Control(command, reply)) => {
+                _ = reply.send(self.control(command));
+            }
+            Some(UIEvent::List(reply)) => {
+                _ = reply.send(self.port_infos());
+            }
+            Some(UIEvent::Subscribe(subscriber)) => {
+                self.subscribers.push(subscriber);
+            }
+            Some(UIEvent::Sockets(sockets)) => {
+                let old = std::mem::replace(&mut self.sockets, sockets);
+                for path in self.unforwarded_sockets() {
+                    if !old.iter().any(|o| o == path) {
+                        info!(
+                            "The server has a Unix domain socket at {path}; \
+                             add it to `sockets` in ~/.fwd to forward it"
+                        );
+                    }
+                }
+            }
+            Some(UIEvent::Browse(url)) => {
+                self.emit(ClientEvent::Browse { url: url.clone() });
+                self.browse(&url);
             }
             Some(UIEvent::ServerLine(line)) => {
                 while self.lines.len() >= 1024 {
@@ -485,7 +1298,11 @@ impl UI {
                 }
                 self.lines.push_back(format!("[SERVER] {line}"));
             }
-            Some(UIEvent::LogLine(_level, line)) => {
+            Some(UIEvent::LogLine(level, line)) => {
+                if level == Level::Error {
+                    self.stats.error(line.clone());
+                    self.emit(ClientEvent::Error { message: line.clone() });
+                }
                 while self.lines.len() >= 1024 {
                     self.lines.pop_front();
                 }
@@ -498,28 +1315,103 @@ impl UI {
     }
 }
 
+/// Rewrite a URL for something on the server's loopback, like
+/// `http://localhost:8080/foo`, to go to wherever we forward that port. If it
+/// isn't for the server's loopback there's nothing to do, and we return
+/// `Err(None)`; if we don't forward it we return `Err` with the port.
+fn local_url(
+    url: &str,
+    local_address: impl Fn(u16) -> Option<SocketAddr>,
+) -> Result<String, Option<u16>> {
+    let (scheme, rest) = url.split_once("://").ok_or(None)?;
+    let end = rest.find(['/', '?', '#']).unwrap_or(rest.len());
+    let (authority, path) = rest.split_at(end);
+    if authority.contains('@') {
+        return Err(None); // Don't go mangling credentials.
+    }
+
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) if !port.contains(']') => {
+            (host, Some(port.parse().map_err(|_| None)?))
+        }
+        _ => (authority, None),
+    };
+    let local = match host.trim_start_matches('[').trim_end_matches(']') {
+        "localhost" => true,
+        host => match host.parse::<IpAddr>() {
+            Ok(ip) => ip.is_loopback() || ip.is_unspecified(),
+            Err(_) => false,
+        },
+    };
+    let port = match (local, port, scheme) {
+        (false, _, _) => return Err(None),
+        (true, Some(port), _) => port,
+        (true, None, "http") => 80,
+        (true, None, "https") => 443,
+        (true, None, _) => return Err(None),
+    };
+
+    match local_address(port) {
+        Some(address) => Ok(format!("{scheme}://{address}{path}")),
+        None => Err(Some(port)),
+    }
+}
+
 impl Drop for UI {
     fn drop(&mut self) {
-        _ = self.disable_raw_mode();
-        _ = self.leave_alternate_screen();
+        self.close();
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::client::config::SocketTarget;
     use assert_matches::assert_matches;
+    use serde_json::json;
+
+    #[test]
+    fn local_urls() {
+        let local = |port| match port {
+            8080 => Some("127.0.0.1:18080".parse().unwrap()),
+            80 => Some("[::1]:8000".parse().unwrap()),
+            _ => None,
+        };
+        assert_eq!(
+            local_url("http://localhost:8080/foo?x=1", local),
+            Ok("http://127.0.0.1:18080/foo?x=1".to_string())
+        );
+        assert_eq!(
+            local_url("http://[::1]:8080", local),
+            Ok("http://127.0.0.1:18080".to_string())
+        );
+        assert_eq!(
+            local_url("http://0.0.0.0/", local),
+            Ok("http://[::1]:8000/".to_string())
+        );
+        assert_eq!(local_url("http://127.0.0.1:3000/", local), Err(Some(3000)));
+        assert_eq!(local_url("https://example.com:8080/", local), Err(None));
+        assert_eq!(local_url("http://me@localhost:8080/", local), Err(None));
+        assert_eq!(local_url("mailto:someone", local), Err(None));
+    }
 
     #[test]
     fn empty_ports() {
         let (sender, receiver) = mpsc::channel(64);
         let config = ServerConfig::default();
-        let mut ui = UI::new(receiver, config);
+        let mut ui = UI::new(
+            receiver,
+            config,
+            Arc::new(History::disabled()),
+            Arc::new(Notify::new()),
+            Output::Tui,
+        );
 
         // There are ports...
         ui.handle_internal_event(Some(UIEvent::Ports(vec![PortDesc {
             port: 8080,
             desc: "my-service".to_string(),
+            pid: None,
         }])));
         ui.selection.select(Some(0));
 
@@ -535,17 +1427,25 @@ mod tests {
     fn port_change_selection() {
         let (sender, receiver) = mpsc::channel(64);
         let config = ServerConfig::default();
-        let mut ui = UI::new(receiver, config);
+        let mut ui = UI::new(
+            receiver,
+            config,
+            Arc::new(History::disabled()),
+            Arc::new(Notify::new()),
+            Output::Tui,
+        );
 
         // There are ports...
         ui.handle_internal_event(Some(UIEvent::Ports(vec![
             PortDesc {
                 port: 8080,
                 desc: "my-service".to_string(),
+                pid: None,
             },
             PortDesc {
                 port: 8081,
                 desc: "my-service".to_string(),
+                pid: None,
             },
         ])));
         ui.selection.select(Some(1));
@@ -554,6 +1454,7 @@ mod tests {
         ui.handle_internal_event(Some(UIEvent::Ports(vec![PortDesc {
             port: 8080,
             desc: "my-service".to_string(),
+            pid: None,
         }])));
         assert_eq!(ui.ports.len(), 1);
         assert_matches!(ui.selection.selected(), Some(0));
@@ -563,10 +1464,12 @@ mod tests {
             PortDesc {
                 port: 8080,
                 desc: "my-service".to_string(),
+                pid: None,
             },
             PortDesc {
                 port: 8081,
                 desc: "my-service".to_string(),
+                pid: None,
             },
         ])));
         assert_eq!(ui.ports.len(), 2);
@@ -575,11 +1478,439 @@ mod tests {
         drop(sender);
     }
 
+    #[test]
+    fn ports_grouped_by_process() {
+        let (sender, receiver) = mpsc::channel(64);
+        let config = ServerConfig::default();
+        let mut ui = UI::new(
+            receiver,
+            config,
+            Arc::new(History::disabled()),
+            Arc::new(Notify::new()),
+            Output::Tui,
+        );
+
+        ui.handle_internal_event(Some(UIEvent::Ports(vec![
+            PortDesc {
+                port: 9000,
+                desc: "docker-compose up".to_string(),
+                pid: Some(4242),
+            },
+            PortDesc {
+                port: 8080,
+                desc: "docker-compose up".to_string(),
+                pid: Some(4243),
+            },
+            PortDesc {
+                port: 8081,
+                desc: "python -m http.server".to_string(),
+                pid: Some(4300),
+            },
+            PortDesc {
+                port: 3000,
+                desc: "".to_string(),
+                pid: None,
+            },
+        ])));
+
+        // Groups are ordered by their lowest port, and ports without a
+        // description are never grouped. Pids don't split a group up.
+        assert_eq!(ui.get_ui_ports(), vec![3000, 8080, 9000, 8081]);
+        assert_eq!(ui.group_size("docker-compose up"), 2);
+        assert_eq!(ui.get_group(3000), None);
+
+        // Selection follows the display order.
+        ui.selection.select(Some(2));
+        assert_eq!(ui.get_selected_port(), Some(9000));
+
+        drop(sender);
+    }
+
+    #[test]
+    fn approval() {
+        let (sender, receiver) = mpsc::channel(64);
+        let mut config = ServerConfig::default();
+        config.set_approve(true);
+        let mut ui = UI::new(
+            receiver,
+            config,
+            Arc::new(History::disabled()),
+            Arc::new(Notify::new()),
+            Output::Json,
+        );
+
+        ui.handle_internal_event(Some(UIEvent::Ports(vec![PortDesc {
+            port: 9000,
+            desc: "nc -l 9000".to_string(),
+            pid: None,
+        }])));
+        assert_eq!(
+            ui.json,
+            vec![json!({
+                "event": "port_added",
+                "port": 9000,
+                "desc": "nc -l 9000",
+                "enabled": false,
+                "local": "127.0.0.1:9000",
+                "pending": true,
+            })]
+        );
+        assert!(ui
+            .control(Command::Status)
+            .unwrap()
+            .contains("9000  waiting to be allowed"));
+
+        assert!(ui.control(Command::Allow(9000)).is_ok());
+        assert!(ui.ports[&9000].enabled);
+        assert!(!ui.ports[&9000].pending);
+        assert!(ui.control(Command::Allow(8080)).is_err());
+
+        drop(sender);
+    }
+
+    #[test]
+    fn other_hosts() {
+        let (sender, receiver) = mpsc::channel(64);
+        let mut config = ServerConfig::default();
+        config.set_host(5432, "db.internal".to_string());
+        let mut ui = UI::new(
+            receiver,
+            config,
+            Arc::new(History::disabled()),
+            Arc::new(Notify::new()),
+            Output::Json,
+        );
+
+        // What the server has on that port doesn't matter...
+        ui.handle_internal_event(Some(UIEvent::Ports(vec![PortDesc {
+            port: 5432,
+            desc: "postgres".to_string(),
+            pid: None,
+        }])));
+        let listener = &ui.ports[&5432];
+        assert_eq!(listener.host.as_deref(), Some("db.internal"));
+        assert_eq!(listener.desc.as_ref().unwrap().desc, "");
+        assert!(listener.enabled());
+
+        // ...and the port stays when it goes away.
+        ui.handle_internal_event(Some(UIEvent::Ports(vec![])));
+        assert!(ui.ports[&5432].desc.is_some());
+        assert!(ui
+            .control(Command::Status)
+            .unwrap()
+            .contains("db.internal:5432"));
+
+        drop(sender);
+    }
+
+    #[test]
+    fn json_events() {
+        let (sender, receiver) = mpsc::channel(64);
+        let config = ServerConfig::default();
+        let mut ui = UI::new(
+            receiver,
+            config,
+            Arc::new(History::disabled()),
+            Arc::new(Notify::new()),
+            Output::Json,
+        );
+
+        ui.handle_internal_event(Some(UIEvent::Ports(vec![PortDesc {
+            port: 8080,
+            desc: "my-service".to_string(),
+            pid: None,
+        }])));
+        assert_eq!(
+            ui.json,
+            vec![json!({
+                "event": "port_added",
+                "port": 8080,
+                "desc": "my-service",
+                "enabled": true,
+                "local": "127.0.0.1:8080",
+            })]
+        );
+        ui.json.clear();
+
+        // Two connections came and one went, moving some bytes...
+        let stats = ui.stats.port(8080);
+        let peer = "127.0.0.1:50000".parse().unwrap();
+        let first = stats.connection_opened(peer);
+        let _second = stats.connection_opened(peer);
+        first.add_sent(10);
+        drop(first);
+        ui.tick();
+        assert_eq!(
+            ui.json,
+            vec![
+                json!({"event": "connection_opened", "port": 8080}),
+                json!({"event": "connection_opened", "port": 8080}),
+                json!({"event": "connection_closed", "port": 8080}),
+                json!({
+                    "event": "bytes",
+                    "port": 8080,
+                    "sent": 10,
+                    "received": 0,
+                }),
+            ]
+        );
+        ui.json.clear();
+
+        // ...and then nothing happened.
+        ui.tick();
+        assert_eq!(ui.json, Vec::<Value>::new());
+
+        ui.handle_internal_event(Some(UIEvent::Ports(vec![])));
+        assert_eq!(
+            ui.json,
+            vec![json!({"event": "port_removed", "port": 8080})]
+        );
+
+        drop(sender);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn idle_exit() {
+        let (_sender, receiver) = mpsc::channel(64);
+        let mut config = ServerConfig::default();
+        config.map_port(8080, 0);
+        config.set_idle_exit(Duration::from_secs(60));
+        let mut ui = UI::new(
+            receiver,
+            config,
+            Arc::new(History::disabled()),
+            Arc::new(Notify::new()),
+            Output::Json,
+        );
+        let desc = PortDesc {
+            port: 8080,
+            desc: String::new(),
+            pid: None,
+        };
+        ui.handle_internal_event(Some(UIEvent::Connected(vec![1080])));
+        ui.handle_internal_event(Some(UIEvent::Ports(vec![desc])));
+
+        // A connection keeps us going for as long as it's open...
+        let peer = "127.0.0.1:50000".parse().unwrap();
+        let connection = ui.ports[&8080].stats.connection_opened(peer);
+        tokio::time::advance(Duration::from_secs(120)).await;
+        ui.tick();
+        assert!(ui.running);
+
+        // ...and once it's closed, nothing else does.
+        drop(connection);
+        tokio::time::advance(Duration::from_secs(59)).await;
+        ui.tick();
+        assert!(ui.running);
+        tokio::time::advance(Duration::from_secs(1)).await;
+        ui.tick();
+        assert!(!ui.running);
+    }
+
+    #[tokio::test]
+    async fn reload() {
+        let (sender, receiver) = mpsc::channel(64);
+        let mut config = ServerConfig::default();
+        config.map_port(8080, 0);
+        let mut ui = UI::new(
+            receiver,
+            config.clone(),
+            Arc::new(History::disabled()),
+            Arc::new(Notify::new()),
+            Output::Json,
+        );
+        let desc = |port| PortDesc { port, desc: String::new(), pid: None };
+        ui.handle_internal_event(Some(UIEvent::Connected(vec![1080])));
+        ui.handle_internal_event(Some(UIEvent::Ports(vec![desc(8080)])));
+        let bound = ui.ports[&8080].bound_port;
+        assert!(bound.is_some());
+
+        // The same rules leave the port alone...
+        ui.handle_internal_event(Some(UIEvent::Config(Box::new(
+            config.clone(),
+        ))));
+        assert_eq!(ui.ports[&8080].bound_port, bound);
+
+        // ...a new limit means a new listener...
+        config.set_limit(8080, 1000);
+        ui.handle_internal_event(Some(UIEvent::Config(Box::new(
+            config.clone(),
+        ))));
+        assert!(ui.ports[&8080].limit.is_some());
+        assert!(ui.ports[&8080].stop.is_some());
+
+        // ...what's done by hand sticks if the rules for it didn't change...
+        ui.enable_disable_port(8080);
+        config.set_limit(8081, 1000);
+        ui.handle_internal_event(Some(UIEvent::Config(Box::new(
+            config.clone(),
+        ))));
+        assert!(!ui.ports[&8080].enabled());
+        ui.enable_disable_port(8080);
+
+        // ...a port the config doesn't want any more stops...
+        let mut approve = ServerConfig::default();
+        approve.set_approve(true);
+        ui.handle_internal_event(Some(UIEvent::Config(Box::new(approve))));
+        assert!(!ui.ports[&8080].enabled());
+        assert!(ui.ports[&8080].stop.is_none());
+
+        // ...and starts again once it does.
+        ui.handle_internal_event(Some(UIEvent::Config(Box::new(config))));
+        assert!(ui.ports[&8080].enabled());
+        assert!(ui.ports[&8080].stop.is_some());
+
+        drop(sender);
+    }
+
+    #[tokio::test]
+    async fn reconnect_keeps_listening() {
+        let (sender, receiver) = mpsc::channel(64);
+        let mut config = ServerConfig::default();
+        config.map_port(8080, 0);
+        let mut ui = UI::new(
+            receiver,
+            config,
+            Arc::new(History::disabled()),
+            Arc::new(Notify::new()),
+            Output::Json,
+        );
+        let ports = || {
+            vec![PortDesc {
+                port: 8080,
+                desc: "my-service".to_string(),
+                pid: None,
+            }]
+        };
+
+        ui.handle_internal_event(Some(UIEvent::Connected(vec![1080])));
+        ui.handle_internal_event(Some(UIEvent::Ports(ports())));
+        let bound = ui.ports[&8080].bound_port;
+        assert!(bound.is_some());
+        ui.json.clear();
+
+        // A blip doesn't close the port, or make it news when it's back...
+        ui.handle_internal_event(Some(UIEvent::Disconnected));
+        assert!(ui.ports[&8080].stop.is_some());
+        ui.handle_internal_event(Some(UIEvent::Connected(vec![1081])));
+        ui.handle_internal_event(Some(UIEvent::Ports(ports())));
+        assert_eq!(ui.ports[&8080].bound_port, bound);
+        assert_eq!(
+            ui.json,
+            vec![
+                json!({"event": "disconnected"}),
+                json!({"event": "connected"})
+            ]
+        );
+
+        // ...but the port is closed if it didn't come back.
+        ui.handle_internal_event(Some(UIEvent::Disconnected));
+        ui.handle_internal_event(Some(UIEvent::Connected(vec![1082])));
+        ui.handle_internal_event(Some(UIEvent::Ports(vec![])));
+        assert!(ui.ports[&8080].stop.is_none());
+
+        drop(sender);
+    }
+
+    #[tokio::test]
+    async fn control() {
+        let (sender, receiver) = mpsc::channel(64);
+        let config = ServerConfig::default();
+        let refresh = Arc::new(Notify::new());
+        let mut ui = UI::new(
+            receiver,
+            config,
+            Arc::new(History::disabled()),
+            refresh.clone(),
+            Output::Tui,
+        );
+
+        ui.handle_internal_event(Some(UIEvent::Ports(vec![PortDesc {
+            port: 8080,
+            desc: "my-service".to_string(),
+            pid: None,
+        }])));
+        assert_eq!(
+            ui.control(Command::Status).unwrap(),
+            "Port  Forwarded to          Conns Description\n\
+             8080  127.0.0.1:8080            0 my-service\n"
+        );
+
+        assert!(ui.control(Command::Stop(8080)).is_ok());
+        assert!(!ui.ports[&8080].enabled);
+        assert!(ui.control(Command::Status).unwrap().contains("8080  - "));
+        assert!(ui.control(Command::Forward(8080)).is_ok());
+        assert!(ui.ports[&8080].enabled);
+        assert!(ui.control(Command::Forward(9000)).is_err());
+
+        // The busiest connection comes first.
+        let stats = ui.stats.port(8080);
+        let quiet = stats.connection_opened("127.0.0.1:50000".parse().unwrap());
+        let busy = stats.connection_opened("127.0.0.1:50001".parse().unwrap());
+        busy.add_received(2048);
+        let connections = ui.control(Command::Connections).unwrap();
+        let lines: Vec<_> = connections.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[1].starts_with("8080  127.0.0.1:50001"));
+        assert!(lines[1].ends_with("0 B    2.0 KiB"));
+        assert!(lines[2].starts_with("8080  127.0.0.1:50000"));
+        drop((quiet, busy));
+        assert_eq!(
+            ui.control(Command::Connections).unwrap().lines().count(),
+            1
+        );
+
+        assert!(ui.control(Command::Refresh).is_ok());
+        let notified = refresh.notified();
+        assert!(tokio::time::timeout(Duration::ZERO, notified).await.is_ok());
+
+        // Quitting works even when we're not connected.
+        let (reply, response) = oneshot::channel();
+        _ = sender.send(UIEvent::Control(Command::Quit, reply)).await;
+        assert_matches!(ui.run().await, Ok(UIReturn::Quit));
+        assert!(response.await.unwrap().is_ok());
+
+        drop(sender);
+    }
+
+    #[test]
+    fn sockets() {
+        let (sender, receiver) = mpsc::channel(64);
+        let mut config = ServerConfig::default();
+        let postgres = "/run/postgresql/.s.PGSQL.5432";
+        config.add_socket(postgres, SocketTarget::Port(5432));
+        let mut ui = UI::new(
+            receiver,
+            config,
+            Arc::new(History::disabled()),
+            Arc::new(Notify::new()),
+            Output::Json,
+        );
+        assert!(!ui.status().contains("Unix"));
+
+        ui.handle_internal_event(Some(UIEvent::Sockets(vec![
+            "/run/docker.sock".to_string(),
+            postgres.to_string(),
+        ])));
+        assert_eq!(ui.unforwarded_sockets(), ["/run/docker.sock"]);
+        assert!(ui.status().ends_with(
+            "\nUnix domain sockets that aren't forwarded:\n  /run/docker.sock\n"
+        ));
+
+        drop(sender);
+    }
+
     #[test]
     fn log_lines() {
         let (sender, receiver) = mpsc::channel(64);
         let config = ServerConfig::default();
-        let mut ui = UI::new(receiver, config);
+        let mut ui = UI::new(
+            receiver,
+            config,
+            Arc::new(History::disabled()),
+            Arc::new(Notify::new()),
+            Output::Tui,
+        );
 
         // Client and server are all formatted right you know.
         ui.handle_internal_event(Some(UIEvent::ServerLine("A".to_string())));
@@ -1,8 +1,18 @@
 mod browse;
 mod client;
-mod message;
+mod logging;
 mod server;
+mod update;
 
 pub use browse::browse_url;
-pub use client::run_client;
-pub use server::run_server;
+pub use client::{
+    list_ports, parse_duration, parse_host, parse_rate, parse_ssh_connections,
+    run_client, run_client_with_transport, run_clients, run_control, run_stdio,
+    run_with_port, show_history, ClientBuilder, ClientHandle, ClientOptions,
+    Command, Event, LocalTransport, Output, PortInfo, Profile, Session,
+    Transport,
+};
+pub use server::{
+    parse_port_range, run_server, run_server_with_io, ServerOptions,
+};
+pub use update::self_update;
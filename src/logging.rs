@@ -0,0 +1,152 @@
+use anyhow::{Context, Result};
+use log::{LevelFilter, Metadata};
+use std::fmt;
+
+/// The environment variable with extra directives for the filter, like
+/// `debug` or `info,fwd::server=trace`.
+pub const ENV_VAR: &str = "FWD_LOG";
+
+/// Decides which log records are worth showing: a level for everything,
+/// and levels for particular modules (and everything under them) that
+/// override it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Filter {
+    default: LevelFilter,
+    targets: Vec<(String, LevelFilter)>,
+}
+
+impl Filter {
+    pub const fn new(default: LevelFilter) -> Filter {
+        Filter { default, targets: Vec::new() }
+    }
+
+    /// The filter for `-v` given `verbosity` times.
+    pub fn verbosity(verbosity: u8) -> Filter {
+        Filter::new(match verbosity {
+            0 => LevelFilter::Info,
+            1 => LevelFilter::Debug,
+            _ => LevelFilter::Trace,
+        })
+    }
+
+    /// Apply the directives in `FWD_LOG`, if there are any.
+    pub fn with_env(self) -> Result<Filter> {
+        match std::env::var(ENV_VAR) {
+            Ok(spec) => self
+                .with_directives(&spec)
+                .with_context(|| format!("Error parsing {ENV_VAR}")),
+            Err(_) => Ok(self),
+        }
+    }
+
+    /// Apply comma-separated directives, each of which is either a level
+    /// for everything or `module=level`.
+    pub fn with_directives(mut self, spec: &str) -> Result<Filter> {
+        for directive in spec.split(',').map(str::trim) {
+            match directive.split_once('=') {
+                None if directive.is_empty() => (),
+                None => {
+                    self.default = directive
+                        .parse()
+                        .with_context(|| format!("Unknown level {directive}"))?
+                }
+                Some((target, level)) => {
+                    let level = level
+                        .parse()
+                        .with_context(|| format!("Unknown level {level}"))?;
+                    self.targets.retain(|(t, _)| t != target);
+                    self.targets.push((target.to_string(), level));
+                }
+            }
+        }
+        Ok(self)
+    }
+
+    /// The level for records from `target`, which is decided by the most
+    /// specific module that it's in.
+    pub fn level(&self, target: &str) -> LevelFilter {
+        self.targets
+            .iter()
+            .filter(|(t, _)| {
+                target == t
+                    || (target.starts_with(&t[..])
+                        && target[t.len()..].starts_with("::"))
+            })
+            .max_by_key(|(t, _)| t.len())
+            .map_or(self.default, |(_, level)| *level)
+    }
+
+    /// The most verbose level that anything gets, for `log::set_max_level`.
+    pub fn max_level(&self) -> LevelFilter {
+        self.targets
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.default, Ord::max)
+    }
+
+    pub fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level(metadata.target())
+    }
+}
+
+impl Default for Filter {
+    fn default() -> Filter {
+        Filter::new(LevelFilter::Info)
+    }
+}
+
+// Written the way `with_directives` reads it, so that the client can hand
+// its filter to the server.
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.default.as_str().to_lowercase())?;
+        for (target, level) in self.targets.iter() {
+            write!(f, ",{target}={}", level.as_str().to_lowercase())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::Level;
+
+    fn enabled(filter: &Filter, target: &str, level: Level) -> bool {
+        let metadata = Metadata::builder().target(target).level(level).build();
+        filter.enabled(&metadata)
+    }
+
+    #[test]
+    fn directives() {
+        let filter = Filter::default()
+            .with_directives("warn, fwd::client=debug,fwd::client::ui=error")
+            .unwrap();
+        assert!(!enabled(&filter, "fwd::server", Level::Info));
+        assert!(enabled(&filter, "fwd::server", Level::Warn));
+        assert!(enabled(&filter, "fwd::client", Level::Debug));
+        assert!(enabled(&filter, "fwd::client::transport", Level::Debug));
+        assert!(!enabled(&filter, "fwd::client::ui", Level::Warn));
+        assert!(!enabled(&filter, "fwd::clientele", Level::Debug));
+        assert_eq!(filter.max_level(), LevelFilter::Debug);
+
+        assert_eq!(
+            filter.to_string(),
+            "warn,fwd::client=debug,fwd::client::ui=error"
+        );
+        assert_eq!(
+            Filter::default()
+                .with_directives(&filter.to_string())
+                .unwrap(),
+            filter
+        );
+
+        assert_eq!(Filter::verbosity(2).to_string(), "trace");
+        assert!(Filter::default().with_directives("loud").is_err());
+        assert!(Filter::default().with_directives("fwd=loud").is_err());
+        assert_eq!(
+            Filter::default().with_directives("").unwrap(),
+            Filter::default()
+        );
+    }
+}
@@ -1,35 +1,156 @@
 // TODO: An actual proper command line parsing
+use fwd::{ClientOptions, Command, Output, Profile, ServerOptions};
 use indoc::indoc;
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
 fn usage() {
     println!(indoc! {"
-usage: fwd [--version] (<server> | browse <url>)
+usage: fwd [--version] ([options] <server>... |
+           list [--json] [options] <server> |
+           run [options] <server> -p <port> -- <command> |
+           stdio [options] <server>:[<host>:]<port> | browse <url> |
+           history <server> [<port>] | status [<server>] | stop [<server>] |
+           ctl [<server>] <command> | self-update)
 
 To connect a client to a server that has an `fwd` installed in its path, run
 `fwd <server>` on the client, where <server> is the name of the server to
 connect to.
 
+Name more than one <server> to forward ports from all of them at once, in one
+table with a column for which server each port is on. Each server gets its own
+ssh connection, and when two have the same port, the second is forwarded to
+another local port.
+
+Options:
+  --profile <profile>  Tune the connection for `latency`, `throughput`, or
+                       leave it `balanced` (the default)
+  --x11                Let programs run on the server open windows on the
+                       local display
+  --multiplex          Share one ssh connection between reconnects and other
+                       runs of fwd, so that you only log in once
+  --ssh-connections <n>
+                       Spread forwarded connections across <n> ssh
+                       connections, for more throughput on slow links
+  --bootstrap          If the server doesn't have fwd, copy this one there (to
+                       ~/.cache/fwd) and run that
+  --approve            Don't forward ports that the server finds until you
+                       allow them, with `a` or `fwd ctl allow <port>`
+  --notify             Show a desktop notification when ports on the server
+                       come and go
+  --idle-exit <time>   Shut down once no connection has used the forwarded
+                       ports for <time>, like 30m or 2h
+  --daemon             Run in the background instead of in this terminal,
+                       with what happens logged to a file
+  --reverse <port>     Make the local <port> available on the server too; can
+                       be given more than once
+  --port [<host>:]<port>
+                       Forward <port> without asking the server which ports
+                       are open, and no others; can be given more than once.
+                       With a <host>, connections go to <port> on <host>,
+                       as the server sees it, instead of on the server
+  --map <remote>:<local>
+                       Forward the remote port <remote> to the local port
+                       <local>; can be given more than once
+  --limit <port>=<rate>
+                       Move no more than <rate> (like 1MBps or 500KiB/s)
+                       through the remote <port>; can be given more than once
+  --socks <port>       Run the SOCKS5 proxy on local <port>, so that other
+                       programs can use it to reach anything the server can
+  --bind <address>     Listen for forwarded ports on <address> instead of
+                       127.0.0.1; use 0.0.0.0 to let other machines connect
+  --output json        Instead of showing the ports in the terminal, write
+                       what happens to stdout as one JSON object per line
+  -p <port>, -i <identity file>, -J <jump host>, -o <option>
+                       Passed on to ssh, like the same options to ssh; can
+                       be given more than once
+  -v, -vv              Log more about what's going on, on both ends; set
+                       FWD_LOG (like `debug` or `fwd::client=trace`) for
+                       finer control
+
 On a server that already has a client connected to it you can use `fwd browse
-<url>` to open `<url>` in the default browser of the client.
+<url>` (or `fwd open <url>`) to open `<url>` in the default browser of the
+client. URLs for ports on the server's loopback, like http://localhost:8080/,
+are pointed at the client's end of the forward.
+
+To see which ports the server has right now without forwarding any of them,
+run `fwd list <server>`, or `fwd list --json <server>` for a JSON array of
+them. It takes the same options as connecting does.
+
+`fwd run <server> -p <port> -- <command>` forwards ports like `fwd <server>`,
+waits until the server has <port> and it's forwarded, runs <command>, and
+stops once <command> exits, with its exit status. Here `-p` is the port to
+wait for; use `-o Port=<port>` for ssh's. <command> finds the local port in
+FWD_LOCAL_PORT, in case it isn't the same.
+
+`fwd stdio <server>:<port>` connects its stdin and stdout to <port> on the
+server, like netcat, or to <port> on <host> as the server sees it with
+`<server>:<host>:<port>`. It can be an ssh ProxyCommand, as in `ssh -o
+ProxyCommand='fwd stdio bastion:%h:%p' inner-host`.
+
+To see when ports on a server came and went, and when you used them, run
+`fwd history <server>`, or `fwd history <server> <port>` for just one port.
+
+While `fwd` is connected, `fwd status` in another terminal lists the ports and
+what's going on with them, `fwd ctl connections` lists the open connections
+with the busiest first, and `fwd ctl forward <port>`, `fwd ctl stop <port>`,
+`fwd ctl allow <port>` and `fwd ctl refresh` do what pressing keys in it
+would. Name the <server> if `fwd` is connected to more than one. `fwd stop`
+disconnects it, which is the way to stop one running with `--daemon`.
+
+`fwd --server`, which is what the client runs on the server, takes `--allow
+<ports>` and `--deny <ports>` (like 3000-4000 or 8080) for a forced command in
+`authorized_keys`. They only leave ports out of the list that clients are told
+about: clients can still connect to any port that sshd lets them, so use
+sshd's `permitopen` to keep them out.
+
+To update `fwd` to the latest release, run `fwd self-update`.
     "});
 }
 
+#[derive(Debug)]
+struct ClientArgs {
+    remote: String,
+    options: ClientOptions,
+}
+
 #[derive(Debug)]
 enum Args {
     Help,
     Version,
-    Server,
-    Client(String),
+    Server(ServerOptions),
+    Client(ClientArgs),
+    /// More than one server at once, each with the same options.
+    Clients(Vec<String>, ClientOptions),
+    List(ClientArgs),
+    Run(ClientArgs, u16, Vec<String>),
+    Stdio(ClientArgs, Option<String>, u16),
     Browse(String),
+    History(String, Option<u16>),
+    Control(Option<String>, Command),
+    SelfUpdate,
     Error,
 }
 
+/// The words that start a command instead of naming a server.
+const COMMANDS: &[&str] = &[
+    "browse",
+    "open",
+    "status",
+    "stop",
+    "list",
+    "run",
+    "stdio",
+    "ctl",
+    "history",
+    "self-update",
+];
+
 fn parse_args(args: Vec<String>) -> Args {
     // Look for help; allow it to come anywhere because sometimes you just
     // want to jam it on the end of an existing command line.
-    for arg in &args {
+    // Anything after `--` is somebody else's.
+    for arg in args.iter().take_while(|a| *a != "--") {
         if arg == "--help" || arg == "-?" || arg == "-h" {
             return Args::Help;
         }
@@ -38,17 +159,261 @@ fn parse_args(args: Vec<String>) -> Args {
     // No help, parse for reals.
     if args.len() >= 2 && args[1] == "--version" {
         Args::Version
-    } else if args.len() == 2 && &args[1] == "--server" {
-        Args::Server
-    } else if args.len() == 3 && args[1] == "browse" {
+    } else if args.len() >= 2 && &args[1] == "--server" {
+        parse_server_args(&args[2..])
+    } else if args.len() == 2 && args[1] == "self-update" {
+        Args::SelfUpdate
+    } else if args.len() == 3 && (args[1] == "browse" || args[1] == "open") {
         Args::Browse(args[2].to_string())
+    } else if args.len() == 2 && args[1] == "status" {
+        Args::Control(None, Command::Status)
+    } else if args.len() == 3 && args[1] == "status" {
+        Args::Control(Some(args[2].to_string()), Command::Status)
+    } else if args.len() == 2 && args[1] == "stop" {
+        Args::Control(None, Command::Quit)
+    } else if args.len() == 3 && args[1] == "stop" {
+        Args::Control(Some(args[2].to_string()), Command::Quit)
+    } else if args.len() >= 3 && args[1] == "list" {
+        parse_list_args(&args[2..])
+    } else if args.len() >= 3 && args[1] == "run" {
+        parse_run_args(&args[2..])
+    } else if args.len() >= 3 && args[1] == "stdio" {
+        parse_stdio_args(&args[2..])
+    } else if args.len() >= 3 && args[1] == "ctl" {
+        parse_control_args(&args[2..])
+    } else if args.len() == 3 && args[1] == "history" {
+        Args::History(args[2].to_string(), None)
+    } else if args.len() == 4 && args[1] == "history" {
+        match args[3].parse() {
+            Ok(port) => Args::History(args[2].to_string(), Some(port)),
+            Err(_) => Args::Error,
+        }
     } else {
-        if args.len() != 2 {
-            Args::Error
-        } else {
-            Args::Client(args[1].to_string())
+        match parse_client_args(&args[1..]) {
+            // A command with the wrong arguments isn't a list of servers.
+            Args::Clients(remotes, _)
+                if COMMANDS.contains(&&remotes[0][..]) =>
+            {
+                Args::Error
+            }
+            args => args,
+        }
+    }
+}
+
+// The server is optional and comes first, which is only ambiguous for
+// servers named after commands.
+fn parse_control_args(args: &[String]) -> Args {
+    if let Ok(command) = args.join(" ").parse() {
+        return Args::Control(None, command);
+    }
+    match args.split_first() {
+        Some((remote, command)) => match command.join(" ").parse() {
+            Ok(command) => Args::Control(Some(remote.to_string()), command),
+            Err(_) => Args::Error,
+        },
+        None => Args::Error,
+    }
+}
+
+// Either just a port, or `<host>:<port>` for a port on another host.
+fn parse_port(port: &str) -> Option<(Option<String>, u16)> {
+    match port.rsplit_once(':') {
+        None => Some((None, port.parse().ok()?)),
+        Some((host, port)) => {
+            Some((Some(fwd::parse_host(host).ok()?), port.parse().ok()?))
+        }
+    }
+}
+
+fn parse_limit(limit: &str) -> Option<(u16, u64)> {
+    let (port, rate) = limit.split_once('=')?;
+    Some((port.parse().ok()?, fwd::parse_rate(rate).ok()?))
+}
+
+fn parse_map(map: &str) -> Option<(u16, u16)> {
+    let (remote, local) = map.split_once(':')?;
+    Some((remote.parse().ok()?, local.parse().ok()?))
+}
+
+fn parse_server_args(args: &[String]) -> Args {
+    let mut options = ServerOptions::default();
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let ranges = match &arg[..] {
+            "--allow" => &mut options.allow,
+            "--deny" => &mut options.deny,
+            _ => return Args::Error,
+        };
+        match args.next().map(|r| fwd::parse_port_range(r)) {
+            Some(Ok(range)) => ranges.push(range),
+            _ => return Args::Error,
+        }
+    }
+
+    Args::Server(options)
+}
+
+// `list` takes what a client does, and `--json` is short for `--output
+// json`.
+fn parse_list_args(args: &[String]) -> Args {
+    let json = args.iter().any(|a| a == "--json");
+    let args: Vec<String> =
+        args.iter().filter(|a| *a != "--json").cloned().collect();
+    match parse_client_args(&args) {
+        Args::Client(mut client) => {
+            if json {
+                client.options.output = Output::Json;
+            }
+            Args::List(client)
+        }
+        Args::Clients(..) => Args::Error,
+        args => args,
+    }
+}
+
+// `run` takes what a client does too, except that `-p` is the port to wait
+// for instead of ssh's, and everything after `--` is the command to run.
+fn parse_run_args(args: &[String]) -> Args {
+    let Some(split) = args.iter().position(|a| a == "--") else {
+        return Args::Error;
+    };
+    let (args, command) = (&args[..split], &args[split + 1..]);
+    let mut port = None;
+    let mut client_args = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg != "-p" {
+            client_args.push(arg.clone());
+            continue;
+        }
+        match args.next().and_then(|p| p.parse().ok()) {
+            Some(p) if port.is_none() => port = Some(p),
+            _ => return Args::Error,
+        }
+    }
+    match (port, parse_client_args(&client_args)) {
+        (Some(port), Args::Client(client)) if !command.is_empty() => {
+            Args::Run(client, port, command.to_vec())
+        }
+        _ => Args::Error,
+    }
+}
+
+// `stdio` takes what a client does, with the port on the end of the server.
+fn parse_stdio_args(args: &[String]) -> Args {
+    let Args::Client(mut client) = parse_client_args(args) else {
+        return Args::Error;
+    };
+    let Some((remote, port)) = client.remote.split_once(':') else {
+        return Args::Error;
+    };
+    match parse_port(port) {
+        Some((host, port)) if !remote.is_empty() => {
+            client.remote = remote.to_string();
+            Args::Stdio(client, host, port)
+        }
+        _ => Args::Error,
+    }
+}
+
+fn parse_client_args(args: &[String]) -> Args {
+    let mut remotes: Vec<String> = Vec::new();
+    let mut options = ClientOptions::default();
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match &arg[..] {
+            "--profile" => {
+                match args.next().and_then(|p| p.parse::<Profile>().ok()) {
+                    Some(profile) => options.profile = Some(profile),
+                    None => return Args::Error,
+                }
+            }
+            "--x11" => options.x11 = true,
+            "--multiplex" => options.multiplex = true,
+            "--ssh-connections" => match args
+                .next()
+                .and_then(|n| fwd::parse_ssh_connections(n).ok())
+            {
+                Some(n) => options.ssh_connections = Some(n),
+                None => return Args::Error,
+            },
+            "--bootstrap" => options.bootstrap = true,
+            "--daemon" => options.daemon = true,
+            "--approve" => options.approve = true,
+            "--notify" => options.notify = true,
+            "--idle-exit" => {
+                match args.next().and_then(|d| fwd::parse_duration(d).ok()) {
+                    Some(idle_exit) => options.idle_exit = Some(idle_exit),
+                    None => return Args::Error,
+                }
+            }
+            "-v" => options.verbosity += 1,
+            "-vv" => options.verbosity += 2,
+            "--map" => match args.next().and_then(|m| parse_map(m)) {
+                Some(map) => options.map.push(map),
+                None => return Args::Error,
+            },
+            "--socks" => match args.next().and_then(|p| p.parse().ok()) {
+                Some(port) => options.socks = Some(port),
+                None => return Args::Error,
+            },
+            "--output" => {
+                match args.next().and_then(|o| o.parse::<Output>().ok()) {
+                    Some(output) => options.output = output,
+                    None => return Args::Error,
+                }
+            }
+            "--bind" => match args.next().and_then(|a| a.parse().ok()) {
+                Some(address) => options.bind = Some(address),
+                None => return Args::Error,
+            },
+            "--reverse" => match args.next().and_then(|p| p.parse().ok()) {
+                Some(port) => options.reverse.push(port),
+                None => return Args::Error,
+            },
+            "--limit" => match args.next().and_then(|l| parse_limit(l)) {
+                Some(limit) => options.limits.push(limit),
+                None => return Args::Error,
+            },
+            "--port" => match args.next().and_then(|p| parse_port(p)) {
+                Some((host, port)) => {
+                    options.ports.push(port);
+                    if let Some(host) = host {
+                        options.hosts.push((port, host));
+                    }
+                }
+                None => return Args::Error,
+            },
+            "-p" => match args.next().filter(|p| p.parse::<u16>().is_ok()) {
+                Some(port) => {
+                    options.ssh_args.extend(["-p".into(), port.into()])
+                }
+                None => return Args::Error,
+            },
+            "-i" | "-J" | "-o" => match args.next() {
+                Some(value) => {
+                    options.ssh_args.extend([arg.clone(), value.clone()])
+                }
+                None => return Args::Error,
+            },
+            flag if flag.starts_with('-') => return Args::Error,
+            _ => {
+                if remotes.contains(arg) {
+                    return Args::Error;
+                }
+                remotes.push(arg.to_string());
+            }
         }
     }
+
+    match remotes.len() {
+        0 => Args::Error,
+        1 => Args::Client(ClientArgs { remote: remotes.remove(0), options }),
+        _ => Args::Clients(remotes, options),
+    }
 }
 
 #[tokio::main]
@@ -60,14 +425,35 @@ async fn main() {
         Args::Version => {
             println!("fwd {VERSION}");
         }
-        Args::Server => {
-            fwd::run_server().await;
+        Args::Server(options) => {
+            fwd::run_server(options).await;
         }
         Args::Browse(url) => {
             fwd::browse_url(&url).await;
         }
-        Args::Client(server) => {
-            fwd::run_client(&server).await;
+        Args::History(remote, port) => {
+            fwd::show_history(&remote, port).await;
+        }
+        Args::Control(remote, command) => {
+            fwd::run_control(remote.as_deref(), command).await;
+        }
+        Args::SelfUpdate => {
+            fwd::self_update().await;
+        }
+        Args::Client(ClientArgs { remote, options }) => {
+            fwd::run_client(&remote, options).await;
+        }
+        Args::Clients(remotes, options) => {
+            fwd::run_clients(&remotes, options).await;
+        }
+        Args::Run(ClientArgs { remote, options }, port, command) => {
+            fwd::run_with_port(&remote, options, port, command).await;
+        }
+        Args::Stdio(ClientArgs { remote, options }, host, port) => {
+            fwd::run_stdio(&remote, options, host, port).await;
+        }
+        Args::List(ClientArgs { remote, options }) => {
+            fwd::list_ports(&remote, options).await;
         }
         Args::Error => {
             usage();
@@ -80,18 +466,18 @@ async fn main() {
 mod tests {
     use super::*;
     use assert_matches::assert_matches;
+    use std::time::Duration;
 
     // Goldarn it.
     fn args(x: &[&str]) -> Vec<String> {
-        let mut vec: Vec<String> =
-            x.into_iter().map(|a| a.to_string()).collect();
+        let mut vec: Vec<String> = x.iter().map(|a| a.to_string()).collect();
         vec.insert(0, "fwd".to_string());
         vec
     }
 
     macro_rules! assert_arg_parse {
-        ( $x:expr, $p:pat ) => {
-            assert_matches!(parse_args(args($x)), $p)
+        ( $x:expr, $p:pat $(if $guard:expr)? ) => {
+            assert_matches!(parse_args(args($x)), $p $(if $guard)?)
         };
     }
 
@@ -109,10 +495,26 @@ mod tests {
     fn errors() {
         assert_arg_parse!(&[], Args::Error);
         assert_arg_parse!(&["browse", "google.com", "what"], Args::Error);
-        assert_arg_parse!(&["a", "b"], Args::Error);
+        assert_arg_parse!(&["a", "a"], Args::Error);
         assert_arg_parse!(&["--server", "something"], Args::Error);
     }
 
+    #[test]
+    fn clients() {
+        assert_arg_parse!(
+            &["a", "--x11", "b"],
+            Args::Clients(ref remotes, ClientOptions { x11: true, .. })
+                if remotes == &["a", "b"]
+        );
+        assert_arg_parse!(&["history", "a", "b", "c"], Args::Error);
+        assert_arg_parse!(&["list", "a", "b"], Args::Error);
+        assert_arg_parse!(
+            &["run", "a", "b", "-p", "80", "--", "x"],
+            Args::Error
+        );
+        assert_arg_parse!(&["stdio", "a:22", "b:22"], Args::Error);
+    }
+
     #[test]
     fn client() {
         assert_arg_parse!(&["foo.com"], Args::Client(_));
@@ -120,13 +522,428 @@ mod tests {
         assert_arg_parse!(&["browse"], Args::Client(_));
     }
 
+    #[test]
+    fn client_profile() {
+        assert_arg_parse!(
+            &["--profile", "latency", "foo.com"],
+            Args::Client(ClientArgs {
+                options: ClientOptions { profile: Some(Profile::Latency), .. },
+                ..
+            })
+        );
+        assert_arg_parse!(
+            &["foo.com", "--profile", "throughput"],
+            Args::Client(ClientArgs {
+                options: ClientOptions {
+                    profile: Some(Profile::Throughput),
+                    ..
+                },
+                ..
+            })
+        );
+        assert_arg_parse!(&["foo.com", "--profile"], Args::Error);
+        assert_arg_parse!(&["foo.com", "--profile", "fast"], Args::Error);
+        assert_arg_parse!(&["--profile", "latency"], Args::Error);
+        assert_arg_parse!(&["foo.com", "--what"], Args::Error);
+    }
+
+    #[test]
+    fn client_multiplex() {
+        assert_arg_parse!(
+            &["--multiplex", "foo.com"],
+            Args::Client(ClientArgs {
+                options: ClientOptions { multiplex: true, .. },
+                ..
+            })
+        );
+    }
+
+    #[test]
+    fn client_ssh_connections() {
+        assert_arg_parse!(
+            &["--ssh-connections", "4", "foo.com"],
+            Args::Client(ClientArgs {
+                options: ClientOptions { ssh_connections: Some(4), .. },
+                ..
+            })
+        );
+        assert_arg_parse!(&["foo.com", "--ssh-connections", "0"], Args::Error);
+        assert_arg_parse!(&["foo.com", "--ssh-connections", "x"], Args::Error);
+        assert_arg_parse!(&["foo.com", "--ssh-connections"], Args::Error);
+    }
+
+    #[test]
+    fn client_bootstrap() {
+        assert_arg_parse!(
+            &["foo.com", "--bootstrap"],
+            Args::Client(ClientArgs {
+                options: ClientOptions { bootstrap: true, .. },
+                ..
+            })
+        );
+    }
+
+    #[test]
+    fn client_approve() {
+        assert_arg_parse!(
+            &["foo.com", "--approve"],
+            Args::Client(ClientArgs {
+                options: ClientOptions { approve: true, .. },
+                ..
+            })
+        );
+    }
+
+    #[test]
+    fn client_notify() {
+        assert_arg_parse!(
+            &["--notify", "foo.com"],
+            Args::Client(ClientArgs {
+                options: ClientOptions { notify: true, .. },
+                ..
+            })
+        );
+    }
+
+    #[test]
+    fn client_idle_exit() {
+        assert_arg_parse!(
+            &["--idle-exit", "2h", "foo.com"],
+            Args::Client(ClientArgs {
+                options: ClientOptions { idle_exit: Some(idle_exit), .. },
+                ..
+            }) if idle_exit == Duration::from_secs(7200)
+        );
+        assert_arg_parse!(&["foo.com", "--idle-exit", "soon"], Args::Error);
+        assert_arg_parse!(&["foo.com", "--idle-exit"], Args::Error);
+    }
+
+    #[test]
+    fn client_daemon() {
+        assert_arg_parse!(
+            &["--daemon", "foo.com"],
+            Args::Client(ClientArgs {
+                options: ClientOptions { daemon: true, .. },
+                ..
+            })
+        );
+    }
+
+    #[test]
+    fn client_x11() {
+        assert_arg_parse!(
+            &["--x11", "foo.com"],
+            Args::Client(ClientArgs {
+                options: ClientOptions { x11: true, .. },
+                ..
+            })
+        );
+        assert_arg_parse!(
+            &["foo.com"],
+            Args::Client(ClientArgs {
+                options: ClientOptions { x11: false, .. },
+                ..
+            })
+        );
+    }
+
+    #[test]
+    fn client_reverse() {
+        assert_arg_parse!(
+            &["--reverse", "3000", "foo.com", "--reverse", "4000"],
+            Args::Client(ClientArgs {
+                options: ClientOptions { reverse, .. },
+                ..
+            }) if reverse == vec![3000, 4000]
+        );
+        assert_arg_parse!(&["foo.com", "--reverse"], Args::Error);
+        assert_arg_parse!(&["foo.com", "--reverse", "http"], Args::Error);
+    }
+
+    #[test]
+    fn client_ssh_args() {
+        assert_arg_parse!(
+            &["-p", "2222", "foo.com", "-J", "bastion", "-o", "Port=22"],
+            Args::Client(ClientArgs {
+                options: ClientOptions { ssh_args, .. },
+                ..
+            }) if ssh_args == ["-p", "2222", "-J", "bastion", "-o", "Port=22"]
+        );
+        assert_arg_parse!(
+            &["-i", "~/.ssh/other", "foo.com"],
+            Args::Client(ClientArgs {
+                options: ClientOptions { ssh_args, .. },
+                ..
+            }) if ssh_args == ["-i", "~/.ssh/other"]
+        );
+        assert_arg_parse!(&["foo.com", "-p", "ssh"], Args::Error);
+        assert_arg_parse!(&["foo.com", "-o"], Args::Error);
+    }
+
+    #[test]
+    fn client_limit() {
+        assert_arg_parse!(
+            &["--limit", "8080=1MBps", "foo.com", "--limit", "22=10KB"],
+            Args::Client(ClientArgs { options: ClientOptions { limits, .. }, .. })
+                if limits == [(8080, 1_000_000), (22, 10_000)]
+        );
+        assert_arg_parse!(&["foo.com", "--limit"], Args::Error);
+        assert_arg_parse!(&["foo.com", "--limit", "8080"], Args::Error);
+        assert_arg_parse!(&["foo.com", "--limit", "8080=fast"], Args::Error);
+        assert_arg_parse!(&["foo.com", "--limit", "http=1MBps"], Args::Error);
+    }
+
+    #[test]
+    fn client_ports() {
+        assert_arg_parse!(
+            &["--port", "5432", "foo.com", "--port", "8080"],
+            Args::Client(ClientArgs { options: ClientOptions { ports, .. }, .. })
+                if ports == [5432, 8080]
+        );
+        assert_arg_parse!(&["foo.com", "--port"], Args::Error);
+        assert_arg_parse!(&["foo.com", "--port", "http"], Args::Error);
+
+        assert_arg_parse!(
+            &["--port", "db.internal:5432", "foo.com", "--port", "[fd00::1]:6379"],
+            Args::Client(ClientArgs { options: ClientOptions { ports, hosts, .. }, .. })
+                if ports == [5432, 6379]
+                    && hosts == [
+                        (5432, "db.internal".to_string()),
+                        (6379, "fd00::1".to_string()),
+                    ]
+        );
+        assert_arg_parse!(&["foo.com", "--port", ":5432"], Args::Error);
+        assert_arg_parse!(&["foo.com", "--port", "db.internal:"], Args::Error);
+    }
+
+    #[test]
+    fn client_socks() {
+        assert_arg_parse!(
+            &["--socks", "1080", "foo.com"],
+            Args::Client(ClientArgs {
+                options: ClientOptions { socks: Some(1080), .. },
+                ..
+            })
+        );
+        assert_arg_parse!(&["foo.com", "--socks"], Args::Error);
+        assert_arg_parse!(&["foo.com", "--socks", "99999"], Args::Error);
+    }
+
+    #[test]
+    fn client_bind() {
+        assert_arg_parse!(
+            &["--bind", "0.0.0.0", "foo.com"],
+            Args::Client(ClientArgs {
+                options: ClientOptions { bind: Some(address), .. },
+                ..
+            }) if address.is_unspecified()
+        );
+        assert_arg_parse!(
+            &["foo.com", "--bind", "::1"],
+            Args::Client(ClientArgs {
+                options: ClientOptions { bind: Some(address), .. },
+                ..
+            }) if address.is_ipv6()
+        );
+        assert_arg_parse!(&["foo.com", "--bind"], Args::Error);
+        assert_arg_parse!(&["foo.com", "--bind", "localhost"], Args::Error);
+    }
+
+    #[test]
+    fn client_output() {
+        assert_arg_parse!(
+            &["--output", "json", "foo.com"],
+            Args::Client(ClientArgs {
+                options: ClientOptions { output: Output::Json, .. },
+                ..
+            })
+        );
+        assert_arg_parse!(
+            &["foo.com"],
+            Args::Client(ClientArgs {
+                options: ClientOptions { output: Output::Tui, .. },
+                ..
+            })
+        );
+        assert_arg_parse!(&["foo.com", "--output"], Args::Error);
+        assert_arg_parse!(&["foo.com", "--output", "xml"], Args::Error);
+    }
+
+    #[test]
+    fn client_verbosity() {
+        assert_arg_parse!(
+            &["foo.com"],
+            Args::Client(ClientArgs {
+                options: ClientOptions { verbosity: 0, .. },
+                ..
+            })
+        );
+        assert_arg_parse!(
+            &["-v", "foo.com"],
+            Args::Client(ClientArgs {
+                options: ClientOptions { verbosity: 1, .. },
+                ..
+            })
+        );
+        assert_arg_parse!(
+            &["-vv", "foo.com"],
+            Args::Client(ClientArgs {
+                options: ClientOptions { verbosity: 2, .. },
+                ..
+            })
+        );
+        assert_arg_parse!(&["-vvv", "foo.com"], Args::Error);
+    }
+
+    #[test]
+    fn client_map() {
+        assert_arg_parse!(
+            &["--map", "8080:18080", "foo.com", "--map", "22:2222"],
+            Args::Client(ClientArgs {
+                options: ClientOptions { map, .. },
+                ..
+            }) if map == vec![(8080, 18080), (22, 2222)]
+        );
+        assert_arg_parse!(&["foo.com", "--map"], Args::Error);
+        assert_arg_parse!(&["foo.com", "--map", "8080"], Args::Error);
+        assert_arg_parse!(&["foo.com", "--map", "8080:x"], Args::Error);
+    }
+
     #[test]
     fn server() {
-        assert_arg_parse!(&["--server"], Args::Server);
+        assert_arg_parse!(&["--server"], Args::Server(_));
+        assert_arg_parse!(
+            &["--server", "--allow", "3000-4000", "--allow", "8080", "--deny", "3306"],
+            Args::Server(ServerOptions { allow, deny })
+                if allow == [3000..=4000, 8080..=8080] && deny == [3306..=3306]
+        );
+        assert_arg_parse!(&["--server", "--allow"], Args::Error);
+        assert_arg_parse!(&["--server", "--allow", "ssh"], Args::Error);
+        assert_arg_parse!(&["--server", "--x11"], Args::Error);
+    }
+
+    #[test]
+    fn list() {
+        assert_arg_parse!(
+            &["list", "foo.com"],
+            Args::List(ClientArgs {
+                options: ClientOptions { output: Output::Tui, .. },
+                ..
+            })
+        );
+        assert_arg_parse!(
+            &["list", "--json", "-p", "2222", "foo.com"],
+            Args::List(ClientArgs {
+                options: ClientOptions { output: Output::Json, .. },
+                ..
+            })
+        );
+        assert_arg_parse!(&["list"], Args::Client(_));
+        assert_arg_parse!(&["list", "--json"], Args::Error);
+        assert_arg_parse!(&["list", "a", "b"], Args::Error);
+    }
+
+    #[test]
+    fn run() {
+        assert_arg_parse!(
+            &["run", "foo.com", "-p", "5432", "--", "psql", "-p", "5432"],
+            Args::Run(ClientArgs { ref remote, options: ClientOptions { ref ssh_args, .. } }, 5432, ref command)
+                if remote == "foo.com" && ssh_args.is_empty() && command == &["psql", "-p", "5432"]
+        );
+        assert_arg_parse!(
+            &["run", "-o", "Port=2222", "foo.com", "-p", "80", "--", "curl", "--help"],
+            Args::Run(ClientArgs { options: ClientOptions { ref ssh_args, .. }, .. }, 80, _)
+                if ssh_args == &["-o", "Port=2222"]
+        );
+        assert_arg_parse!(&["run", "foo.com", "-p", "80"], Args::Error);
+        assert_arg_parse!(&["run", "foo.com", "-p", "80", "--"], Args::Error);
+        assert_arg_parse!(&["run", "foo.com", "--", "ls"], Args::Error);
+        assert_arg_parse!(&["run", "-p", "80", "--", "ls"], Args::Error);
+        assert_arg_parse!(
+            &["run", "foo.com", "-p", "80", "-p", "81", "--", "ls"],
+            Args::Error
+        );
+        assert_arg_parse!(&["run", "foo.com", "--help"], Args::Help);
+    }
+
+    #[test]
+    fn stdio() {
+        assert_arg_parse!(
+            &["stdio", "bastion:22"],
+            Args::Stdio(ClientArgs { ref remote, .. }, None, 22)
+                if remote == "bastion"
+        );
+        assert_arg_parse!(
+            &["stdio", "-p", "2222", "me@bastion:inner.host:22"],
+            Args::Stdio(ClientArgs { ref remote, .. }, Some(ref host), 22)
+                if remote == "me@bastion" && host == "inner.host"
+        );
+        assert_arg_parse!(
+            &["stdio", "bastion:[::1]:22"],
+            Args::Stdio(_, Some(ref host), 22) if host == "::1"
+        );
+        assert_arg_parse!(&["stdio", "bastion"], Args::Error);
+        assert_arg_parse!(&["stdio", ":22"], Args::Error);
+        assert_arg_parse!(&["stdio", "bastion:ssh"], Args::Error);
+        assert_arg_parse!(&["stdio"], Args::Client(_));
+    }
+
+    #[test]
+    fn history() {
+        assert_arg_parse!(&["history", "foo.com"], Args::History(_, None));
+        assert_arg_parse!(
+            &["history", "foo.com", "8080"],
+            Args::History(_, Some(8080))
+        );
+        assert_arg_parse!(&["history", "foo.com", "http"], Args::Error);
+    }
+
+    #[test]
+    fn control() {
+        assert_arg_parse!(&["status"], Args::Control(None, Command::Status));
+        assert_arg_parse!(
+            &["status", "foo.com"],
+            Args::Control(Some(r), Command::Status) if r == "foo.com"
+        );
+        assert_arg_parse!(
+            &["ctl", "forward", "9000"],
+            Args::Control(None, Command::Forward(9000))
+        );
+        assert_arg_parse!(
+            &["ctl", "foo.com", "stop", "9000"],
+            Args::Control(Some(r), Command::Stop(9000)) if r == "foo.com"
+        );
+        assert_arg_parse!(
+            &["ctl", "foo.com", "allow", "9000"],
+            Args::Control(Some(r), Command::Allow(9000)) if r == "foo.com"
+        );
+        assert_arg_parse!(
+            &["ctl", "refresh"],
+            Args::Control(None, Command::Refresh)
+        );
+        assert_arg_parse!(
+            &["ctl", "connections"],
+            Args::Control(None, Command::Connections)
+        );
+        assert_arg_parse!(&["stop"], Args::Control(None, Command::Quit));
+        assert_arg_parse!(
+            &["stop", "foo.com"],
+            Args::Control(Some(r), Command::Quit) if r == "foo.com"
+        );
+        assert_arg_parse!(&["ctl", "forward"], Args::Error);
+        assert_arg_parse!(&["ctl", "foo.com", "reboot"], Args::Error);
+        assert_arg_parse!(&["status", "foo.com", "8080"], Args::Error);
+    }
+
+    #[test]
+    fn self_update() {
+        assert_arg_parse!(&["self-update"], Args::SelfUpdate);
+        assert_arg_parse!(&["self-update", "now"], Args::Error);
     }
 
     #[test]
     fn browse() {
         assert_arg_parse!(&["browse", "google.com"], Args::Browse(_));
+        assert_arg_parse!(&["open", "http://localhost:8080"], Args::Browse(_));
     }
 }
@@ -0,0 +1,384 @@
+use crate::logging::Filter;
+use anyhow::{bail, Result};
+use fwd_proto::{capabilities, get_header, has_capability};
+use log::warn;
+use std::collections::HashSet;
+use std::ops::RangeInclusive;
+use std::time::Duration;
+use toml::Value;
+
+/// How long we wait to hear anything at all from the client before we decide
+/// that it's gone. Clients refresh a couple of times a second, so this is
+/// very generous.
+pub const DEFAULT_KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How to run an external command that reports ports, for sites that know
+/// about services that a scan won't find (or won't describe well).
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiscoveryConfig {
+    /// The command to run, with `sh -c`.
+    pub command: String,
+    /// If true, the output of the command is the whole list of ports;
+    /// otherwise it's merged into the list we find ourselves.
+    pub replace: bool,
+}
+
+/// Server-side configuration, which lives in the `[server]` table of the
+/// same `~/.fwd` file the client uses.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ServerConfig {
+    pub discovery: Option<DiscoveryConfig>,
+    /// Whether to ask the Docker daemon about the ports that containers
+    /// publish, so that they're described by container instead of as
+    /// `docker-proxy`.
+    pub docker: bool,
+    /// Whether to talk to each port a little to find out what it is, like
+    /// HTTP or redis, and say so in its description.
+    pub probe: bool,
+    /// If there are any, the only ports we tell the client about.
+    pub allow: Vec<RangeInclusive<u16>>,
+    /// Ports we never tell the client about.
+    pub deny: Vec<RangeInclusive<u16>>,
+}
+
+impl ServerConfig {
+    /// Whether the client gets to hear about `port` at all, going by `allow`
+    /// and `deny`.
+    ///
+    /// That's all they decide. The client can still reach any port it
+    /// likes through ssh, with `--port`, a `host` or `--socks`, unless sshd's
+    /// `permitopen` keeps it out.
+    pub fn lists(&self, port: u16) -> bool {
+        (self.allow.is_empty() || self.allow.iter().any(|r| r.contains(&port)))
+            && !self.deny.iter().any(|r| r.contains(&port))
+    }
+}
+
+/// A port, like `8080`, or a range of them, like `3000-4000`.
+pub fn parse_port_range(range: &str) -> Result<RangeInclusive<u16>> {
+    let (start, end) = range.split_once('-').unwrap_or((range, range));
+    let (start, end): (u16, u16) = match (start.parse(), end.parse()) {
+        (Ok(start), Ok(end)) => (start, end),
+        _ => bail!("expected a port or a range like 3000-4000, got {range}"),
+    };
+    if start > end {
+        bail!("the range {range} is backwards");
+    }
+    Ok(start..=end)
+}
+
+/// Settings the client sent us with `Configure`, which only last as long as
+/// the connection does.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SessionSettings {
+    /// Whether to run the discovery command, if there is one.
+    pub discovery: bool,
+    /// Ports the client doesn't want to hear about at all.
+    pub ignore: HashSet<u16>,
+    /// Whether to send the Unix domain sockets along with the ports.
+    pub sockets: bool,
+    /// How long the client can be quiet before we give up on it.
+    pub keepalive_timeout: Duration,
+    /// Whether to send the list of ports whenever it changes, instead of
+    /// waiting for the client to ask.
+    pub push: bool,
+    /// Whether to leave out ports that other users are listening on.
+    pub only_mine: bool,
+    /// What the client wants to see in our log.
+    pub log: Filter,
+    /// Whether the client understands `Goodbye`.
+    pub goodbye: bool,
+    /// Whether to write a checksum after every message.
+    pub checksums: bool,
+    /// Whether to send only what changed in the list of ports.
+    pub delta: bool,
+    /// Whether to send the pid of the process on each port.
+    pub pids: bool,
+}
+
+impl Default for SessionSettings {
+    fn default() -> SessionSettings {
+        SessionSettings {
+            discovery: true,
+            ignore: HashSet::new(),
+            sockets: false,
+            keepalive_timeout: DEFAULT_KEEPALIVE_TIMEOUT,
+            push: false,
+            only_mine: false,
+            log: Filter::default(),
+            goodbye: false,
+            checksums: false,
+            delta: false,
+            pids: false,
+        }
+    }
+}
+
+impl SessionSettings {
+    /// Parse the settings from a `Configure` message. Anything we don't
+    /// understand is left at its default, so that newer clients can still
+    /// talk to us.
+    pub fn from_settings(settings: &[String]) -> SessionSettings {
+        let mut result = SessionSettings::default();
+        match get_header(settings, "discovery") {
+            None => (),
+            Some("true") => result.discovery = true,
+            Some("false") => result.discovery = false,
+            Some(v) => warn!("Ignoring invalid discovery setting {v}"),
+        }
+        match get_header(settings, "sockets") {
+            None => (),
+            Some("true") => result.sockets = true,
+            Some("false") => result.sockets = false,
+            Some(v) => warn!("Ignoring invalid sockets setting {v}"),
+        }
+        match get_header(settings, "push") {
+            None => (),
+            Some("true") => result.push = true,
+            Some("false") => result.push = false,
+            Some(v) => warn!("Ignoring invalid push setting {v}"),
+        }
+        match get_header(settings, "only_mine") {
+            None => (),
+            Some("true") => result.only_mine = true,
+            Some("false") => result.only_mine = false,
+            Some(v) => warn!("Ignoring invalid only_mine setting {v}"),
+        }
+        if let Some(ports) = get_header(settings, "ignore") {
+            for port in ports.split(',').filter(|p| !p.is_empty()) {
+                match port.parse() {
+                    Ok(port) => _ = result.ignore.insert(port),
+                    Err(_) => warn!("Ignoring invalid port to ignore {port}"),
+                }
+            }
+        }
+        if let Some(secs) = get_header(settings, "keepalive_timeout") {
+            match secs.parse() {
+                Ok(secs) if secs > 0 => {
+                    result.keepalive_timeout = Duration::from_secs(secs)
+                }
+                _ => warn!("Ignoring invalid keepalive timeout {secs}"),
+            }
+        }
+        if let Some(spec) = get_header(settings, "log") {
+            match Filter::default().with_directives(spec) {
+                Ok(filter) => result.log = filter,
+                Err(e) => warn!("Ignoring invalid log setting: {e:#}"),
+            }
+        }
+        result.goodbye = has_capability(settings, capabilities::GOODBYE);
+        result.checksums = has_capability(settings, capabilities::CHECKSUM);
+        result.delta = has_capability(settings, capabilities::DELTA);
+        result.pids = has_capability(settings, capabilities::PIDS);
+        result
+    }
+}
+
+pub fn load_config() -> Result<ServerConfig> {
+    use std::io::ErrorKind;
+
+    let mut home = match home::home_dir() {
+        Some(h) => h,
+        None => return Ok(ServerConfig::default()),
+    };
+    home.push(".fwd");
+
+    let contents = match std::fs::read_to_string(home) {
+        Ok(contents) => contents,
+        Err(e) => match e.kind() {
+            ErrorKind::NotFound => return Ok(ServerConfig::default()),
+            _ => return Err(e.into()),
+        },
+    };
+
+    parse_config(&contents.parse::<Value>()?)
+}
+
+fn parse_config(value: &Value) -> Result<ServerConfig> {
+    match value {
+        Value::Table(table) => match table.get("server") {
+            None => Ok(ServerConfig::default()),
+            Some(Value::Table(table)) => Ok(ServerConfig {
+                discovery: get_discovery(table)?,
+                docker: match table.get("docker") {
+                    None => false,
+                    Some(Value::Boolean(v)) => *v,
+                    Some(v) => bail!("expected true or false, got {:?}", v),
+                },
+                probe: match table.get("probe") {
+                    None => false,
+                    Some(Value::Boolean(v)) => *v,
+                    Some(v) => bail!("expected true or false, got {:?}", v),
+                },
+                allow: get_port_ranges(table, "allow")?,
+                deny: get_port_ranges(table, "deny")?,
+            }),
+            Some(v) => bail!("expected a table in the server key, got {:?}", v),
+        },
+        _ => bail!("top level must be a table"),
+    }
+}
+
+fn get_port_ranges(
+    table: &toml::value::Table,
+    key: &str,
+) -> Result<Vec<RangeInclusive<u16>>> {
+    let ranges = match table.get(key) {
+        None => return Ok(Vec::new()),
+        Some(Value::Array(ranges)) => ranges,
+        Some(v) => bail!("expected a list of ports for {key}, got {:?}", v),
+    };
+    let mut result = Vec::new();
+    for range in ranges {
+        result.push(match range {
+            Value::Integer(port) => match u16::try_from(*port) {
+                Ok(port) => port..=port,
+                Err(_) => bail!("{port} is not a valid port number"),
+            },
+            Value::String(range) => parse_port_range(range)?,
+            v => bail!("expected a port or a range of ports, got {:?}", v),
+        });
+    }
+    Ok(result)
+}
+
+fn get_discovery(
+    table: &toml::value::Table,
+) -> Result<Option<DiscoveryConfig>> {
+    match table.get("discovery") {
+        None => Ok(None),
+        Some(Value::Table(table)) => Ok(Some(DiscoveryConfig {
+            command: match table.get("command") {
+                Some(Value::String(command)) => command.clone(),
+                v => bail!("expected a command string, got {:?}", v),
+            },
+            replace: match table.get("replace") {
+                None => false,
+                Some(Value::Boolean(v)) => *v,
+                Some(v) => bail!("expected true or false, got {:?}", v),
+            },
+        })),
+        Some(v) => bail!("expected a table for discovery, got {:?}", v),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Result<ServerConfig> {
+        parse_config(&text.parse::<Value>().expect("Invalid TOML"))
+    }
+
+    #[test]
+    fn empty() {
+        assert_eq!(parse("").unwrap(), ServerConfig::default());
+        assert_eq!(parse("auto = false").unwrap(), ServerConfig::default());
+    }
+
+    #[test]
+    fn session_settings() {
+        let settings = |s: &[&str]| {
+            let s: Vec<String> = s.iter().map(|s| s.to_string()).collect();
+            SessionSettings::from_settings(&s)
+        };
+
+        assert_eq!(settings(&[]), SessionSettings::default());
+        assert!(!settings(&["discovery=false"]).discovery);
+        assert!(settings(&["discovery=maybe"]).discovery);
+        assert!(settings(&["sockets=true"]).sockets);
+        assert!(settings(&["push=true"]).push);
+        assert!(settings(&["only_mine=true"]).only_mine);
+        assert_eq!(
+            settings(&["ignore=22,x,5432", "future=thing"]).ignore,
+            HashSet::from([22, 5432])
+        );
+        assert_eq!(
+            settings(&["keepalive_timeout=5"]).keepalive_timeout,
+            Duration::from_secs(5)
+        );
+        assert_eq!(
+            settings(&["keepalive_timeout=0"]).keepalive_timeout,
+            DEFAULT_KEEPALIVE_TIMEOUT
+        );
+        assert_eq!(
+            settings(&["log=debug"]).log,
+            Filter::new(log::LevelFilter::Debug)
+        );
+        assert_eq!(settings(&["log=loud"]).log, Filter::default());
+        assert!(settings(&["capabilities=goodbye,teleport"]).goodbye);
+        assert!(!settings(&["capabilities=teleport"]).goodbye);
+        assert!(settings(&["capabilities=checksum"]).checksums);
+    }
+
+    #[test]
+    fn docker() {
+        assert!(!parse("[server]").unwrap().docker);
+        assert!(parse("[server]\ndocker = true").unwrap().docker);
+        assert!(parse("[server]\ndocker = 'yes'").is_err());
+    }
+
+    #[test]
+    fn probe() {
+        assert!(!parse("[server]").unwrap().probe);
+        assert!(parse("[server]\nprobe = true").unwrap().probe);
+        assert!(parse("[server]\nprobe = 1").is_err());
+    }
+
+    #[test]
+    fn allow_and_deny() {
+        let config = parse(
+            r#"
+            [server]
+            allow = ["3000-4000", 8080]
+            deny = [3306]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.allow, [3000..=4000, 8080..=8080]);
+        assert!(config.lists(3000));
+        assert!(config.lists(8080));
+        assert!(!config.lists(3306));
+        assert!(!config.lists(22));
+
+        let config = parse("[server]\ndeny = ['1-1023']").unwrap();
+        assert!(config.lists(8080));
+        assert!(!config.lists(22));
+
+        assert!(parse("[server]\nallow = 8080").is_err());
+        assert!(parse("[server]\nallow = [70000]").is_err());
+        assert!(parse("[server]\nallow = ['4000-3000']").is_err());
+        assert!(parse("[server]\nallow = ['http']").is_err());
+        assert_eq!(parse_port_range("22").unwrap(), 22..=22);
+    }
+
+    #[test]
+    fn discovery() {
+        let config = parse(
+            r#"
+            [server.discovery]
+            command = "consul-ports --json"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.discovery,
+            Some(DiscoveryConfig {
+                command: "consul-ports --json".to_string(),
+                replace: false
+            })
+        );
+
+        let config = parse(
+            r#"
+            [server.discovery]
+            command = "consul-ports --json"
+            replace = true
+            "#,
+        )
+        .unwrap();
+        assert!(config.discovery.unwrap().replace);
+
+        assert!(parse("[server.discovery]\nreplace = true").is_err());
+    }
+}
@@ -0,0 +1,147 @@
+use super::config::DiscoveryConfig;
+use anyhow::{bail, Context, Result};
+use fwd_proto::PortDesc;
+use std::collections::BTreeMap;
+use tokio::process::Command;
+use tokio::time::{timeout, Duration};
+
+/// How long we give the discovery command before we give up on it. We run
+/// it on every refresh, so it had better be quick.
+const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Parse the output of a discovery command, which is a JSON array of
+/// objects like `{"port": 8080, "desc": "my-service"}`. The description is
+/// optional.
+fn parse_output(output: &[u8]) -> Result<Vec<PortDesc>> {
+    use serde_json::Value;
+
+    let value: Value = serde_json::from_slice(output)
+        .context("discovery output is not valid JSON")?;
+    let entries = match value {
+        Value::Array(entries) => entries,
+        v => bail!("expected an array of ports, got {v}"),
+    };
+
+    let mut ports = Vec::with_capacity(entries.len());
+    for entry in entries {
+        let port = match entry.get("port").and_then(|p| p.as_u64()) {
+            Some(port) => port.try_into().context("port is out of range")?,
+            None => bail!("expected a port number in {entry}"),
+        };
+        let desc = match entry.get("desc") {
+            None => String::new(),
+            Some(Value::String(desc)) => desc.clone(),
+            Some(v) => bail!("expected a string description, got {v}"),
+        };
+        ports.push(PortDesc { port, desc, pid: None });
+    }
+    Ok(ports)
+}
+
+/// Combine the ports we found with the ones the discovery command found.
+/// Where both know about a port, the discovery command wins.
+pub fn merge(
+    scanned: Vec<PortDesc>,
+    discovered: Vec<PortDesc>,
+) -> Vec<PortDesc> {
+    let mut ports: BTreeMap<u16, PortDesc> =
+        scanned.into_iter().map(|p| (p.port, p)).collect();
+    for port in discovered {
+        ports.insert(port.port, port);
+    }
+    ports.into_values().collect()
+}
+
+async fn run(command: &str) -> Result<Vec<PortDesc>> {
+    let output = timeout(
+        DISCOVERY_TIMEOUT,
+        Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .kill_on_drop(true)
+            .output(),
+    )
+    .await
+    .context("discovery command timed out")?
+    .context("error running discovery command")?;
+    if !output.status.success() {
+        bail!(
+            "discovery command failed with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    parse_output(&output.stdout)
+}
+
+/// Apply the configured discovery command to the ports we scanned.
+pub async fn discover(
+    config: &DiscoveryConfig,
+    scanned: Vec<PortDesc>,
+) -> Result<Vec<PortDesc>> {
+    let discovered = run(&config.command).await?;
+    if config.replace {
+        Ok(discovered)
+    } else {
+        Ok(merge(scanned, discovered))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn port(port: u16, desc: &str) -> PortDesc {
+        PortDesc { port, desc: desc.to_string(), pid: None }
+    }
+
+    #[test]
+    fn parse() {
+        let ports =
+            parse_output(br#"[{"port": 8080, "desc": "api"}, {"port": 9090}]"#)
+                .unwrap();
+        assert_eq!(ports, vec![port(8080, "api"), port(9090, "")]);
+
+        assert!(parse_output(b"{}").is_err());
+        assert!(parse_output(br#"[{"port": 70000}]"#).is_err());
+        assert!(parse_output(br#"[{"desc": "no port"}]"#).is_err());
+        assert!(parse_output(b"garbage").is_err());
+    }
+
+    #[test]
+    fn merging() {
+        let merged = merge(
+            vec![port(22, "sshd"), port(8080, "java")],
+            vec![port(8080, "billing-api"), port(9000, "metrics")],
+        );
+        assert_eq!(
+            merged,
+            vec![
+                port(22, "sshd"),
+                port(8080, "billing-api"),
+                port(9000, "metrics")
+            ]
+        );
+    }
+
+    #[cfg(target_family = "unix")]
+    #[tokio::test]
+    async fn command() {
+        let config = DiscoveryConfig {
+            command: r#"echo '[{"port": 1234, "desc": "hooked"}]'"#.to_string(),
+            replace: false,
+        };
+        let ports = discover(&config, vec![port(22, "sshd")]).await.unwrap();
+        assert_eq!(ports, vec![port(22, "sshd"), port(1234, "hooked")]);
+
+        let config = DiscoveryConfig { replace: true, ..config };
+        let ports = discover(&config, vec![port(22, "sshd")]).await.unwrap();
+        assert_eq!(ports, vec![port(1234, "hooked")]);
+
+        let config = DiscoveryConfig {
+            command: "exit 3".to_string(),
+            replace: true,
+        };
+        assert!(discover(&config, vec![]).await.is_err());
+    }
+}
@@ -0,0 +1,169 @@
+use anyhow::{bail, Context, Result};
+use fwd_proto::PortDesc;
+use std::collections::BTreeMap;
+use tokio::time::{timeout, Duration};
+
+/// Where the Docker daemon listens unless `DOCKER_HOST` says otherwise.
+#[cfg(target_family = "unix")]
+const DEFAULT_SOCKET: &str = "/var/run/docker.sock";
+
+/// How long we wait for the Docker daemon to answer. A hung daemon shouldn't
+/// stop us from reporting everything else.
+const DOCKER_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Find the socket to talk to the daemon on. Only `unix://` hosts are
+/// supported; a daemon on TCP is somebody else's machine anyway.
+#[cfg(target_family = "unix")]
+fn socket_path() -> Result<String> {
+    match std::env::var("DOCKER_HOST") {
+        Err(_) => Ok(DEFAULT_SOCKET.to_string()),
+        Ok(host) => match host.strip_prefix("unix://") {
+            Some(path) => Ok(path.to_string()),
+            None => bail!("DOCKER_HOST {host} is not a unix socket"),
+        },
+    }
+}
+
+/// Ask the daemon for the running containers, with a plain HTTP/1.0 request
+/// so that the answer is never chunked.
+#[cfg(target_family = "unix")]
+async fn list_containers() -> Result<Vec<u8>> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let path = socket_path()?;
+    let mut socket = tokio::net::UnixStream::connect(&path)
+        .await
+        .with_context(|| format!("Error connecting to {path}"))?;
+    socket
+        .write_all(b"GET /containers/json HTTP/1.0\r\nHost: docker\r\n\r\n")
+        .await?;
+    let mut response = Vec::new();
+    socket.read_to_end(&mut response).await?;
+    Ok(response)
+}
+
+#[cfg(not(target_family = "unix"))]
+async fn list_containers() -> Result<Vec<u8>> {
+    bail!("Docker is only supported on unix sockets");
+}
+
+/// Get the body out of an HTTP response, as long as it was a success.
+fn response_body(response: &[u8]) -> Result<&[u8]> {
+    let split = match response.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(split) => split,
+        None => bail!("Docker sent an incomplete response"),
+    };
+    let head = String::from_utf8_lossy(&response[..split]);
+    let status = head.lines().next().unwrap_or("");
+    match status.split(' ').nth(1) {
+        Some("200") => Ok(&response[split + 4..]),
+        _ => bail!("Docker answered {status}"),
+    }
+}
+
+/// Parse the list of containers into the ports they publish on the host,
+/// described by the container's name and image. Ports published only on some
+/// other interface are left out, just like when we scan.
+fn parse_containers(body: &[u8]) -> Result<Vec<PortDesc>> {
+    use serde_json::Value;
+
+    let value: Value = serde_json::from_slice(body)
+        .context("Docker's list of containers is not valid JSON")?;
+    let containers = match value {
+        Value::Array(containers) => containers,
+        v => bail!("expected an array of containers, got {v}"),
+    };
+
+    let mut ports = BTreeMap::new();
+    for container in containers {
+        let name = container["Names"][0].as_str().unwrap_or("");
+        let name = name.trim_start_matches('/');
+        let image = container["Image"].as_str().unwrap_or("");
+        let Some(published) = container["Ports"].as_array() else {
+            continue;
+        };
+        for port in published {
+            if port["Type"] != "tcp" {
+                continue;
+            }
+            let Some(public) = port["PublicPort"].as_u64() else {
+                continue; // Exposed but not published.
+            };
+            let local = match port["IP"].as_str() {
+                None | Some("") => true,
+                Some(ip) => match ip.parse::<std::net::IpAddr>() {
+                    Ok(ip) => ip.is_loopback() || ip.is_unspecified(),
+                    Err(_) => false,
+                },
+            };
+            if let (true, Ok(public)) = (local, u16::try_from(public)) {
+                let desc = format!("docker: {name} ({image})");
+                ports
+                    .insert(public, PortDesc { port: public, desc, pid: None });
+            }
+        }
+    }
+    Ok(ports.into_values().collect())
+}
+
+/// Find the ports that running containers publish on this machine.
+pub async fn discover() -> Result<Vec<PortDesc>> {
+    let response = timeout(DOCKER_TIMEOUT, list_containers())
+        .await
+        .context("Docker took too long to answer")??;
+    parse_containers(response_body(&response)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn body() {
+        let response =
+            b"HTTP/1.0 200 OK\r\nContent-Type: application/json\r\n\r\n[]";
+        assert_eq!(response_body(response).unwrap(), b"[]");
+        assert!(response_body(b"HTTP/1.0 404 Not Found\r\n\r\n").is_err());
+        assert!(response_body(b"HTTP/1.0 200 OK\r\n").is_err());
+    }
+
+    #[test]
+    fn containers() {
+        let body = br#"[
+            {
+                "Names": ["/billing-db"],
+                "Image": "postgres:16",
+                "Ports": [
+                    {"IP": "0.0.0.0", "PrivatePort": 5432, "PublicPort": 15432, "Type": "tcp"},
+                    {"IP": "::", "PrivatePort": 5432, "PublicPort": 15432, "Type": "tcp"}
+                ]
+            },
+            {
+                "Names": ["/web"],
+                "Image": "nginx",
+                "Ports": [
+                    {"IP": "127.0.0.1", "PrivatePort": 80, "PublicPort": 8080, "Type": "tcp"},
+                    {"IP": "10.0.0.5", "PrivatePort": 443, "PublicPort": 8443, "Type": "tcp"},
+                    {"PrivatePort": 9000, "Type": "tcp"},
+                    {"IP": "0.0.0.0", "PrivatePort": 53, "PublicPort": 53, "Type": "udp"}
+                ]
+            }
+        ]"#;
+        assert_eq!(
+            parse_containers(body).unwrap(),
+            vec![
+                PortDesc {
+                    port: 8080,
+                    desc: "docker: web (nginx)".to_string(),
+                    pid: None
+                },
+                PortDesc {
+                    port: 15432,
+                    desc: "docker: billing-db (postgres:16)".to_string(),
+                    pid: None
+                },
+            ]
+        );
+        assert!(parse_containers(b"{}").is_err());
+    }
+}
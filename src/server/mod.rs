@@ -1,26 +1,271 @@
 use crate::browse::handle_browser_open;
-use crate::message::{Message, MessageReader, MessageWriter};
-use anyhow::Result;
-use log::{error, warn};
+use crate::logging::Filter;
+use anyhow::{bail, Result};
+use fwd_proto::{
+    capabilities, Message, MessageReader, MessageWriter, PortDesc,
+};
+use log::{debug, error, trace, warn, LevelFilter, Metadata, Record};
+use std::future::Future;
+use std::ops::RangeInclusive;
+use std::sync::RwLock;
 use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, BufWriter};
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, watch};
+use tokio::time::{timeout, Duration, Instant};
 
+mod config;
+mod discovery;
+mod docker;
+mod probe;
 mod refresh;
 
+pub use config::parse_port_range;
+use config::{ServerConfig, SessionSettings};
+use probe::Prober;
+
+/// What the server was told on its command line, like in a forced command in
+/// `authorized_keys`.
+///
+/// `allow` and `deny` only decide which ports clients are told about, not
+/// which they can connect to; see `ServerConfig::lists`.
+#[derive(Debug, Default, Clone)]
+pub struct ServerOptions {
+    /// Replaces the `allow` list from the configuration, if there are any.
+    pub allow: Vec<RangeInclusive<u16>>,
+    /// Added to the `deny` list from the configuration.
+    pub deny: Vec<RangeInclusive<u16>>,
+}
+
 // We drive writes through an mpsc queue, because we not only handle requests
 // and responses from the client (refresh ports and the like) but also need
 // to asynchronously send messages to the client (open this URL, etc).
 async fn write_driver<Writer: AsyncWrite + Unpin>(
     messages: &mut mpsc::Receiver<Message>,
     writer: &mut MessageWriter<Writer>,
-) -> () {
+    settings: watch::Receiver<SessionSettings>,
+) {
+    // Every list goes through here, pushed or asked for, so this is where
+    // we know what the client has been told.
+    let mut client_has: Option<Vec<PortDesc>> = None;
+    let mut client_sockets: Option<Vec<String>> = None;
+    while let Some(m) = messages.recv().await {
+        let (checksums, delta, pids) = {
+            let settings = settings.borrow();
+            (settings.checksums, settings.delta, settings.pids)
+        };
+        let m = if pids { m } else { without_pids(m) };
+        let m = match (m, &client_has) {
+            (Message::Ports(ports), Some(old)) if delta => {
+                let changes = port_changes(old, &ports);
+                client_has = Some(ports.clone());
+                // A new list is shorter than a lot of changes.
+                if changes.len() < ports.len() {
+                    changes
+                } else {
+                    vec![Message::Ports(ports)]
+                }
+            }
+            (Message::Ports(ports), _) => {
+                client_has = Some(ports.clone());
+                vec![Message::Ports(ports)]
+            }
+            // The sockets change much less often than we look at them.
+            (Message::Sockets(sockets), _) => {
+                if client_sockets.as_ref() == Some(&sockets) {
+                    vec![]
+                } else {
+                    client_sockets = Some(sockets.clone());
+                    vec![Message::Sockets(sockets)]
+                }
+            }
+            (m, _) => vec![m],
+        };
+        for m in m {
+            trace!("> {m:?}");
+            writer.set_checksums(checksums);
+            writer.write(m).await.expect("Failed to write the message")
+        }
+    }
+}
+
+/// `m` for a client that doesn't know about pids, which can't read a list
+/// that has them.
+fn without_pids(m: Message) -> Message {
+    let strip = |p: PortDesc| PortDesc { pid: None, ..p };
+    match m {
+        Message::Ports(ports) => {
+            Message::Ports(ports.into_iter().map(strip).collect())
+        }
+        Message::PortAdded(port) => Message::PortAdded(strip(port)),
+        m => m,
+    }
+}
+
+/// What to tell a client that has `old` so that it has `new`.
+fn port_changes(old: &[PortDesc], new: &[PortDesc]) -> Vec<Message> {
+    let mut changes: Vec<_> = old
+        .iter()
+        .filter(|o| !new.iter().any(|n| n.port == o.port))
+        .map(|o| Message::PortRemoved(o.port))
+        .collect();
+    changes.extend(
+        new.iter()
+            .filter(|n| !old.contains(n))
+            .map(|n| Message::PortAdded(n.clone())),
+    );
+    changes
+}
+
+// Scan for ports, fill in what Docker knows about them if we're asked to,
+// and then run them through the discovery command if there is one and the
+// client wants it. What's left, we probe if we're asked to.
+async fn get_ports(
+    config: &ServerConfig,
+    settings: &SessionSettings,
+    prober: &Prober,
+) -> Vec<PortDesc> {
+    let ports = match refresh::get_entries(settings.only_mine) {
+        Ok(ports) => ports,
+        Err(e) => {
+            error!("Error scanning: {:?}", e);
+            vec![]
+        }
+    };
+
+    let ports = if config.docker {
+        match docker::discover().await {
+            Ok(containers) => discovery::merge(ports, containers),
+            Err(e) => {
+                error!("Error asking Docker about containers: {:?}", e);
+                ports
+            }
+        }
+    } else {
+        ports
+    };
+
+    let mut ports = match &config.discovery {
+        Some(discovery) if settings.discovery => {
+            match discovery::discover(discovery, ports.clone()).await {
+                Ok(ports) => ports,
+                Err(e) => {
+                    error!("Error running discovery command: {:?}", e);
+                    ports
+                }
+            }
+        }
+        _ => ports,
+    };
+    ports.retain(|p| !settings.ignore.contains(&p.port));
+    ports.retain(|p| {
+        let listed = config.lists(p.port);
+        if !listed {
+            trace!("Not telling the client about port {}", p.port);
+        }
+        listed
+    });
+    debug!("Found {} port(s)", ports.len());
+    if config.probe {
+        prober.describe(ports).await
+    } else {
+        ports
+    }
+}
+
+/// The Unix domain sockets to tell the client about, if it wants to hear,
+/// which go out next to the ports but aren't among them.
+fn get_sockets(settings: &SessionSettings) -> Option<Message> {
+    if !settings.sockets {
+        return None;
+    }
+    match refresh::get_sockets(settings.only_mine) {
+        Ok(sockets) => Some(Message::Sockets(sockets)),
+        Err(e) => {
+            error!("Error scanning for Unix domain sockets: {:?}", e);
+            None
+        }
+    }
+}
+
+/// How often we ping the client, so that it knows we're still here even when
+/// it hasn't asked us for anything.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(5);
+
+async fn keepalive(writer: mpsc::Sender<Message>) -> Result<()> {
     loop {
-        match messages.recv().await {
-            Some(m) => {
-                writer.write(m).await.expect("Failed to write the message")
+        tokio::time::sleep(KEEPALIVE_INTERVAL).await;
+        writer.send(Message::Ping).await?;
+    }
+}
+
+/// The optional parts of the protocol that we have, for the `Hello`.
+const CAPABILITIES: &[&str] = &[
+    capabilities::PUSH,
+    capabilities::GOODBYE,
+    capabilities::CHECKSUM,
+    capabilities::DELTA,
+    capabilities::PIDS,
+];
+
+/// How often we look for changes to push to clients that asked for them.
+const PUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+// For clients that want to be told when the ports change rather than having
+// to keep asking, scan every so often and send the list when it's different
+// from the last one we sent.
+async fn push_ports(
+    config: &ServerConfig,
+    prober: &Prober,
+    mut settings: watch::Receiver<SessionSettings>,
+    writer: mpsc::Sender<Message>,
+) -> Result<()> {
+    let mut last_sent = None;
+    loop {
+        tokio::time::sleep(PUSH_INTERVAL).await;
+        if settings.has_changed()? {
+            last_sent = None; // The list might look different now.
+        }
+        let settings = settings.borrow_and_update().clone();
+        if !settings.push {
+            continue;
+        }
+
+        let ports = get_ports(config, &settings, prober).await;
+        if last_sent.as_ref() != Some(&ports) {
+            writer.send(Message::Ports(ports.clone())).await?;
+            last_sent = Some(ports);
+        }
+        if let Some(sockets) = get_sockets(&settings) {
+            writer.send(sockets).await?;
+        }
+    }
+}
+
+/// The shortest time we allow between two scans. Clients normally refresh
+/// every half second, so anything faster than this gets the results of the
+/// last scan instead of making us walk procfs again.
+const MIN_SCAN_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Remembers the last scan we did so that we can rate-limit scanning, no
+/// matter how often a client asks us to refresh.
+#[derive(Default)]
+struct PortCache {
+    last_scan: Option<(Instant, Vec<PortDesc>)>,
+}
+
+impl PortCache {
+    async fn get<F: Future<Output = Vec<PortDesc>>>(
+        &mut self,
+        scan: F,
+    ) -> Vec<PortDesc> {
+        if let Some((when, ports)) = &self.last_scan {
+            if when.elapsed() < MIN_SCAN_INTERVAL {
+                return ports.clone();
             }
-            None => break,
         }
+
+        let ports = scan.await;
+        self.last_scan = Some((Instant::now(), ports.clone()));
+        ports
     }
 }
 
@@ -28,40 +273,83 @@ async fn write_driver<Writer: AsyncWrite + Unpin>(
 async fn server_loop<Reader: AsyncRead + Unpin>(
     reader: &mut MessageReader<Reader>,
     writer: &mut mpsc::Sender<Message>,
+    config: &ServerConfig,
+    prober: &Prober,
+    settings_sender: watch::Sender<SessionSettings>,
 ) -> Result<()> {
     // The first message we send must be an announcement.
-    writer.send(Message::Hello(0, 2, vec![])).await?;
+    let mut headers = vec![
+        format!("version={}", env!("CARGO_PKG_VERSION")),
+        format!("capabilities={}", CAPABILITIES.join(",")),
+    ];
+    // With X11 forwarding, ssh only sets DISPLAY for us, so the client has
+    // to tell the user what it is for it to be any use in another shell.
+    if let Ok(display) = std::env::var("DISPLAY") {
+        headers.push(format!("display={display}"));
+    }
+    writer.send(Message::Hello(0, 5, headers)).await?;
 
+    let mut cache = PortCache::default();
+    let mut settings = SessionSettings::default();
     loop {
         use Message::*;
-        match reader.read().await? {
-            Ping => (),
+        let message =
+            match timeout(settings.keepalive_timeout, reader.read()).await {
+                // A newer client can say things we haven't heard of; the
+                // whole frame has been read, so we can carry on after it.
+                Ok(Err(fwd_proto::Error::Unknown(kind))) => {
+                    warn!("Ignoring a message of unknown type 0x{kind:02x}");
+                    continue;
+                }
+                Ok(message) => message?,
+                Err(_) => bail!(
+                    "Heard nothing from the client in {}s, giving up",
+                    settings.keepalive_timeout.as_secs()
+                ),
+            };
+        trace!("< {message:?}");
+        match message {
+            Ping => writer.send(Pong).await?,
             Refresh => {
-                let ports = match refresh::get_entries() {
-                    Ok(ports) => ports,
-                    Err(e) => {
-                        error!("Error scanning: {:?}", e);
-                        vec![]
-                    }
-                };
+                let scan = get_ports(config, &settings, prober);
+                let ports = cache.get(scan).await;
                 if let Err(e) = writer.send(Message::Ports(ports)).await {
                     // Writer has been closed for some reason, we can just
                     // quit.... I hope everything is OK?
                     warn!("Warning: Error sending: {:?}", e);
                 }
+                if let Some(sockets) = get_sockets(&settings) {
+                    _ = writer.send(sockets).await;
+                }
+            }
+            Goodbye(reason) => {
+                debug!("The client is going away: {reason}");
+                return Ok(());
+            }
+            Configure(new_settings) => {
+                settings = SessionSettings::from_settings(&new_settings);
+                LOGGER.set_filter(settings.log.clone());
+                if cfg!(target_os = "windows") && settings.only_mine {
+                    warn!("Can't tell whose ports are whose on Windows");
+                }
+                cache = PortCache::default(); // Scanned with the old settings.
+                _ = settings_sender.send(settings.clone());
             }
-            message => panic!("Unsupported: {:?}", message),
+            message => warn!("Ignoring a message we don't handle: {message:?}"),
         };
     }
 }
 
-// Run the various server loops.
+// Run the various server loops, until the client goes away or `shutdown`
+// says why we should.
 async fn server_main<
     In: AsyncRead + Unpin + Send,
     Out: AsyncWrite + Unpin + Send,
 >(
     stdin: In,
     stdout: Out,
+    config: ServerConfig,
+    shutdown: impl Future<Output = &'static str>,
 ) -> Result<()> {
     let reader = BufReader::new(stdin);
     let mut writer = BufWriter::new(stdout);
@@ -78,30 +366,161 @@ async fn server_main<
     let mut reader = MessageReader::new(reader);
 
     let browse_sender = sender.clone();
+    let keepalive_sender = sender.clone();
+    let push_sender = sender.clone();
+    let (settings_sender, settings) =
+        watch::channel(SessionSettings::default());
+    let session = settings.clone();
+    let prober = Prober::default();
 
-    tokio::select! {
-        _ = write_driver(&mut receiver, &mut writer) => Ok(()),
-        r = server_loop(&mut reader, &mut sender) => r,
-        r = handle_browser_open(browse_sender) => r,
+    let reason = tokio::select! {
+        _ = write_driver(&mut receiver, &mut writer, session.clone()) => {
+            return Ok(())
+        }
+        r = server_loop(
+            &mut reader, &mut sender, &config, &prober, settings_sender
+        ) => {
+            return r
+        }
+        r = handle_browser_open(browse_sender) => return r,
+        r = keepalive(keepalive_sender) => return r,
+        r = push_ports(&config, &prober, settings, push_sender) => return r,
+        reason = shutdown => reason,
+    };
+
+    // Let the client know that this isn't just the connection dropping, if
+    // it knows what that means.
+    warn!("Exiting: {reason}");
+    let (goodbye, checksums) = {
+        let session = session.borrow();
+        (session.goodbye, session.checksums)
+    };
+    if goodbye {
+        writer.set_checksums(checksums);
+        writer.write(Message::Goodbye(reason.to_string())).await?;
     }
+    Ok(())
 }
 
-pub async fn run_server() {
+/// Wait for a signal telling us to exit, like when the machine shuts down,
+/// and say what it was.
+async fn terminated() -> &'static str {
+    #[cfg(target_family = "unix")]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        if let Ok(mut terminate) = signal(SignalKind::terminate()) {
+            terminate.recv().await;
+            return "fwd was terminated";
+        }
+    }
+    std::future::pending().await
+}
+
+/// Our stderr goes back to the client, which shows it in its log, so that's
+/// where our log goes too.
+struct Logger {
+    filter: RwLock<Filter>,
+}
+
+static LOGGER: Logger = Logger {
+    filter: RwLock::new(Filter::new(LevelFilter::Info)),
+};
+
+impl Logger {
+    /// Show what `filter` allows, and what `FWD_LOG` on this side says on
+    /// top of that.
+    fn set_filter(&self, filter: Filter) {
+        let filter = filter.clone().with_env().unwrap_or(filter);
+        log::set_max_level(filter.max_level());
+        *self.filter.write().unwrap() = filter;
+    }
+}
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.filter.read().unwrap().enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!(
+                "{} {}: {}",
+                record.level(),
+                record.target(),
+                record.args()
+            );
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+fn load_config(options: ServerOptions) -> ServerConfig {
+    let mut config = match config::load_config() {
+        Ok(config) => config,
+        Err(e) => {
+            error!("Error loading configuration: {:?}", e);
+            ServerConfig::default()
+        }
+    };
+    // Whoever put these on the command line gets the last word over the
+    // user's own configuration.
+    if !options.allow.is_empty() {
+        config.allow = options.allow;
+    }
+    config.deny.extend(options.deny);
+    config
+}
+
+pub async fn run_server(options: ServerOptions) {
+    _ = log::set_logger(&LOGGER);
+    if let Err(e) = Filter::default().with_env() {
+        warn!("{e:#}");
+    }
+    LOGGER.set_filter(Filter::default());
+
+    let config = load_config(options);
     let stdin = tokio::io::stdin();
     let stdout = tokio::io::stdout();
-    if let Err(e) = server_main(stdin, stdout).await {
+    if let Err(e) = server_main(stdin, stdout, config, terminated()).await {
         error!("Error: {:?}", e);
+        // Don't wait around for stdin, which might never come back.
+        std::process::exit(1);
     }
 }
 
+/// Serve a client on `reader` and `writer`, the way `fwd --server` does on
+/// stdin and stdout, until the client goes away or `shutdown` finishes. In
+/// that case the client is told we're going, if it understands that. This
+/// is for running a server inside some other program, so it leaves logging
+/// to that program.
+pub async fn run_server_with_io<R, W>(
+    reader: R,
+    writer: W,
+    options: ServerOptions,
+    shutdown: impl Future<Output = ()>,
+) -> Result<()>
+where
+    R: AsyncRead + Unpin + Send,
+    W: AsyncWrite + Unpin + Send,
+{
+    let config = load_config(options);
+    let shutdown = async {
+        shutdown.await;
+        "fwd was shut down"
+    };
+    server_main(reader, writer, config, shutdown).await
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use assert_matches::assert_matches;
+    use std::future::pending;
     use tokio::io::{AsyncReadExt, DuplexStream};
 
     async fn sync(client_read: &mut DuplexStream) {
-        print!("[client] Waiting for server sync...\n");
+        println!("[client] Waiting for server sync...");
         for _ in 0..8 {
             let b = client_read
                 .read_u8()
@@ -111,9 +530,9 @@ mod tests {
         }
 
         let mut reader = MessageReader::new(client_read);
-        print!("[client] Reading first message...\n");
+        println!("[client] Reading first message...");
         let msg = reader.read().await.expect("Error reading first message");
-        assert_matches!(msg, Message::Hello(0, 2, _));
+        assert_matches!(msg, Message::Hello(0, 5, _));
     }
 
     #[tokio::test]
@@ -122,11 +541,352 @@ mod tests {
         let (mut client_read, server_write) = tokio::io::duplex(4096);
 
         tokio::spawn(async move {
-            server_main(server_read, server_write)
-                .await
-                .expect("Error in server!");
+            server_main(
+                server_read,
+                server_write,
+                ServerConfig::default(),
+                pending(),
+            )
+            .await
+            .expect("Error in server!");
+        });
+
+        sync(&mut client_read).await;
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn sockets() {
+        let dir = tempdir::TempDir::new("fwd-sockets").unwrap();
+        let path = dir.path().join("app.sock");
+        let _listener = tokio::net::UnixListener::bind(&path).unwrap();
+
+        let (server_read, client_write) = tokio::io::duplex(4096);
+        let (mut client_read, server_write) = tokio::io::duplex(4096);
+        tokio::spawn(async move {
+            let config = ServerConfig::default();
+            server_main(server_read, server_write, config, pending()).await
+        });
+        sync(&mut client_read).await;
+        let mut reader = MessageReader::new(client_read);
+        let mut writer = MessageWriter::new(client_write);
+
+        let settings = vec!["sockets=true".to_string()];
+        writer.write(Message::Configure(settings)).await.unwrap();
+        writer.write(Message::Refresh).await.unwrap();
+        assert_matches!(reader.read().await, Ok(Message::Ports(_)));
+        assert_matches!(
+            reader.read().await,
+            Ok(Message::Sockets(sockets))
+                if sockets.contains(&path.display().to_string())
+        );
+
+        // The same list again isn't news, but a new socket is.
+        writer.write(Message::Refresh).await.unwrap();
+        assert_matches!(reader.read().await, Ok(Message::Ports(_)));
+        let other = dir.path().join("other.sock");
+        let _other = tokio::net::UnixListener::bind(&other).unwrap();
+        writer.write(Message::Refresh).await.unwrap();
+        assert_matches!(reader.read().await, Ok(Message::Ports(_)));
+        assert_matches!(
+            reader.read().await,
+            Ok(Message::Sockets(sockets))
+                if sockets.contains(&other.display().to_string())
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn refresh_is_rate_limited() {
+        let mut cache = PortCache::default();
+        let mut scans = 0;
+        let scan = |scans: &mut i32| {
+            *scans += 1;
+            let port = PortDesc {
+                port: 8080,
+                desc: format!("scan {scans}"),
+                pid: None,
+            };
+            async move { vec![port] }
+        };
+
+        let first = cache.get(scan(&mut scans)).await;
+        let second = cache.get(scan(&mut scans)).await;
+        assert_eq!(first, second);
+
+        tokio::time::advance(MIN_SCAN_INTERVAL).await;
+        let third = cache.get(scan(&mut scans)).await;
+        assert_ne!(first, third);
+        assert_eq!(third[0].desc, "scan 3");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ping_and_timeout() {
+        let (server_read, client_write) = tokio::io::duplex(4096);
+        let (client_read, server_write) = tokio::io::duplex(4096);
+        let server = tokio::spawn(async move {
+            server_main(
+                server_read,
+                server_write,
+                ServerConfig::default(),
+                pending(),
+            )
+            .await
+        });
+
+        let mut client_read = client_read;
+        sync(&mut client_read).await;
+        let mut reader = MessageReader::new(client_read);
+        let mut writer = MessageWriter::new(client_write);
+        let start = Instant::now();
+        writer.write(Message::Ping).await.unwrap();
+        assert_matches!(reader.read().await, Ok(Message::Pong));
+
+        // The server pings us too.
+        assert_matches!(reader.read().await, Ok(Message::Ping));
+        assert_eq!(start.elapsed(), KEEPALIVE_INTERVAL);
+
+        // Now that we've gone quiet, the server should give up on us.
+        assert_matches!(server.await.unwrap(), Err(_));
+        assert_eq!(start.elapsed(), config::DEFAULT_KEEPALIVE_TIMEOUT);
+    }
+
+    #[tokio::test]
+    async fn goodbye() {
+        let (server_read, client_write) = tokio::io::duplex(4096);
+        let (mut client_read, server_write) = tokio::io::duplex(4096);
+        let server = tokio::spawn(async move {
+            server_main(
+                server_read,
+                server_write,
+                ServerConfig::default(),
+                pending(),
+            )
+            .await
+        });
+        sync(&mut client_read).await;
+
+        let mut writer = MessageWriter::new(client_write);
+        let goodbye = Message::Goodbye("quit".to_string());
+        writer.write(goodbye).await.unwrap();
+        assert_matches!(server.await.unwrap(), Ok(()));
+    }
+
+    #[tokio::test]
+    async fn unsupported_messages() {
+        let (server_read, client_write) = tokio::io::duplex(4096);
+        let (mut client_read, server_write) = tokio::io::duplex(4096);
+        tokio::spawn(async move {
+            server_main(
+                server_read,
+                server_write,
+                ServerConfig::default(),
+                pending(),
+            )
+            .await
+        });
+        sync(&mut client_read).await;
+        let mut reader = MessageReader::new(client_read);
+
+        // Neither a message we've never heard of, nor one that only we
+        // send, stops us from answering what comes after.
+        let mut client_write = client_write;
+        client_write
+            .write_all(&[0, 0, 0, 2, 0xfe, 0])
+            .await
+            .unwrap();
+        let mut writer = MessageWriter::new(client_write);
+        writer.write(Message::Ports(vec![])).await.unwrap();
+        writer.write(Message::Ping).await.unwrap();
+        assert_matches!(reader.read().await, Ok(Message::Pong));
+    }
+
+    #[tokio::test]
+    async fn shutdown() {
+        let (server_read, client_write) = tokio::io::duplex(4096);
+        let (mut client_read, server_write) = tokio::io::duplex(4096);
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(async move {
+            let shutdown = async {
+                _ = stopped.await;
+                "fwd was shut down"
+            };
+            server_main(
+                server_read,
+                server_write,
+                ServerConfig::default(),
+                shutdown,
+            )
+            .await
         });
+        sync(&mut client_read).await;
+        let mut reader = MessageReader::new(client_read);
+        let mut writer = MessageWriter::new(client_write);
+
+        let capabilities = format!("capabilities={}", capabilities::GOODBYE);
+        writer
+            .write(Message::Configure(vec![capabilities]))
+            .await
+            .unwrap();
+        writer.write(Message::Ping).await.unwrap();
+        assert_matches!(reader.read().await, Ok(Message::Pong));
 
+        _ = stop.send(());
+        assert_matches!(server.await.unwrap(), Ok(()));
+        assert_eq!(
+            reader.read().await.unwrap(),
+            Message::Goodbye("fwd was shut down".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn push() {
+        let dir = tempdir::TempDir::new("fwd-push").unwrap();
+        let path = dir.path().join("ports.json");
+        std::fs::write(&path, r#"[{"port": 8080}]"#).unwrap();
+        let config = ServerConfig {
+            discovery: Some(config::DiscoveryConfig {
+                command: format!("cat {}", path.display()),
+                replace: true,
+            }),
+            ..ServerConfig::default()
+        };
+
+        let (server_read, client_write) = tokio::io::duplex(4096);
+        let (mut client_read, server_write) = tokio::io::duplex(4096);
+        tokio::spawn(async move {
+            server_main(server_read, server_write, config, pending()).await
+        });
         sync(&mut client_read).await;
+        let mut reader = MessageReader::new(client_read);
+        let mut writer = MessageWriter::new(client_write);
+
+        let configure = Message::Configure(vec!["push=true".to_string()]);
+        writer.write(configure).await.unwrap();
+        let ports = |ports: &[u16]| {
+            let ports = ports.iter().map(|&port| PortDesc {
+                port,
+                desc: String::new(),
+                pid: None,
+            });
+            Message::Ports(ports.collect())
+        };
+        assert_eq!(reader.read().await.unwrap(), ports(&[8080]));
+
+        // We hear about changes without asking...
+        std::fs::write(&path, r#"[{"port": 8080}, {"port": 9090}]"#).unwrap();
+        assert_eq!(reader.read().await.unwrap(), ports(&[8080, 9090]));
+
+        // ...but not about things that stayed the same.
+        writer.write(Message::Ping).await.unwrap();
+        assert_matches!(reader.read().await, Ok(Message::Pong));
+    }
+
+    #[tokio::test]
+    async fn push_changes() {
+        let dir = tempdir::TempDir::new("fwd-delta").unwrap();
+        let path = dir.path().join("ports.json");
+        let list = r#"[{"port": 8080}, {"port": 8081}, {"port": 8082}]"#;
+        std::fs::write(&path, list).unwrap();
+        let config = ServerConfig {
+            discovery: Some(config::DiscoveryConfig {
+                command: format!("cat {}", path.display()),
+                replace: true,
+            }),
+            ..ServerConfig::default()
+        };
+
+        let (server_read, client_write) = tokio::io::duplex(4096);
+        let (mut client_read, server_write) = tokio::io::duplex(4096);
+        tokio::spawn(async move {
+            server_main(server_read, server_write, config, pending()).await
+        });
+        sync(&mut client_read).await;
+        let mut reader = MessageReader::new(client_read);
+        let mut writer = MessageWriter::new(client_write);
+
+        let configure = Message::Configure(vec![
+            "push=true".to_string(),
+            "capabilities=delta".to_string(),
+        ]);
+        writer.write(configure).await.unwrap();
+        assert_matches!(reader.read().await, Ok(Message::Ports(p)) if p.len() == 3);
+
+        // After the first list, only what changed...
+        let list = r#"[{"port": 8080}, {"port": 8082}, {"port": 9090}]"#;
+        std::fs::write(&path, list).unwrap();
+        assert_eq!(reader.read().await.unwrap(), Message::PortRemoved(8081));
+        assert_eq!(
+            reader.read().await.unwrap(),
+            Message::PortAdded(PortDesc {
+                port: 9090,
+                desc: String::new(),
+                pid: None
+            })
+        );
+
+        // ...unless most of it did.
+        std::fs::write(&path, r#"[{"port": 3000}]"#).unwrap();
+        assert_eq!(
+            reader.read().await.unwrap(),
+            Message::Ports(vec![PortDesc {
+                port: 3000,
+                desc: String::new(),
+                pid: None
+            }])
+        );
+    }
+
+    #[tokio::test]
+    async fn denied_ports_are_hidden() {
+        let dir = tempdir::TempDir::new("fwd-deny").unwrap();
+        let path = dir.path().join("ports.json");
+        std::fs::write(&path, r#"[{"port": 22}, {"port": 3000}]"#).unwrap();
+        let config = ServerConfig {
+            discovery: Some(config::DiscoveryConfig {
+                command: format!("cat {}", path.display()),
+                replace: true,
+            }),
+            deny: vec![1..=1023],
+            ..ServerConfig::default()
+        };
+
+        let settings = SessionSettings::default();
+        let ports = get_ports(&config, &settings, &Prober::default()).await;
+        assert_eq!(
+            ports,
+            [PortDesc {
+                port: 3000,
+                desc: String::new(),
+                pid: None
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn pids_only_if_asked() {
+        let (client_read, server_write) = tokio::io::duplex(4096);
+        let mut reader = MessageReader::new(client_read);
+        let (sender, mut messages) = mpsc::channel(4);
+        let (settings, receiver) = watch::channel(SessionSettings::default());
+        tokio::spawn(async move {
+            let mut writer = MessageWriter::new(server_write);
+            write_driver(&mut messages, &mut writer, receiver).await
+        });
+
+        let ports = vec![PortDesc {
+            port: 8080,
+            desc: "node".to_string(),
+            pid: Some(4242),
+        }];
+        sender.send(Message::Ports(ports.clone())).await.unwrap();
+        assert_matches!(
+            reader.read().await,
+            Ok(Message::Ports(p)) if p[0].pid.is_none() && p[0].desc == "node"
+        );
+
+        let settings_with_pids = vec!["capabilities=pids".to_string()];
+        _ = settings.send(SessionSettings::from_settings(&settings_with_pids));
+        sender.send(Message::Ports(ports.clone())).await.unwrap();
+        assert_eq!(reader.read().await.unwrap(), Message::Ports(ports));
     }
 }
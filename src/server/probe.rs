@@ -0,0 +1,282 @@
+use fwd_proto::PortDesc;
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::sync::Mutex;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::task::JoinSet;
+use tokio::time::{timeout, Duration};
+
+/// How long one probe gets to connect and hear back. Anything that's slower
+/// than this on the loopback is busy, and we're only being curious.
+const PROBE_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// The most we read of an answer; the interesting part is at the start.
+const MAX_ANSWER: usize = 4096;
+
+/// Works out what's listening on ports by talking to them a little, with
+/// `probe = true` in the server config. Each port is only probed once for
+/// as long as the same thing is listening on it, so scanning often doesn't
+/// mean poking at services often.
+#[derive(Debug, Default)]
+pub struct Prober {
+    /// What we found for each port and the description and pid it had then.
+    seen: Mutex<HashMap<Listener, Option<String>>>,
+}
+
+/// A port, and what we know about what's listening on it.
+type Listener = (u16, String, Option<u32>);
+
+impl Prober {
+    /// Add what we can find out about each of `ports` to its description.
+    pub async fn describe(&self, ports: Vec<PortDesc>) -> Vec<PortDesc> {
+        let key = |p: &PortDesc| (p.port, p.desc.clone(), p.pid);
+        let unknown: Vec<_> = {
+            let seen = self.seen.lock().unwrap();
+            ports
+                .iter()
+                .map(key)
+                .filter(|k| !seen.contains_key(k))
+                .collect()
+        };
+
+        let mut probes = JoinSet::new();
+        for key in unknown {
+            probes.spawn(async move {
+                let found = probe(key.0).await;
+                (key, found)
+            });
+        }
+        let mut found = Vec::new();
+        while let Some(result) = probes.join_next().await {
+            if let Ok(result) = result {
+                found.push(result);
+            }
+        }
+
+        let mut seen = self.seen.lock().unwrap();
+        seen.retain(|k, _| ports.iter().any(|p| key(p) == *k));
+        seen.extend(found);
+        ports
+            .into_iter()
+            .map(|p| match seen.get(&key(&p)) {
+                Some(Some(what)) if p.desc.is_empty() => {
+                    PortDesc { desc: what.clone(), ..p }
+                }
+                Some(Some(what)) => {
+                    let desc = format!("{} ({what})", p.desc);
+                    PortDesc { desc, ..p }
+                }
+                _ => p,
+            })
+            .collect()
+    }
+}
+
+/// Try each protocol we know in turn, on a new connection each time, and
+/// say what the first one that answers is.
+async fn probe(port: u16) -> Option<String> {
+    if let Some(answer) = ask(port, b"PING\r\n").await {
+        if let Some(version) = redis(&answer) {
+            return Some(match ask(port, REDIS_INFO).await {
+                Some(info) => redis_version(&info)
+                    .map_or(version, |v| format!("redis {v}")),
+                None => version,
+            });
+        }
+    }
+    let head = b"HEAD / HTTP/1.0\r\nHost: localhost\r\n\r\n";
+    if let Some(answer) = ask(port, head).await {
+        if let Some(http) = http(&answer) {
+            return Some(http);
+        }
+    }
+    if let Some(answer) = ask(port, &client_hello()).await {
+        if is_tls(&answer) {
+            return Some("TLS".to_string());
+        }
+    }
+    None
+}
+
+/// Connect to `port` on the loopback, send `request`, and return what comes
+/// back before the other end stops talking or we stop waiting.
+async fn ask(port: u16, request: &[u8]) -> Option<Vec<u8>> {
+    let exchange = async {
+        let mut socket = match TcpStream::connect((Ipv4Addr::LOCALHOST, port))
+            .await
+        {
+            Ok(socket) => socket,
+            Err(_) => TcpStream::connect((Ipv6Addr::LOCALHOST, port)).await?,
+        };
+        socket.write_all(request).await?;
+        let mut answer = vec![0; MAX_ANSWER];
+        let n = socket.read(&mut answer).await?;
+        answer.truncate(n);
+        std::io::Result::Ok(answer)
+    };
+    match timeout(PROBE_TIMEOUT, exchange).await {
+        Ok(Ok(answer)) if !answer.is_empty() => Some(answer),
+        _ => None,
+    }
+}
+
+const REDIS_INFO: &[u8] = b"*2\r\n$4\r\nINFO\r\n$6\r\nserver\r\n";
+
+/// Whether the answer to `PING` came from redis, which says `+PONG`, or
+/// that it wants a password first.
+fn redis(answer: &[u8]) -> Option<String> {
+    let redis = answer.starts_with(b"+PONG\r\n")
+        || answer.starts_with(b"-NOAUTH")
+        || answer.starts_with(b"-DENIED");
+    redis.then(|| "redis".to_string())
+}
+
+/// The major and minor version from the answer to `INFO server`.
+fn redis_version(info: &[u8]) -> Option<String> {
+    let info = String::from_utf8_lossy(info);
+    let version = info
+        .lines()
+        .find_map(|l| l.strip_prefix("redis_version:"))?;
+    let mut parts = version.trim().split('.');
+    Some(format!("{}.{}", parts.next()?, parts.next()?))
+}
+
+/// The HTTP version from the status line, and the name of the server if it
+/// says, like `HTTP/1.1 nginx`.
+fn http(answer: &[u8]) -> Option<String> {
+    let answer = String::from_utf8_lossy(answer);
+    let mut lines = answer.lines();
+    let version = lines.next()?.split(' ').next()?;
+    if !version.starts_with("HTTP/") {
+        return None;
+    }
+    let server = lines.take_while(|l| !l.is_empty()).find_map(|l| {
+        let (name, value) = l.split_once(':')?;
+        name.eq_ignore_ascii_case("server").then(|| value.trim())
+    });
+    // Just the product, like nginx for `nginx/1.25.3`.
+    match server.and_then(|s| s.split(['/', ' ']).next()) {
+        Some(server) if !server.is_empty() => {
+            Some(format!("{version} {server}"))
+        }
+        _ => Some(version.to_string()),
+    }
+}
+
+/// Whether the answer is a TLS record: a handshake, or an alert if it
+/// didn't like our hello. Either way it's TLS.
+fn is_tls(answer: &[u8]) -> bool {
+    answer.len() >= 3 && matches!(answer[0], 0x15 | 0x16) && answer[1] == 0x03
+}
+
+/// A TLS 1.2 ClientHello with the most common cipher suites, which is
+/// enough to get any TLS server to answer, if only to turn us down.
+fn client_hello() -> Vec<u8> {
+    let suites: &[u8] = &[
+        0x13, 0x01, 0x13, 0x02, 0xC0, 0x2B, 0xC0, 0x2F, 0xC0, 0x2C, 0xC0, 0x30,
+        0x00, 0x9C, 0x00, 0x2F,
+    ];
+    let mut hello = vec![0x03, 0x03]; // TLS 1.2
+    hello.extend_from_slice(&[0x42; 32]); // Random enough for a probe.
+    hello.push(0); // No session ID.
+    hello.extend_from_slice(&(suites.len() as u16).to_be_bytes());
+    hello.extend_from_slice(suites);
+    hello.extend_from_slice(&[0x01, 0x00]); // No compression.
+
+    let mut handshake = vec![0x01]; // ClientHello
+    handshake.extend_from_slice(&(hello.len() as u32).to_be_bytes()[1..]);
+    handshake.extend_from_slice(&hello);
+
+    let mut record = vec![0x16, 0x03, 0x01]; // Handshake, TLS 1.0 record
+    record.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+    record.extend_from_slice(&handshake);
+    record
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    // A server on a free port that answers every connection with `answer`,
+    // whatever it's sent.
+    async fn answering(answer: &'static [u8]) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let mut buffer = [0; 1024];
+                _ = socket.read(&mut buffer).await;
+                _ = socket.write_all(answer).await;
+            }
+        });
+        port
+    }
+
+    #[test]
+    fn answers() {
+        assert_eq!(redis(b"+PONG\r\n"), Some("redis".to_string()));
+        assert!(redis(b"-NOAUTH Authentication required.\r\n").is_some());
+        assert_eq!(redis(b"HTTP/1.1 400 Bad Request\r\n"), None);
+        let info = b"$100\r\n# Server\r\nredis_version:7.2.4\r\nredis_mode:standalone\r\n";
+        assert_eq!(redis_version(info), Some("7.2".to_string()));
+        assert_eq!(redis_version(b"-NOAUTH\r\n"), None);
+
+        let response = b"HTTP/1.1 200 OK\r\nserver: nginx/1.25.3\r\n\r\n";
+        assert_eq!(http(response), Some("HTTP/1.1 nginx".to_string()));
+        let response = b"HTTP/1.0 404 Not Found\r\nContent-Length: 0\r\n\r\n";
+        assert_eq!(http(response), Some("HTTP/1.0".to_string()));
+        assert_eq!(http(b"SSH-2.0-OpenSSH_9.6\r\n"), None);
+
+        assert!(is_tls(&[0x15, 0x03, 0x03, 0x00, 0x02, 0x02, 0x28]));
+        assert!(!is_tls(b"+PONG\r\n"));
+        let hello = client_hello();
+        assert_eq!(usize::from(hello[4]) + 5, hello.len());
+    }
+
+    #[tokio::test]
+    async fn probes() {
+        let redis = answering(b"+PONG\r\n").await;
+        let web =
+            answering(b"HTTP/1.1 400 Bad Request\r\nServer: Caddy\r\n\r\n")
+                .await;
+        let tls = answering(&[0x15, 0x03, 0x03, 0x00, 0x02, 0x02, 0x28]).await;
+        let ssh = answering(b"SSH-2.0-OpenSSH_9.6\r\n").await;
+        assert_eq!(probe(redis).await, Some("redis".to_string()));
+        assert_eq!(probe(web).await, Some("HTTP/1.1 Caddy".to_string()));
+        assert_eq!(probe(tls).await, Some("TLS".to_string()));
+        assert_eq!(probe(ssh).await, None);
+    }
+
+    #[tokio::test]
+    async fn described_once() {
+        let port = answering(b"HTTP/1.1 200 OK\r\n\r\n").await;
+        let prober = Prober::default();
+        let ports = vec![
+            PortDesc {
+                port,
+                desc: "node".to_string(),
+                pid: None,
+            },
+            PortDesc { port: 1, desc: String::new(), pid: None },
+        ];
+        let described = prober.describe(ports.clone()).await;
+        assert_eq!(described[0].desc, "node (HTTP/1.1)");
+        assert_eq!(described[1].desc, "");
+        assert_eq!(prober.seen.lock().unwrap().len(), 2);
+
+        // The same things on the same ports aren't asked again.
+        prober
+            .seen
+            .lock()
+            .unwrap()
+            .insert((1, String::new(), None), Some("x".into()));
+        let described = prober.describe(ports).await;
+        assert_eq!(described[1].desc, "x");
+
+        // And what's gone is forgotten.
+        prober.describe(vec![]).await;
+        assert!(prober.seen.lock().unwrap().is_empty());
+    }
+}
@@ -1,36 +1,77 @@
-use crate::message::PortDesc;
 use anyhow::Result;
+use fwd_proto::PortDesc;
 
-#[cfg(not(target_os = "linux"))]
-pub fn get_entries() -> Result<Vec<PortDesc>> {
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "windows",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "dragonfly"
+)))]
+pub fn get_entries(_only_mine: bool) -> Result<Vec<PortDesc>> {
     use anyhow::bail;
     bail!("Not supported on this operating system");
 }
 
+/// Find the ports that are listening on loopback (or everywhere), and what's
+/// listening on them. If `only_mine` is set, leave out the ones that belong
+/// to other users.
 #[cfg(target_os = "linux")]
-pub fn get_entries() -> Result<Vec<PortDesc>> {
+pub fn get_entries(only_mine: bool) -> Result<Vec<PortDesc>> {
     use procfs::process::FDTarget;
-    use std::collections::HashMap;
+    use std::collections::{HashMap, HashSet};
+
+    let all_procs: Vec<_> = procfs::process::all_processes()?
+        .flatten()
+        .filter(|p| p.is_alive()) // Ignore zombies.
+        .collect();
 
-    let all_procs = procfs::process::all_processes()?;
+    // Find all the fwd processes, so that we don't report any of our own
+    // sockets. If the client and server are on the same machine then the
+    // client's listeners and the ssh it spawned for the SOCKS proxy would
+    // show up here, and forwarding those just makes a mess.
+    let own_pid: i32 = std::process::id().try_into()?;
+    let own_exe = std::env::current_exe().ok();
+    let fwd_pids: HashSet<i32> = all_procs
+        .iter()
+        .filter(|p| {
+            p.pid() == own_pid || (own_exe.is_some() && p.exe().ok() == own_exe)
+        })
+        .map(|p| p.pid())
+        .collect();
+
+    let own_uid = users::get_current_uid();
 
     // build up a map between socket inodes and process stat info. Ignore any
     // error we encounter as it probably means we have no access to that
     // process or something.
-    let mut map: HashMap<u64, String> = HashMap::new();
-    for p in all_procs {
-        if let Ok(process) = p {
-            if !process.is_alive() {
-                continue; // Ignore zombies.
+    let mut map: HashMap<u64, (String, u32)> = HashMap::new();
+    for process in all_procs {
+        if fwd_pids.contains(&process.pid()) {
+            continue;
+        }
+        if only_mine && process.uid().ok() != Some(own_uid) {
+            continue;
+        }
+        let stat = process.stat().ok();
+        if let Some(stat) = &stat {
+            if fwd_pids.contains(&stat.ppid) {
+                continue; // Something we spawned, like ssh.
             }
+        }
 
-            if let (Ok(fds), Ok(cmd)) = (process.fd(), process.cmdline()) {
-                for fd in fds {
-                    if let Ok(fd) = fd {
-                        if let FDTarget::Socket(inode) = fd.target {
-                            map.insert(inode, cmd.join(" "));
-                        }
-                    }
+        if let (Ok(fds), Ok(cmd)) = (process.fd(), process.cmdline()) {
+            // Some processes blank out their command line; fall back to the
+            // name of the executable, the way ps does.
+            let cmd = match (cmd.is_empty(), &stat) {
+                (true, Some(stat)) => format!("[{}]", stat.comm),
+                _ => cmd.join(" "),
+            };
+            let pid = process.pid().try_into()?;
+            for fd in fds.flatten() {
+                if let FDTarget::Socket(inode) = fd.target {
+                    map.insert(inode, (cmd.clone(), pid));
                 }
             }
         }
@@ -49,12 +90,13 @@ pub fn get_entries() -> Result<Vec<PortDesc>> {
                 || tcp_entry.local_address.ip().is_unspecified())
             && !h.contains_key(&tcp_entry.local_address.port())
         {
-            if let Some(cmd) = map.get(&tcp_entry.inode) {
+            if let Some((cmd, pid)) = map.get(&tcp_entry.inode) {
                 h.insert(
                     tcp_entry.local_address.port(),
                     PortDesc {
                         port: tcp_entry.local_address.port(),
                         desc: cmd.clone(),
+                        pid: Some(*pid),
                     },
                 );
             }
@@ -63,3 +105,495 @@ pub fn get_entries() -> Result<Vec<PortDesc>> {
 
     Ok(h.into_values().collect())
 }
+
+/// Find the Unix domain sockets that something is listening on, by path. If
+/// `only_mine` is set, leave out the ones that belong to other users.
+#[cfg(target_os = "linux")]
+pub fn get_sockets(only_mine: bool) -> Result<Vec<String>> {
+    use std::os::unix::fs::MetadataExt;
+
+    let unix = std::fs::read_to_string("/proc/net/unix")?;
+    let own_uid = users::get_current_uid();
+    // Ours, for `fwd browse` and ssh's shared connections, would only make a
+    // mess, like our own ports.
+    let own_directory = crate::browse::socket_directory().ok();
+    let mut sockets = parse_proc_net_unix(&unix);
+    sockets.retain(|path| {
+        let path = std::path::Path::new(path);
+        if own_directory.as_ref().is_some_and(|d| path.starts_with(d)) {
+            return false;
+        }
+        // One that we can't see is one that ssh can't reach either.
+        match std::fs::metadata(path) {
+            Ok(metadata) => !only_mine || metadata.uid() == own_uid,
+            Err(_) => false,
+        }
+    });
+    Ok(sockets)
+}
+
+/// Only procfs lists Unix domain sockets by path, so elsewhere there are
+/// none to find; the ones in the configuration are still forwarded.
+#[cfg(not(target_os = "linux"))]
+pub fn get_sockets(_only_mine: bool) -> Result<Vec<String>> {
+    Ok(Vec::new())
+}
+
+/// On macOS there's no procfs, so ask `lsof` for the listening sockets and
+/// `ps` for the command lines of the processes that own them.
+#[cfg(target_os = "macos")]
+pub fn get_entries(only_mine: bool) -> Result<Vec<PortDesc>> {
+    use anyhow::Context;
+
+    // lsof exits with an error when it finds nothing, so only worry about
+    // what it printed.
+    let lsof = std::process::Command::new("lsof")
+        .args(["-nP", "-iTCP", "-sTCP:LISTEN", "-F", "pn"])
+        .output()
+        .context("Error running lsof")?;
+    let listeners = parse_lsof(&String::from_utf8_lossy(&lsof.stdout));
+    describe_listeners(listeners, only_mine)
+}
+
+/// The BSDs that have `sockstat` can tell us about listening sockets with it.
+/// (OpenBSD doesn't have it, and isn't supported yet.)
+#[cfg(any(
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "dragonfly"
+))]
+pub fn get_entries(only_mine: bool) -> Result<Vec<PortDesc>> {
+    use anyhow::Context;
+
+    let sockstat = std::process::Command::new("sockstat")
+        .args(["-4", "-6", "-l", "-P", "tcp"])
+        .output()
+        .context("Error running sockstat")?;
+    let listeners = parse_sockstat(&String::from_utf8_lossy(&sockstat.stdout));
+    describe_listeners(listeners, only_mine)
+}
+
+/// Turn pairs of (pid, port) into port descriptions, using `ps` for the
+/// command lines.
+#[cfg(any(
+    target_os = "macos",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "dragonfly"
+))]
+fn describe_listeners(
+    listeners: Vec<(u32, u16)>,
+    only_mine: bool,
+) -> Result<Vec<PortDesc>> {
+    use anyhow::Context;
+    use std::collections::BTreeMap;
+
+    let ps = std::process::Command::new("ps")
+        .args(["-axo", "pid=,ppid=,uid=,command="])
+        .output()
+        .context("Error running ps")?;
+    let processes = parse_ps(&String::from_utf8_lossy(&ps.stdout));
+
+    // Leave out our own sockets and those of anything we spawned, like on
+    // Linux.
+    let own_uid = users::get_current_uid();
+    let own_exe = std::env::current_exe().ok();
+    let fwd_pids =
+        fwd_processes(&processes, std::process::id(), own_exe.as_deref());
+
+    let mut ports = BTreeMap::new();
+    for (pid, port) in listeners {
+        if fwd_pids.contains(&pid) || ports.contains_key(&port) {
+            continue;
+        }
+        if let Some(p) = processes.iter().find(|p| p.pid == pid) {
+            if only_mine && p.uid != own_uid {
+                continue;
+            }
+            let desc = p.command.clone();
+            ports.insert(port, PortDesc { port, desc, pid: Some(pid) });
+        }
+    }
+    Ok(ports.into_values().collect())
+}
+
+#[cfg(any(
+    target_os = "macos",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "dragonfly",
+    test
+))]
+#[derive(Debug, PartialEq)]
+struct Process {
+    pid: u32,
+    ppid: u32,
+    uid: u32,
+    command: String,
+}
+
+/// Parse the output of `ps -o pid=,ppid=,uid=,command=`.
+#[cfg(any(
+    target_os = "macos",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "dragonfly",
+    test
+))]
+fn parse_ps(output: &str) -> Vec<Process> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let mut parts = line.split_whitespace();
+            let pid = parts.next()?.parse().ok()?;
+            let ppid = parts.next()?.parse().ok()?;
+            let uid = parts.next()?.parse().ok()?;
+            let command = parts.collect::<Vec<_>>().join(" ");
+            Some(Process { pid, ppid, uid, command })
+        })
+        .collect()
+}
+
+/// The pids of the fwd processes in `processes`, and of what they spawned.
+/// ps only gives us the command line, which has whatever path fwd was run
+/// by (often just `fwd`, from ssh), so copies of fwd are spotted by the
+/// name of the executable rather than by the path of ours.
+#[cfg(any(
+    target_os = "macos",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "dragonfly",
+    test
+))]
+fn fwd_processes(
+    processes: &[Process],
+    own_pid: u32,
+    own_exe: Option<&std::path::Path>,
+) -> std::collections::HashSet<u32> {
+    use std::path::Path;
+
+    let own_name = own_exe.and_then(Path::file_name);
+    let is_fwd = |p: &Process| {
+        let program = p.command.split(' ').next().map(Path::new);
+        p.pid == own_pid
+            || (own_name.is_some()
+                && program.and_then(Path::file_name) == own_name)
+    };
+    let fwd: std::collections::HashSet<u32> = processes
+        .iter()
+        .filter(|p| is_fwd(p))
+        .map(|p| p.pid)
+        .collect();
+    processes
+        .iter()
+        .filter(|p| fwd.contains(&p.pid) || fwd.contains(&p.ppid))
+        .map(|p| p.pid)
+        .collect()
+}
+
+/// Parse /proc/net/unix into the paths of the stream sockets that are
+/// listening, once each. Sockets in the abstract namespace have no path that
+/// ssh could forward, so they're left out.
+#[cfg(any(target_os = "linux", test))]
+fn parse_proc_net_unix(output: &str) -> Vec<String> {
+    const ACCEPTING: u32 = 0x10000; // __SO_ACCEPTCON, for listen()
+    const STREAM: u32 = 1; // SOCK_STREAM
+
+    let mut result = Vec::new();
+    for line in output.lines().skip(1) {
+        // Num RefCount Protocol Flags Type St Inode Path
+        let mut fields = line.split_whitespace();
+        let flags = fields.nth(3).map(|f| u32::from_str_radix(f, 16));
+        let kind = fields.next().map(|k| u32::from_str_radix(k, 16));
+        let path = fields.skip(2).collect::<Vec<_>>().join(" ");
+        if let (Some(Ok(flags)), Some(Ok(STREAM))) = (flags, kind) {
+            if flags & ACCEPTING != 0
+                && !path.is_empty()
+                && !path.starts_with('@')
+                && !result.contains(&path)
+            {
+                result.push(path);
+            }
+        }
+    }
+    result
+}
+
+/// Parse the output of `lsof -F pn` into pairs of (pid, port), keeping only
+/// the sockets listening on loopback or on every address.
+#[cfg(any(target_os = "macos", test))]
+fn parse_lsof(output: &str) -> Vec<(u32, u16)> {
+    let mut result = Vec::new();
+    let mut pid = None;
+    for line in output.lines() {
+        if let Some(p) = line.strip_prefix('p') {
+            pid = p.parse().ok();
+        } else if let (Some(name), Some(pid)) = (line.strip_prefix('n'), pid) {
+            if let Some((address, port)) = name.rsplit_once(':') {
+                if let (true, Ok(port)) = (is_local(address), port.parse()) {
+                    result.push((pid, port));
+                }
+            }
+        }
+    }
+    result
+}
+
+/// Parse the output of `sockstat -l` into pairs of (pid, port), keeping only
+/// the sockets listening on loopback or on every address. IPv6 addresses
+/// aren't in brackets here, but the port still comes after the last colon.
+#[cfg(any(
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "dragonfly",
+    test
+))]
+fn parse_sockstat(output: &str) -> Vec<(u32, u16)> {
+    let mut result = Vec::new();
+    for line in output.lines() {
+        // USER COMMAND PID FD PROTO LOCAL FOREIGN
+        let parts: Vec<_> = line.split_whitespace().collect();
+        if parts.len() < 7 || !parts[4].starts_with("tcp") {
+            continue;
+        }
+        let Some((address, port)) = parts[5].rsplit_once(':') else {
+            continue;
+        };
+        if let (true, Ok(port), Ok(pid)) =
+            (is_local(address), port.parse(), parts[2].parse())
+        {
+            result.push((pid, port));
+        }
+    }
+    result
+}
+
+/// On Windows, ask `netstat` for the listening sockets and `tasklist` for
+/// the names of the processes that own them. tasklist can't tell us who owns
+/// a process without being much slower, so `only_mine` isn't supported.
+#[cfg(target_os = "windows")]
+pub fn get_entries(_only_mine: bool) -> Result<Vec<PortDesc>> {
+    use anyhow::Context;
+    use std::collections::BTreeMap;
+    use std::process::Command;
+
+    let netstat = Command::new("netstat")
+        .args(["-ano", "-p", "TCP"])
+        .output()
+        .context("Error running netstat")?;
+    let tasklist = Command::new("tasklist")
+        .args(["/fo", "csv", "/nh"])
+        .output()
+        .context("Error running tasklist")?;
+    let names = parse_tasklist(&String::from_utf8_lossy(&tasklist.stdout));
+
+    // tasklist doesn't tell us who spawned what, so the best we can do to
+    // leave out our own sockets is to skip anything running as fwd.
+    let own_pid = std::process::id();
+    let own_name = std::env::current_exe()
+        .ok()
+        .and_then(|exe| Some(exe.file_name()?.to_string_lossy().to_string()));
+
+    let mut ports = BTreeMap::new();
+    let output = String::from_utf8_lossy(&netstat.stdout);
+    for (pid, port) in parse_netstat(&output) {
+        if pid == own_pid || ports.contains_key(&port) {
+            continue;
+        }
+        if let Some(name) = names.get(&pid) {
+            if Some(name) != own_name.as_ref() {
+                let desc = name.clone();
+                ports.insert(port, PortDesc { port, desc, pid: Some(pid) });
+            }
+        }
+    }
+    Ok(ports.into_values().collect())
+}
+
+/// Parse the output of `netstat -ano -p TCP` into pairs of (pid, port),
+/// keeping only the sockets listening on loopback or on every address.
+///
+/// The state column is translated into the local language, so we spot
+/// listening sockets by their lack of a remote port instead.
+#[cfg(any(target_os = "windows", test))]
+fn parse_netstat(output: &str) -> Vec<(u32, u16)> {
+    let mut result = Vec::new();
+    for line in output.lines() {
+        let parts: Vec<_> = line.split_whitespace().collect();
+        if parts.len() != 5 || parts[0] != "TCP" {
+            continue;
+        }
+        let (Some((address, port)), Some((_, "0"))) =
+            (parts[1].rsplit_once(':'), parts[2].rsplit_once(':'))
+        else {
+            continue;
+        };
+        if let (true, Ok(port), Ok(pid)) =
+            (is_local(address), port.parse(), parts[4].parse())
+        {
+            result.push((pid, port));
+        }
+    }
+    result
+}
+
+/// Parse the output of `tasklist /fo csv /nh` into a map from pid to the
+/// name of the program.
+#[cfg(any(target_os = "windows", test))]
+fn parse_tasklist(output: &str) -> std::collections::HashMap<u32, String> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split("\",\"");
+            let name = fields.next()?.strip_prefix('"')?;
+            let pid = fields.next()?.parse().ok()?;
+            Some((pid, name.to_string()))
+        })
+        .collect()
+}
+
+/// Whether a listening address, as the BSD tools (and Windows) print it, can
+/// be reached from loopback.
+#[cfg(any(
+    target_os = "macos",
+    target_os = "windows",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "dragonfly",
+    test
+))]
+fn is_local(address: &str) -> bool {
+    let address = address.trim_start_matches('[').trim_end_matches(']');
+    if address == "*" {
+        return true;
+    }
+    match address.parse::<std::net::IpAddr>() {
+        Ok(ip) => ip.is_loopback() || ip.is_unspecified(),
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lsof_output() {
+        let output = "p101\nf4\nn127.0.0.1:8080\nf5\nn[::1]:8080\n\
+                      p202\nf6\nn*:3000\nf7\nn10.0.0.5:22\nf8\nn[::]:5432\n";
+        assert_eq!(
+            parse_lsof(output),
+            vec![(101, 8080), (101, 8080), (202, 3000), (202, 5432)]
+        );
+    }
+
+    #[test]
+    fn ps_output() {
+        let output = "  101     1   501 node  server.js --port 8080\n  bogus\n";
+        assert_eq!(
+            parse_ps(output),
+            vec![Process {
+                pid: 101,
+                ppid: 1,
+                uid: 501,
+                command: "node server.js --port 8080".to_string()
+            }]
+        );
+    }
+
+    #[test]
+    fn fwd_processes_are_left_out() {
+        let output = "\
+    1     0     0 /sbin/launchd
+  200     1   501 fwd some.server
+  201   200   501 ssh -T -D 1080 some.server fwd --server
+  300     1   501 /Users/me/.cache/fwd/fwd --server
+  400     1   501 node server.js
+  401   400   501 /usr/local/bin/fwd-helper
+";
+        let processes = parse_ps(output);
+        let exe = std::path::Path::new("/Users/me/.cache/fwd/fwd");
+        let mut pids: Vec<_> = fwd_processes(&processes, 300, Some(exe))
+            .into_iter()
+            .collect();
+        pids.sort();
+        assert_eq!(pids, [200, 201, 300]);
+
+        // Without knowing our executable, we still leave out ourselves and
+        // what we spawned.
+        let pids = fwd_processes(&processes, 400, None);
+        assert_eq!(pids, [400, 401].into());
+    }
+
+    #[test]
+    fn sockstat_output() {
+        let output = "\
+USER     COMMAND    PID   FD PROTO  LOCAL ADDRESS         FOREIGN ADDRESS
+www      nginx      1234  6  tcp4   *:80                  *:*
+root     sshd       800   3  tcp6   ::1:22                *:*
+root     sshd       800   4  tcp4   10.0.0.5:22           *:*
+dev      node       4242  20 tcp46  *:3000                *:*
+";
+        assert_eq!(
+            parse_sockstat(output),
+            vec![(1234, 80), (800, 22), (4242, 3000)]
+        );
+    }
+
+    #[test]
+    fn netstat_output() {
+        let output = "
+Active Connections
+
+  Proto  Local Address          Foreign Address        State           PID
+  TCP    0.0.0.0:135            0.0.0.0:0              LISTENING       1032
+  TCP    127.0.0.1:8080         0.0.0.0:0              ABHÖREN         4242
+  TCP    127.0.0.1:8080         127.0.0.1:51234        ESTABLISHED     4242
+  TCP    192.168.1.5:139        0.0.0.0:0              LISTENING       4
+  TCP    [::1]:3000             [::]:0                 LISTENING       5150
+";
+        assert_eq!(
+            parse_netstat(output),
+            vec![(1032, 135), (4242, 8080), (5150, 3000)]
+        );
+    }
+
+    #[test]
+    fn tasklist_output() {
+        let output = "\"System\",\"4\",\"Services\",\"0\",\"148 K\"\n\
+                      \"node.exe\",\"4242\",\"Console\",\"1\",\"45,000 K\"\n";
+        let names = parse_tasklist(output);
+        assert_eq!(names.get(&4242).map(|n| n.as_str()), Some("node.exe"));
+        assert_eq!(names.get(&4).map(|n| n.as_str()), Some("System"));
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn own_sockets_excluded() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0")
+            .expect("Unable to bind listener");
+        let port = listener.local_addr().unwrap().port();
+
+        let entries = get_entries(false).expect("Unable to get entries");
+        assert!(entries.iter().all(|e| e.port != port));
+    }
+
+    #[test]
+    fn proc_net_unix_output() {
+        let output = "\
+Num       RefCount Protocol Flags    Type St Inode Path
+0000000000000000: 00000002 00000000 00010000 0001 01 21616 /run/docker.sock
+0000000000000000: 00000003 00000000 00000000 0001 03 33222 /run/docker.sock
+0000000000000000: 00000002 00000000 00010000 0001 01 21620 /run/docker.sock
+0000000000000000: 00000002 00000000 00010000 0001 01 17025 @/tmp/.X11-unix/X0
+0000000000000000: 00000002 00000000 00010000 0005 01 14511 /run/udev/control
+0000000000000000: 00000002 00000000 00000000 0002 01 13047 /run/systemd/notify
+0000000000000000: 00000002 00000000 00010000 0001 01 19921
+0000000000000000: 00000002 00000000 00010000 0001 01 19922 /tmp/my app.sock
+";
+        assert_eq!(
+            parse_proc_net_unix(output),
+            vec!["/run/docker.sock", "/tmp/my app.sock"]
+        );
+    }
+}
@@ -0,0 +1,234 @@
+use anyhow::{bail, Context, Result};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+const LATEST_RELEASE_URL: &str =
+    "https://api.github.com/repos/DeCarabas/fwd/releases/latest";
+const DOWNLOAD_URL: &str = "https://github.com/DeCarabas/fwd/releases/download";
+
+// These need to match the targets that the release workflow builds.
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+const TARGET: Option<&str> = Some("x86_64-unknown-linux-musl");
+#[cfg(all(target_os = "macos", target_arch = "x86_64"))]
+const TARGET: Option<&str> = Some("x86_64-apple-darwin");
+#[cfg(all(target_os = "windows", target_arch = "x86_64"))]
+const TARGET: Option<&str> = Some("x86_64-pc-windows-msvc");
+#[cfg(not(any(
+    all(target_os = "linux", target_arch = "x86_64"),
+    all(target_os = "macos", target_arch = "x86_64"),
+    all(target_os = "windows", target_arch = "x86_64"),
+)))]
+const TARGET: Option<&str> = None;
+
+#[cfg(target_family = "windows")]
+const ARCHIVE_EXTENSION: &str = "zip";
+#[cfg(not(target_family = "windows"))]
+const ARCHIVE_EXTENSION: &str = "tar.gz";
+
+/// Parse a version like `v1.2.3` or `1.2.3` into something comparable.
+fn parse_version(version: &str) -> Option<(u32, u32, u32)> {
+    let version = version.strip_prefix('v').unwrap_or(version);
+    let mut parts = version.split('.').map(|p| p.parse::<u32>().ok());
+    match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(Some(major)), Some(Some(minor)), Some(Some(patch)), None) => {
+            Some((major, minor, patch))
+        }
+        _ => None,
+    }
+}
+
+/// Checksum files are in the format written by `shasum -a 256`, which is the
+/// hex digest followed by the file name.
+fn parse_checksum(contents: &str) -> Option<&str> {
+    contents
+        .split_whitespace()
+        .next()
+        .filter(|c| c.len() == 64 && c.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+fn curl(url: &str, output: Option<&Path>) -> Result<Vec<u8>> {
+    let mut cmd = Command::new("curl");
+    cmd.arg("--silent")
+        .arg("--show-error")
+        .arg("--fail")
+        .arg("--location")
+        .arg(url);
+    if let Some(output) = output {
+        cmd.arg("--output").arg(output);
+    }
+
+    let result = cmd.output().context("Error running curl")?;
+    if !result.status.success() {
+        bail!(
+            "Error downloading {url}: {}",
+            String::from_utf8_lossy(&result.stderr).trim()
+        );
+    }
+    Ok(result.stdout)
+}
+
+fn latest_release() -> Result<String> {
+    let body = curl(LATEST_RELEASE_URL, None)?;
+    let release: serde_json::Value = serde_json::from_slice(&body)
+        .context("Error parsing the release information")?;
+    match release.get("tag_name") {
+        Some(serde_json::Value::String(tag)) => Ok(tag.clone()),
+        _ => bail!("The latest release does not have a tag"),
+    }
+}
+
+fn verify_checksum(archive: &Path, checksum: &Path) -> Result<()> {
+    let contents = std::fs::read_to_string(checksum)
+        .context("Error reading the checksum file")?;
+    let expected = match parse_checksum(&contents) {
+        Some(c) => c.to_lowercase(),
+        None => bail!("The checksum file is not valid"),
+    };
+
+    let data = std::fs::read(archive).context("Error reading the archive")?;
+    let actual: String = Sha256::digest(&data)
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect();
+    if actual != expected {
+        bail!("Checksum mismatch: expected {expected}, got {actual}");
+    }
+    Ok(())
+}
+
+/// Replace the binary at `target` with `source`. Both must be on the same
+/// filesystem for the rename to be atomic.
+fn replace_binary(source: &Path, target: &Path) -> Result<()> {
+    let staged = target.with_extension("update");
+    std::fs::copy(source, &staged)
+        .with_context(|| format!("Error copying to {}", staged.display()))?;
+    let permissions = std::fs::metadata(target)?.permissions();
+    std::fs::set_permissions(&staged, permissions)?;
+
+    // Windows won't let us replace a running executable, but it will let us
+    // rename it out of the way.
+    #[cfg(target_family = "windows")]
+    {
+        let old = target.with_extension("old");
+        _ = std::fs::remove_file(&old);
+        std::fs::rename(target, &old)?;
+    }
+
+    std::fs::rename(&staged, target)
+        .with_context(|| format!("Error replacing {}", target.display()))?;
+    Ok(())
+}
+
+fn update_from(tag: &str, target: &str, work_dir: &Path) -> Result<()> {
+    let staging = format!("fwd-{target}");
+    let archive_name = format!("{staging}.{ARCHIVE_EXTENSION}");
+    let archive = work_dir.join(&archive_name);
+    let checksum = work_dir.join(format!("{archive_name}.sha256"));
+
+    println!("Downloading {archive_name}...");
+    curl(
+        &format!("{DOWNLOAD_URL}/{tag}/{archive_name}"),
+        Some(&archive),
+    )?;
+    curl(
+        &format!("{DOWNLOAD_URL}/{tag}/{archive_name}.sha256"),
+        Some(&checksum),
+    )
+    .context("The release does not have a checksum, not updating")?;
+    verify_checksum(&archive, &checksum)?;
+
+    let status = Command::new("tar")
+        .arg("-xf")
+        .arg(&archive)
+        .arg("-C")
+        .arg(work_dir)
+        .status()
+        .context("Error running tar")?;
+    if !status.success() {
+        bail!("Error extracting {archive_name}");
+    }
+
+    let current = std::env::current_exe()?;
+    let extracted = work_dir.join(&staging);
+    let exe_name = match current.file_name() {
+        Some(name) => name.to_owned(),
+        None => bail!("Cannot tell where fwd is installed"),
+    };
+    replace_binary(&extracted.join(&exe_name), &current)?;
+
+    // Keep fwd-browse in sync, if it was installed alongside us.
+    let browse_name = PathBuf::from("fwd-browse")
+        .with_extension(std::env::consts::EXE_EXTENSION);
+    if let Some(dir) = current.parent() {
+        let browse = dir.join(&browse_name);
+        let new_browse = extracted.join(&browse_name);
+        if browse.exists() && new_browse.exists() {
+            replace_binary(&new_browse, &browse)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn self_update_impl() -> Result<()> {
+    let target = match TARGET {
+        Some(t) => t,
+        None => bail!("There are no releases for this platform"),
+    };
+
+    let tag = latest_release()?;
+    let (latest, current) = match (parse_version(&tag), parse_version(VERSION))
+    {
+        (Some(l), Some(c)) => (l, c),
+        _ => bail!("Cannot compare version {tag} with {VERSION}"),
+    };
+    if latest <= current {
+        println!("fwd {VERSION} is up to date");
+        return Ok(());
+    }
+
+    let mut work_dir = std::env::temp_dir();
+    work_dir.push(format!("fwd-update-{}", std::process::id()));
+    std::fs::create_dir_all(&work_dir)?;
+    let result = update_from(&tag, target, &work_dir);
+    _ = std::fs::remove_dir_all(&work_dir);
+    result?;
+
+    println!("Updated fwd {VERSION} to {tag}");
+    Ok(())
+}
+
+pub async fn self_update() {
+    if let Err(e) = self_update_impl() {
+        eprintln!("Error updating fwd: {e:#}");
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn versions() {
+        assert_eq!(parse_version("v0.5.0"), Some((0, 5, 0)));
+        assert_eq!(parse_version("1.22.333"), Some((1, 22, 333)));
+        assert_eq!(parse_version("v1.2"), None);
+        assert_eq!(parse_version("v1.2.3.4"), None);
+        assert_eq!(parse_version("v1.2.x"), None);
+        assert!(parse_version("v0.10.0") > parse_version("v0.9.1"));
+    }
+
+    #[test]
+    fn checksums() {
+        let digest = "a".repeat(64);
+        assert_eq!(
+            parse_checksum(&format!("{digest}  fwd.tar.gz\n")),
+            Some(&digest[..])
+        );
+        assert_eq!(parse_checksum("abc  fwd.tar.gz"), None);
+        assert_eq!(parse_checksum(""), None);
+    }
+}